@@ -0,0 +1,322 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+use digest::Digest;
+use digest::AmhNonce;
+
+pub const DEFAULT_BIN_LEN: Duration = Duration::from_secs(1);
+/// Default tolerance for clock skew and flight time between when the router
+/// logs an element and when the accumulator receives it.
+pub const DEFAULT_SKEW: Duration = Duration::from_millis(10);
+
+/// Digests of the elements received in each fixed-length time bin of an
+/// epoch, kept alongside the main accumulator. If the main accumulator is
+/// invalid, comparing the bins to the timestamps of the router logs narrows
+/// down when the misbehavior happened.
+///
+/// Bins are aligned to the UNIX epoch, so bins with the same index cover the
+/// same time range on the router and the accumulator. Only bins that
+/// received an element are stored. The router and the accumulator timestamp
+/// elements with their own clocks, so an element logged near the end of a bin
+/// may be received in the next bin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeBins {
+    bin_len: Duration,
+    nonce: AmhNonce,
+    bins: BTreeMap<u64, Digest>,
+}
+
+/// A bin whose received elements differ from the logged elements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinReport {
+    pub bin: u64,
+    /// The start of the bin since the UNIX epoch, inclusive.
+    pub start: Duration,
+    /// The end of the bin since the UNIX epoch, exclusive.
+    pub end: Duration,
+    pub logged: usize,
+    pub received: usize,
+    /// Elements logged in other mismatched bins within the skew of this bin,
+    /// which may have been received in this bin instead.
+    pub movable: usize,
+}
+
+impl BinReport {
+    /// Whether an element was received in the bin that was never logged.
+    /// A mismatched bin that received at least as many elements as were
+    /// logged received at least one element, or the surplus if larger, that
+    /// was not logged in the bin. Those are injected unless enough elements
+    /// of the neighbouring bins could have moved into this one. If fewer
+    /// elements were received than logged, the difference could be explained
+    /// by drops alone, so it is unknown.
+    pub fn injected(&self) -> bool {
+        self.received >= self.logged
+            && std::cmp::max(1, self.received - self.logged) > self.movable
+    }
+}
+
+impl TimeBins {
+    pub fn new(bin_len: Duration, seed: Option<u64>) -> Self {
+        assert!(!bin_len.is_zero());
+        let nonce = match seed {
+            Some(seed) => seed.to_be_bytes(),
+            None => Digest::new().nonce,
+        };
+        Self {
+            bin_len,
+            nonce,
+            bins: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        bincode::deserialize(bytes).unwrap()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn bin_len(&self) -> Duration {
+        self.bin_len
+    }
+
+    /// The bin that contains the timestamp since the UNIX epoch.
+    pub fn bin(&self, timestamp: Duration) -> u64 {
+        (timestamp.as_nanos() / self.bin_len.as_nanos()) as u64
+    }
+
+    /// The start of the bin since the UNIX epoch.
+    pub fn start(&self, bin: u64) -> Duration {
+        Duration::from_nanos((self.bin_len.as_nanos() * bin as u128) as u64)
+    }
+
+    /// Processes an element received at the timestamp since the UNIX epoch.
+    pub fn process(&mut self, timestamp: Duration, elem: &[u8]) {
+        let nonce = self.nonce;
+        self.bins.entry(self.bin(timestamp))
+            .or_insert_with(|| Digest::new_with_seed(nonce))
+            .add(elem);
+    }
+
+    pub fn reset(&mut self) {
+        self.bins.clear();
+    }
+
+    /// The number of bins that received an element.
+    pub fn num_bins(&self) -> usize {
+        self.bins.len()
+    }
+
+    /// The number of elements processed in all bins.
+    pub fn total(&self) -> usize {
+        self.bins.values().map(|digest| digest.count as usize).sum()
+    }
+
+    /// Compares the bins to the logged elements and the timestamps they were
+    /// logged at, and returns the bins where they differ in order. Elements
+    /// may be received up to `skew` before or after they were logged.
+    ///
+    /// Bisects over the bins: if the union of the received digests in a range
+    /// of bins equals the digest of the logged elements in the range, every
+    /// bin in the range is assumed to match. Otherwise both halves of the
+    /// range are checked. Logs usually match in most bins, so only a few
+    /// ranges need to be compared.
+    pub fn locate(
+        &self,
+        logs: &[(Duration, Vec<u8>)],
+        skew: Duration,
+    ) -> Vec<BinReport> {
+        let mut logged: BTreeMap<u64, Digest> = BTreeMap::new();
+        for (timestamp, elem) in logs {
+            logged.entry(self.bin(*timestamp))
+                .or_insert_with(|| Digest::new_with_seed(self.nonce))
+                .add(elem);
+        }
+        let mut bins = self.bins.keys().chain(logged.keys())
+            .copied()
+            .collect::<Vec<_>>();
+        bins.sort_unstable();
+        bins.dedup();
+
+        let mut reports = vec![];
+        let mut ranges = vec![&bins[..]];
+        while let Some(range) = ranges.pop() {
+            let received = self.union(&self.bins, range);
+            let logged = self.union(&logged, range);
+            if received.equals(&logged) {
+                continue;
+            }
+            if range.len() == 1 {
+                let bin = range[0];
+                reports.push(BinReport {
+                    bin,
+                    start: self.start(bin),
+                    end: self.start(bin + 1),
+                    logged: logged.count as usize,
+                    received: received.count as usize,
+                    movable: 0,
+                });
+            } else {
+                // Push the second half first to check the first half first.
+                let (first, second) = range.split_at(range.len() / 2);
+                ranges.push(second);
+                ranges.push(first);
+            }
+        }
+
+        // Only the elements of mismatched bins could have moved, since a bin
+        // that lost an element to its neighbour no longer matches either.
+        let mismatched = reports.iter()
+            .map(|report| report.bin)
+            .collect::<BTreeSet<_>>();
+        for report in &mut reports {
+            let lo = report.start.saturating_sub(skew);
+            let hi = report.end.saturating_add(skew);
+            report.movable = logs.iter()
+                .filter(|(timestamp, _)| *timestamp >= lo && *timestamp < hi)
+                .map(|(timestamp, _)| self.bin(*timestamp))
+                .filter(|bin| *bin != report.bin && mismatched.contains(bin))
+                .count();
+        }
+        reports
+    }
+
+    fn union(&self, digests: &BTreeMap<u64, Digest>, bins: &[u64]) -> Digest {
+        let mut union = Digest::new_with_seed(self.nonce);
+        for digest in bins.iter().filter_map(|bin| digests.get(bin)) {
+            union.union(digest);
+        }
+        union
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha12Rng;
+
+    const BIN_LEN: Duration = Duration::from_millis(100);
+
+    /// Elements logged every millisecond starting at 10 seconds.
+    fn gen_logs(n: usize, seed: u64) -> Vec<(Duration, Vec<u8>)> {
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        (0..n).map(|i| {
+            let timestamp = Duration::from_secs(10) + Duration::from_millis(i as u64);
+            (timestamp, (0..16).map(|_| rng.gen()).collect())
+        }).collect()
+    }
+
+    fn process_all(bins: &mut TimeBins, logs: &[(Duration, Vec<u8>)]) {
+        for (timestamp, elem) in logs {
+            bins.process(*timestamp, elem);
+        }
+    }
+
+    #[test]
+    fn test_bins() {
+        let bins = TimeBins::new(BIN_LEN, Some(111));
+        assert_eq!(bins.bin(Duration::from_millis(1050)), 10);
+        assert_eq!(bins.bin(Duration::from_millis(1100)), 11);
+        assert_eq!(bins.start(11), Duration::from_millis(1100));
+        let now = Duration::from_millis(1_700_000_000_050);
+        assert_eq!(bins.start(bins.bin(now)), Duration::from_secs(1_700_000_000));
+    }
+
+    #[test]
+    fn test_serialization() {
+        let logs = gen_logs(1000, 222);
+        let mut bins = TimeBins::new(BIN_LEN, Some(111));
+        process_all(&mut bins, &logs);
+        assert_eq!(bins.num_bins(), 10);
+        assert_eq!(bins.total(), 1000);
+        let bytes = bins.to_bytes();
+        let bins2 = TimeBins::from_bytes(&bytes);
+        assert_eq!(bins2.to_bytes(), bytes);
+        assert_eq!(bins2.bin_len(), BIN_LEN);
+        assert!(bins2.locate(&logs, Duration::ZERO).is_empty());
+        let mut bins3 = bins2.clone();
+        bins3.reset();
+        assert_eq!(bins3.total(), 0);
+    }
+
+    #[test]
+    fn test_locate_injected() {
+        let logs = gen_logs(1000, 222);
+        let mut bins = TimeBins::new(BIN_LEN, Some(111));
+        process_all(&mut bins, &logs);
+        let injected = gen_logs(1, 333).remove(0).1;
+        bins.process(Duration::from_millis(10_450), &injected);
+        let reports = bins.locate(&logs, Duration::ZERO);
+        assert_eq!(reports, vec![BinReport {
+            bin: 104,
+            start: Duration::from_millis(10_400),
+            end: Duration::from_millis(10_500),
+            logged: 100,
+            received: 101,
+            movable: 0,
+        }]);
+        assert!(reports[0].injected());
+    }
+
+    #[test]
+    fn test_locate_dropped_and_injected() {
+        let logs = gen_logs(1000, 222);
+        let mut bins = TimeBins::new(BIN_LEN, Some(111));
+        // Drop a packet in the second bin, and replace a packet with an
+        // injected packet in the eighth bin.
+        let injected = gen_logs(1, 333).remove(0).1;
+        for (i, (timestamp, elem)) in logs.iter().enumerate() {
+            if i == 150 {
+                continue;
+            } else if i == 720 {
+                bins.process(*timestamp, &injected);
+            } else {
+                bins.process(*timestamp, elem);
+            }
+        }
+        // An injected packet after the logs end.
+        bins.process(Duration::from_secs(20), &injected);
+        let reports = bins.locate(&logs, Duration::ZERO);
+        assert_eq!(reports.iter().map(|r| r.bin).collect::<Vec<_>>(),
+            vec![101, 107, 200]);
+        assert!(!reports[0].injected());
+        assert!(reports[1].injected());
+        assert_eq!((reports[2].logged, reports[2].received), (0, 1));
+    }
+
+    #[test]
+    fn test_locate_across_bin_boundary() {
+        // Every element is received 3ms after it was logged, so the last 3
+        // elements logged in each bin are received in the next bin, and one
+        // element is dropped.
+        let logs = gen_logs(1000, 222);
+        let latency = Duration::from_millis(3);
+        let mut bins = TimeBins::new(BIN_LEN, Some(111));
+        for (i, (timestamp, elem)) in logs[..997].iter().enumerate() {
+            if i != 450 {
+                bins.process(*timestamp + latency, elem);
+            }
+        }
+        let injected_bins = |bins: &TimeBins, skew| bins
+            .locate(&logs[..997], skew)
+            .into_iter()
+            .filter(|report| report.injected())
+            .map(|report| report.bin)
+            .collect::<Vec<_>>();
+        let skew = Duration::from_millis(5);
+        assert!(!bins.locate(&logs[..997], skew).is_empty());
+        assert_eq!(injected_bins(&bins, skew), vec![]);
+        // Without the tolerance, bins look like they received elements that
+        // were never logged.
+        assert!(!injected_bins(&bins, Duration::ZERO).is_empty());
+
+        // More injected elements than could have crossed the boundary.
+        for (_, elem) in gen_logs(20, 333) {
+            bins.process(Duration::from_millis(10_450), &elem);
+        }
+        assert_eq!(injected_bins(&bins, skew), vec![104]);
+    }
+}
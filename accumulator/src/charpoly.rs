@@ -0,0 +1,498 @@
+#[cfg(not(feature = "disable_validation"))]
+use std::ops::RangeInclusive;
+#[cfg(not(feature = "disable_validation"))]
+use std::time::Instant;
+#[cfg(not(feature = "disable_validation"))]
+use std::collections::{HashMap, HashSet};
+
+use serde::{Serialize, Deserialize};
+use crate::{Accumulator, ValidationOptions, ValidationResult};
+use crate::power_sum::{mul_and_mod, DJB_MASK, LARGE_PRIME_U32};
+#[cfg(not(feature = "disable_validation"))]
+use crate::executor::find_matching_combination;
+#[cfg(not(feature = "disable_validation"))]
+use crate::power_sum::find_roots_among;
+#[cfg(not(feature = "disable_validation"))]
+use crate::window::{WindowSums, WindowValidator};
+#[cfg(not(feature = "disable_validation"))]
+use crate::poly::{
+    evaluate, find_roots, inverse, solve_linear_system, sub_and_mod,
+};
+use bloom_sd::StrataEstimator;
+use digest::{AmhHash, Digest};
+#[cfg(not(feature = "disable_validation"))]
+use itertools::Itertools;
+
+pub const DEFAULT_MAX_INJECTED: usize = 4;
+/// Number of evaluation points beyond the unknown coefficients, which check
+/// that the interpolated rational function is the right one.
+const NUM_CHECK_POINTS: usize = 2;
+
+/// The i-th evaluation point. The points are above the range of the DJB
+/// hashes, so the characteristic polynomial is never zero at them.
+fn point(i: usize) -> u32 {
+    LARGE_PRIME_U32 - 1 - i as u32
+}
+
+/// The characteristic polynomial accumulator stores the digest and the
+/// values of the characteristic polynomial prod(z - x) of the processed
+/// elements at fixed points z, as in the set reconciliation protocol of
+/// Minsky and Trachtenberg.
+///
+/// On validation, the accumulator evaluates the characteristic polynomial of
+/// the given list of elements at the same points. The ratio of the two is a
+/// rational function whose numerator has the dropped elements as roots, and
+/// whose denominator has the injected elements as roots, i.e. elements that
+/// were processed but not logged. Since the difference in their degrees is
+/// the difference in the number of elements, the function is interpolated
+/// with the fewest injected elements that fit every point. The log is valid
+/// if nothing was injected and removing the dropped elements from the list
+/// reproduces the digest. Injected elements are reported by their DJB hash.
+///
+/// Validation is undetermined if the number of dropped and injected elements
+/// exceeds the threshold plus the maximum number of injected elements.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CharPolyAccumulator {
+    digest: Digest,
+    evals: Vec<u32>,
+    estimator: StrataEstimator,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MiniCharPolyAccumulator {
+    hash: AmhHash,       // [u8; HASH_SIZE]
+    count: u16,          // expect ~1024 = 2^10
+    seed: u64,           // seed for multiset hash
+    evals: Vec<u8>,      // DJB_HASH_SIZE bits per evaluation
+    estimator: StrataEstimator,
+}
+
+/// Multiplies the evaluations of a characteristic polynomial by (z - x).
+fn add_to_evals(evals: &mut [u32], elem_u32: u32) {
+    for (i, eval) in evals.iter_mut().enumerate() {
+        *eval = mul_and_mod(*eval, point(i) - elem_u32);
+    }
+}
+
+/// Interpolates the rational function P/Q with monic P of degree `n_dropped`
+/// and monic Q of degree `n_injected` from its values at the first points,
+/// and checks it against the rest. Returns the coefficients of P and Q,
+/// where the coefficient of z^i is at index i.
+#[cfg(not(feature = "disable_validation"))]
+fn interpolate(
+    ratios: &[u32],
+    n_dropped: usize,
+    n_injected: usize,
+) -> Option<(Vec<u32>, Vec<u32>)> {
+    // P(z) - f(z) Q(z) = 0, so the equation for each point is
+    // sum(p_j z^j) - f(z) sum(q_j z^j) = f(z) z^n_injected - z^n_dropped
+    let n = n_dropped + n_injected;
+    let mut matrix = Vec::with_capacity(n);
+    let mut rhs = Vec::with_capacity(n);
+    for (i, &ratio) in ratios.iter().enumerate().take(n) {
+        let z = point(i);
+        let mut row = Vec::with_capacity(n);
+        let mut power = 1;
+        for _ in 0..n_dropped {
+            row.push(power);
+            power = mul_and_mod(power, z);
+        }
+        let z_dropped = power;
+        let mut power = 1;
+        for _ in 0..n_injected {
+            row.push(sub_and_mod(0, mul_and_mod(ratio, power)));
+            power = mul_and_mod(power, z);
+        }
+        let z_injected = power;
+        rhs.push(sub_and_mod(mul_and_mod(ratio, z_injected), z_dropped));
+        matrix.push(row);
+    }
+    let solution = solve_linear_system(matrix, rhs)?;
+    let mut p = solution[..n_dropped].to_vec();
+    p.push(1);
+    let mut q = solution[n_dropped..].to_vec();
+    q.push(1);
+    for (i, &ratio) in ratios.iter().enumerate().skip(n) {
+        let z = point(i);
+        if evaluate(&p, z) != mul_and_mod(ratio, evaluate(&q, z)) {
+            return None;
+        }
+    }
+    Some((p, q))
+}
+
+impl CharPolyAccumulator {
+    /// Creates an accumulator that recovers up to `threshold` dropped and
+    /// `max_injected` injected elements, or more dropped elements if fewer
+    /// are injected.
+    pub fn new_with_params(
+        threshold: usize,
+        max_injected: usize,
+        seed: Option<u64>,
+    ) -> Self {
+        let digest = if let Some(seed) = seed {
+            Digest::new_with_seed(seed.to_be_bytes())
+        } else {
+            Digest::new()
+        };
+        let estimator =
+            StrataEstimator::new(u64::from_be_bytes(digest.nonce));
+        Self {
+            digest,
+            evals: vec![1; threshold + max_injected + NUM_CHECK_POINTS],
+            estimator,
+        }
+    }
+
+    pub fn new(threshold: usize, seed: Option<u64>) -> Self {
+        Self::new_with_params(threshold, DEFAULT_MAX_INJECTED, seed)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let x: MiniCharPolyAccumulator = bincode::deserialize(bytes).unwrap();
+        Self {
+            digest: Digest {
+                hash: x.hash,
+                count: x.count as u32,
+                nonce: x.seed.to_be_bytes(),
+            },
+            evals: x.evals
+                .chunks_exact(4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            estimator: x.estimator,
+        }
+    }
+
+    /// Checks the digest against the list of elements with the dropped
+    /// elements removed, trying every choice of elements with the same DJB
+    /// hash as a dropped element.
+    #[cfg(not(feature = "disable_validation"))]
+    fn check_digest(
+        &self,
+        elems: &[Vec<u8>],
+        dropped: Vec<u32>,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        let mut multiplicities: HashMap<u32, usize> = HashMap::new();
+        for elem_u32 in dropped {
+            *multiplicities.entry(elem_u32).or_insert(0) += 1;
+        }
+        let mut digest = Digest::new();
+        let mut candidates: HashMap<u32, Vec<&Vec<u8>>> = HashMap::new();
+        for elem in elems {
+            let elem_u32 = bloom_sd::elem_to_u32(elem) & DJB_MASK;
+            if multiplicities.contains_key(&elem_u32) {
+                candidates.entry(elem_u32).or_default().push(elem);
+            } else {
+                digest.add(elem);
+            }
+        }
+        let mut collisions = false;
+        let mut choices = vec![];
+        for (elem_u32, multiplicity) in multiplicities {
+            let elems = candidates.remove(&elem_u32).unwrap_or_default();
+            if elems.len() < multiplicity {
+                debug!("dropped element does not exist in log: {}", elem_u32);
+                return ValidationResult::Invalid;
+            }
+            collisions |= elems.len() > multiplicity;
+            // choose the received elements
+            let n_received = elems.len() - multiplicity;
+            choices.push(elems.into_iter().combinations(n_received));
+        }
+        if choices.is_empty() {
+            return if digest.equals(&self.digest) {
+                ValidationResult::Valid
+            } else {
+                ValidationResult::Invalid
+            };
+        }
+        let matched = find_matching_combination(
+            &digest,
+            &self.digest,
+            choices.into_iter().multi_cartesian_product(),
+            options,
+        );
+        match (matched, collisions) {
+            (Some(true), false) => ValidationResult::Valid,
+            (Some(false), false) => ValidationResult::Invalid,
+            (Some(true), true) => ValidationResult::PsumCollisionsValid,
+            (Some(false), true) => ValidationResult::PsumCollisionsInvalid,
+            (None, _) => ValidationResult::BudgetExceeded,
+        }
+    }
+
+    /// Validates against the logged elements, given the evaluations of their
+    /// characteristic polynomial.
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_logged_evals(
+        &self,
+        elems: &[Vec<u8>],
+        logged_evals: Vec<u32>,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        let t2 = Instant::now();
+        let ratios = logged_evals.into_iter()
+            .zip(&self.evals)
+            .map(|(logged, &received)| mul_and_mod(logged, inverse(received)))
+            .collect::<Vec<_>>();
+
+        // Every element processed beyond the log size was injected.
+        let max_unknowns = self.evals.len() - NUM_CHECK_POINTS;
+        let mut n_injected = self.total().saturating_sub(elems.len());
+        let (p, q) = loop {
+            let n_dropped = elems.len() + n_injected - self.total();
+            if n_dropped + n_injected > max_unknowns {
+                debug!("more than {} dropped and injected elements",
+                    max_unknowns);
+                return ValidationResult::PsumExceedsThreshold;
+            }
+            if options.is_exceeded() {
+                return ValidationResult::BudgetExceeded;
+            }
+            if let Some(pq) = interpolate(&ratios, n_dropped, n_injected) {
+                break pq;
+            }
+            n_injected += 1;
+        };
+        let t3 = Instant::now();
+        debug!("interpolated {} dropped and {} injected elements: {:?}",
+            p.len() - 1, q.len() - 1, t3 - t2);
+
+        // The injected elements were never logged, so the roots of Q are
+        // found in the whole field. The dropped elements were logged, so the
+        // roots of P are found among the log.
+        let injected = match find_roots(&q) {
+            Some(injected) => injected,
+            None => {
+                debug!("the rational function has roots outside the field");
+                return ValidationResult::PsumErrorFindingRoots;
+            },
+        };
+        if !injected.is_empty() {
+            warn!("{} elements were received but never logged",
+                injected.len());
+            return ValidationResult::CharPolyInjected(injected);
+        }
+        let candidates: HashSet<u32> = elems.iter()
+            .map(|elem| bloom_sd::elem_to_u32(elem) & DJB_MASK)
+            .collect();
+        // the leading coefficient of P is last, and first for the search
+        let dropped = match find_roots_among(
+                p.into_iter().rev().collect(), &candidates) {
+            Some(dropped) => dropped,
+            None => {
+                debug!("dropped elements that were never logged");
+                return ValidationResult::Invalid;
+            },
+        };
+        let t4 = Instant::now();
+        debug!("found the roots: {:?}", t4 - t3);
+        let result = self.check_digest(elems, dropped, options);
+        debug!("checked the digest: {:?}", Instant::now() - t4);
+        result
+    }
+}
+
+impl Accumulator for CharPolyAccumulator {
+    fn to_bytes(&self) -> Vec<u8> {
+        assert_eq!(self.digest.count, (self.digest.count as u16) as u32);
+        bincode::serialize(&MiniCharPolyAccumulator {
+            hash: self.digest.hash,
+            count: self.digest.count as u16,
+            seed: u64::from_be_bytes(self.digest.nonce),
+            evals: self.evals.iter()
+                .flat_map(|eval| eval.to_be_bytes()).collect(),
+            estimator: self.estimator.clone(),
+        }).unwrap()
+    }
+
+    fn reset(&mut self) {
+        self.digest = Digest::new();
+        self.evals = vec![1; self.evals.len()];
+        self.estimator =
+            StrataEstimator::new(u64::from_be_bytes(self.digest.nonce));
+    }
+
+    fn process(&mut self, elem: &[u8]) {
+        self.digest.add(elem);
+        self.estimator.insert(elem);
+        let elem_u32 = bloom_sd::elem_to_u32(elem) & DJB_MASK;
+        add_to_evals(&mut self.evals, elem_u32);
+    }
+
+    fn process_batch(&mut self, elems: &Vec<Vec<u8>>) {
+        for elem in elems {
+            self.process(elem);
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.digest.count as usize
+    }
+
+    fn estimator(&self) -> &StrataEstimator {
+        &self.estimator
+    }
+
+    #[cfg(feature = "disable_validation")]
+    fn validate_with_options(
+        &self,
+        _elems: &Vec<Vec<u8>>,
+        _options: &ValidationOptions,
+    ) -> ValidationResult {
+        panic!("validation not enabled")
+    }
+
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_with_options(
+        &self,
+        elems: &Vec<Vec<u8>>,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        // The ratio of the characteristic polynomials of the log and of the
+        // processed elements at each point.
+        let t1 = Instant::now();
+        let partial_evals = options.executor.map_chunks(elems, |chunk| {
+            let mut evals = vec![1; self.evals.len()];
+            for elem in chunk {
+                let elem_u32 = bloom_sd::elem_to_u32(elem) & DJB_MASK;
+                add_to_evals(&mut evals, elem_u32);
+            }
+            evals
+        });
+        let mut logged_evals = vec![1; self.evals.len()];
+        for evals in partial_evals {
+            for (logged, eval) in logged_evals.iter_mut().zip(evals) {
+                *logged = mul_and_mod(*logged, eval);
+            }
+        }
+        debug!("evaluated the characteristic polynomial: {:?}",
+            Instant::now() - t1);
+        self.validate_logged_evals(elems, logged_evals, options)
+    }
+
+    /// Prepares the evaluations of the characteristic polynomial of every
+    /// window, so the evaluations of each window combine three of them.
+    #[cfg(not(feature = "disable_validation"))]
+    fn window_validator<'a>(
+        &'a self,
+        log: &'a [Vec<u8>],
+        starts: RangeInclusive<usize>,
+        ends: RangeInclusive<usize>,
+    ) -> Box<dyn WindowValidator + 'a> {
+        let evals = WindowSums::new(
+            log, starts, ends, vec![1; self.evals.len()],
+            |evals, elem| add_to_evals(
+                evals, bloom_sd::elem_to_u32(elem) & DJB_MASK),
+            |lhs, rhs| lhs.iter().zip(rhs)
+                .map(|(&lhs, &rhs)| mul_and_mod(lhs, rhs))
+                .collect());
+        Box::new(CharPolyWindows { accumulator: self, log, evals })
+    }
+}
+
+/// Validates windows of a log with the precomputed evaluations.
+#[cfg(not(feature = "disable_validation"))]
+struct CharPolyWindows<'a> {
+    accumulator: &'a CharPolyAccumulator,
+    log: &'a [Vec<u8>],
+    evals: WindowSums<Vec<u32>>,
+}
+
+#[cfg(not(feature = "disable_validation"))]
+impl WindowValidator for CharPolyWindows<'_> {
+    fn validate_window(
+        &self,
+        start: usize,
+        end: usize,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        self.accumulator.validate_logged_evals(
+            &self.log[start..end], self.evals.window(start, end), options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gen_elems_with_seed;
+
+    #[test]
+    fn test_serialization() {
+        let mut acc1 = CharPolyAccumulator::new(100, None);
+        let acc2 = CharPolyAccumulator::from_bytes(&acc1.to_bytes());
+        assert_eq!(acc1, acc2);
+        acc1.process_batch(&gen_elems_with_seed(10, 111));
+        let acc3 = CharPolyAccumulator::from_bytes(&acc1.to_bytes());
+        assert_ne!(acc1, acc2);
+        assert_eq!(acc1, acc3);
+    }
+
+    #[test]
+    fn test_dropped() {
+        let elems = gen_elems_with_seed(1000, 222);
+        for &n_dropped in &[0, 1, 10, 20] {
+            let mut acc = CharPolyAccumulator::new(20, Some(333));
+            acc.process_batch(&elems[n_dropped..].to_vec());
+            assert_eq!(acc.validate(&elems), ValidationResult::Valid);
+        }
+    }
+
+    #[test]
+    fn test_injected() {
+        let elems = gen_elems_with_seed(1000, 222);
+        let injected = gen_elems_with_seed(3, 444);
+        let mut acc = CharPolyAccumulator::new(20, Some(333));
+        acc.process_batch(&elems[10..].to_vec());
+        acc.process_batch(&injected);
+        let mut expected = injected.iter()
+            .map(|elem| bloom_sd::elem_to_u32(elem) & DJB_MASK)
+            .collect::<Vec<_>>();
+        expected.sort();
+        match acc.validate(&elems) {
+            ValidationResult::CharPolyInjected(mut injected) => {
+                injected.sort();
+                assert_eq!(injected, expected);
+            },
+            result => panic!("unexpected result {:?}", result),
+        }
+        // more received than logged
+        match acc.validate(&elems[12..].to_vec()) {
+            ValidationResult::CharPolyInjected(injected) => {
+                assert_eq!(injected.len(), 5);
+            },
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_dropped_never_logged() {
+        // Remove an element that was never logged from the evaluations, so
+        // it looks like it was dropped.
+        let elems = gen_elems_with_seed(100, 222);
+        let mut acc = CharPolyAccumulator::new(20, Some(333));
+        acc.process_batch(&elems[..91].to_vec());
+        let mut never_logged = CharPolyAccumulator::new(20, Some(333));
+        never_logged.process(&elems[99]);
+        for (eval, removed) in acc.evals.iter_mut().zip(&never_logged.evals) {
+            *eval = mul_and_mod(*eval, inverse(*removed));
+        }
+        acc.digest.count -= 1;
+        assert_eq!(acc.validate(&elems[..91].to_vec()),
+            ValidationResult::Invalid);
+    }
+
+    #[test]
+    fn test_exceeds_threshold() {
+        let elems = gen_elems_with_seed(100, 222);
+        let mut acc = CharPolyAccumulator::new_with_params(10, 2, Some(333));
+        acc.process_batch(&elems[13..].to_vec());
+        assert_eq!(acc.validate(&elems), ValidationResult::PsumExceedsThreshold);
+        // fewer injected elements leave room for more dropped ones
+        let mut acc = CharPolyAccumulator::new_with_params(10, 2, Some(333));
+        acc.process_batch(&elems[12..].to_vec());
+        assert_eq!(acc.validate(&elems), ValidationResult::Valid);
+    }
+}
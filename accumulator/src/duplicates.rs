@@ -0,0 +1,162 @@
+//! Validation that tolerates extra copies of logged elements, e.g. packets
+//! the network duplicated. The received elements are then the logged
+//! elements, less the dropped elements, plus the duplicated elements, and
+//! both the dropped and the duplicated elements are among those logged.
+use std::collections::HashMap;
+
+use digest::Digest;
+use itertools::Itertools;
+use crate::{ValidationOptions, ValidationResult};
+use crate::executor::find_matching_combination;
+
+/// Counts the occurrences of each hash.
+pub(crate) fn count(hashes: impl IntoIterator<Item = u32>) -> HashMap<u32, usize> {
+    let mut counts = HashMap::new();
+    for hash in hashes {
+        *counts.entry(hash).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Checks whether the digest of the logged elements, with the `dropped`
+/// elements removed and the `duplicated` elements added again, is the
+/// expected digest. Both map the hash of an element under `hash` to the
+/// number of copies. If several logged elements have the same hash, tries
+/// every choice of which of them were dropped or duplicated.
+///
+/// Returns whether the digest is valid, or None if trying combinations
+/// exceeded the options.
+pub(crate) fn check_digest_with_duplicates<F>(
+    expected: &Digest,
+    elems: &[Vec<u8>],
+    dropped: &HashMap<u32, usize>,
+    duplicated: &HashMap<u32, usize>,
+    hash: F,
+    options: &ValidationOptions,
+) -> Option<bool>
+where
+    F: Fn(&[u8]) -> u32,
+{
+    let mut digest = Digest::new();
+    let mut candidates: HashMap<u32, Vec<&Vec<u8>>> = HashMap::new();
+    for elem in elems {
+        let elem_u32 = hash(elem);
+        if dropped.contains_key(&elem_u32) || duplicated.contains_key(&elem_u32) {
+            candidates.entry(elem_u32).or_default().push(elem);
+        } else {
+            digest.add(elem);
+        }
+    }
+
+    // Each hash contributes the logged elements that were not dropped, and
+    // the extra copies of those that were duplicated. Choices that contribute
+    // the same elements are only tried once.
+    let mut combinations = vec![];
+    for elem_u32 in dropped.keys().chain(duplicated.keys()).unique() {
+        let Some(elems) = candidates.get(elem_u32) else {
+            debug!("element was never logged: {}", elem_u32);
+            return Some(false);
+        };
+        let n_dropped = dropped.get(elem_u32).copied().unwrap_or(0);
+        let n_duplicated = duplicated.get(elem_u32).copied().unwrap_or(0);
+        if n_dropped > elems.len() {
+            debug!("more elements dropped than logged: {}", elem_u32);
+            return Some(false);
+        }
+        let mut choices = elems.iter().copied()
+            .combinations(elems.len() - n_dropped)
+            .cartesian_product(elems.iter().copied()
+                .combinations_with_replacement(n_duplicated))
+            .map(|(received, copies)| {
+                let mut choice = [received, copies].concat();
+                choice.sort();
+                choice
+            })
+            .unique()
+            .collect::<Vec<_>>();
+        if choices.len() == 1 {
+            for elem in choices.pop().unwrap() {
+                digest.add(elem);
+            }
+        } else {
+            combinations.push(choices);
+        }
+    }
+    if combinations.is_empty() {
+        return Some(digest.equals(expected));
+    }
+    debug!("handling collisions for {} hashes", combinations.len());
+    find_matching_combination(
+        &digest,
+        expected,
+        combinations.into_iter().multi_cartesian_product(),
+        options,
+    )
+}
+
+/// The verdict from `check_digest_with_duplicates()`, which names the
+/// duplicated elements if there were any.
+pub(crate) fn duplicates_result(
+    checked: Option<bool>,
+    duplicated: &HashMap<u32, usize>,
+) -> ValidationResult {
+    match checked {
+        Some(true) if duplicated.is_empty() => ValidationResult::Valid,
+        Some(true) => {
+            let mut hashes = duplicated.iter()
+                .flat_map(|(&elem_u32, &n)| std::iter::repeat_n(elem_u32, n))
+                .collect::<Vec<_>>();
+            hashes.sort();
+            warn!("valid with {} duplicated elements", hashes.len());
+            ValidationResult::DuplicatesValid(hashes)
+        },
+        Some(false) => ValidationResult::Invalid,
+        None => ValidationResult::BudgetExceeded,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gen_sequential_elems;
+
+    #[test]
+    fn test_check_digest_with_duplicates() {
+        let elems = gen_sequential_elems(10);
+        let mut expected = Digest::new();
+        expected.add_all(&elems[1..].to_vec());
+        expected.add(&elems[2]);
+        expected.add(&elems[2]);
+        let hash = |elem: &[u8]| bloom_sd::elem_to_u32(elem);
+        let options = ValidationOptions::default();
+        let dropped = count([hash(&elems[0])]);
+        let duplicated = count([hash(&elems[2]), hash(&elems[2])]);
+        assert_eq!(check_digest_with_duplicates(
+            &expected, &elems, &dropped, &duplicated, hash, &options),
+            Some(true));
+        assert_eq!(check_digest_with_duplicates(
+            &expected, &elems, &dropped, &count([hash(&elems[2])]), hash,
+            &options), Some(false));
+        assert_eq!(duplicates_result(Some(true), &duplicated),
+            ValidationResult::DuplicatesValid(vec![hash(&elems[2]); 2]));
+    }
+
+    #[test]
+    fn test_check_digest_with_duplicates_collisions() {
+        // Every element has the same hash, so any of them could have been
+        // dropped or duplicated.
+        let elems = gen_sequential_elems(5);
+        let mut expected = Digest::new();
+        expected.add_all(&elems[..4].to_vec());
+        expected.add(&elems[1]);
+        let options = ValidationOptions::default();
+        let dropped = count([0]);
+        let duplicated = count([0]);
+        assert_eq!(check_digest_with_duplicates(
+            &expected, &elems, &dropped, &duplicated, |_| 0, &options),
+            Some(true));
+        assert_eq!(check_digest_with_duplicates(
+            &expected, &elems, &dropped, &HashMap::new(), |_| 0, &options),
+            Some(false));
+    }
+}
@@ -0,0 +1,331 @@
+use crate::ValidationResult;
+#[cfg(not(feature = "disable_validation"))]
+use crate::{Accumulator, ValidationOptions};
+
+/// Validates consecutive snapshots of an accumulator that is reset after
+/// each snapshot, i.e. one snapshot per epoch, against a single log of every
+/// element.
+///
+/// Elements logged just before the accumulator was reset may still be in
+/// flight, and be received in the next epoch instead. Of the last `slack`
+/// logged elements of each epoch, the ones the snapshot did not receive are
+/// carried forward and may be attributed to the next epoch, so the next
+/// snapshot does not look like it contains elements that were never logged.
+/// An element the snapshot received is never carried forward, so it cannot
+/// be received again in the next epoch.
+///
+/// If it cannot be decided which of those elements were received, none of
+/// them are carried forward. Instead the next epoch may start up to `slack`
+/// entries earlier in the log, i.e. the epochs overlap.
+#[derive(Debug, Clone, Default)]
+pub struct EpochValidator {
+    slack: usize,
+    carried: Vec<Vec<u8>>,
+    overlap: usize,
+}
+
+/// The verdict on the snapshot of a single epoch.
+#[derive(Debug)]
+pub struct EpochReport {
+    pub result: ValidationResult,
+    /// Index of the log where the epoch starts. Only the entries that overlap
+    /// with the previous epoch can be skipped over.
+    pub start: usize,
+    /// Number of entries of the log that belong to the epoch, not including
+    /// the entries carried from the previous epoch. The next epoch starts at
+    /// this offset in the log.
+    pub consumed: usize,
+    /// Number of entries before `consumed` that may also belong to the next
+    /// epoch.
+    pub overlap: usize,
+    /// Number of logged elements the snapshot was validated against,
+    /// including the entries carried from the previous epoch.
+    pub logged: usize,
+    /// Number of elements the snapshot processed.
+    pub received: usize,
+    /// Number of logged elements carried forward to the next epoch.
+    pub carried: usize,
+}
+
+impl EpochReport {
+    /// The most elements that could have been dropped in the epoch. Fewer
+    /// were dropped if some carried elements are received in the next epoch.
+    pub fn dropped(&self) -> usize {
+        self.logged.saturating_sub(self.received)
+    }
+}
+
+impl EpochValidator {
+    pub fn new(slack: usize) -> Self {
+        Self { slack, carried: vec![], overlap: 0 }
+    }
+
+    pub fn slack(&self) -> usize {
+        self.slack
+    }
+
+    /// The logged elements carried forward from the previous epoch.
+    pub fn carried(&self) -> &[Vec<u8>] {
+        &self.carried
+    }
+
+    /// Number of entries at the start of the next log that may belong to the
+    /// previous epoch.
+    pub fn overlap(&self) -> usize {
+        self.overlap
+    }
+
+    /// Starts over at an epoch with the given carried elements, e.g. to
+    /// resume validation.
+    pub fn set_carried(&mut self, carried: Vec<Vec<u8>>) {
+        self.carried = carried;
+        self.overlap = 0;
+    }
+
+    /// Starts over at an epoch that may overlap the previous one by the given
+    /// number of entries, e.g. to resume validation.
+    pub fn set_overlap(&mut self, overlap: usize) {
+        self.carried = vec![];
+        self.overlap = overlap;
+    }
+
+    /// Validates the snapshot of the next epoch against the carried elements
+    /// followed by `log`, which starts where the previous epoch ended and
+    /// should extend at least to the end of this epoch.
+    ///
+    /// If valid, bisects for the latest start among the entries that overlap
+    /// the previous epoch, then for the shortest prefix of the log that is
+    /// still valid, so the epoch ends after the last element it received.
+    /// The elements among the last `slack` that the window is still valid
+    /// without are then carried forward. If not valid, assumes the epoch
+    /// ended after as many elements as it received, and lets the next epoch
+    /// overlap the last `slack` entries instead.
+    #[cfg(not(feature = "disable_validation"))]
+    pub fn validate_epoch(
+        &mut self,
+        accumulator: &dyn Accumulator,
+        log: &[Vec<u8>],
+        options: &ValidationOptions,
+    ) -> EpochReport {
+        let carried = std::mem::take(&mut self.carried);
+        let overlap =
+            std::cmp::min(std::mem::take(&mut self.overlap), log.len());
+        let window = |start: usize, end: usize| {
+            carried.iter().chain(&log[start..end]).cloned().collect::<Vec<_>>()
+        };
+        let received = accumulator.total();
+        let mut result = accumulator.validate_with_options(
+            &window(0, log.len()), options);
+        let mut start = 0;
+        let end = if result.is_valid() {
+            let mut hi = overlap;
+            while start < hi && !options.is_exceeded() {
+                let mid = (start + hi).div_ceil(2);
+                let mid_result = accumulator.validate_with_options(
+                    &window(mid, log.len()), options);
+                if mid_result.is_valid() {
+                    result = mid_result;
+                    start = mid;
+                } else {
+                    hi = mid - 1;
+                }
+            }
+            let mut lo = std::cmp::min(log.len(),
+                start + received.saturating_sub(carried.len()));
+            let mut hi = log.len();
+            while lo < hi && !options.is_exceeded() {
+                let mid = (lo + hi) / 2;
+                let mid_result = accumulator.validate_with_options(
+                    &window(start, mid), options);
+                if mid_result.is_valid() {
+                    result = mid_result;
+                    hi = mid;
+                } else {
+                    lo = mid + 1;
+                }
+            }
+            debug!("epoch spans log entries {}..{}/{}", start, hi, log.len());
+            hi
+        } else {
+            warn!("invalid epoch: {:?}", result);
+            std::cmp::min(log.len(), received.saturating_sub(carried.len()))
+        };
+
+        // Carry the dropped elements at the end of the epoch forward.
+        let logged = window(start, end);
+        let dropped = if result.is_valid() {
+            self.dropped_tail(accumulator, &logged, options)
+        } else {
+            None
+        };
+        match dropped {
+            Some(dropped) => {
+                self.carried = dropped.into_iter()
+                    .map(|i| logged[i].clone())
+                    .collect();
+            },
+            None => {
+                debug!("overlapping the next epoch by {} entries", self.slack);
+                self.overlap = std::cmp::min(self.slack, end - start);
+            },
+        }
+        EpochReport {
+            result,
+            start,
+            consumed: end - self.overlap,
+            overlap: self.overlap,
+            logged: logged.len(),
+            received,
+            carried: self.carried.len(),
+        }
+    }
+
+    /// Indexes of the elements among the last `slack` elements of a valid
+    /// window that the snapshot did not receive, or None if that could not
+    /// be decided. Removes the elements one at a time starting from the end,
+    /// keeping each removed if the window is still valid without it, so the
+    /// removed elements could all have been dropped together.
+    #[cfg(not(feature = "disable_validation"))]
+    fn dropped_tail(
+        &self,
+        accumulator: &dyn Accumulator,
+        window: &[Vec<u8>],
+        options: &ValidationOptions,
+    ) -> Option<Vec<usize>> {
+        let n_dropped = window.len().saturating_sub(accumulator.total());
+        let mut dropped = vec![];
+        for i in (window.len().saturating_sub(self.slack)..window.len()).rev() {
+            if dropped.len() == n_dropped {
+                break;
+            }
+            if options.is_exceeded() {
+                return None;
+            }
+            let remaining = window.iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i && !dropped.contains(j))
+                .map(|(_, elem)| elem.clone())
+                .collect::<Vec<_>>();
+            let result = accumulator.validate_with_options(&remaining, options);
+            if result.is_valid() {
+                dropped.push(i);
+            } else if result.is_undetermined() {
+                return None;
+            }
+        }
+        dropped.reverse();
+        Some(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CharPolyAccumulator;
+    use crate::test_util::gen_elems_with_seed;
+
+    /// Three epochs of 100 logged elements each. The 96th element is still in
+    /// flight at the end of the first epoch, and one element is dropped in
+    /// the last epoch.
+    fn gen_epochs(log: &[Vec<u8>]) -> Vec<CharPolyAccumulator> {
+        let mut epochs = (0..3)
+            .map(|_| CharPolyAccumulator::new(60, Some(222)))
+            .collect::<Vec<_>>();
+        for (i, elem) in log.iter().enumerate() {
+            match i {
+                95 => epochs[1].process(elem),
+                250 => {},
+                _ => epochs[i / 100].process(elem),
+            }
+        }
+        epochs
+    }
+
+    fn validate_epochs(slack: usize) -> Vec<EpochReport> {
+        let log = gen_elems_with_seed(300, 111);
+        let options = ValidationOptions::default();
+        let mut validator = EpochValidator::new(slack);
+        let mut start = 0;
+        gen_epochs(&log).iter().map(|accumulator| {
+            let end = std::cmp::min(log.len(), start + 150);
+            let report = validator.validate_epoch(
+                accumulator, &log[start..end], &options);
+            start += report.consumed;
+            report
+        }).collect()
+    }
+
+    #[test]
+    fn test_carry_across_boundary() {
+        let reports = validate_epochs(5);
+        assert!(reports.iter().all(|report| report.result.is_valid()),
+            "{:?}", reports);
+        assert_eq!(reports.iter().map(|r| r.consumed).collect::<Vec<_>>(),
+            vec![100, 100, 100]);
+        assert_eq!(reports[0].dropped(), 1);
+        // only the element in flight is carried from the first epoch
+        assert_eq!(reports.iter().map(|r| r.carried).collect::<Vec<_>>(),
+            vec![1, 0, 0]);
+        assert_eq!((reports[1].logged, reports[1].received), (101, 101));
+        assert_eq!(reports[2].dropped(), 1);
+        assert!(reports.iter().all(|report| report.overlap == 0));
+    }
+
+    #[test]
+    fn test_received_boundary_element_is_not_carried() {
+        let log = gen_elems_with_seed(300, 111);
+        let mut epochs = gen_epochs(&log);
+        // a second copy of the last element received in the first epoch
+        epochs[1].process(&log[99]);
+        let options = ValidationOptions::default();
+        let mut validator = EpochValidator::new(5);
+        let report =
+            validator.validate_epoch(&epochs[0], &log[..150], &options);
+        assert!(report.result.is_valid());
+        assert_eq!(validator.carried(), &log[95..96]);
+        let report = validator.validate_epoch(
+            &epochs[1], &log[report.consumed..250], &options);
+        assert!(!report.result.is_valid(), "{:?}", report);
+    }
+
+    #[test]
+    fn test_overlap_when_undetermined() {
+        let log = gen_elems_with_seed(300, 111);
+        let mut epochs = (0..2)
+            .map(|_| CharPolyAccumulator::new(10, Some(222)))
+            .collect::<Vec<_>>();
+        for (i, elem) in log[..200].iter().enumerate() {
+            match i {
+                95 => epochs[1].process(elem),
+                _ => epochs[i / 100].process(elem),
+            }
+        }
+        let options = ValidationOptions::default();
+        let mut validator = EpochValidator::new(5);
+
+        // Too many elements to tell which were dropped.
+        let report =
+            validator.validate_epoch(&epochs[0], &log[..120], &options);
+        assert!(report.result.is_undetermined(), "{:?}", report);
+        assert_eq!((report.consumed, report.overlap, report.carried),
+            (94, 5, 0));
+        assert_eq!(validator.overlap(), 5);
+
+        // The next epoch starts at the element in flight.
+        let report =
+            validator.validate_epoch(&epochs[1], &log[94..200], &options);
+        assert!(report.result.is_valid(), "{:?}", report);
+        assert_eq!((report.start, report.consumed), (1, 106));
+        assert_eq!((report.logged, report.received), (105, 101));
+        assert_eq!(validator.overlap(), 0);
+    }
+
+    #[test]
+    fn test_no_slack() {
+        let reports = validate_epochs(0);
+        assert!(reports[0].result.is_valid());
+        assert_eq!(reports[0].consumed, 100);
+        assert!(!reports[1].result.is_valid(), "{:?}", reports[1]);
+        assert_eq!(reports[1].carried, 0);
+    }
+}
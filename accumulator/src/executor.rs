@@ -0,0 +1,209 @@
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+#[cfg(not(feature = "disable_validation"))]
+use digest::Digest;
+#[cfg(not(feature = "disable_validation"))]
+use crate::ValidationOptions;
+
+/// Runs the parallel parts of validation: calculating power sums,
+/// constructing the difference IBLT, and searching combinations of colliding
+/// elements for a matching digest.
+#[derive(Clone)]
+pub enum Executor {
+    /// Runs everything on the calling thread, e.g. on embedded devices.
+    CurrentThread,
+    /// Runs work on a thread pool, which can be shared between validations.
+    Pool(Arc<ThreadPool>),
+}
+
+impl Executor {
+    /// Builds a new thread pool with this many threads.
+    pub fn new(num_threads: usize) -> Self {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|i| format!("validation-{}", i))
+            .build()
+            .unwrap();
+        Executor::Pool(Arc::new(pool))
+    }
+
+    /// The thread pool shared by the whole process, with a thread per CPU.
+    /// It is built the first time it is used.
+    pub fn shared() -> Self {
+        static SHARED: OnceLock<Executor> = OnceLock::new();
+        SHARED.get_or_init(|| Executor::new(num_cpus::get())).clone()
+    }
+
+    pub fn num_threads(&self) -> usize {
+        match self {
+            Executor::CurrentThread => 1,
+            Executor::Pool(pool) => pool.current_num_threads(),
+        }
+    }
+
+    /// Splits `items` into a contiguous chunk per thread, and maps each
+    /// chunk with `f`.
+    pub fn map_chunks<T, R, F>(&self, items: &[T], f: F) -> Vec<R>
+    where
+        T: Sync,
+        R: Send,
+        F: Fn(&[T]) -> R + Sync + Send,
+    {
+        match self {
+            Executor::CurrentThread => vec![f(items)],
+            Executor::Pool(pool) => {
+                let chunk_size = std::cmp::max(
+                    1, items.len().div_ceil(pool.current_num_threads()));
+                pool.install(|| items.par_chunks(chunk_size).map(f).collect())
+            },
+        }
+    }
+
+    /// Returns the result of `f` on any item for which it is `Some`, and
+    /// stops looking once one is found. Items are taken from `iter` in order
+    /// but may be mapped in any order.
+    pub fn find_map_any<I, R, F>(&self, mut iter: I, f: F) -> Option<R>
+    where
+        I: Iterator + Send,
+        I::Item: Send,
+        R: Send,
+        F: Fn(I::Item) -> Option<R> + Sync + Send,
+    {
+        match self {
+            Executor::CurrentThread => iter.find_map(f),
+            Executor::Pool(pool) => {
+                pool.install(|| iter.par_bridge().find_map_any(f))
+            },
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::shared()
+    }
+}
+
+impl fmt::Debug for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Executor::CurrentThread => write!(f, "CurrentThread"),
+            Executor::Pool(pool) => {
+                write!(f, "Pool({} threads)", pool.current_num_threads())
+            },
+        }
+    }
+}
+
+/// Adds each combination of elements to a copy of `digest`, and checks if
+/// any of them equal the `expected` digest. The combinations are checked in
+/// parallel on the executor in the options.
+///
+/// Returns whether a combination matched, or None if the options were
+/// exceeded first.
+#[cfg(not(feature = "disable_validation"))]
+pub(crate) fn find_matching_combination<I, C, E>(
+    digest: &Digest,
+    expected: &Digest,
+    combinations: I,
+    options: &ValidationOptions,
+) -> Option<bool>
+where
+    I: Iterator<Item = Vec<C>> + Send,
+    C: IntoIterator<Item = E> + Send,
+    E: AsRef<[u8]>,
+{
+    let result = options.executor.find_map_any(
+        combinations.enumerate(),
+        |(n_digests, combination)| {
+            if options.count_digest() {
+                return Some(Err(n_digests));
+            }
+            let mut digest = digest.clone();
+            for elem in combination.into_iter().flatten() {
+                digest.add(elem.as_ref());
+            }
+            if digest.equals(expected) {
+                Some(Ok(n_digests))
+            } else {
+                None
+            }
+        },
+    );
+    match result {
+        Some(Ok(n_digests)) => {
+            debug!("found matching digest after {} digests", n_digests);
+            Some(true)
+        },
+        Some(Err(n_digests)) => {
+            debug!("gave up after {} digests", n_digests);
+            None
+        },
+        None => Some(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_chunks() {
+        let items = (0..1000).collect::<Vec<u64>>();
+        for executor in [Executor::CurrentThread, Executor::new(4)] {
+            let sums = executor.map_chunks(&items, |chunk| chunk.iter().sum::<u64>());
+            assert!(sums.len() <= executor.num_threads());
+            assert_eq!(sums.into_iter().sum::<u64>(), 499500);
+        }
+        let sums = Executor::new(4).map_chunks(&items[..0], |chunk| chunk.len());
+        assert!(sums.is_empty());
+    }
+
+    #[test]
+    fn test_find_map_any() {
+        for executor in [Executor::CurrentThread, Executor::new(4)] {
+            let found = executor.find_map_any(0..1000, |i| {
+                if i == 700 { Some(i * 2) } else { None }
+            });
+            assert_eq!(found, Some(1400));
+            assert_eq!(executor.find_map_any(0..1000, |_| None::<()>), None);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "disable_validation"))]
+    fn test_find_matching_combination_budget() {
+        let elems = (0..4u8).map(|i| vec![i]).collect::<Vec<_>>();
+        let mut expected = Digest::new();
+        expected.add(&elems[3]);
+        let combinations = || elems.iter()
+            .map(|elem| vec![vec![elem]])
+            .collect::<Vec<_>>()
+            .into_iter();
+        let options = ValidationOptions {
+            executor: Executor::CurrentThread,
+            max_digests: Some(6),
+            ..Default::default()
+        };
+        let digest = Digest::new();
+        assert_eq!(find_matching_combination(
+            &digest, &expected, combinations(), &options), Some(true));
+        // The digests of the first call count against the same budget.
+        assert_eq!(find_matching_combination(
+            &digest, &expected, combinations(), &options), None);
+        assert!(options.is_exceeded());
+    }
+
+    #[test]
+    fn test_shared() {
+        let executor = Executor::shared();
+        assert_eq!(executor.num_threads(), num_cpus::get());
+        match (executor, Executor::default()) {
+            (Executor::Pool(a), Executor::Pool(b)) => assert!(Arc::ptr_eq(&a, &b)),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,353 @@
+#[cfg(not(feature = "disable_validation"))]
+use std::time::Instant;
+use std::hash::Hasher;
+
+use serde::{Serialize, Deserialize};
+use siphasher::sip::SipHasher13;
+use bloom_sd::StrataEstimator;
+use crate::{Accumulator, ValidationOptions, ValidationResult};
+use crate::{IBLTAccumulator, PowerSumAccumulator};
+use crate::iblt::{DEFAULT_BITS_PER_ENTRY, DEFAULT_NUM_HASHES};
+use digest::Digest;
+use digest::canonicalize::FlowKey;
+
+pub const DEFAULT_NUM_BUCKETS: usize = 16;
+
+/// The accumulator that keeps the state of each bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BucketKind {
+    Iblt,
+    PowerSum,
+}
+
+/// The flow-partitioned accumulator hashes the flow key of each element into
+/// one of a fixed number of buckets, and each bucket has its own small
+/// accumulator. Validating each bucket separately shows which flows or
+/// destination prefixes had packets dropped or injected, instead of only
+/// whether the router is valid.
+pub struct FlowPartitionedAccumulator {
+    key: FlowKey,
+    kind: BucketKind,
+    seed: u64,
+    buckets: Vec<Box<dyn Accumulator + Send>>,
+    estimator: StrataEstimator,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MiniFlowPartitionedAccumulator {
+    key: FlowKey,
+    kind: BucketKind,
+    seed: u64,                // seed for bucket hash, and of each bucket
+    buckets: Vec<Vec<u8>>,    // serialized accumulator of each bucket
+    estimator: StrataEstimator,
+}
+
+/// The verdict on a single bucket of a flow-partitioned accumulator.
+#[derive(Debug)]
+pub struct FlowReport {
+    pub bucket: usize,
+    /// Number of logged elements in the bucket.
+    pub logged: usize,
+    /// Number of elements the bucket processed.
+    pub received: usize,
+    pub result: ValidationResult,
+}
+
+impl FlowReport {
+    pub fn dropped(&self) -> usize {
+        self.logged.saturating_sub(self.received)
+    }
+
+    /// Combines the reports of every bucket into a single result. Valid if
+    /// every bucket is valid. Otherwise the result of the first invalid
+    /// bucket, or of the first undetermined bucket if none are invalid.
+    pub fn combine(reports: Vec<FlowReport>) -> ValidationResult {
+        let mut undetermined = None;
+        for report in reports {
+            if report.result.is_valid() {
+                continue;
+            } else if report.result.is_undetermined() {
+                undetermined.get_or_insert(report.result);
+            } else {
+                return report.result;
+            }
+        }
+        undetermined.unwrap_or(ValidationResult::Valid)
+    }
+}
+
+/// Each bucket has its own seed for its digest and hash functions.
+fn bucket_seed(seed: u64, bucket: usize) -> u64 {
+    seed.wrapping_add(bucket as u64 + 1)
+}
+
+fn new_bucket(
+    kind: BucketKind,
+    threshold: usize,
+    seed: u64,
+) -> Box<dyn Accumulator + Send> {
+    match kind {
+        BucketKind::Iblt => Box::new(IBLTAccumulator::new(threshold, Some(seed))),
+        BucketKind::PowerSum => {
+            Box::new(PowerSumAccumulator::new(threshold, Some(seed)))
+        },
+    }
+}
+
+impl FlowPartitionedAccumulator {
+    /// Each of the `num_buckets` buckets has the given `threshold`.
+    pub fn new_with_params(
+        num_buckets: usize,
+        threshold: usize,
+        key: FlowKey,
+        kind: BucketKind,
+        seed: Option<u64>,
+    ) -> Self {
+        assert!(num_buckets > 0);
+        let seed = seed.unwrap_or_else(|| u64::from_be_bytes(Digest::new().nonce));
+        let buckets = (0..num_buckets)
+            .map(|bucket| new_bucket(kind, threshold, bucket_seed(seed, bucket)))
+            .collect();
+        Self {
+            key,
+            kind,
+            seed,
+            buckets,
+            estimator: StrataEstimator::new(seed),
+        }
+    }
+
+    /// The threshold is split evenly between the default number of buckets.
+    pub fn new(
+        threshold: usize,
+        key: FlowKey,
+        kind: BucketKind,
+        seed: Option<u64>,
+    ) -> Self {
+        Self::new_with_params(
+            DEFAULT_NUM_BUCKETS,
+            threshold.div_ceil(DEFAULT_NUM_BUCKETS),
+            key,
+            kind,
+            seed,
+        )
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let x: MiniFlowPartitionedAccumulator =
+            bincode::deserialize(bytes).unwrap();
+        let buckets = x.buckets.into_iter()
+            .map(|bytes| -> Box<dyn Accumulator + Send> { match x.kind {
+                BucketKind::Iblt => Box::new(IBLTAccumulator::from_bytes(
+                    &bytes, DEFAULT_BITS_PER_ENTRY, DEFAULT_NUM_HASHES)),
+                BucketKind::PowerSum => {
+                    Box::new(PowerSumAccumulator::from_bytes(&bytes))
+                },
+            }})
+            .collect();
+        Self {
+            key: x.key,
+            kind: x.kind,
+            seed: x.seed,
+            buckets,
+            estimator: x.estimator,
+        }
+    }
+
+    pub fn key(&self) -> FlowKey {
+        self.key
+    }
+
+    pub fn num_buckets(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// The bucket of the element, from a keyed hash of its flow key.
+    pub fn bucket(&self, elem: &[u8]) -> usize {
+        let mut hasher = SipHasher13::new_with_keys(self.seed, !self.seed);
+        hasher.write(&self.key.key(elem));
+        (hasher.finish() % self.buckets.len() as u64) as usize
+    }
+
+    /// Validates each bucket against the logged elements in the bucket.
+    #[cfg(not(feature = "disable_validation"))]
+    pub fn validate_flows(
+        &self,
+        elems: &[Vec<u8>],
+        options: &ValidationOptions,
+    ) -> Vec<FlowReport> {
+        let mut bucket_elems = vec![vec![]; self.buckets.len()];
+        for elem in elems {
+            bucket_elems[self.bucket(elem)].push(elem.clone());
+        }
+        self.buckets.iter()
+            .zip(bucket_elems)
+            .enumerate()
+            .map(|(bucket, (accumulator, elems))| {
+                let t1 = Instant::now();
+                let result = if options.is_exceeded() {
+                    ValidationResult::BudgetExceeded
+                } else {
+                    accumulator.validate_with_options(&elems, options)
+                };
+                debug!("validated bucket {}: {:?}", bucket, Instant::now() - t1);
+                FlowReport {
+                    bucket,
+                    logged: elems.len(),
+                    received: accumulator.total(),
+                    result,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Accumulator for FlowPartitionedAccumulator {
+    fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&MiniFlowPartitionedAccumulator {
+            key: self.key,
+            kind: self.kind,
+            seed: self.seed,
+            buckets: self.buckets.iter()
+                .map(|accumulator| accumulator.to_bytes())
+                .collect(),
+            estimator: self.estimator.clone(),
+        }).unwrap()
+    }
+
+    fn reset(&mut self) {
+        for accumulator in self.buckets.iter_mut() {
+            accumulator.reset();
+        }
+        self.estimator = self.estimator.empty_clone();
+    }
+
+    fn process(&mut self, elem: &[u8]) {
+        self.estimator.insert(elem);
+        let bucket = self.bucket(elem);
+        self.buckets[bucket].process(elem);
+    }
+
+    fn process_batch(&mut self, elems: &Vec<Vec<u8>>) {
+        for elem in elems {
+            self.process(elem);
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.buckets.iter().map(|accumulator| accumulator.total()).sum()
+    }
+
+    fn estimator(&self) -> &StrataEstimator {
+        &self.estimator
+    }
+
+    #[cfg(feature = "disable_validation")]
+    fn validate_with_options(
+        &self,
+        _elems: &Vec<Vec<u8>>,
+        _options: &ValidationOptions,
+    ) -> ValidationResult {
+        panic!("validation not enabled")
+    }
+
+    /// Valid if every bucket is valid. See `FlowReport::combine()`.
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_with_options(
+        &self,
+        elems: &Vec<Vec<u8>>,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        FlowReport::combine(self.validate_flows(elems, options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use digest::canonicalize::ELEM_LEN;
+    use crate::test_util::gen_elems_with_len;
+
+    /// Canonical-length elements with random flow keys.
+    fn gen_elems(n: usize, seed: u64) -> Vec<Vec<u8>> {
+        gen_elems_with_len(n, ELEM_LEN, seed)
+    }
+
+    #[test]
+    fn test_serialization() {
+        let elems = gen_elems(100, 111);
+        for kind in [BucketKind::Iblt, BucketKind::PowerSum] {
+            let mut acc = FlowPartitionedAccumulator::new(
+                100, FlowKey::FiveTuple, kind, Some(222));
+            acc.process_batch(&elems);
+            let bytes = acc.to_bytes();
+            let acc2 = FlowPartitionedAccumulator::from_bytes(&bytes);
+            assert_eq!(acc2.to_bytes(), bytes);
+            assert_eq!(acc2.total(), 100);
+            assert_eq!(acc2.key(), FlowKey::FiveTuple);
+            assert_eq!(acc2.num_buckets(), DEFAULT_NUM_BUCKETS);
+        }
+    }
+
+    #[test]
+    fn test_buckets_by_flow_key() {
+        let acc = FlowPartitionedAccumulator::new(
+            100, FlowKey::DstPrefix(8), BucketKind::Iblt, Some(222));
+        let mut elems = gen_elems(2, 111);
+        let (version, dst) = (elems[0][0], elems[0][8]);
+        elems[1][0] = version;
+        elems[1][8] = dst;
+        assert_eq!(acc.bucket(&elems[0]), acc.bucket(&elems[1]));
+        let buckets = gen_elems(100, 333).iter()
+            .map(|elem| acc.bucket(elem))
+            .collect::<std::collections::HashSet<_>>();
+        assert!(buckets.len() > DEFAULT_NUM_BUCKETS / 2);
+    }
+
+    #[test]
+    fn test_validate_flows_dropped() {
+        let elems = gen_elems(1000, 111);
+        let mut acc = FlowPartitionedAccumulator::new(
+            160, FlowKey::FiveTuple, BucketKind::Iblt, Some(222));
+        let dropped_bucket = acc.bucket(&elems[0]);
+        let mut n_dropped = 0;
+        for elem in &elems {
+            if acc.bucket(elem) == dropped_bucket && n_dropped < 5 {
+                n_dropped += 1;
+            } else {
+                acc.process(elem);
+            }
+        }
+        let reports = acc.validate_flows(&elems, &ValidationOptions::default());
+        assert_eq!(reports.len(), DEFAULT_NUM_BUCKETS);
+        for report in reports {
+            assert!(report.result.is_valid(), "{:?}", report);
+            if report.bucket == dropped_bucket {
+                assert_eq!(report.dropped(), 5);
+            } else {
+                assert_eq!(report.dropped(), 0);
+            }
+        }
+        assert!(acc.validate(&elems).is_valid());
+    }
+
+    #[test]
+    fn test_validate_flows_injected() {
+        let elems = gen_elems(1000, 111);
+        let injected = gen_elems(1, 444).remove(0);
+        let mut acc = FlowPartitionedAccumulator::new(
+            160, FlowKey::FiveTuple, BucketKind::Iblt, Some(222));
+        acc.process_batch(&elems[1..].to_vec());
+        acc.process(&injected);
+        let injected_bucket = acc.bucket(&injected);
+        let reports = acc.validate_flows(&elems, &ValidationOptions::default());
+        for report in reports {
+            if report.bucket == injected_bucket {
+                assert!(!report.result.is_valid(), "{:?}", report);
+            } else {
+                assert!(report.result.is_valid(), "{:?}", report);
+            }
+        }
+        assert!(!acc.validate(&elems).is_valid());
+    }
+}
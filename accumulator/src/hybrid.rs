@@ -0,0 +1,523 @@
+#[cfg(not(feature = "disable_validation"))]
+use std::time::Instant;
+#[cfg(not(feature = "disable_validation"))]
+use std::collections::{HashSet, HashMap};
+#[cfg(not(feature = "disable_validation"))]
+use std::ops::RangeInclusive;
+
+use serde::{Serialize, Deserialize};
+use bloom_sd::{ValueVec, InvBloomLookupTable, StrataEstimator};
+#[cfg(not(feature = "disable_validation"))]
+use bloom_sd::{PeelError, PeelResult};
+use crate::{Accumulator, ValidationOptions, ValidationResult};
+use crate::iblt::{DEFAULT_BITS_PER_ENTRY, DEFAULT_CELLS_MULTIPLIER};
+use crate::iblt::DEFAULT_NUM_HASHES;
+#[cfg(not(feature = "disable_validation"))]
+use crate::iblt::{
+    add_iblt, calculate_difference_iblt, check_digest_from_removed_set,
+    logged_iblt, validate_candidates,
+};
+use crate::power_sum::{add_power_sums, DJB_MASK};
+#[cfg(not(feature = "disable_validation"))]
+use crate::power_sum::{
+    add_and_mod, add_power_sums_batch, calculate_difference,
+    compute_polynomial_coefficients, find_roots_among,
+};
+#[cfg(not(feature = "disable_validation"))]
+use crate::window::{WindowSums, WindowValidator};
+use digest::{Digest, AmhHash};
+#[cfg(not(feature = "disable_validation"))]
+use itertools::Itertools;
+
+pub const DEFAULT_NUM_POWER_SUMS: usize = 8;
+
+/// The hybrid accumulator stores the digest, an IBLT, and a small number of
+/// power sums of all processed packets.
+///
+/// On validation, the accumulator peels the difference IBLT as in the IBLT
+/// accumulator. If peeling gets stuck, the power sums of the elements left in
+/// the 2-core are the power sums of the logged elements, less those of the
+/// received and the peeled elements. Since the dropped elements are among the
+/// logged elements that hash entirely into the 2-core, the roots of the
+/// polynomial with these power sums are found by trying each of them, without
+/// solving an ILP or factoring the polynomial. Validation is undetermined if
+/// more elements are left in the 2-core than there are power sums.
+#[derive(Serialize, Deserialize)]
+pub struct HybridAccumulator {
+    digest: Digest,
+    iblt: InvBloomLookupTable,
+    power_sums: Vec<u32>,
+    estimator: StrataEstimator,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MiniHybridAccumulator {
+    hash: AmhHash,       // [u8; HASH_SIZE]
+    count: u16,          // expect ~1024 = 2^10
+    seed: u64,           // seed for multiset hash, IBLT hash
+    counters: Vec<u8>,   // bits_per_val = IBLT_BITS_PER_ENTRY
+    data: Vec<u8>,       // bits_per_val = DJB_HASH_SIZE
+    power_sums: Vec<u8>, // DJB_HASH_SIZE bits per power sum
+    estimator: StrataEstimator,
+}
+
+impl HybridAccumulator {
+    pub fn new_with_params(
+        threshold: usize,
+        bits_per_entry: usize,
+        cells_multiplier: usize,
+        num_hashes: u32,
+        num_power_sums: usize,
+        seed: Option<u64>,
+    ) -> Self {
+        let digest = if let Some(seed) = seed {
+            Digest::new_with_seed(seed.to_be_bytes())
+        } else {
+            Digest::new()
+        };
+        let seed = u64::from_be_bytes(digest.nonce);
+        let iblt = InvBloomLookupTable::new_with_seed(
+            seed,
+            bits_per_entry,
+            cells_multiplier * threshold,
+            num_hashes,
+        );
+        Self {
+            digest,
+            iblt,
+            power_sums: vec![0; num_power_sums],
+            estimator: StrataEstimator::new(seed),
+        }
+    }
+
+    pub fn new(threshold: usize, seed: Option<u64>) -> Self {
+        Self::new_with_params(
+            threshold,
+            DEFAULT_BITS_PER_ENTRY,
+            DEFAULT_CELLS_MULTIPLIER,
+            DEFAULT_NUM_HASHES,
+            DEFAULT_NUM_POWER_SUMS,
+            seed,
+        )
+    }
+
+    pub fn from_bytes(
+        bytes: &[u8],
+        bits_per_entry: usize,
+        num_hashes: u32,
+    ) -> Self {
+        let x: MiniHybridAccumulator = bincode::deserialize(bytes).unwrap();
+        let num_entries = x.counters.len() * 8 / bits_per_entry;
+        let mut iblt = InvBloomLookupTable::new_with_seed(
+            x.seed,
+            bits_per_entry,
+            num_entries,
+            num_hashes,
+        );
+        *iblt.counters_mut() = ValueVec::from_bytes(bits_per_entry, &x.counters);
+        *iblt.data_mut() =
+            ValueVec::from_bytes(bloom_sd::DJB_HASH_SIZE, &x.data);
+        Self {
+            digest: Digest {
+                hash: x.hash,
+                count: x.count as u32,
+                nonce: x.seed.to_be_bytes(),
+            },
+            iblt,
+            power_sums: x.power_sums
+                .chunks_exact(4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            estimator: x.estimator,
+        }
+    }
+
+    pub fn equals(&self, other: &Self) -> bool {
+        self.digest == other.digest
+            && self.iblt.equals(&other.iblt)
+            && self.power_sums == other.power_sums
+            && self.estimator == other.estimator
+    }
+
+    /// Validates the accumulator against the logged elements, where
+    /// `logged_iblt` calculates the IBLT of the logged elements and
+    /// `logged_power_sums` the given number of their power sums.
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_logged<F, G>(
+        &self,
+        elems: &[Vec<u8>],
+        logged_iblt: F,
+        logged_power_sums: G,
+        options: &ValidationOptions,
+    ) -> ValidationResult
+    where
+        F: FnOnce() -> InvBloomLookupTable,
+        G: Fn(usize) -> Vec<u32>,
+    {
+        let t1 = Instant::now();
+        if elems.len() < self.total() {
+            warn!("more elements received than logged");
+            return ValidationResult::Invalid;
+        }
+
+        // If no elements are missing, just recalculate the digest.
+        let n_dropped = elems.len() - self.total();
+        if n_dropped == 0 {
+            let mut digest = Digest::new();
+            for elem in elems {
+                digest.add(elem);
+            }
+            return if digest.equals(&self.digest) {
+                ValidationResult::Valid
+            } else {
+                ValidationResult::Invalid
+            };
+        }
+
+        let candidates =
+            match calculate_difference_iblt(
+                    n_dropped, elems, logged_iblt(), &self.iblt) {
+                Ok(candidates) => candidates,
+                Err(result) => { return result; }
+            };
+        debug!("calculated the difference iblt: {:?}", Instant::now() - t1);
+        validate_candidates(candidates, options, |iblt| {
+            self.validate_difference(
+                n_dropped, elems, iblt, &logged_power_sums, options)
+        })
+    }
+
+    /// Validates one candidate difference IBLT.
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_difference<G>(
+        &self,
+        n_dropped: usize,
+        elems: &[Vec<u8>],
+        mut iblt: InvBloomLookupTable,
+        logged_power_sums: &G,
+        options: &ValidationOptions,
+    ) -> ValidationResult
+    where
+        G: Fn(usize) -> Vec<u32>,
+    {
+        let t1 = Instant::now();
+        let peeled = match iblt.eliminate_elems() {
+            Ok(peeled) => peeled,
+            Err(PeelError::DuplicateElem(elem)) => {
+                warn!("eliminated element {} more than once", elem);
+                return ValidationResult::Invalid;
+            },
+            Err(PeelError::HashCheckMismatch(_)) => unreachable!(),
+        };
+        debug!("eliminated {}/{} elements using the iblt: {:?}",
+            peeled.removed.len(), n_dropped, Instant::now() - t1);
+        if peeled.removed.len() == n_dropped {
+            debug!("all iblt elements removed");
+            let checked = check_digest_from_removed_set(
+                &self.digest,
+                elems.iter().collect(),
+                peeled.removed,
+                options,
+            );
+            return match checked {
+                Some((true, false)) => ValidationResult::Valid,
+                Some((false, false)) => ValidationResult::Invalid,
+                Some((true, true)) => ValidationResult::IbltCollisionsValid,
+                Some((false, true)) => ValidationResult::IbltCollisionsInvalid,
+                None => ValidationResult::BudgetExceeded,
+            };
+        }
+        self.solve_core(
+            n_dropped, elems, &iblt, peeled, logged_power_sums, options)
+    }
+
+    /// Finds the elements left in the 2-core of the difference IBLT using
+    /// the power sums, and checks the digest against the solution.
+    #[cfg(not(feature = "disable_validation"))]
+    fn solve_core<G>(
+        &self,
+        n_dropped: usize,
+        elems: &[Vec<u8>],
+        iblt: &InvBloomLookupTable,
+        peeled: PeelResult,
+        logged_power_sums: &G,
+        options: &ValidationOptions,
+    ) -> ValidationResult
+    where
+        G: Fn(usize) -> Vec<u32>,
+    {
+        let t1 = Instant::now();
+        let n_remaining = n_dropped - peeled.removed.len();
+        if n_remaining > self.power_sums.len() {
+            debug!("{} elements in the iblt core exceed {} power sums",
+                n_remaining, self.power_sums.len());
+            return ValidationResult::PsumExceedsThreshold;
+        }
+
+        // The power sums of the elements in the core are those of the logged
+        // elements less those of the received and the peeled elements.
+        let logged_sums = logged_power_sums(n_remaining);
+        let mut peeled_sums = vec![0; n_remaining];
+        add_power_sums_batch(&mut peeled_sums, &peeled.removed.iter()
+            .map(|elem_u32| elem_u32 & DJB_MASK)
+            .collect::<Vec<_>>());
+        let core_sums = calculate_difference(
+            calculate_difference(
+                logged_sums, &self.power_sums[..n_remaining].to_vec()),
+            &peeled_sums,
+        );
+        let coeffs = compute_polynomial_coefficients(core_sums);
+        debug!("computed polynomial coefficients of {} core elements: {:?}",
+            n_remaining, Instant::now() - t1);
+        let t2 = Instant::now();
+
+        // Only the logged elements that hash entirely into the core could
+        // have been dropped, so they are the only candidate roots.
+        let core = peeled.core.iter().collect::<HashSet<_>>();
+        let mut candidates: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, elem) in elems.iter().enumerate() {
+            if iblt.indexes(elem).iter().all(|cell| core.contains(cell)) {
+                let elem_u32 = bloom_sd::elem_to_u32(elem) & DJB_MASK;
+                candidates.entry(elem_u32).or_default().push(i);
+            }
+        }
+        let roots = match find_roots_among(
+                coeffs, &candidates.keys().copied().collect()) {
+            Some(roots) => roots,
+            None => {
+                debug!("the power sums have roots outside the logged elements");
+                return ValidationResult::IbltPsumInvalid;
+            },
+        };
+        debug!("found {} roots among {} candidates in the iblt core: {:?}",
+            roots.len(), candidates.len(), Instant::now() - t2);
+
+        // Try every way to drop elements with the same value as each root.
+        let mut multiplicities: HashMap<u32, usize> = HashMap::new();
+        for root in roots {
+            *multiplicities.entry(root).or_insert(0) += 1;
+        }
+        let mut root_collisions = false;
+        let mut choices = vec![];
+        for (root, multiplicity) in multiplicities {
+            let indexes = candidates.remove(&root).unwrap();
+            if indexes.len() < multiplicity {
+                return ValidationResult::IbltPsumInvalid;
+            }
+            root_collisions |= indexes.len() > multiplicity;
+            choices.push(indexes.into_iter().combinations(multiplicity));
+        }
+        let mut result = None;
+        for dropped in choices.into_iter().multi_cartesian_product() {
+            if options.count_digest() {
+                return ValidationResult::BudgetExceeded;
+            }
+            let dropped = dropped.into_iter().flatten().collect::<HashSet<_>>();
+            let remaining = elems.iter()
+                .enumerate()
+                .filter(|(i, _)| !dropped.contains(i))
+                .map(|(_, elem)| elem)
+                .collect::<Vec<_>>();
+            let (valid, collisions) = match check_digest_from_removed_set(
+                    &self.digest, remaining, peeled.removed.clone(), options) {
+                Some(checked) => checked,
+                None => { return ValidationResult::BudgetExceeded; },
+            };
+            let collisions = collisions || root_collisions;
+            let current = match (valid, collisions) {
+                (true, false) => ValidationResult::IbltPsumValid,
+                (false, false) => ValidationResult::IbltPsumInvalid,
+                (true, true) => ValidationResult::IbltPsumCollisionsValid,
+                (false, true) => ValidationResult::IbltPsumCollisionsInvalid,
+            };
+            if valid {
+                return current;
+            }
+            result.get_or_insert(current);
+        }
+        result.unwrap_or(ValidationResult::IbltPsumInvalid)
+    }
+}
+
+impl Accumulator for HybridAccumulator {
+    fn to_bytes(&self) -> Vec<u8> {
+        assert_eq!(self.digest.count, (self.digest.count as u16) as u32);
+        bincode::serialize(&MiniHybridAccumulator {
+            hash: self.digest.hash,
+            count: self.digest.count as u16,
+            seed: u64::from_be_bytes(self.digest.nonce),
+            counters: self.iblt.counters().bits.to_bytes(),
+            data: self.iblt.data().bits.to_bytes(),
+            power_sums: self.power_sums.iter()
+                .flat_map(|psum| psum.to_be_bytes()).collect(),
+            estimator: self.estimator.clone(),
+        }).unwrap()
+    }
+
+    fn reset(&mut self) {
+        // The IBLT hash functions are derived from the seed, so rebuild the
+        // IBLT with the new one.
+        self.digest = Digest::new();
+        let seed = u64::from_be_bytes(self.digest.nonce);
+        self.iblt = InvBloomLookupTable::new_with_seed(
+            seed,
+            self.iblt.counters().bits_per_val(),
+            self.iblt.num_entries() as usize,
+            self.iblt.num_hashes(),
+        );
+        self.power_sums = vec![0; self.power_sums.len()];
+        self.estimator = StrataEstimator::new(seed);
+    }
+
+    fn process(&mut self, elem: &[u8]) {
+        self.digest.add(elem);
+        self.iblt.insert(elem);
+        let elem_u32 = bloom_sd::elem_to_u32(elem) & DJB_MASK;
+        add_power_sums(&mut self.power_sums, elem_u32);
+        self.estimator.insert(elem);
+    }
+
+    fn process_batch(&mut self, elems: &Vec<Vec<u8>>) {
+        for elem in elems {
+            self.process(elem);
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.digest.count as usize
+    }
+
+    fn estimator(&self) -> &StrataEstimator {
+        &self.estimator
+    }
+
+    #[cfg(feature = "disable_validation")]
+    fn validate_with_options(
+        &self,
+        _elems: &Vec<Vec<u8>>,
+        _options: &ValidationOptions,
+    ) -> ValidationResult {
+        panic!("validation not enabled")
+    }
+
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_with_options(
+        &self,
+        elems: &Vec<Vec<u8>>,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        self.validate_logged(elems, || {
+            logged_iblt(elems, &self.iblt, &options.executor)
+        }, |num_psums| {
+            let mut power_sums = vec![0; num_psums];
+            add_power_sums_batch(&mut power_sums, &elems.iter()
+                .map(|elem| bloom_sd::elem_to_u32(elem) & DJB_MASK)
+                .collect::<Vec<_>>());
+            power_sums
+        }, options)
+    }
+
+    /// Precomputes the IBLTs and power sums of the parts of the log around
+    /// the window ends, so those of each window add up three of them.
+    #[cfg(not(feature = "disable_validation"))]
+    fn window_validator<'a>(
+        &'a self,
+        log: &'a [Vec<u8>],
+        starts: RangeInclusive<usize>,
+        ends: RangeInclusive<usize>,
+    ) -> Box<dyn WindowValidator + 'a> {
+        let sums = WindowSums::new(
+            log, starts, ends,
+            (self.iblt.empty_clone(), vec![0; self.power_sums.len()]),
+            |(iblt, power_sums), elem| {
+                iblt.insert(elem);
+                add_power_sums(
+                    power_sums, bloom_sd::elem_to_u32(elem) & DJB_MASK);
+            },
+            |(lhs_iblt, lhs_sums), (rhs_iblt, rhs_sums)| {
+                let mut iblt = lhs_iblt.clone();
+                add_iblt(&mut iblt, rhs_iblt);
+                let power_sums = lhs_sums.iter().zip(rhs_sums)
+                    .map(|(&lhs, &rhs)| add_and_mod(lhs, rhs))
+                    .collect();
+                (iblt, power_sums)
+            });
+        Box::new(HybridWindows { accumulator: self, log, sums })
+    }
+}
+
+/// Validates windows of a log with the precomputed IBLTs and power sums.
+#[cfg(not(feature = "disable_validation"))]
+struct HybridWindows<'a> {
+    accumulator: &'a HybridAccumulator,
+    log: &'a [Vec<u8>],
+    sums: WindowSums<(InvBloomLookupTable, Vec<u32>)>,
+}
+
+#[cfg(not(feature = "disable_validation"))]
+impl WindowValidator for HybridWindows<'_> {
+    fn validate_window(
+        &self,
+        start: usize,
+        end: usize,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        let (iblt, power_sums) = self.sums.window(start, end);
+        self.accumulator.validate_logged(&self.log[start..end], || iblt,
+            |num_psums| power_sums[..num_psums].to_vec(), options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gen_elems_with_seed;
+
+    #[test]
+    fn test_serialization() {
+        let mut acc1 = HybridAccumulator::new(100, None);
+        let acc2 = HybridAccumulator::from_bytes(
+            &acc1.to_bytes(), DEFAULT_BITS_PER_ENTRY, DEFAULT_NUM_HASHES);
+        assert!(acc1.equals(&acc2));
+        acc1.process_batch(&gen_elems_with_seed(10, 111));
+        let acc3 = HybridAccumulator::from_bytes(
+            &acc1.to_bytes(), DEFAULT_BITS_PER_ENTRY, DEFAULT_NUM_HASHES);
+        assert!(!acc1.equals(&acc2));
+        assert!(acc1.equals(&acc3));
+    }
+
+    /// Processes all but the first `n_dropped` elements with an IBLT that is
+    /// too small to peel them all, and validates the log.
+    fn validate_core(
+        n_dropped: usize,
+        malicious: bool,
+        num_power_sums: usize,
+    ) -> ValidationResult {
+        let elems = gen_elems_with_seed(1000, 222);
+        let mut acc = HybridAccumulator::new_with_params(
+            n_dropped, 16, 1, 3, num_power_sums, Some(333));
+        for elem in &elems[n_dropped..] {
+            acc.process(elem);
+        }
+        if malicious {
+            acc.process(&[0; 16]);
+        }
+        acc.validate(&elems)
+    }
+
+    #[test]
+    fn test_power_sums_solve_core() {
+        assert_eq!(validate_core(20, false, 20), ValidationResult::IbltPsumValid);
+    }
+
+    #[test]
+    fn test_power_sums_solve_core_malicious() {
+        assert!(!validate_core(19, true, 20).is_valid());
+    }
+
+    #[test]
+    fn test_core_exceeds_power_sums() {
+        assert_eq!(validate_core(20, false, 1),
+            ValidationResult::PsumExceedsThreshold);
+    }
+}
@@ -3,13 +3,20 @@ use std::time::Instant;
 #[cfg(not(feature = "disable_validation"))]
 use std::collections::{HashSet, HashMap};
 #[cfg(not(feature = "disable_validation"))]
-use std::num::Wrapping;
+use std::ops::RangeInclusive;
 
 use bincode;
-use bit_vec::BitVec;
 use serde::{Serialize, Deserialize};
-use bloom_sd::{ValueVec, InvBloomLookupTable};
-use crate::{Accumulator, ValidationResult};
+use bloom_sd::{ValueVec, InvBloomLookupTable, StrataEstimator};
+#[cfg(not(feature = "disable_validation"))]
+use bloom_sd::{PeelError, PeelResult, SignedElems};
+use crate::{Accumulator, ValidationOptions, ValidationResult};
+#[cfg(not(feature = "disable_validation"))]
+use crate::Executor;
+#[cfg(not(feature = "disable_validation"))]
+use crate::executor::find_matching_combination;
+#[cfg(not(feature = "disable_validation"))]
+use crate::window::{WindowSums, WindowValidator};
 use digest::{Digest, AmhHash};
 #[cfg(not(feature = "disable_validation"))]
 use itertools::Itertools;
@@ -24,6 +31,7 @@ extern "C" {
         n_packets: usize,
         pkt_hashes: *const u32,
         n_dropped: usize,
+        time_limit_ms: i32,
         dropped: *mut usize,
     ) -> i32;
 }
@@ -32,6 +40,12 @@ extern "C" {
 pub const DEFAULT_BITS_PER_ENTRY: usize = 8;
 pub const DEFAULT_CELLS_MULTIPLIER: usize = 10;
 pub const DEFAULT_NUM_HASHES: u32 = 2;
+/// Maximum number of candidate counts of the difference IBLT to consider if
+/// its counters wrapped around.
+pub const MAX_WRAPAROUND_CANDIDATES: usize = 1000;
+/// Bits per counter of the difference IBLT after resolving wraparound.
+#[cfg(not(feature = "disable_validation"))]
+const UNWRAPPED_BITS_PER_ENTRY: usize = 32;
 
 /// The counting bloom filter (IBLT) accumulator stores a IBLT of all processed
 /// packets in addition to the digest.
@@ -45,49 +59,99 @@ pub const DEFAULT_NUM_HASHES: u32 = 2;
 pub struct IBLTAccumulator {
     digest: Digest,
     iblt: InvBloomLookupTable,
+    estimator: StrataEstimator,
+}
+
+/// Adds the cells of `other` to `iblt`, modulo the range of each field. The
+/// result is the IBLT of the elements of both, since every field is a sum
+/// over the elements in the cell.
+#[cfg(not(feature = "disable_validation"))]
+pub(crate) fn add_iblt(
+    iblt: &mut InvBloomLookupTable,
+    other: &InvBloomLookupTable,
+) {
+    iblt.counters_mut().wrapping_add(other.counters());
+    iblt.data_mut().wrapping_add(other.data());
+    if let Some(checks) = other.hash_checks() {
+        iblt.hash_checks_mut().unwrap().wrapping_add(checks);
+    }
+}
+
+/// Calculate an IBLT from the logged elements with the same parameters as
+/// the IBLT of the received elements.
+/// - `logged_elems`: the list of logged elements
+/// - `received_iblt`: the IBLT of the receiving accumulator
+/// - `executor`: where to insert chunks of the log into partial IBLTs
+#[cfg(not(feature = "disable_validation"))]
+pub(crate) fn logged_iblt(
+    logged_elems: &[Vec<u8>],
+    received_iblt: &InvBloomLookupTable,
+    executor: &Executor,
+) -> InvBloomLookupTable {
+    let mut partial_iblts = executor.map_chunks(logged_elems, |chunk| {
+        let mut iblt = received_iblt.empty_clone();
+        for elem in chunk {
+            iblt.insert(elem);
+        }
+        iblt
+    }).into_iter();
+    let mut iblt = partial_iblts.next()
+        .unwrap_or_else(|| received_iblt.empty_clone());
+    for partial_iblt in partial_iblts {
+        add_iblt(&mut iblt, &partial_iblt);
+    }
+    iblt
+}
+
+/// Subtract the IBLT of the received elements from the IBLT of the logged
+/// elements. Returns the difference IBLT and the sum of its counters.
+/// - `iblt`: the IBLT of the logged elements
+/// - `received_iblt`: the IBLT of the receiving accumulator
+#[cfg(not(feature = "disable_validation"))]
+fn subtract_iblt(
+    mut iblt: InvBloomLookupTable,
+    received_iblt: &InvBloomLookupTable,
+) -> (InvBloomLookupTable, u32) {
+    // Subtract cell by cell, modulo the range of each field. This handles
+    // counter overflows i.e. if the Bloom filter stores the count modulo some
+    // number instead of the exact count, derived from the bits per entry.
+    iblt.counters_mut().wrapping_sub(received_iblt.counters());
+    iblt.data_mut().wrapping_sub(received_iblt.data());
+    if let Some(received_checks) = received_iblt.hash_checks() {
+        iblt.hash_checks_mut().unwrap().wrapping_sub(received_checks);
+    }
+    let iblt_sum = iblt.counters().sum() as u32;
+    (iblt, iblt_sum)
 }
 
-/// Calculate an IBLT from the logged elements, and subtract the IBLT of the
-/// received elements from this newly-constructed IBLT.
+/// Calculate the difference IBLT between the logged elements and the IBLT of
+/// the received elements, and check that its counters are consistent with
+/// only dropped elements. Returns the candidate difference IBLTs: just the
+/// difference if its counters did not wrap around, otherwise one for every
+/// way its counters could have wrapped around (see `unwrap_difference_iblt`).
+/// Each candidate is built as it is iterated over.
 /// - `n_dropped`: expected number of dropped elements
 /// - `logged_elems`: the list of logged elements
+/// - `logged_iblt`: the IBLT of the logged elements, see `logged_iblt()`
 /// - `received_iblt`: the IBLT of the receiving accumulator
 #[cfg(not(feature = "disable_validation"))]
-fn calculate_difference_iblt(
+pub(crate) fn calculate_difference_iblt(
     n_dropped: usize,
-    logged_elems: &Vec<Vec<u8>>,
+    logged_elems: &[Vec<u8>],
+    logged_iblt: InvBloomLookupTable,
     received_iblt: &InvBloomLookupTable,
-) -> Result<InvBloomLookupTable, ValidationResult> {
-    let mut iblt = received_iblt.empty_clone();
-    for elem in logged_elems {
-        iblt.insert(elem);
-    }
-    let mut iblt_sum = 0;
+) -> Result<DifferenceCandidates, ValidationResult> {
+    let (iblt, iblt_sum) = subtract_iblt(logged_iblt, received_iblt);
     let wraparound_mask = (1 << (iblt.counters().bits_per_val() as u32)) - 1;
-    for i in 0..(iblt.num_entries() as usize) {
-        let logged_count = iblt.counters().get(i);
-        let received_count = received_iblt.counters().get(i);
-        // Handle counter overflows i.e. if the Bloom filter
-        // stores the count modulo some number instead of the exact count.
-        // This number is derived from the bits per entry.
-        let difference_count =
-            (Wrapping(logged_count) - Wrapping(received_count)).0
-            & wraparound_mask;
-        iblt.counters_mut().set(i, difference_count);
-        iblt_sum += difference_count;
-
-        // Additionally set the data value
-        let logged_data = iblt.data().get(i);
-        let received_data = received_iblt.data().get(i);
-        let difference_data =
-            (Wrapping(logged_data) - Wrapping(received_data)).0;
-        iblt.data_mut().set(i, difference_data);
-    }
 
     // If the number of dropped packets multiplied by the number of hashes is
     // equal to the sum of all entries in the IBLT, proceed with the ILP check.
     if (n_dropped as u32) * iblt.num_hashes() == iblt_sum {
-        return Ok(iblt);
+        return Ok(DifferenceCandidates {
+            iblt: Some(iblt),
+            modulus: 0,
+            wraps: vec![vec![]].into_iter(),
+        });
     }
 
     // Otherwise there was wraparound, which either occurs if a counter has a
@@ -95,16 +159,179 @@ fn calculate_difference_iblt(
     // difference is some larger number modulo the max value of an IBLT entry.
     // We can say that if the number of dropped packets does not exceed this
     // max value, then wraparound definitely should not have occurred.
-    // Otherwise, we simply do not handle wraparound, as the user should choose
-    // a threshold that allows the number of dropped packets in a cell to rarely
-    // exceed the threshold (unless dropped packets have high multiplicity?).
+    // Otherwise, the counters are residues and we consider every count they
+    // could stand for, as long as there are not too many.
     if (n_dropped as u32) <= wraparound_mask {
         debug!("malicious wraparound detected");
         Err(ValidationResult::IbltMaliciousWraparound)
     } else {
-        info!("not handling potentially benign wraparound, may need to select
-            a bigger threshold");
-        Err(ValidationResult::IbltBenignWraparound)
+        unwrap_difference_iblt(n_dropped, logged_elems, iblt)
+    }
+}
+
+/// Enumerates the counts of a difference IBLT whose counters are only known
+/// modulo 2^bits_per_entry. The count of each cell must be congruent to its
+/// counter, at most the number of logged elements in that cell, and the
+/// counts must sum to the number of dropped elements times the number of
+/// hashes. Returns the candidates, which are copies of the IBLT with wider
+/// counters.
+///
+/// Returns `IbltMaliciousWraparound` if no counts are consistent, and
+/// `IbltBenignWraparound` if there are more than `MAX_WRAPAROUND_CANDIDATES`.
+/// - `n_dropped`: expected number of dropped elements
+/// - `logged_elems`: the list of logged elements
+/// - `iblt`: the difference IBLT
+#[cfg(not(feature = "disable_validation"))]
+fn unwrap_difference_iblt(
+    n_dropped: usize,
+    logged_elems: &[Vec<u8>],
+    iblt: InvBloomLookupTable,
+) -> Result<DifferenceCandidates, ValidationResult> {
+    let modulus = 1_u64 << iblt.counters().bits_per_val();
+    let total = n_dropped as u64 * iblt.num_hashes() as u64;
+    let residues_sum = iblt.counters().sum();
+    if residues_sum > total || !(total - residues_sum).is_multiple_of(modulus) {
+        debug!("malicious wraparound detected");
+        return Err(ValidationResult::IbltMaliciousWraparound);
+    }
+    let n_wraps = (total - residues_sum) / modulus;
+
+    // Count the logged elements in each cell to bound how many times each
+    // counter could have wrapped around.
+    let mut logged = iblt.empty_clone()
+        .with_counter_bits(UNWRAPPED_BITS_PER_ENTRY);
+    for elem in logged_elems {
+        logged.insert(elem);
+    }
+    let mut max_wraps = vec![];
+    for (i, residue) in iblt.counters().iter().enumerate() {
+        let n_logged = logged.counters().get(i);
+        if residue > n_logged {
+            debug!("malicious wraparound detected in cell {}", i);
+            return Err(ValidationResult::IbltMaliciousWraparound);
+        }
+        let wraps = (n_logged - residue) / modulus;
+        if wraps > 0 {
+            max_wraps.push((i, wraps));
+        }
+    }
+
+    // Distribute the wraps among the cells depth-first.
+    fn distribute(
+        max_wraps: &[(usize, u64)],
+        n_wraps: u64,
+        wraps: &mut Vec<(usize, u64)>,
+        candidates: &mut Vec<Vec<(usize, u64)>>,
+    ) -> bool {
+        if n_wraps == 0 {
+            candidates.push(wraps.clone());
+            return candidates.len() <= MAX_WRAPAROUND_CANDIDATES;
+        }
+        let capacity: u64 = max_wraps.iter().map(|&(_, max)| max).sum();
+        if capacity < n_wraps {
+            return true;
+        }
+        let (i, max) = max_wraps[0];
+        for n in (0..=std::cmp::min(max, n_wraps)).rev() {
+            if n > 0 {
+                wraps.push((i, n));
+            }
+            let ok = distribute(&max_wraps[1..], n_wraps - n, wraps, candidates);
+            if n > 0 {
+                wraps.pop();
+            }
+            if !ok {
+                return false;
+            }
+        }
+        true
+    }
+    let mut candidates = vec![];
+    if !distribute(&max_wraps, n_wraps, &mut vec![], &mut candidates) {
+        info!("more than {} ways the iblt counters could have wrapped around, \
+            may need to select a bigger threshold", MAX_WRAPAROUND_CANDIDATES);
+        return Err(ValidationResult::IbltBenignWraparound);
+    }
+    if candidates.is_empty() {
+        debug!("malicious wraparound detected");
+        return Err(ValidationResult::IbltMaliciousWraparound);
+    }
+    debug!("{} candidate counts for {} wraps of the iblt counters",
+        candidates.len(), n_wraps);
+    Ok(DifferenceCandidates {
+        iblt: Some(iblt.with_counter_bits(UNWRAPPED_BITS_PER_ENTRY)),
+        modulus,
+        wraps: candidates.into_iter(),
+    })
+}
+
+/// The candidate difference IBLTs, each a copy of the difference IBLT with
+/// some of its counters wrapped around some number of times. Only the number
+/// of wraps of each candidate is stored, and the IBLT is copied when the
+/// candidate is needed.
+#[cfg(not(feature = "disable_validation"))]
+pub(crate) struct DifferenceCandidates {
+    iblt: Option<InvBloomLookupTable>,
+    modulus: u64,
+    wraps: std::vec::IntoIter<Vec<(usize, u64)>>,
+}
+
+#[cfg(not(feature = "disable_validation"))]
+impl Iterator for DifferenceCandidates {
+    type Item = InvBloomLookupTable;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let wraps = self.wraps.next()?;
+        // The last candidate takes the IBLT instead of copying it.
+        let mut candidate = if self.wraps.as_slice().is_empty() {
+            self.iblt.take()?
+        } else {
+            self.iblt.clone()?
+        };
+        for (i, n) in wraps {
+            let count = candidate.counters().get(i) + n * self.modulus;
+            candidate.counters_mut().set(i, count);
+        }
+        Some(candidate)
+    }
+}
+
+/// Validates each candidate difference IBLT as it is built, and combines the
+/// verdicts. Only the candidate with the true counts is consistent with the
+/// log, so the log is valid if any candidate is valid, and invalid only if
+/// every candidate is definitely invalid. Otherwise the verdict is
+/// undetermined: the verdict on the only candidate, or `IbltBenignWraparound`
+/// if there were several. Stops as soon as the budget is exceeded.
+#[cfg(not(feature = "disable_validation"))]
+pub(crate) fn validate_candidates<F>(
+    candidates: DifferenceCandidates,
+    options: &ValidationOptions,
+    mut validate: F,
+) -> ValidationResult
+where
+    F: FnMut(InvBloomLookupTable) -> ValidationResult,
+{
+    let mut n_candidates = 0;
+    let mut invalid = None;
+    let mut undetermined = None;
+    for iblt in candidates {
+        if options.is_exceeded() {
+            return ValidationResult::BudgetExceeded;
+        }
+        n_candidates += 1;
+        let result = validate(iblt);
+        if result.is_valid() || result == ValidationResult::BudgetExceeded {
+            return result;
+        } else if result.is_undetermined() {
+            undetermined.get_or_insert(result);
+        } else {
+            invalid.get_or_insert(result);
+        }
+    }
+    match undetermined {
+        Some(_) if n_candidates > 1 => ValidationResult::IbltBenignWraparound,
+        Some(result) => result,
+        None => invalid.unwrap(),
     }
 }
 
@@ -112,17 +339,18 @@ fn calculate_difference_iblt(
 /// dropped elements that produce the same digest.
 /// - `elems`: the list of logged elements
 /// - `removed_u32`: the set of DJB hashes of removed elements from the IBLT.
-///    Elements are necessarily unique or they would have hashed to the same
-///    slot in the IBLT.
+///   Elements are necessarily unique or they would have hashed to the same
+///   slot in the IBLT.
 ///
 /// Returns whether the digest is valid, and whether any collisions were
-/// resolved.
+/// resolved, or None if trying combinations exceeded the options.
 #[cfg(not(feature = "disable_validation"))]
-fn check_digest_from_removed_set(
+pub(crate) fn check_digest_from_removed_set(
     expected_digest: &Digest,
     elems: Vec<&Vec<u8>>,
     removed: HashSet<u32>,
-) -> (bool, bool) {
+    options: &ValidationOptions,
+) -> Option<(bool, bool)> {
     // Create a map from DJB hash to elements that hash to that value. If the
     // DJB hash is not in the removed set, then the packet was not dropped, so
     // add it to the digest. Otherwise, it might have been dropped.
@@ -140,7 +368,7 @@ fn check_digest_from_removed_set(
     // If not every element in the removed set has a preimage, we are missing
     // an element from the log.
     if removed.len() != collisions_map.len() {
-        return (false, false);
+        return Some((false, false));
     }
 
     // Remove any entries from the collisions map with only one preimage value.
@@ -154,60 +382,65 @@ fn check_digest_from_removed_set(
     if combinations.len() == 0 {
         debug!("no collisions, checking digest");
         assert_eq!(digest.count, expected_digest.count);
-        return (digest.equals(&expected_digest), false);
+        return Some((digest.equals(&expected_digest), false));
     }
     debug!("handling collisions for {} removed elems", combinations.len());
 
     // Try every combination of remaining elements with one removed per slot,
     // and if any of them produce a matching digest, accept.
     let t1 = Instant::now();
-    for (n_digests, combination) in
-            combinations.into_iter().multi_cartesian_product().enumerate() {
-        let mut digest = digest.clone();
-        for elem in combination.into_iter().flat_map(|val| val) {
-            digest.add(&elem);
-        }
-        assert_eq!(digest.count, expected_digest.count);
-        if digest.equals(&expected_digest) {
-            debug!(
-                "found matching digest after checking {} digests: {:?}",
-                n_digests,
-                Instant::now() - t1,
-            );
-            return (true, true);
-        }
-    }
-    (false, true)
+    let matched = find_matching_combination(
+        &digest,
+        expected_digest,
+        combinations.into_iter().multi_cartesian_product(),
+        options,
+    );
+    debug!("checked combinations: {:?}", Instant::now() - t1);
+    matched.map(|valid| (valid, true))
 }
 
 /// Returns the indexes of the dropped elements in `elems` that satisfy the
 /// counters in the IBLT. Does not check the data fields in the IBLT, which may
 /// not be accurate if there is more than one solution these constraints.
 /// - `n_dropped`: expected number of dropped elements less the number of
-///    elements already removed from the IBLT
+///   elements already removed from the IBLT
 /// - `elems`: the list of logged elements
 /// - `iblt`: the difference IBLT
+/// - `core`: the indexes of the cells that could not be peeled. All other
+///   cells are zero, so only the core cells and the elements that hash
+///   entirely into the core are included in the ILP.
+/// - `options`: the time left until the deadline limits the solver
+///
+/// Returns the verdict instead if there is no solution or the solver ran
+/// out of time.
 #[cfg(not(feature = "disable_validation"))]
 fn solve_ilp_for_iblt(
     n_dropped_remaining: usize,
-    elems: &Vec<Vec<u8>>,
+    elems: &[Vec<u8>],
     iblt: InvBloomLookupTable,
-) -> Option<HashSet<usize>> {
+    core: &[usize],
+    options: &ValidationOptions,
+) -> Result<HashSet<usize>, ValidationResult> {
     // Number of equations = # of remaining candidate elements in `elems_i`.
-    // Number of variables = number of cells in the IBLT.
+    // Number of variables = number of cells in the core of the IBLT.
+    let rows: HashMap<usize, u32> = core.iter()
+        .enumerate()
+        .map(|(row, &cell)| (cell, row as u32))
+        .collect();
     let mut elems_i: Vec<usize> = vec![];
     let pkt_hashes: Vec<u32> = elems
         .iter()
         .enumerate()
-        .filter(|(_, elem)| iblt.contains(&elem))
-        .flat_map(|(i, elem)| {
+        .map(|(i, elem)| (i, iblt.indexes(elem)))
+        .filter(|(_, indexes)| indexes.iter().all(|i| rows.contains_key(i)))
+        .flat_map(|(i, indexes)| {
             elems_i.push(i);
-            iblt.indexes(&elem)
+            indexes
         })
-        .map(|hash| hash as u32)
+        .map(|cell| rows[&cell])
         .collect();
-    let counters: Vec<usize> = (0..(iblt.num_entries() as usize))
-        .map(|i| iblt.counters().get(i))
+    let counters: Vec<usize> = core.iter()
+        .map(|&i| iblt.counters().get(i))
         .map(|count| count.try_into().unwrap())
         .collect();
     assert!(n_dropped_remaining <= elems_i.len());
@@ -222,6 +455,13 @@ fn solve_ilp_for_iblt(
     // Instead, if there are fewer (linearly independent) equations than
     // the sum of the counters divided by the number of hashes, then there
     // is no solution. If there are more, there may be multiple solutions.
+    if options.is_exceeded() {
+        return Err(ValidationResult::BudgetExceeded);
+    }
+    // GLPK takes the time limit in milliseconds, where i32::MAX means none.
+    let time_limit_ms = options.time_remaining()
+        .map(|time| time.as_millis().clamp(1, i32::MAX as u128) as i32)
+        .unwrap_or(i32::MAX);
     let mut dropped: Vec<usize> = vec![0; n_dropped_remaining];
     let err = unsafe {
         solve_ilp_glpk(
@@ -231,14 +471,18 @@ fn solve_ilp_for_iblt(
             elems_i.len(),
             pkt_hashes.as_ptr(),
             n_dropped_remaining,
+            time_limit_ms,
             dropped.as_mut_ptr(),
         )
     };
-    if err != 0 {
+    if err == -4 {
+        warn!("ILP solver ran out of time");
+        return Err(ValidationResult::BudgetExceeded);
+    } else if err != 0 {
         warn!("ILP solving error: {}", err);
-        return None;
+        return Err(ValidationResult::IbltIlpInvalid);
     }
-    Some(dropped
+    Ok(dropped
         .into_iter()
         .map(|dropped_i| elems_i[dropped_i])
         .collect::<HashSet<_>>())
@@ -251,6 +495,9 @@ struct MiniIBLTAccumulator {
     seed: u64,         // seed for multiset hash, IBLT hash
     counters: Vec<u8>, // bits_per_val = IBLT_BITS_PER_ENTRY
     data: Vec<u8>,     // bits_per_val = DJB_HASH_SIZE
+    hash_checks: Option<Vec<u8>>, // bits_per_val = DJB_HASH_SIZE
+    partitioned: bool, // whether the IBLT hashes into disjoint subtables
+    estimator: StrataEstimator,
 }
 
 impl IBLTAccumulator {
@@ -260,6 +507,38 @@ impl IBLTAccumulator {
         cells_multiplier: usize,
         num_hashes: u32,
         seed: Option<u64>,
+    ) -> Self {
+        Self::new_with_options(
+            threshold, bits_per_entry, cells_multiplier, num_hashes,
+            false, false, seed)
+    }
+
+    /// Like `new_with_params()`, but each IBLT cell also stores a keyed
+    /// checksum of its elements. Validation then only peels cells that hold
+    /// a single element, and reports cells that mix dropped and injected
+    /// elements as invalid.
+    pub fn new_with_hash_check(
+        threshold: usize,
+        bits_per_entry: usize,
+        cells_multiplier: usize,
+        num_hashes: u32,
+        seed: Option<u64>,
+    ) -> Self {
+        Self::new_with_options(
+            threshold, bits_per_entry, cells_multiplier, num_hashes,
+            true, false, seed)
+    }
+
+    /// The general constructor. See `InvBloomLookupTable::new_with_options()`
+    /// for the meaning of `hash_check` and `partitioned`.
+    pub fn new_with_options(
+        threshold: usize,
+        bits_per_entry: usize,
+        cells_multiplier: usize,
+        num_hashes: u32,
+        hash_check: bool,
+        partitioned: bool,
+        seed: Option<u64>,
     ) -> Self {
         let digest = if let Some(seed) = seed {
             Digest::new_with_seed(seed.to_be_bytes())
@@ -267,13 +546,16 @@ impl IBLTAccumulator {
             Digest::new()
         };
         let seed = u64::from_be_bytes(digest.nonce);
-        let iblt = InvBloomLookupTable::new_with_seed(
+        let iblt = InvBloomLookupTable::new_with_options(
             seed,
             bits_per_entry,
             cells_multiplier * threshold,
             num_hashes,
+            hash_check,
+            partitioned,
         );
-        Self { digest, iblt }
+        let estimator = StrataEstimator::new(seed);
+        Self { digest, iblt, estimator }
     }
 
     pub fn new(threshold: usize, seed: Option<u64>) -> Self {
@@ -287,25 +569,28 @@ impl IBLTAccumulator {
     }
 
     pub fn from_bytes(
-        bytes: &Vec<u8>,
+        bytes: &[u8],
         bits_per_entry: usize,
         num_hashes: u32,
     ) -> Self {
-        fn to_valuevec(bytes: Vec<u8>, bpe: usize) -> ValueVec {
-            ValueVec {
-                bits_per_val: bpe,
-                mask: ((1_u64 << (bpe as u64))-1) as u32,
-                bits: BitVec::from_bytes(&bytes),
-            }
-        }
-
         let x: MiniIBLTAccumulator =
             bincode::deserialize(bytes).unwrap();
         let num_entries = x.counters.len() * 8 / bits_per_entry;
-        let mut iblt = InvBloomLookupTable::new_with_seed(
-            x.seed, bits_per_entry, num_entries, num_hashes);
-        *iblt.counters_mut() = to_valuevec(x.counters, bits_per_entry);
-        *iblt.data_mut() = to_valuevec(x.data, bloom_sd::DJB_HASH_SIZE);
+        let mut iblt = InvBloomLookupTable::new_with_options(
+            x.seed,
+            bits_per_entry,
+            num_entries,
+            num_hashes,
+            x.hash_checks.is_some(),
+            x.partitioned,
+        );
+        *iblt.counters_mut() = ValueVec::from_bytes(bits_per_entry, &x.counters);
+        *iblt.data_mut() =
+            ValueVec::from_bytes(bloom_sd::DJB_HASH_SIZE, &x.data);
+        if let Some(hash_checks) = x.hash_checks {
+            *iblt.hash_checks_mut().unwrap() =
+                ValueVec::from_bytes(bloom_sd::DJB_HASH_SIZE, &hash_checks);
+        }
         Self {
             digest: Digest {
                 hash: x.hash,
@@ -313,54 +598,29 @@ impl IBLTAccumulator {
                 nonce: x.seed.to_be_bytes(),
             },
             iblt,
+            estimator: x.estimator,
         }
     }
 
     pub fn equals(&self, other: &Self) -> bool {
         self.digest == other.digest
             && self.iblt.equals(&other.iblt)
-    }
-}
-
-impl Accumulator for IBLTAccumulator {
-    fn to_bytes(&self) -> Vec<u8> {
-        assert_eq!(self.digest.count, (self.digest.count as u16) as u32);
-        bincode::serialize(&MiniIBLTAccumulator {
-            hash: self.digest.hash,
-            count: self.digest.count as u16,
-            seed: u64::from_be_bytes(self.digest.nonce),
-            counters: self.iblt.counters().bits.to_bytes(),
-            data: self.iblt.data().bits.to_bytes(),
-        }).unwrap()
-    }
-
-    fn reset(&mut self) {
-        self.digest = Digest::new();
-        self.iblt = self.iblt.empty_clone();
-    }
-
-    fn process(&mut self, elem: &[u8]) {
-        self.digest.add(elem);
-        self.iblt.insert(elem);
-    }
-
-    fn process_batch(&mut self, elems: &Vec<Vec<u8>>) {
-        for elem in elems {
-            self.process(elem);
-        }
-    }
-
-    fn total(&self) -> usize {
-        self.digest.count as usize
-    }
-
-    #[cfg(feature = "disable_validation")]
-    fn validate(&self, _elems: &Vec<Vec<u8>>) -> ValidationResult {
-        panic!("validation not enabled")
+            && self.estimator == other.estimator
     }
 
     #[cfg(not(feature = "disable_validation"))]
-    fn validate(&self, elems: &Vec<Vec<u8>>) -> ValidationResult {
+    /// Validates that the received elements are a subset of the logged
+    /// elements, assuming the only difference is dropped elements.
+    /// `logged_iblt` calculates the IBLT of the logged elements.
+    fn validate_subset<F>(
+        &self,
+        elems: &[Vec<u8>],
+        logged_iblt: F,
+        options: &ValidationOptions,
+    ) -> ValidationResult
+    where
+        F: FnOnce() -> InvBloomLookupTable,
+    {
         let t1 = Instant::now();
         if elems.len() < self.total() {
             warn!("more elements received than logged");
@@ -381,23 +641,57 @@ impl Accumulator for IBLTAccumulator {
             };
         }
 
-        let mut iblt = {
-            match calculate_difference_iblt(n_dropped, elems, &self.iblt) {
-                Ok(iblt) => iblt,
+        let candidates =
+            match calculate_difference_iblt(
+                    n_dropped, elems, logged_iblt(), &self.iblt) {
+                Ok(candidates) => candidates,
                 Err(result) => { return result; }
+            };
+        debug!("calculated the difference iblt: {:?}", Instant::now() - t1);
+
+        // Peel each candidate difference IBLT, and solve an ILP if it could
+        // not be peeled, before building the next candidate.
+        validate_candidates(candidates, options, |mut iblt| {
+            match self.peel_difference(n_dropped, elems, &mut iblt, options) {
+                Ok(peeled) => self.solve_difference(
+                    n_dropped, elems, iblt, peeled, options),
+                Err(result) => result,
             }
-        };
-        let t2 = Instant::now();
-        debug!("calculated the difference iblt: {:?}", t2 - t1);
+        })
+    }
+
+    /// Peels the difference IBLT. Returns the peeled elements if some could
+    /// not be peeled, or the verdict if peeling was enough to decide.
+    #[cfg(not(feature = "disable_validation"))]
+    fn peel_difference(
+        &self,
+        n_dropped: usize,
+        elems: &[Vec<u8>],
+        iblt: &mut InvBloomLookupTable,
+        options: &ValidationOptions,
+    ) -> Result<PeelResult, ValidationResult> {
+        let t1 = Instant::now();
 
         // Remove any elements that are definitely dropped based on counters
         // in the IBLT that are set to 1. Then find the remaining list of
         // candidate dropped elements by based on any whose indexes are still
         // not 0. If elements are not unique, the ILP can find _a_ solution.
-        let removed = iblt.eliminate_elems();
-        let t3 = Instant::now();
+        // Unless the counts are one guess at how the counters wrapped around,
+        // a cell with a counter of 1 that fails the hash check must contain an
+        // injected element.
+        let peeled = match iblt.eliminate_elems() {
+            Ok(peeled) => peeled,
+            Err(PeelError::HashCheckMismatch(cells)) => {
+                warn!("{} iblt cells failed the hash check", cells.len());
+                return Err(ValidationResult::IbltHashCheckMismatch);
+            },
+            Err(PeelError::DuplicateElem(elem)) => {
+                warn!("eliminated element {} more than once", elem);
+                return Err(ValidationResult::Invalid);
+            },
+        };
         debug!("eliminated {}/{} elements using the iblt: {:?}",
-            removed.len(), n_dropped, t3 - t2);
+            peeled.removed.len(), n_dropped, Instant::now() - t1);
 
         // The remaining maybe dropped elements should make up any non-zero
         // entries in the IBLT. Since we checked that the number of dropped
@@ -406,33 +700,57 @@ impl Accumulator for IBLTAccumulator {
         // entries. This means solving an ILP is unnecessary but we still
         // check that the digest matches in case the router constructed a
         // preimage collision.
-        if removed.len() == n_dropped {
-            debug!("all iblt elements removed");
-            let (valid, collisions) = check_digest_from_removed_set(
-                &self.digest,
-                elems.iter().collect(),
-                removed,
-            );
-            return match (valid, collisions) {
-                (true, false) => ValidationResult::Valid,
-                (false, false) => ValidationResult::Invalid,
-                (true, true) => ValidationResult::IbltCollisionsValid,
-                (false, true) => ValidationResult::IbltCollisionsInvalid,
-            };
+        if peeled.removed.len() != n_dropped {
+            return Ok(peeled);
         }
+        debug!("all iblt elements removed");
+        let checked = check_digest_from_removed_set(
+            &self.digest,
+            elems.iter().collect(),
+            peeled.removed,
+            options,
+        );
+        Err(match checked {
+            Some((true, false)) => ValidationResult::Valid,
+            Some((false, false)) => ValidationResult::Invalid,
+            Some((true, true)) => ValidationResult::IbltCollisionsValid,
+            Some((false, true)) => ValidationResult::IbltCollisionsInvalid,
+            None => ValidationResult::BudgetExceeded,
+        })
+    }
+
+    /// Solves an ILP for the elements that could not be peeled from the
+    /// difference IBLT, and checks the digest against the solution.
+    #[cfg(not(feature = "disable_validation"))]
+    fn solve_difference(
+        &self,
+        n_dropped: usize,
+        elems: &[Vec<u8>],
+        iblt: InvBloomLookupTable,
+        peeled: PeelResult,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        let t1 = Instant::now();
 
         // Then there are still some remaining candidate dropped elements,
         // and the IBLT is not empty. Solve an ILP to determine which elements
-        // could make up the counters in the IBLT.
+        // could make up the counters in the IBLT. If there is no solution, no
+        // subset of the logged elements explains the counters.
+        let removed = peeled.removed;
         assert!(n_dropped > removed.len());
         let n_dropped_remaining = n_dropped - removed.len();
-        let dropped_is = solve_ilp_for_iblt(
+        debug!("{} iblt cells in the unresolved core", peeled.core.len());
+        let dropped_is = match solve_ilp_for_iblt(
             n_dropped_remaining,
             elems,
             iblt,
-        ).expect("error solving ilp");
-        let t4 = Instant::now();
-        debug!("solved ILP: {:?}", t4 - t3);
+            &peeled.core,
+            options,
+        ) {
+            Ok(dropped_is) => dropped_is,
+            Err(result) => { return result; },
+        };
+        debug!("solved ILP: {:?}", Instant::now() - t1);
 
         // Right now we have:
         // * `removed` - the djb hash of elems that were definitely dropped
@@ -444,29 +762,258 @@ impl Accumulator for IBLTAccumulator {
             .filter(|(i, _)| !dropped_is.contains(&i))
             .map(|(_, elem)| elem)
             .collect::<Vec<_>>();
-        let (valid, collisions) =
-            check_digest_from_removed_set(&self.digest, elems, removed);
-        return match (valid, collisions) {
-            (true, false) => ValidationResult::IbltIlpValid,
-            (false, false) => ValidationResult::IbltIlpInvalid,
-            (true, true) => ValidationResult::IbltIlpCollisionsValid,
-            (false, true) => ValidationResult::IbltIlpCollisionsInvalid,
-        };
+        let checked = check_digest_from_removed_set(
+            &self.digest, elems, removed, options);
+        match checked {
+            Some((true, false)) => ValidationResult::IbltIlpValid,
+            Some((false, false)) => ValidationResult::IbltIlpInvalid,
+            Some((true, true)) => ValidationResult::IbltIlpCollisionsValid,
+            Some((false, true)) => ValidationResult::IbltIlpCollisionsInvalid,
+            None => ValidationResult::BudgetExceeded,
+        }
+    }
+
+    /// Decodes both sides of the difference between the logged elements and
+    /// the received elements using signed peeling, given the IBLT of the
+    /// logged elements. Returns None if the difference IBLT could not be
+    /// peeled.
+    #[cfg(not(feature = "disable_validation"))]
+    fn peel_signed(
+        &self,
+        logged_iblt: InvBloomLookupTable,
+    ) -> Option<SignedElems> {
+        let t1 = Instant::now();
+        let (mut iblt, _) = subtract_iblt(logged_iblt, &self.iblt);
+        let result = iblt.eliminate_elems_signed();
+        debug!("signed peeling of the difference iblt: {:?}",
+            Instant::now() - t1);
+        match result {
+            Ok(signed) => Some(signed),
+            Err(e) => {
+                debug!("could not peel signed iblt: {:?}", e);
+                None
+            },
+        }
+    }
+
+    /// Returns the sorted DJB hashes of the elements that were received but
+    /// not logged, if the difference IBLT could be peeled and there is at
+    /// least one.
+    #[cfg(not(feature = "disable_validation"))]
+    fn find_injected(
+        &self,
+        logged_iblt: InvBloomLookupTable,
+    ) -> Option<Vec<u32>> {
+        let signed = self.peel_signed(logged_iblt)?;
+        debug!("found {} dropped and {} injected elements",
+            signed.inserted.len(), signed.removed.len());
+        if signed.removed.is_empty() {
+            return None;
+        }
+        let mut injected = signed.removed.into_iter().collect::<Vec<_>>();
+        injected.sort();
+        Some(injected)
+    }
+
+    /// Validates allowing up to `options.max_duplicates` extra copies of
+    /// logged elements to have been received. The elements received but not
+    /// logged according to signed peeling are duplicates if they hash to a
+    /// logged element, and injected otherwise. Each element can only be
+    /// duplicated once, since a cell with two extra copies is not peeled.
+    ///
+    /// Returns None if the difference IBLT could not be peeled.
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_with_duplicates(
+        &self,
+        elems: &[Vec<u8>],
+        logged_iblt: InvBloomLookupTable,
+        options: &ValidationOptions,
+    ) -> Option<ValidationResult> {
+        let signed = self.peel_signed(logged_iblt)?;
+        let logged = elems.iter()
+            .map(|elem| bloom_sd::elem_to_u32(elem))
+            .collect::<HashSet<_>>();
+        let (duplicated, mut injected): (Vec<u32>, Vec<u32>) =
+            signed.removed.into_iter()
+                .partition(|elem_u32| logged.contains(elem_u32));
+        if !injected.is_empty() {
+            warn!("found {} injected elements", injected.len());
+            injected.sort();
+            return Some(ValidationResult::IbltInjected(injected));
+        }
+        if duplicated.len() > options.max_duplicates {
+            warn!("found {} duplicated elements, more than {}",
+                duplicated.len(), options.max_duplicates);
+            return Some(ValidationResult::Invalid);
+        }
+        if elems.len() + duplicated.len() != self.total() + signed.inserted.len() {
+            debug!("could not peel every element");
+            return None;
+        }
+        let duplicated = crate::duplicates::count(duplicated);
+        let checked = crate::duplicates::check_digest_with_duplicates(
+            &self.digest,
+            elems,
+            &crate::duplicates::count(signed.inserted),
+            &duplicated,
+            bloom_sd::elem_to_u32,
+            options,
+        );
+        Some(crate::duplicates::duplicates_result(checked, &duplicated))
+    }
+
+    /// Validates the accumulator against the logged elements, where
+    /// `logged_iblt` calculates the IBLT of the logged elements each time
+    /// it is needed.
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_logged<F>(
+        &self,
+        elems: &[Vec<u8>],
+        logged_iblt: F,
+        options: &ValidationOptions,
+    ) -> ValidationResult
+    where
+        F: Fn() -> InvBloomLookupTable,
+    {
+        let result = self.validate_subset(elems, &logged_iblt, options);
+        if result.is_valid() || result == ValidationResult::BudgetExceeded {
+            return result;
+        }
+        if !self.iblt.has_hash_check() {
+            if options.max_duplicates > 0 {
+                warn!("tolerating duplicates requires the iblt hash check");
+            }
+            return result;
+        }
+
+        // The log is not valid as a superset of the received elements.
+        // With the hash check, we can additionally try to tell duplicated
+        // elements apart from those that were received but never logged.
+        if options.max_duplicates > 0 {
+            if let Some(result) = self.validate_with_duplicates(
+                    elems, logged_iblt(), options) {
+                return result;
+            }
+        }
+        if let Some(injected) = self.find_injected(logged_iblt()) {
+            warn!("found {} injected elements", injected.len());
+            ValidationResult::IbltInjected(injected)
+        } else {
+            result
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Accumulator for IBLTAccumulator {
+    fn to_bytes(&self) -> Vec<u8> {
+        assert_eq!(self.digest.count, (self.digest.count as u16) as u32);
+        bincode::serialize(&MiniIBLTAccumulator {
+            hash: self.digest.hash,
+            count: self.digest.count as u16,
+            seed: u64::from_be_bytes(self.digest.nonce),
+            counters: self.iblt.counters().bits.to_bytes(),
+            data: self.iblt.data().bits.to_bytes(),
+            hash_checks: self.iblt.hash_checks()
+                .map(|hash_checks| hash_checks.bits.to_bytes()),
+            partitioned: self.iblt.partitioned(),
+            estimator: self.estimator.clone(),
+        }).unwrap()
+    }
+
+    fn reset(&mut self) {
+        self.digest = Digest::new();
+        self.iblt = self.iblt.empty_clone();
+        self.estimator =
+            StrataEstimator::new(u64::from_be_bytes(self.digest.nonce));
+    }
+
+    fn process(&mut self, elem: &[u8]) {
+        self.digest.add(elem);
+        self.iblt.insert(elem);
+        self.estimator.insert(elem);
+    }
+
+    fn process_batch(&mut self, elems: &Vec<Vec<u8>>) {
+        for elem in elems {
+            self.process(elem);
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.digest.count as usize
+    }
+
+    fn estimator(&self) -> &StrataEstimator {
+        &self.estimator
+    }
+
+    #[cfg(feature = "disable_validation")]
+    fn validate_with_options(
+        &self,
+        _elems: &Vec<Vec<u8>>,
+        _options: &ValidationOptions,
+    ) -> ValidationResult {
+        panic!("validation not enabled")
+    }
+
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_with_options(
+        &self,
+        elems: &Vec<Vec<u8>>,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        self.validate_logged(elems, || {
+            logged_iblt(elems, &self.iblt, &options.executor)
+        }, options)
+    }
 
-    const NBYTES: usize = 16;
+    /// Precomputes the IBLTs of the parts of the log around the window ends,
+    /// so the IBLT of each window adds up three of them.
+    #[cfg(not(feature = "disable_validation"))]
+    fn window_validator<'a>(
+        &'a self,
+        log: &'a [Vec<u8>],
+        starts: RangeInclusive<usize>,
+        ends: RangeInclusive<usize>,
+    ) -> Box<dyn WindowValidator + 'a> {
+        let iblts = WindowSums::new(
+            log, starts, ends, self.iblt.empty_clone(),
+            |iblt, elem| { iblt.insert(elem); },
+            |lhs, rhs| {
+                let mut iblt = lhs.clone();
+                add_iblt(&mut iblt, rhs);
+                iblt
+            });
+        Box::new(IBLTWindows { accumulator: self, log, iblts })
+    }
+}
+
+/// Validates windows of a log with the precomputed IBLTs.
+#[cfg(not(feature = "disable_validation"))]
+struct IBLTWindows<'a> {
+    accumulator: &'a IBLTAccumulator,
+    log: &'a [Vec<u8>],
+    iblts: WindowSums<InvBloomLookupTable>,
+}
 
-    fn gen_elems_with_seed(n: usize, seed: u64) -> Vec<Vec<u8>> {
-        use rand::{SeedableRng, Rng};
-        use rand_chacha::ChaCha8Rng;
-        let mut rng = ChaCha8Rng::seed_from_u64(seed);
-        (0..n).map(|_| (0..NBYTES).map(|_| rng.gen::<u8>()).collect()).collect()
+#[cfg(not(feature = "disable_validation"))]
+impl WindowValidator for IBLTWindows<'_> {
+    fn validate_window(
+        &self,
+        start: usize,
+        end: usize,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        self.accumulator.validate_logged(&self.log[start..end], || {
+            self.iblts.window(start, end)
+        }, options)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gen_elems_with_seed;
 
     #[test]
     fn test_not_equals() {
@@ -546,9 +1093,38 @@ mod tests {
         assert!(acc1.equals(&acc3));
     }
 
+    #[test]
+    fn serialization_with_hash_check() {
+        let mut acc1 = IBLTAccumulator::new_with_hash_check(
+            1000, DEFAULT_BITS_PER_ENTRY, DEFAULT_CELLS_MULTIPLIER,
+            DEFAULT_NUM_HASHES, None);
+        acc1.process_batch(&gen_elems_with_seed(10, 111));
+        let acc2 = IBLTAccumulator::from_bytes(
+            &acc1.to_bytes(),
+            DEFAULT_BITS_PER_ENTRY,
+            DEFAULT_NUM_HASHES,
+        );
+        assert!(acc2.iblt.has_hash_check());
+        assert!(acc1.equals(&acc2));
+    }
+
+    #[test]
+    fn serialization_partitioned() {
+        let mut acc1 = IBLTAccumulator::new_with_options(
+            1000, DEFAULT_BITS_PER_ENTRY, DEFAULT_CELLS_MULTIPLIER,
+            DEFAULT_NUM_HASHES, false, true, None);
+        acc1.process_batch(&gen_elems_with_seed(10, 111));
+        let acc2 = IBLTAccumulator::from_bytes(
+            &acc1.to_bytes(),
+            DEFAULT_BITS_PER_ENTRY,
+            DEFAULT_NUM_HASHES,
+        );
+        assert!(acc2.iblt.partitioned());
+        assert!(acc1.equals(&acc2));
+    }
+
     fn vvsum(vec: &ValueVec) -> usize {
-        let num_entries = vec.len() / vec.bits_per_val();
-        (0..num_entries).map(|i| vec.get(i)).sum::<u32>() as usize
+        vec.sum() as usize
     }
 
     #[test]
@@ -562,8 +1138,27 @@ mod tests {
         for elem in &log {
             iblt.insert(&elem);
         }
-        let diff = calculate_difference_iblt(n_dropped, &log, &iblt).unwrap();
-        assert_eq!(vvsum(diff.counters()), 0);
+        let logged = logged_iblt(&log, &iblt, &Executor::CurrentThread);
+        let diffs = calculate_difference_iblt(
+            n_dropped, &log, logged, &iblt).unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(vvsum(diffs[0].counters()), 0);
+    }
+
+    #[test]
+    fn test_subtract_iblt_on_pool() {
+        let log = gen_elems_with_seed(1000, 222);
+        let mut iblt = InvBloomLookupTable::new_with_hash_check(111, 8, 100, 3);
+        for elem in &log[..900] {
+            iblt.insert(elem);
+        }
+        let (expected, expected_sum) = subtract_iblt(
+            logged_iblt(&log, &iblt, &Executor::CurrentThread), &iblt);
+        let (diff, sum) =
+            subtract_iblt(logged_iblt(&log, &iblt, &Executor::new(4)), &iblt);
+        assert_eq!(sum, expected_sum);
+        assert!(diff.equals(&expected));
     }
 
     #[test]
@@ -586,41 +1181,36 @@ mod tests {
         }
 
         // Calculate the difference.
-        let diff = calculate_difference_iblt(n_dropped, &log, &d2).unwrap();
+        let logged = logged_iblt(&log, &d2, &Executor::CurrentThread);
+        let mut diffs = calculate_difference_iblt(
+            n_dropped, &log, logged, &d2).unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(diffs.len(), 1);
+        let diff = diffs.remove(0);
 
-        // Check that every case with and without wraparound is tested.
-        let (mut counter_no_wrap, mut counter_wrap) = (1 << 31, 1 << 31);
-        let (mut data_no_wrap, mut data_wrap) = (1 << 31, 1 << 31);
-        for i in 0..(d1.num_entries() as usize) {
-            if d1.counters().get(i) >= d2.counters().get(i) {
-                counter_no_wrap = i;
-            } else {
-                counter_wrap = i;
-            }
-            if d1.data().get(i) >= d2.data().get(i) {
-                data_no_wrap = i;
-            } else {
-                data_wrap = i;
+        // Each dropped element is in `num_hashes` distinct cells, so the
+        // difference counts the dropped elements in each cell, modulo the
+        // range of the counters. The data sums their DJB hashes.
+        let mut counts = vec![0_u64; d1.num_entries() as usize];
+        let mut sums = vec![0_u32; d1.num_entries() as usize];
+        for elem in &log[(n_logged - n_dropped)..] {
+            let indexes = d1.indexes(elem);
+            assert_eq!(indexes.iter().collect::<HashSet<_>>().len(),
+                d1.num_hashes() as usize);
+            for i in indexes {
+                counts[i] += 1;
+                sums[i] = sums[i].wrapping_add(bloom_sd::elem_to_u32(elem));
             }
         }
-        assert!(counter_no_wrap < (d1.num_entries() as usize));
-        assert!(counter_wrap < (d1.num_entries() as usize));
-        assert!(data_no_wrap < (d1.num_entries() as usize));
-        assert!(data_wrap < (d1.num_entries() as usize));
-
-        // Check that the counters and data values were subtracted correctly.
-        assert_eq!(
-            diff.counters().get(counter_no_wrap),
-            d1.counters().get(counter_no_wrap) - d2.counters().get(counter_no_wrap));
-        assert_eq!(
-            diff.counters().get(counter_wrap),
-            ((1 << bpe) - 1) - d2.counters().get(counter_wrap) + d1.counters().get(counter_wrap) + 1);
-        assert_eq!(
-            diff.data().get(data_no_wrap),
-            d1.data().get(data_no_wrap) - d2.data().get(data_no_wrap));
-        assert_eq!(
-            diff.data().get(data_wrap),  // u32
-            u32::max_value() - d2.data().get(data_wrap) + d1.data().get(data_wrap) + 1);
+        let mut data_wrap = false;
+        for i in 0..(d1.num_entries() as usize) {
+            assert_eq!(diff.counters().get(i), counts[i] % (1 << bpe));
+            assert_eq!(diff.data().get(i), sums[i] as u64);
+            data_wrap |= d1.data().get(i) < d2.data().get(i);
+        }
+        // With 5 elements per cell on average, no counter is expected to
+        // wrap around, but the data of some cell almost surely does.
+        assert!(data_wrap);
     }
 
     #[test]
@@ -630,15 +1220,46 @@ mod tests {
         let log = (0..(n_logged as u32))
             .map(|i| i.to_be_bytes().into_iter().collect::<Vec<_>>())
             .collect::<Vec<_>>();
-        let mut d1 = InvBloomLookupTable::new_with_seed(111, 4, 6, 3);
         let mut d2 = InvBloomLookupTable::new_with_seed(111, 4, 6, 3);
-        for i in 0..n_logged {
-            d1.insert(&log[i]);
+        for i in 0..(n_logged - n_dropped) {
+            d2.insert(&log[i]);
+        }
+        let logged = logged_iblt(&log, &d2, &Executor::CurrentThread);
+        let diffs = calculate_difference_iblt(
+            n_dropped, &log, logged, &d2).unwrap()
+            .collect::<Vec<_>>();
+        assert!(diffs.len() > 1);
+
+        // Every candidate is consistent with the residues and the total, and
+        // one of them has the true counts.
+        let mut expected = InvBloomLookupTable::new_with_seed(111, 4, 6, 3)
+            .with_counter_bits(UNWRAPPED_BITS_PER_ENTRY);
+        for elem in &log[(n_logged - n_dropped)..] {
+            expected.insert(elem);
+        }
+        for diff in &diffs {
+            assert_eq!(vvsum(diff.counters()), n_dropped * 3);
+            for i in 0..6 {
+                assert_eq!(diff.counters().get(i) % 16,
+                    expected.counters().get(i) % 16);
+            }
         }
+        assert!(diffs.iter().any(|diff| diff.counters() == expected.counters()));
+    }
+
+    #[test]
+    fn test_calculate_difference_iblt_with_too_much_wraparound() {
+        let n_logged = 100;
+        let n_dropped = 60;
+        let log = (0..(n_logged as u32))
+            .map(|i| i.to_be_bytes().into_iter().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut d2 = InvBloomLookupTable::new_with_seed(111, 1, 60, 3);
         for i in 0..(n_logged - n_dropped) {
             d2.insert(&log[i]);
         }
-        let res = calculate_difference_iblt(n_dropped, &log, &d2);
+        let logged = logged_iblt(&log, &d2, &Executor::CurrentThread);
+        let res = calculate_difference_iblt(n_dropped, &log, logged, &d2);
         assert!(res.is_err());
         match res {
             Ok(_) => unreachable!(),
@@ -646,6 +1267,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_validate_with_wraparound() {
+        let log = (0..30_u32)
+            .map(|i| i.to_be_bytes().into_iter().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut acc = IBLTAccumulator::new_with_params(30, 1, 10, 3, Some(9));
+        acc.process_batch(&log[10..].to_vec());
+        let logged = logged_iblt(&log, &acc.iblt, &Executor::CurrentThread);
+        let diffs = calculate_difference_iblt(
+            10, &log, logged, &acc.iblt).unwrap();
+        assert!(diffs.count() > 1, "counters wrapped around");
+        assert_eq!(acc.validate(&log), ValidationResult::Valid);
+    }
+
+    #[test]
+    fn test_validate_candidates() {
+        let log = (0..30_u32)
+            .map(|i| i.to_be_bytes().into_iter().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut acc = IBLTAccumulator::new_with_params(30, 1, 10, 3, Some(9));
+        acc.process_batch(&log[10..].to_vec());
+        let candidates = || calculate_difference_iblt(
+            10, &log, logged_iblt(&log, &acc.iblt, &Executor::CurrentThread),
+            &acc.iblt).unwrap();
+        let options = ValidationOptions::default();
+        // Validates the first candidate as `first` and the rest as `rest`.
+        let validate = |first: ValidationResult, rest: ValidationResult| {
+            let mut n_validated = 0;
+            let result = validate_candidates(candidates(), &options, |_| {
+                n_validated += 1;
+                if n_validated == 1 { first.clone() } else { rest.clone() }
+            });
+            (result, n_validated)
+        };
+        let n_candidates = candidates().count();
+        assert!(n_candidates > 1);
+
+        // A wrong candidate is invalid but the true one is undetermined.
+        assert_eq!(validate(ValidationResult::IbltHashCheckMismatch,
+            ValidationResult::PsumExceedsThreshold),
+            (ValidationResult::IbltBenignWraparound, n_candidates));
+        assert_eq!(validate(ValidationResult::IbltHashCheckMismatch,
+            ValidationResult::Invalid),
+            (ValidationResult::IbltHashCheckMismatch, n_candidates));
+        assert_eq!(validate(ValidationResult::Invalid,
+            ValidationResult::IbltIlpValid),
+            (ValidationResult::IbltIlpValid, 2));
+        assert_eq!(validate(ValidationResult::BudgetExceeded,
+            ValidationResult::Valid),
+            (ValidationResult::BudgetExceeded, 1));
+    }
+
     #[test]
     fn test_calculate_difference_iblt_with_malicious_wraparound() {
         let n_logged = 100;
@@ -662,8 +1335,9 @@ mod tests {
         for i in 0..(n_logged - n_dropped) {
             d2.insert(&log[i]);
         }
-        let res = calculate_difference_iblt(
-            n_dropped, &log[log_start_i..].to_vec(), &d2);
+        let log = &log[log_start_i..];
+        let logged = logged_iblt(log, &d2, &Executor::CurrentThread);
+        let res = calculate_difference_iblt(n_dropped, log, logged, &d2);
         assert!(res.is_err());
         match res {
             Ok(_) => unreachable!(),
@@ -683,8 +1357,8 @@ mod tests {
         }
         // Succeeds because no elements are dropped
         let elems_ref = elems.iter().collect::<Vec<_>>();
-        let (valid, collisions) =
-            check_digest_from_removed_set(&d, elems_ref, HashSet::new());
+        let (valid, collisions) = check_digest_from_removed_set(
+            &d, elems_ref, HashSet::new(), &ValidationOptions::default()).unwrap();
         assert!(valid);
         assert!(!collisions);
     }
@@ -713,8 +1387,8 @@ mod tests {
         };
         // Fails because a dropped element is not in the original log
         let elems_ref = elems.iter().collect::<Vec<_>>();
-        let (valid, collisions) =
-            check_digest_from_removed_set(&d, elems_ref, removed);
+        let (valid, collisions) = check_digest_from_removed_set(
+            &d, elems_ref, removed, &ValidationOptions::default()).unwrap();
         assert!(!valid);
         assert!(!collisions);
     }
@@ -743,8 +1417,8 @@ mod tests {
             the remove set (the property is also enforced because the elems \
             eliminated from the IBLT must be unique).");
         let elems_ref = elems.iter().collect::<Vec<_>>();
-        let (valid, collisions) =
-            check_digest_from_removed_set(&d, elems_ref, removed);
+        let (valid, collisions) = check_digest_from_removed_set(
+            &d, elems_ref, removed, &ValidationOptions::default()).unwrap();
         assert!(valid);
         assert!(!collisions);
     }
@@ -776,8 +1450,8 @@ mod tests {
             in the remove set (the property is also enforced because the elems \
             eliminated from the IBLT must be unique).");
         let elems_ref = elems.iter().collect::<Vec<_>>();
-        let (valid, collisions) =
-            check_digest_from_removed_set(&d, elems_ref, removed);
+        let (valid, collisions) = check_digest_from_removed_set(
+            &d, elems_ref, removed, &ValidationOptions::default()).unwrap();
         assert!(valid);
         assert!(collisions);
     }
@@ -793,11 +1467,13 @@ mod tests {
         for i in 0..n_dropped {
             iblt.insert(&elems[i]);
         }
-        let mut removed = iblt.eliminate_elems();
+        let peeled = iblt.eliminate_elems().unwrap();
+        let mut removed = peeled.removed;
         let n_dropped_remaining = n_dropped - removed.len();
         assert_ne!(n_dropped_remaining, 0, "this test requires the ILP");
-        let result = solve_ilp_for_iblt(n_dropped_remaining, &elems, iblt);
-        assert!(result.is_some(), "no error when solving ILP");
+        let result = solve_ilp_for_iblt(n_dropped_remaining, &elems, iblt,
+            &peeled.core, &ValidationOptions::default());
+        assert!(result.is_ok(), "no error when solving ILP");
         let result = result.unwrap();
         assert_eq!(result.len(), n_dropped_remaining);
 
@@ -824,11 +1500,12 @@ mod tests {
         for i in 0..n_dropped {
             iblt.insert(&elems[i]);
         }
-        let removed = iblt.eliminate_elems();
-        let n_dropped_remaining = n_dropped - removed.len();
+        let peeled = iblt.eliminate_elems().unwrap();
+        let n_dropped_remaining = n_dropped - peeled.removed.len();
         assert_ne!(n_dropped_remaining, 0, "this test requires the ILP");
-        let result = solve_ilp_for_iblt(n_dropped_remaining, &elems, iblt);
-        assert!(result.is_some(), "no error when solving ILP");
+        let result = solve_ilp_for_iblt(n_dropped_remaining, &elems, iblt,
+            &peeled.core, &ValidationOptions::default());
+        assert!(result.is_ok(), "no error when solving ILP");
         let result = result.unwrap();
         assert_eq!(result.len(), n_dropped_remaining);
 
@@ -24,6 +24,8 @@
  *   indicates the indices to set in the first row of the matrix, based on
  *   which buckets the first packet hashes to (which can be repeated).
  * - n_dropped: expected number of dropped packets
+ * - time_limit_ms: time limit for the solver in milliseconds, or INT_MAX
+ *   for no limit
  *
  * Returns:
  * - dropped: vector of length `n_dropped`, the indices of the packets that
@@ -35,6 +37,7 @@ int32_t solve_ilp_glpk(size_t n_buckets,
                        size_t n_packets,
                        uint32_t *pkt_hashes,
                        size_t n_dropped,
+                       int time_limit_ms,
                        size_t *dropped) {
     glp_prob *prob = glp_create_prob();
     glp_add_rows(prob, n_buckets);
@@ -75,7 +78,12 @@ next_hash:  continue;
     glp_iocp parm;
     glp_init_iocp(&parm);
     parm.presolve = GLP_ON;
+    parm.tm_lim = time_limit_ms;
     int result = glp_intopt(prob, &parm);
+    // ran out of time
+    if (result == GLP_ETMLIM) {
+        return -4;
+    }
     // no solution to the ILP
     if (result != 0) {
         return -1;
@@ -1,15 +1,55 @@
-#[macro_use]
+#[cfg_attr(not(feature = "disable_validation"), macro_use)]
 extern crate log;
 
+mod bins;
+mod charpoly;
+#[cfg(not(feature = "disable_validation"))]
+mod duplicates;
+mod epochs;
+mod executor;
+mod flow;
+mod hybrid;
 mod iblt;
+mod multilevel;
 mod naive;
+mod options;
+mod plan;
+#[cfg(not(feature = "disable_validation"))]
+mod poly;
 mod power_sum;
-
+mod sampled;
+mod span;
+#[cfg(test)]
+mod test_util;
+mod window;
+
+pub use bins::{BinReport, TimeBins, DEFAULT_BIN_LEN, DEFAULT_SKEW};
+pub use charpoly::{CharPolyAccumulator, DEFAULT_MAX_INJECTED};
+pub use epochs::{EpochReport, EpochValidator};
+pub use executor::Executor;
+pub use flow::{
+    BucketKind, FlowPartitionedAccumulator, FlowReport, DEFAULT_NUM_BUCKETS,
+};
+pub use hybrid::HybridAccumulator;
 pub use iblt::*;
+pub use multilevel::MultiLevelIBLTAccumulator;
 pub use naive::NaiveAccumulator;
+pub use options::{CancellationToken, DigestCounter, ValidationOptions};
 pub use power_sum::PowerSumAccumulator;
-
-#[derive(Debug, PartialEq, Eq)]
+pub use sampled::SampledAccumulator;
+pub use span::CaptureSpan;
+pub use window::{WindowReport, WindowValidator};
+#[cfg(not(feature = "disable_validation"))]
+pub use window::search_window;
+pub use plan::{collision_probability, plan_iblt, IBLTPlan};
+pub use bloom_sd::StrataEstimator;
+pub use digest::canonicalize::FlowKey;
+pub use digest::capture::{strip_link_layer, Packet, PacketExtractor};
+pub use digest::sampling::Sampler;
+
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ValidationResult {
     Valid,
     Invalid,
@@ -25,6 +65,29 @@ pub enum ValidationResult {
     IbltIlpCollisionsValid,
     IbltIlpCollisionsInvalid,
     IbltMaliciousWraparound,
+    IbltHashCheckMismatch,
+    /// The DJB hashes of elements that were received but never logged.
+    IbltInjected(Vec<u32>),
+    /// Only the elements sampled in this level of a multi-level IBLT could be
+    /// validated, and the number of dropped elements is extrapolated from
+    /// those dropped in the sample. Undetermined, since the digest is not
+    /// checked and injected elements outside the sample go unnoticed.
+    IbltSampledValid { level: usize, estimated_dropped: usize },
+    IbltNoLevelDecoded,
+    /// The 2-core of the difference IBLT was solved with power sums.
+    IbltPsumValid,
+    IbltPsumInvalid,
+    IbltPsumCollisionsValid,
+    IbltPsumCollisionsInvalid,
+    /// The DJB hashes of elements that were received but never logged,
+    /// recovered by interpolating the characteristic polynomials.
+    CharPolyInjected(Vec<u32>),
+    /// Valid once some logged elements are counted more than once. The DJB
+    /// hashes of the duplicated elements, once per extra copy received.
+    DuplicatesValid(Vec<u32>),
+    /// Validation gave up because it exceeded the deadline or the maximum
+    /// number of digests in the `ValidationOptions`, or was cancelled.
+    BudgetExceeded,
 }
 
 impl ValidationResult {
@@ -35,6 +98,9 @@ impl ValidationResult {
             ValidationResult::IbltIlpValid => true,
             ValidationResult::IbltCollisionsValid => true,
             ValidationResult::IbltIlpCollisionsValid => true,
+            ValidationResult::IbltPsumValid => true,
+            ValidationResult::IbltPsumCollisionsValid => true,
+            ValidationResult::DuplicatesValid(_) => true,
             _ => false,
         }
     }
@@ -43,6 +109,9 @@ impl ValidationResult {
         match self {
             ValidationResult::PsumExceedsThreshold => true,
             ValidationResult::IbltBenignWraparound => true,
+            ValidationResult::IbltSampledValid { .. } => true,
+            ValidationResult::IbltNoLevelDecoded => true,
+            ValidationResult::BudgetExceeded => true,
             _ => false,
         }
     }
@@ -55,6 +124,8 @@ impl ValidationResult {
             ValidationResult::IbltCollisionsInvalid => true,
             ValidationResult::IbltIlpCollisionsValid => true,
             ValidationResult::IbltIlpCollisionsInvalid => true,
+            ValidationResult::IbltPsumCollisionsValid => true,
+            ValidationResult::IbltPsumCollisionsInvalid => true,
             _ => false,
         }
     }
@@ -83,11 +154,47 @@ pub trait Accumulator {
     fn process_batch(&mut self, elems: &Vec<Vec<u8>>);
     /// The total number of processed elements.
     fn total(&self) -> usize;
+    /// The strata estimator of the processed elements.
+    fn estimator(&self) -> &StrataEstimator;
+    /// Estimates the number of elements in the provided list that the
+    /// accumulator has not processed, without validating. This is cheap
+    /// compared to validation, so it can be used to choose how to validate.
+    /// Elements the accumulator processed but that are not in the list
+    /// inflate the estimate.
+    fn estimate_difference(&self, elems: &[Vec<u8>]) -> usize {
+        let mut estimator = self.estimator().empty_clone();
+        for elem in elems {
+            estimator.insert(elem);
+        }
+        estimator.estimate_difference(self.estimator())
+    }
     /// Validate the accumulator against a list of elements.
     ///
     /// The accumulator is valid if the elements that the accumulator has
     /// processed are a subset of the provided list of elements.
-    fn validate(&self, elems: &Vec<Vec<u8>>) -> ValidationResult;
+    fn validate(&self, elems: &Vec<Vec<u8>>) -> ValidationResult {
+        self.validate_with_options(elems, &ValidationOptions::default())
+    }
+    /// Like `validate()`, but gives up with `ValidationResult::BudgetExceeded`
+    /// if validation exceeds the limits in the options.
+    fn validate_with_options(
+        &self,
+        elems: &Vec<Vec<u8>>,
+        options: &ValidationOptions,
+    ) -> ValidationResult;
+    /// Validates the accumulator against windows `log[start..end]` of a log,
+    /// with the start in `starts` and the end in `ends`. Accumulators that
+    /// can combine summaries of parts of the log avoid summarizing each
+    /// window from scratch; by default every window is validated from
+    /// scratch.
+    fn window_validator<'a>(
+        &'a self,
+        log: &'a [Vec<u8>],
+        _starts: RangeInclusive<usize>,
+        _ends: RangeInclusive<usize>,
+    ) -> Box<dyn WindowValidator + 'a> {
+        Box::new(window::FromScratch { accumulator: self, log })
+    }
 }
 
 #[cfg(test)]
@@ -128,6 +235,60 @@ mod tests {
         assert_eq!(valid, !malicious);
     }
 
+    /// Drops one of the logged elements and receives the next three twice,
+    /// then injects an element. Returns the verdict on the injected element.
+    fn duplicates_test(
+        mut accumulator: Box<dyn Accumulator>,
+        hash: fn(&[u8]) -> u32,
+    ) -> ValidationResult {
+        let mut rng = rand::thread_rng();
+        let elems: Vec<Vec<u8>> = (0..100)
+            .map(|_| (0..NBYTES).map(|_| rng.gen::<u8>()).collect())
+            .collect();
+        let mut options = ValidationOptions {
+            max_duplicates: 3,
+            ..Default::default()
+        };
+        accumulator.process_batch(&elems[1..].to_vec());
+        assert_eq!(accumulator.validate_with_options(&elems, &options),
+            ValidationResult::Valid);
+
+        accumulator.process_batch(&elems[1..4].to_vec());
+        assert!(!accumulator.validate(&elems).is_valid());
+        let mut duplicated = elems[1..4].iter()
+            .map(|elem| hash(elem))
+            .collect::<Vec<_>>();
+        duplicated.sort();
+        assert_eq!(accumulator.validate_with_options(&elems, &options),
+            ValidationResult::DuplicatesValid(duplicated));
+        options.max_duplicates = 2;
+        assert!(!accumulator.validate_with_options(&elems, &options).is_valid());
+
+        options.max_duplicates = 3;
+        accumulator.process(&MALICIOUS_ELEM);
+        accumulator.validate_with_options(&elems, &options)
+    }
+
+    #[test]
+    fn estimate_difference() {
+        let elems: Vec<Vec<u8>> = (0..1000_u32)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        let accumulators: Vec<Box<dyn Accumulator>> = vec![
+            Box::new(NaiveAccumulator::new(SEED)),
+            Box::new(PowerSumAccumulator::new(10, SEED)),
+            Box::new(IBLTAccumulator::new(10, SEED)),
+            Box::new(MultiLevelIBLTAccumulator::new(10, SEED)),
+            Box::new(CharPolyAccumulator::new(10, SEED)),
+        ];
+        for mut accumulator in accumulators {
+            accumulator.process_batch(&elems[10..].to_vec());
+            assert_eq!(accumulator.estimate_difference(&elems[10..]), 0);
+            let estimate = accumulator.estimate_difference(&elems);
+            assert!((5..=40).contains(&estimate), "{}", estimate);
+        }
+    }
+
     #[test]
     fn naive_none_dropped() {
         let accumulator = NaiveAccumulator::new(SEED);
@@ -211,6 +372,14 @@ mod tests {
         base_accumulator_test(Box::new(accumulator), 1000, 10, false);
     }
 
+    #[test]
+    fn power_sum_tolerates_duplicates() {
+        let accumulator = PowerSumAccumulator::new(10, SEED);
+        let hash = |elem: &[u8]| bloom_sd::elem_to_u32(elem) & power_sum::DJB_MASK;
+        assert_eq!(duplicates_test(Box::new(accumulator), hash),
+            ValidationResult::Invalid);
+    }
+
     #[test]
     fn power_sum_one_malicious_and_none_dropped() {
         let accumulator = PowerSumAccumulator::new(100, SEED);
@@ -277,4 +446,68 @@ mod tests {
         let accumulator = IBLTAccumulator::new(100, SEED);
         base_accumulator_test(Box::new(accumulator), 1000, 10, true);
     }
+
+    #[test]
+    fn iblt_hash_check_many_dropped() {
+        let accumulator = IBLTAccumulator::new_with_hash_check(
+            1000, DEFAULT_BITS_PER_ENTRY, DEFAULT_CELLS_MULTIPLIER,
+            DEFAULT_NUM_HASHES, SEED);
+        base_accumulator_test(Box::new(accumulator), 1000, 10, false);
+    }
+
+    #[test]
+    fn iblt_hash_check_names_injected_elem() {
+        let mut accumulator = IBLTAccumulator::new_with_hash_check(
+            100, DEFAULT_BITS_PER_ENTRY, DEFAULT_CELLS_MULTIPLIER,
+            DEFAULT_NUM_HASHES, SEED);
+        let mut rng = rand::thread_rng();
+        let elems: Vec<Vec<u8>> = (0..100)
+            .map(|_| (0..NBYTES).map(|_| rng.gen::<u8>()).collect())
+            .collect();
+        for elem in &elems[10..] {
+            accumulator.process(elem);
+        }
+        accumulator.process(&MALICIOUS_ELEM);
+        assert_eq!(
+            accumulator.validate(&elems),
+            ValidationResult::IbltInjected(
+                vec![bloom_sd::elem_to_u32(&MALICIOUS_ELEM)]));
+    }
+
+    #[test]
+    fn iblt_hash_check_one_malicious_and_many_dropped() {
+        let accumulator = IBLTAccumulator::new_with_hash_check(
+            100, DEFAULT_BITS_PER_ENTRY, DEFAULT_CELLS_MULTIPLIER,
+            DEFAULT_NUM_HASHES, SEED);
+        base_accumulator_test(Box::new(accumulator), 1000, 10, true);
+    }
+
+    #[test]
+    fn iblt_hash_check_tolerates_duplicates() {
+        let accumulator = IBLTAccumulator::new_with_hash_check(
+            10, DEFAULT_BITS_PER_ENTRY, DEFAULT_CELLS_MULTIPLIER,
+            DEFAULT_NUM_HASHES, SEED);
+        assert_eq!(
+            duplicates_test(Box::new(accumulator), bloom_sd::elem_to_u32),
+            ValidationResult::IbltInjected(
+                vec![bloom_sd::elem_to_u32(&MALICIOUS_ELEM)]));
+    }
+
+    #[test]
+    fn charpoly_none_dropped() {
+        let accumulator = CharPolyAccumulator::new(100, SEED);
+        base_accumulator_test(Box::new(accumulator), 1000, 0, false);
+    }
+
+    #[test]
+    fn charpoly_many_dropped() {
+        let accumulator = CharPolyAccumulator::new(100, SEED);
+        base_accumulator_test(Box::new(accumulator), 1000, 10, false);
+    }
+
+    #[test]
+    fn charpoly_one_malicious_and_many_dropped() {
+        let accumulator = CharPolyAccumulator::new(100, SEED);
+        base_accumulator_test(Box::new(accumulator), 1000, 10, true);
+    }
 }
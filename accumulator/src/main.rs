@@ -5,12 +5,41 @@ use std::fs::{OpenOptions, File};
 use std::net::TcpListener;
 use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clap::{Arg, Command};
 use accumulator::*;
+use digest::canonicalize::{canonicalize, Profile};
 
 use pcap_parser::*;
 
+/// Flags in the first byte of a request on the TCP port.
+const REQUEST_RESET: u8 = 0x01;
+const REQUEST_BINS: u8 = 0x02;
+const REQUEST_SPAN: u8 = 0x04;
+
+/// The accumulator, time bins and capture span of the current epoch, which
+/// are reset together.
+struct State {
+    accumulator: SampledAccumulator,
+    bins: Option<TimeBins>,
+    span: CaptureSpan,
+}
+
+impl State {
+    fn process(&mut self, timestamp: Option<Duration>, elem: &[u8]) {
+        if !self.accumulator.process_if_kept(elem) {
+            return;
+        }
+        let timestamp = timestamp.unwrap_or_else(||
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
+        self.span.process(timestamp);
+        if let Some(bins) = self.bins.as_mut() {
+            bins.process(timestamp, elem);
+        }
+    }
+}
+
 fn write_data(f: &mut File, bytes: usize, data: &[u8]) {
     let len = std::cmp::min(data.len(), bytes);
     if len < bytes {
@@ -22,7 +51,8 @@ fn write_data(f: &mut File, bytes: usize, data: &[u8]) {
 async fn pcap_listen_mock(
     log: Option<&str>,
     bytes: usize,
-    accumulator: Arc<Mutex<Box<dyn Accumulator + Send>>>,
+    profile: Profile,
+    state: Arc<Mutex<State>>,
 ) {
     let packets = vec![
         vec![125; bytes],
@@ -39,22 +69,22 @@ async fn pcap_listen_mock(
             .open(filename)
             .unwrap()
     });
-    let mut accumulator = accumulator.lock().unwrap();
+    let mut state = state.lock().unwrap();
     for data in packets {
         let len = std::cmp::min(data.len(), bytes as usize);
-        let elem = &data[..len];
         if let Some(f) = log.as_mut() {
             write_data(f, bytes, &data[..len]);
         }
-        accumulator.process(&elem);
+        state.process(None, &canonicalize(profile, &data, bytes));
     }
-    drop(accumulator);
+    drop(state);
 }
 
 async fn pcap_listen(
     log: Option<&str>,
     bytes: usize,
-    accumulator: Arc<Mutex<Box<dyn Accumulator + Send>>>,
+    profile: Profile,
+    state: Arc<Mutex<State>>,
 ) {
     use std::process::{Command, Stdio};
     let mut child = {
@@ -68,7 +98,7 @@ async fn pcap_listen(
             .arg("-w")
             .arg("/dev/stdout")
             .arg("-s")
-            .arg(format!("{}", 14 + bytes))
+            .arg(format!("{}", profile.capture_len(bytes)))
             .stdout(Stdio::piped())
             .spawn()
             .unwrap();
@@ -87,41 +117,26 @@ async fn pcap_listen(
     let stdout = child.stdout.as_mut().unwrap();
 
     let mut reader = create_reader(65536, stdout).unwrap();
+    let mut extractor = PacketExtractor::new();
     let mut n: usize = 0;
     loop {
         // TODO: This isn't perfect, because tcpdump is set up to buffer so reader.next() could
         // theoretically block for an arbitrarily long period of time.
         match reader.next() {
             Ok((offset, block)) => {
-                match block {
-                    PcapBlockOwned::Legacy(block) => {
-                        if block.data.len() < 14 {
-                            warn!("TOO SMALL");
-                            continue;
-                        }
-                        let hi = std::cmp::min(14 + bytes, block.data.len());
-                        let mut elem = block.data[14..hi].to_vec();
-                        if elem.len() < bytes {
-                            elem.append(&mut vec![0; bytes - elem.len()]);
-                        }
-                        let hi = std::cmp::min(block.data.len(), 14 + bytes as usize);
-                        let elem = &block.data[14..hi];
-                        // NOTE: many of these elements are not unique
-                        // TODO: probably slow to put a lock around each packet.
-                        // Maybe we can buffer and batch.
-                        let mut accumulator = accumulator.lock().unwrap();
-                        accumulator.process(&elem);
-                        drop(accumulator);
-                        n += 1;
-                        trace!("processed {} packets block {:?} offset={}", n, block.data, offset);
-                        if n % 1000 == 0 {
-                            trace!("processed {} packets", n);
-                        }
-                    },
-                    PcapBlockOwned::NG(block) => {
-                        debug!("ignoring NG({:?}) offset={}", block, offset);
-                    },
-                    PcapBlockOwned::LegacyHeader(_) => {},
+                if let Some(packet) = extractor.extract_packet(&block) {
+                    let elem = canonicalize(profile, packet.data, bytes);
+                    // NOTE: many of these elements are not unique
+                    // TODO: probably slow to put a lock around each packet.
+                    // Maybe we can buffer and batch.
+                    let mut state = state.lock().unwrap();
+                    state.process(packet.timestamp, &elem);
+                    drop(state);
+                    n += 1;
+                    trace!("processed {} packets packet {:?} offset={}", n, packet, offset);
+                    if n % 1000 == 0 {
+                        trace!("processed {} packets", n);
+                    }
                 }
                 reader.consume(offset);
             },
@@ -138,23 +153,38 @@ async fn pcap_listen(
     }
 }
 
+/// Returns the serialized accumulator to any connection on the port. If the
+/// request asks for the time bins or the capture span, returns the serialized
+/// accumulator together with whichever of them were requested.
 async fn tcp_listen(
-    accumulator: Arc<Mutex<Box<dyn Accumulator + Send>>>,
+    state: Arc<Mutex<State>>,
     port: u32,
 ) {
     info!("listening on port {}", port);
     let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap();
     for stream in listener.incoming() {
         let mut stream = stream.unwrap();
-        let mut reset = [0; 1];
-        stream.read(&mut reset).unwrap();
-        let mut accumulator = accumulator.lock().unwrap();
-        let bytes = accumulator.to_bytes();
-        info!("reset byte = {}", reset[0]);
-        if reset[0] != 0 {
-            accumulator.reset();
+        let mut request = [0; 1];
+        stream.read(&mut request).unwrap();
+        let mut state = state.lock().unwrap();
+        let bytes = if request[0] & (REQUEST_BINS | REQUEST_SPAN) != 0 {
+            let bins = state.bins.as_ref()
+                .filter(|_| request[0] & REQUEST_BINS != 0);
+            let span = (request[0] & REQUEST_SPAN != 0).then_some(state.span);
+            bincode::serialize(&(state.accumulator.to_bytes(), bins, span))
+                .unwrap()
+        } else {
+            state.accumulator.to_bytes()
+        };
+        info!("request byte = {}", request[0]);
+        if request[0] & REQUEST_RESET != 0 {
+            state.accumulator.reset();
+            if let Some(bins) = state.bins.as_mut() {
+                bins.reset();
+            }
+            state.span.reset();
         }
-        drop(accumulator);
+        drop(state);
         info!("sending {} bytes to {:?}", bytes.len(), stream.peer_addr());
         stream.write_all(&bytes).unwrap();
         stream.flush().unwrap();
@@ -189,6 +219,14 @@ async fn main() {
             .long("bytes")
             .takes_value(true)
             .default_value("40"))
+        .arg(Arg::new("profile")
+            .help("Which header fields to mask so that packets rewritten by \
+                routers along the way still match. The raw profile records \
+                the first bytes of each packet unchanged.")
+            .long("profile")
+            .takes_value(true)
+            .possible_values(Profile::NAMES)
+            .default_value("raw"))
         .arg(Arg::new("threshold")
             .help("Threshold number of log packets for the IBLT \
                 and power sum accumulators.")
@@ -196,6 +234,42 @@ async fn main() {
             .long("threshold")
             .takes_value(true)
             .default_value("10000"))
+        .arg(Arg::new("psum-buffer")
+            .help("Number of packets the power sum accumulator buffers \
+                before updating the power sums in a batch. Updates on \
+                every packet if 0.")
+            .long("psum-buffer")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::new("buckets")
+            .help("Number of buckets of the flow-partitioned accumulators. \
+                The threshold is split evenly between the buckets.")
+            .long("buckets")
+            .takes_value(true)
+            .default_value("16"))
+        .arg(Arg::new("flow-key")
+            .help("What the flow-partitioned accumulators partition packets \
+                by: the 5-tuple, or a destination prefix such as prefix/24.")
+            .long("flow-key")
+            .takes_value(true)
+            .default_value("5tuple"))
+        .arg(Arg::new("sample-rate")
+            .help("Fraction of packets to process, sampled by a keyed hash \
+                of each packet. Must match the router's.")
+            .long("sample-rate")
+            .takes_value(true)
+            .requires("sample-key"))
+        .arg(Arg::new("sample-key")
+            .help("Key of the hash that samples packets. Must match the \
+                router's, and should not be known on the forwarding path.")
+            .long("sample-key")
+            .takes_value(true))
+        .arg(Arg::new("bin-ms")
+            .help("Also keep a digest of the packets received in each time \
+                bin of this many milliseconds, to locate when packets were \
+                injected if the accumulator is invalid.")
+            .long("bin-ms")
+            .takes_value(true))
         .arg(Arg::new("accumulator")
             .help("")
             .short('a')
@@ -204,11 +278,18 @@ async fn main() {
             .possible_value("naive")
             .possible_value("cbf")
             .possible_value("iblt")
+            .possible_value("multi_iblt")
+            .possible_value("hybrid")
             .possible_value("power_sum")
+            .possible_value("charpoly")
+            .possible_value("flow_iblt")
+            .possible_value("flow_power_sum")
             .required(true))
         .get_matches();
 
     let bytes: usize = matches.value_of("bytes").unwrap().parse().unwrap();
+    let profile: Profile = matches.value_of("profile").unwrap().parse()
+        .unwrap();
     let port: u32 = matches.value_of("port").unwrap().parse().unwrap();
     let log = matches.value_of("log");
     let accumulator: Box<dyn Accumulator + Send> = {
@@ -217,19 +298,57 @@ async fn main() {
         match matches.value_of("accumulator").unwrap() {
             "naive" => Box::new(NaiveAccumulator::new(None)),
             "iblt" => Box::new(IBLTAccumulator::new(threshold, None)),
-            "power_sum" => Box::new(PowerSumAccumulator::new(threshold, None)),
+            "multi_iblt" => Box::new(
+                MultiLevelIBLTAccumulator::new(threshold, None)),
+            "hybrid" => Box::new(HybridAccumulator::new(threshold, None)),
+            "power_sum" => {
+                let buffer_len: usize = matches.value_of("psum-buffer")
+                    .unwrap().parse().unwrap();
+                Box::new(PowerSumAccumulator::new_buffered(
+                    threshold, buffer_len, None))
+            },
+            "charpoly" => Box::new(CharPolyAccumulator::new(threshold, None)),
+            ty @ ("flow_iblt" | "flow_power_sum") => {
+                let num_buckets: usize = matches.value_of("buckets")
+                    .unwrap().parse().unwrap();
+                let key: FlowKey = matches.value_of("flow-key").unwrap()
+                    .parse().unwrap();
+                let kind = if ty == "flow_iblt" {
+                    BucketKind::Iblt
+                } else {
+                    BucketKind::PowerSum
+                };
+                Box::new(FlowPartitionedAccumulator::new_with_params(
+                    num_buckets, threshold.div_ceil(num_buckets), key, kind,
+                    None))
+            },
             _ => unreachable!(),
         }
     };
-    let lock = Arc::new(Mutex::new(accumulator));
+    let sampler = match matches.value_of("sample-rate") {
+        Some(rate) => Sampler::new(
+            matches.value_of("sample-key").unwrap().parse().unwrap(),
+            rate.parse().unwrap(),
+        ),
+        None => Sampler::all(),
+    };
+    let accumulator = SampledAccumulator::new(sampler, accumulator);
+    let bins = matches.value_of("bin-ms").map(|ms| {
+        TimeBins::new(Duration::from_millis(ms.parse().unwrap()), None)
+    });
+    let lock = Arc::new(Mutex::new(State {
+        accumulator,
+        bins,
+        span: CaptureSpan::new(),
+    }));
     let lock_clone = Arc::clone(&lock);
     let join = tokio::spawn(async move {
         tcp_listen(lock_clone, port).await;
     });
     if matches.is_present("mock") {
-        pcap_listen_mock(log, bytes, lock).await;
+        pcap_listen_mock(log, bytes, profile, lock).await;
     } else {
-        pcap_listen(log, bytes, lock).await;
+        pcap_listen(log, bytes, profile, lock).await;
     }
     join.await.unwrap();
 }
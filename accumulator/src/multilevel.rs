@@ -0,0 +1,493 @@
+#[cfg(not(feature = "disable_validation"))]
+use std::time::Instant;
+#[cfg(not(feature = "disable_validation"))]
+use std::collections::HashSet;
+#[cfg(not(feature = "disable_validation"))]
+use std::ops::RangeInclusive;
+use std::hash::Hasher;
+
+use serde::{Serialize, Deserialize};
+use siphasher::sip::SipHasher13;
+use bloom_sd::{ValueVec, InvBloomLookupTable, StrataEstimator};
+use crate::{Accumulator, ValidationOptions, ValidationResult};
+#[cfg(not(feature = "disable_validation"))]
+use crate::iblt::{calculate_difference_iblt, check_digest_from_removed_set};
+#[cfg(not(feature = "disable_validation"))]
+use crate::iblt::{add_iblt, logged_iblt};
+use crate::iblt::{DEFAULT_BITS_PER_ENTRY, DEFAULT_NUM_HASHES};
+use digest::{Digest, AmhHash};
+#[cfg(not(feature = "disable_validation"))]
+use crate::window::{WindowSums, WindowValidator};
+
+pub const DEFAULT_NUM_LEVELS: usize = 8;
+pub const DEFAULT_LEVEL_CELLS_MULTIPLIER: usize = 2;
+
+/// The multi-level IBLT accumulator stores the digest of all processed
+/// packets and a fixed number of equally-sized IBLTs. Level i only contains
+/// the packets whose keyed hash has at least i leading zeros, i.e. a sample
+/// of about 1/2^i of the packets.
+///
+/// On validation, the accumulator decodes the most complete level whose
+/// difference IBLT can be peeled. Level 0 contains every packet, so it is
+/// validated against the digest as in the IBLT accumulator. At a deeper
+/// level, only the sampled packets can be validated, and the number of
+/// dropped packets is extrapolated from the sample. This way the digest has
+/// a bounded size, but degrades gracefully when more packets are dropped
+/// than any one level can decode. The verdict on a deeper level is only an
+/// estimate, and is undetermined since packets injected outside the sample
+/// go unnoticed.
+#[derive(Serialize, Deserialize)]
+pub struct MultiLevelIBLTAccumulator {
+    digest: Digest,
+    levels: Vec<InvBloomLookupTable>,
+    // number of processed packets in each level
+    counts: Vec<u32>,
+    estimator: StrataEstimator,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MiniMultiLevelIBLTAccumulator {
+    hash: AmhHash,        // [u8; HASH_SIZE]
+    count: u16,           // expect ~1024 = 2^10
+    seed: u64,            // seed for multiset hash, IBLT hash, sampling
+    counts: Vec<u16>,     // count of each level
+    counters: Vec<Vec<u8>>, // bits_per_val = IBLT_BITS_PER_ENTRY
+    data: Vec<Vec<u8>>,   // bits_per_val = DJB_HASH_SIZE
+    estimator: StrataEstimator,
+}
+
+/// The keyed hash that decides which levels a packet is sampled in.
+fn sampler_from_seed(seed: u64) -> SipHasher13 {
+    SipHasher13::new_with_keys(seed, seed.rotate_left(32) ^ 0x5A5A_5A5A)
+}
+
+/// Each level has its own IBLT hash functions.
+fn level_seed(seed: u64, level: usize) -> u64 {
+    seed.wrapping_add(level as u64)
+}
+
+impl MultiLevelIBLTAccumulator {
+    pub fn new_with_params(
+        threshold: usize,
+        num_levels: usize,
+        bits_per_entry: usize,
+        cells_multiplier: usize,
+        num_hashes: u32,
+        seed: Option<u64>,
+    ) -> Self {
+        assert!(num_levels > 0);
+        let digest = if let Some(seed) = seed {
+            Digest::new_with_seed(seed.to_be_bytes())
+        } else {
+            Digest::new()
+        };
+        let seed = u64::from_be_bytes(digest.nonce);
+        let levels = (0..num_levels)
+            .map(|level| InvBloomLookupTable::new_with_seed(
+                level_seed(seed, level),
+                bits_per_entry,
+                cells_multiplier * threshold,
+                num_hashes,
+            ))
+            .collect();
+        Self {
+            digest,
+            levels,
+            counts: vec![0; num_levels],
+            estimator: StrataEstimator::new(seed),
+        }
+    }
+
+    pub fn new(threshold: usize, seed: Option<u64>) -> Self {
+        Self::new_with_params(
+            threshold,
+            DEFAULT_NUM_LEVELS,
+            DEFAULT_BITS_PER_ENTRY,
+            DEFAULT_LEVEL_CELLS_MULTIPLIER,
+            DEFAULT_NUM_HASHES,
+            seed,
+        )
+    }
+
+    pub fn from_bytes(
+        bytes: &[u8],
+        bits_per_entry: usize,
+        num_hashes: u32,
+    ) -> Self {
+        let x: MiniMultiLevelIBLTAccumulator =
+            bincode::deserialize(bytes).unwrap();
+        let levels = x.counters.into_iter()
+            .zip(x.data)
+            .enumerate()
+            .map(|(level, (counters, data))| {
+                let num_entries = counters.len() * 8 / bits_per_entry;
+                let mut iblt = InvBloomLookupTable::new_with_seed(
+                    level_seed(x.seed, level),
+                    bits_per_entry,
+                    num_entries,
+                    num_hashes,
+                );
+                *iblt.counters_mut() =
+                    ValueVec::from_bytes(bits_per_entry, &counters);
+                *iblt.data_mut() =
+                    ValueVec::from_bytes(bloom_sd::DJB_HASH_SIZE, &data);
+                iblt
+            })
+            .collect();
+        Self {
+            digest: Digest {
+                hash: x.hash,
+                count: x.count as u32,
+                nonce: x.seed.to_be_bytes(),
+            },
+            levels,
+            counts: x.counts.into_iter().map(|count| count as u32).collect(),
+            estimator: x.estimator,
+        }
+    }
+
+    pub fn equals(&self, other: &Self) -> bool {
+        self.digest == other.digest
+            && self.counts == other.counts
+            && self.levels.len() == other.levels.len()
+            && self.levels.iter().zip(&other.levels)
+                .all(|(iblt1, iblt2)| iblt1.equals(iblt2))
+            && self.estimator == other.estimator
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// The deepest level the element is sampled in. The element is also in
+    /// every level above it.
+    fn max_level(&self, elem: &[u8]) -> usize {
+        let seed = u64::from_be_bytes(self.digest.nonce);
+        let mut hasher = sampler_from_seed(seed);
+        hasher.write(elem);
+        let leading_zeros = hasher.finish().leading_zeros() as usize;
+        std::cmp::min(leading_zeros, self.levels.len() - 1)
+    }
+
+    /// Validates the elements sampled in a level, where `logged_iblt`
+    /// calculates the IBLT of the sampled logged elements. Returns None if the
+    /// difference IBLT cannot be fully peeled.
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_level<F>(
+        &self,
+        level: usize,
+        elems: &[Vec<u8>],
+        logged_iblt: F,
+        options: &ValidationOptions,
+    ) -> Option<ValidationResult>
+    where
+        F: FnOnce(&[Vec<u8>]) -> InvBloomLookupTable,
+    {
+        let sampled = elems.iter()
+            .filter(|elem| self.max_level(elem) >= level)
+            .cloned()
+            .collect::<Vec<_>>();
+        let count = self.counts[level] as usize;
+        if sampled.len() < count {
+            warn!("more elements received than logged in level {}", level);
+            return Some(ValidationResult::Invalid);
+        }
+        let n_dropped = sampled.len() - count;
+        let logged = logged_iblt(&sampled);
+        let candidates = match calculate_difference_iblt(
+                n_dropped, &sampled, logged, &self.levels[level]) {
+            Ok(candidates) => candidates,
+            Err(ValidationResult::IbltBenignWraparound) => { return None; },
+            Err(result) => { return Some(result); },
+        };
+
+        // Only peeling is used since the next level is much cheaper to decode
+        // than solving an ILP.
+        let mut removed = None;
+        for mut iblt in candidates {
+            if let Ok(peeled) = iblt.eliminate_elems() {
+                if peeled.removed.len() == n_dropped {
+                    removed = Some(peeled.removed);
+                    break;
+                }
+            }
+        }
+        let removed = removed?;
+        debug!("decoded level {} with {} dropped elements", level, n_dropped);
+        if level == 0 {
+            let checked = check_digest_from_removed_set(
+                &self.digest,
+                elems.iter().collect(),
+                removed,
+                options,
+            );
+            return Some(match checked {
+                Some((true, false)) => ValidationResult::Valid,
+                Some((false, false)) => ValidationResult::Invalid,
+                Some((true, true)) => ValidationResult::IbltCollisionsValid,
+                Some((false, true)) => ValidationResult::IbltCollisionsInvalid,
+                None => ValidationResult::BudgetExceeded,
+            });
+        }
+
+        // Without every dropped element the digest cannot be checked, but
+        // the dropped elements in the sample must have been logged.
+        let sampled_u32 = sampled.iter()
+            .map(|elem| bloom_sd::elem_to_u32(elem))
+            .collect::<HashSet<_>>();
+        if !removed.is_subset(&sampled_u32) {
+            warn!("decoded elements that were never logged in level {}", level);
+            return Some(ValidationResult::Invalid);
+        }
+        Some(ValidationResult::IbltSampledValid {
+            level,
+            estimated_dropped: n_dropped << level,
+        })
+    }
+
+    /// Validates the accumulator against the logged elements, where
+    /// `logged_iblt` calculates the IBLT of the logged elements sampled in a
+    /// level, given the level and those elements.
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_logged<F>(
+        &self,
+        elems: &[Vec<u8>],
+        logged_iblt: F,
+        options: &ValidationOptions,
+    ) -> ValidationResult
+    where
+        F: Fn(usize, &[Vec<u8>]) -> InvBloomLookupTable,
+    {
+        let t1 = Instant::now();
+        if elems.len() < self.total() {
+            warn!("more elements received than logged");
+            return ValidationResult::Invalid;
+        }
+        for level in 0..self.levels.len() {
+            if options.is_exceeded() {
+                return ValidationResult::BudgetExceeded;
+            }
+            let result = self.validate_level(level, elems,
+                |sampled| logged_iblt(level, sampled), options);
+            if let Some(result) = result {
+                debug!("validated level {}: {:?}", level, Instant::now() - t1);
+                return result;
+            }
+        }
+        warn!("could not decode any of {} levels", self.levels.len());
+        ValidationResult::IbltNoLevelDecoded
+    }
+}
+
+impl Accumulator for MultiLevelIBLTAccumulator {
+    fn to_bytes(&self) -> Vec<u8> {
+        assert_eq!(self.digest.count, (self.digest.count as u16) as u32);
+        bincode::serialize(&MiniMultiLevelIBLTAccumulator {
+            hash: self.digest.hash,
+            count: self.digest.count as u16,
+            seed: u64::from_be_bytes(self.digest.nonce),
+            counts: self.counts.iter().map(|&count| count as u16).collect(),
+            counters: self.levels.iter()
+                .map(|iblt| iblt.counters().bits.to_bytes())
+                .collect(),
+            data: self.levels.iter()
+                .map(|iblt| iblt.data().bits.to_bytes())
+                .collect(),
+            estimator: self.estimator.clone(),
+        }).unwrap()
+    }
+
+    fn reset(&mut self) {
+        // The IBLT hash functions and the sampling are derived from the seed,
+        // so rebuild the levels with the new one.
+        self.digest = Digest::new();
+        let seed = u64::from_be_bytes(self.digest.nonce);
+        for (level, iblt) in self.levels.iter_mut().enumerate() {
+            *iblt = InvBloomLookupTable::new_with_seed(
+                level_seed(seed, level),
+                iblt.counters().bits_per_val(),
+                iblt.num_entries() as usize,
+                iblt.num_hashes(),
+            );
+        }
+        self.counts = vec![0; self.levels.len()];
+        self.estimator = StrataEstimator::new(seed);
+    }
+
+    fn process(&mut self, elem: &[u8]) {
+        self.digest.add(elem);
+        self.estimator.insert(elem);
+        for level in 0..=self.max_level(elem) {
+            self.levels[level].insert(elem);
+            self.counts[level] += 1;
+        }
+    }
+
+    fn process_batch(&mut self, elems: &Vec<Vec<u8>>) {
+        for elem in elems {
+            self.process(elem);
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.digest.count as usize
+    }
+
+    fn estimator(&self) -> &StrataEstimator {
+        &self.estimator
+    }
+
+    #[cfg(feature = "disable_validation")]
+    fn validate_with_options(
+        &self,
+        _elems: &Vec<Vec<u8>>,
+        _options: &ValidationOptions,
+    ) -> ValidationResult {
+        panic!("validation not enabled")
+    }
+
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_with_options(
+        &self,
+        elems: &Vec<Vec<u8>>,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        self.validate_logged(elems, |level, sampled| {
+            logged_iblt(sampled, &self.levels[level], &options.executor)
+        }, options)
+    }
+
+    /// Precomputes the IBLTs of every level of the parts of the log around
+    /// the window ends, so those of each window add up three of them.
+    #[cfg(not(feature = "disable_validation"))]
+    fn window_validator<'a>(
+        &'a self,
+        log: &'a [Vec<u8>],
+        starts: RangeInclusive<usize>,
+        ends: RangeInclusive<usize>,
+    ) -> Box<dyn WindowValidator + 'a> {
+        let empty = self.levels.iter()
+            .map(|iblt| iblt.empty_clone())
+            .collect::<Vec<_>>();
+        let levels = WindowSums::new(
+            log, starts, ends, empty,
+            |levels, elem| {
+                for iblt in &mut levels[..=self.max_level(elem)] {
+                    iblt.insert(elem);
+                }
+            },
+            |lhs, rhs| lhs.iter().zip(rhs)
+                .map(|(lhs, rhs)| {
+                    let mut iblt = lhs.clone();
+                    add_iblt(&mut iblt, rhs);
+                    iblt
+                })
+                .collect());
+        Box::new(MultiLevelWindows { accumulator: self, log, levels })
+    }
+}
+
+/// Validates windows of a log with the precomputed IBLTs of every level.
+#[cfg(not(feature = "disable_validation"))]
+struct MultiLevelWindows<'a> {
+    accumulator: &'a MultiLevelIBLTAccumulator,
+    log: &'a [Vec<u8>],
+    levels: WindowSums<Vec<InvBloomLookupTable>>,
+}
+
+#[cfg(not(feature = "disable_validation"))]
+impl WindowValidator for MultiLevelWindows<'_> {
+    fn validate_window(
+        &self,
+        start: usize,
+        end: usize,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        let levels = self.levels.window(start, end);
+        self.accumulator.validate_logged(&self.log[start..end],
+            |level, _| levels[level].clone(), options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gen_sequential_elems;
+
+    #[test]
+    fn test_sampling_rate() {
+        let acc = MultiLevelIBLTAccumulator::new(100, Some(1234));
+        let elems = gen_sequential_elems(10000);
+        for level in 1..4 {
+            let sampled = elems.iter()
+                .filter(|elem| acc.max_level(elem) >= level)
+                .count();
+            let expected = elems.len() >> level;
+            assert!(sampled > expected * 8 / 10 && sampled < expected * 12 / 10,
+                "{} elements sampled in level {}", sampled, level);
+        }
+    }
+
+    #[test]
+    fn test_serialization() {
+        let mut acc1 = MultiLevelIBLTAccumulator::new(100, Some(1234));
+        acc1.process_batch(&gen_sequential_elems(1000));
+        let acc2 = MultiLevelIBLTAccumulator::from_bytes(
+            &acc1.to_bytes(),
+            DEFAULT_BITS_PER_ENTRY,
+            DEFAULT_NUM_HASHES,
+        );
+        assert!(acc1.equals(&acc2));
+        assert_eq!(acc1.max_level(&[1, 2, 3]), acc2.max_level(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn test_validate_few_dropped() {
+        let elems = gen_sequential_elems(1000);
+        let mut acc = MultiLevelIBLTAccumulator::new(100, Some(1234));
+        acc.process_batch(&elems[10..].to_vec());
+        assert_eq!(acc.validate(&elems), ValidationResult::Valid);
+    }
+
+    #[test]
+    fn test_validate_many_dropped() {
+        let elems = gen_sequential_elems(3000);
+        let mut acc = MultiLevelIBLTAccumulator::new(100, Some(1234));
+        acc.process_batch(&elems[1000..].to_vec());
+        let result = acc.validate(&elems);
+        assert!(!result.is_valid() && result.is_undetermined());
+        match result {
+            ValidationResult::IbltSampledValid { level, estimated_dropped } => {
+                assert!(level > 0);
+                assert!(estimated_dropped > 500 && estimated_dropped < 2000,
+                    "estimated {} dropped", estimated_dropped);
+            },
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_validate_many_dropped_and_injected_outside_sample() {
+        let elems = gen_sequential_elems(3000);
+        let mut acc = MultiLevelIBLTAccumulator::new(100, Some(1234));
+        acc.process_batch(&elems[1000..].to_vec());
+        let injected = (3000..4000_u32)
+            .map(|i| i.to_be_bytes().to_vec())
+            .find(|elem| acc.max_level(elem) == 0)
+            .unwrap();
+        acc.process(&injected);
+        let result = acc.validate(&elems);
+        assert!(matches!(result, ValidationResult::IbltSampledValid { .. }),
+            "{:?}", result);
+        assert!(!result.is_valid());
+    }
+
+    #[test]
+    fn test_validate_malicious() {
+        let elems = gen_sequential_elems(1000);
+        let mut acc = MultiLevelIBLTAccumulator::new(100, Some(1234));
+        acc.process_batch(&elems[11..].to_vec());
+        acc.process(&[0; 16]);
+        assert!(!acc.validate(&elems).is_valid());
+    }
+}
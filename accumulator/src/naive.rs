@@ -5,7 +5,8 @@ use bincode;
 use itertools::Itertools;
 use serde::{Serialize, Deserialize};
 
-use crate::{Accumulator, ValidationResult};
+use crate::{Accumulator, ValidationOptions, ValidationResult};
+use bloom_sd::StrataEstimator;
 use digest::Digest;
 
 /// The naive accumulator stores no auxiliary data structures outside
@@ -19,6 +20,7 @@ use digest::Digest;
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NaiveAccumulator {
     digest: Digest,
+    estimator: StrataEstimator,
 }
 
 impl NaiveAccumulator {
@@ -28,7 +30,9 @@ impl NaiveAccumulator {
         } else {
             Digest::new()
         };
-        Self { digest }
+        let estimator =
+            StrataEstimator::new(u64::from_be_bytes(digest.nonce));
+        Self { digest, estimator }
     }
 }
 
@@ -39,10 +43,13 @@ impl Accumulator for NaiveAccumulator {
 
     fn reset(&mut self) {
         self.digest = Digest::new();
+        self.estimator =
+            StrataEstimator::new(u64::from_be_bytes(self.digest.nonce));
     }
 
     fn process(&mut self, elem: &[u8]) {
         self.digest.add(elem);
+        self.estimator.insert(elem);
     }
 
     fn process_batch(&mut self, elems: &Vec<Vec<u8>>) {
@@ -55,16 +62,33 @@ impl Accumulator for NaiveAccumulator {
         self.digest.count as usize
     }
 
+    fn estimator(&self) -> &StrataEstimator {
+        &self.estimator
+    }
+
     #[cfg(feature = "disable_validation")]
-    fn validate(&self, _elems: &Vec<Vec<u8>>) -> ValidationResult {
+    fn validate_with_options(
+        &self,
+        _elems: &Vec<Vec<u8>>,
+        _options: &ValidationOptions,
+    ) -> ValidationResult {
         panic!("validation not enabled")
     }
 
     #[cfg(not(feature = "disable_validation"))]
-    fn validate(&self, elems: &Vec<Vec<u8>>) -> ValidationResult {
+    fn validate_with_options(
+        &self,
+        elems: &Vec<Vec<u8>>,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
         let start = Instant::now();
         for (i, combination) in (0..elems.len())
                 .combinations(self.total()).enumerate() {
+            if options.count_digest() {
+                debug!("gave up after {} combinations: {:?}", i,
+                    Instant::now() - start);
+                return ValidationResult::BudgetExceeded;
+            }
             let mut digest = Digest::new();
             // We could amortize digest calculation using the previous digest,
             // but it's still exponential in the number of subsets
@@ -86,15 +110,7 @@ impl Accumulator for NaiveAccumulator {
 mod tests {
     use super::*;
     use bincode;
-    use rand;
-    use rand::Rng;
-
-    const NBYTES: usize = 16;
-
-    fn gen_elems(n: usize) -> Vec<Vec<u8>> {
-        let mut rng = rand::thread_rng();
-        (0..n).map(|_| (0..NBYTES).map(|_| rng.gen::<u8>()).collect()).collect()
-    }
+    use crate::test_util::gen_elems;
 
     #[test]
     fn test_not_equals() {
@@ -122,4 +138,19 @@ mod tests {
         assert_ne!(acc1, acc2);
         assert_eq!(acc1, acc3);
     }
+
+    #[test]
+    fn validate_with_max_digests() {
+        let mut acc = NaiveAccumulator::new(None);
+        let elems = gen_elems(100);
+        for elem in &elems[3..] {
+            acc.process(elem);
+        }
+        let options = ValidationOptions {
+            max_digests: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(acc.validate_with_options(&elems, &options),
+            ValidationResult::BudgetExceeded);
+    }
 }
@@ -0,0 +1,184 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::Executor;
+
+/// A flag shared between threads to cancel validation in progress.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels validation with this token, and any clones of it.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A count of the digests computed so far, shared between threads and by
+/// clones of the options of one validation.
+#[derive(Debug, Clone, Default)]
+pub struct DigestCounter(Arc<AtomicUsize>);
+
+impl DigestCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `n` digests to the count, and returns the new count.
+    pub fn add(&self, n: usize) -> usize {
+        self.0.fetch_add(n, Ordering::Relaxed) + n
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits on the work validation may do. Validation that exceeds any of them
+/// gives up and returns `ValidationResult::BudgetExceeded`. There are no
+/// limits by default, and validation is strict.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationOptions {
+    /// Wall-clock time by which validation should finish. The ILP and root
+    /// finding backends are given the time that is left as a time limit.
+    pub deadline: Option<Instant>,
+    /// Maximum number of digests to compute, e.g. when trying every
+    /// combination of elements with the same DJB hash. The limit is on the
+    /// digests computed in total, across every candidate and level.
+    pub max_digests: Option<usize>,
+    /// Digests computed so far against `max_digests`. Clones of the options
+    /// share the count, so replace it to start a new validation.
+    pub digests: DigestCounter,
+    /// Token to cancel validation from another thread.
+    pub cancellation: Option<CancellationToken>,
+    /// Where to run the parallel parts of validation. Defaults to the thread
+    /// pool shared by the whole process.
+    pub executor: Executor,
+    /// Number of extra copies of logged elements that may have been received,
+    /// e.g. packets duplicated by the network. Only the IBLT accumulator with
+    /// the hash check and the power sum accumulator tolerate duplicates, and
+    /// report them with `ValidationResult::DuplicatesValid`. Defaults to 0.
+    pub max_duplicates: usize,
+}
+
+impl ValidationOptions {
+    /// Options with a deadline `timeout` from now.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            deadline: Some(Instant::now() + timeout),
+            ..Default::default()
+        }
+    }
+
+    /// Records that another digest is about to be computed, and returns
+    /// whether validation should give up instead.
+    pub fn count_digest(&self) -> bool {
+        self.digests.add(1);
+        self.is_exceeded()
+    }
+
+    /// Whether validation should give up.
+    pub fn is_exceeded(&self) -> bool {
+        if let Some(max_digests) = self.max_digests {
+            if self.digests.get() > max_digests {
+                return true;
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return true;
+            }
+        }
+        if let Some(cancellation) = &self.cancellation {
+            if cancellation.is_cancelled() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The time left until the deadline, if there is one.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline|
+            deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_limits() {
+        let options = ValidationOptions::default();
+        options.digests.add(usize::MAX);
+        assert!(!options.is_exceeded());
+        assert_eq!(options.time_remaining(), None);
+    }
+
+    #[test]
+    fn test_max_digests() {
+        let options = ValidationOptions {
+            max_digests: Some(10),
+            ..Default::default()
+        };
+        for _ in 0..10 {
+            assert!(!options.count_digest());
+        }
+        assert!(options.count_digest());
+    }
+
+    #[test]
+    fn test_max_digests_shared() {
+        let options = ValidationOptions {
+            max_digests: Some(10),
+            ..Default::default()
+        };
+        // The count carries over between steps and clones of the options.
+        let clone = options.clone();
+        for _ in 0..5 {
+            assert!(!options.count_digest());
+            assert!(!clone.count_digest());
+        }
+        assert_eq!(options.digests.get(), 10);
+        assert!(clone.count_digest());
+        assert!(options.is_exceeded());
+
+        // A new counter starts a new validation.
+        let mut options = options;
+        options.digests = DigestCounter::new();
+        assert!(!options.is_exceeded());
+        assert!(clone.is_exceeded());
+    }
+
+    #[test]
+    fn test_deadline() {
+        let options = ValidationOptions::with_timeout(Duration::from_secs(60));
+        assert!(!options.is_exceeded());
+        assert!(options.time_remaining().unwrap() > Duration::from_secs(59));
+        let options = ValidationOptions::with_timeout(Duration::ZERO);
+        assert!(options.is_exceeded());
+        assert_eq!(options.time_remaining(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let token = CancellationToken::new();
+        let options = ValidationOptions {
+            cancellation: Some(token.clone()),
+            ..Default::default()
+        };
+        assert!(!options.is_exceeded());
+        token.cancel();
+        assert!(options.is_exceeded());
+    }
+}
@@ -0,0 +1,273 @@
+use std::mem;
+
+use bloom_sd::{expected_wraps, peel_failure_probability};
+use bloom_sd::wraparound_probability;
+use bloom_sd::{StrataEstimator, DEFAULT_NUM_STRATA, DEFAULT_CELLS_PER_STRATUM};
+use digest::AmhHash;
+use crate::{IBLTAccumulator, MAX_WRAPAROUND_CANDIDATES};
+
+/// The range of hash functions the planner considers.
+const MIN_NUM_HASHES: u32 = 2;
+const MAX_NUM_HASHES: u32 = 6;
+/// The range of bits per counter the planner considers.
+const MAX_BITS_PER_ENTRY: usize = 32;
+/// The range of cells per dropped packet the planner considers. The failure
+/// probabilities keep decreasing with more cells, so without a bound the
+/// search would never end for unreachable targets.
+const MAX_CELLS_MULTIPLIER: usize = 1 << 10;
+
+/// IBLT accumulator parameters chosen by `plan_iblt()`, along with their
+/// predicted behavior at the maximum number of dropped packets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IBLTPlan {
+    pub threshold: usize,
+    pub bits_per_entry: usize,
+    pub cells_multiplier: usize,
+    pub num_hashes: u32,
+    /// Size of the serialized accumulator.
+    pub num_bytes: usize,
+    /// Probability that peeling the difference IBLT recovers every dropped
+    /// packet. Otherwise validation falls back to the ILP, which may pick the
+    /// wrong subset and report a valid log as invalid.
+    pub p_peel_success: f64,
+    /// Probability that a counter of the difference IBLT wraps around, in
+    /// which case validation has to consider each count the counters could
+    /// stand for.
+    pub p_wraparound: f64,
+    /// Expected number of counts the counters could stand for if they wrap
+    /// around. It grows with the logged packets per cell, which bound how
+    /// many times each counter could have wrapped.
+    pub wraparound_candidates: f64,
+    /// Probability that validation is undetermined because a wraparound has
+    /// more than `MAX_WRAPAROUND_CANDIDATES` candidates.
+    pub p_undetermined: f64,
+}
+
+impl IBLTPlan {
+    /// Creates an accumulator with the planned parameters.
+    pub fn build(&self, seed: Option<u64>) -> IBLTAccumulator {
+        IBLTAccumulator::new_with_params(
+            self.threshold,
+            self.bits_per_entry,
+            self.cells_multiplier,
+            self.num_hashes,
+            seed,
+        )
+    }
+}
+
+/// The size of a serialized IBLT accumulator without the hash check.
+/// See `MiniIBLTAccumulator`.
+fn serialized_len(num_entries: usize, bits_per_entry: usize) -> usize {
+    // bincode prefixes each Vec with a u64 length
+    let counters_len = 8 + (num_entries * bits_per_entry).div_ceil(8);
+    let data_len = 8 + num_entries * bloom_sd::DJB_HASH_SIZE / 8;
+    let estimator_len = StrataEstimator::serialized_len(
+        DEFAULT_NUM_STRATA, DEFAULT_CELLS_PER_STRATUM);
+    mem::size_of::<AmhHash>()   // hash
+        + mem::size_of::<u16>() // count
+        + mem::size_of::<u64>() // seed
+        + counters_len
+        + data_len
+        + 1                     // hash_checks: None
+        + 1                     // partitioned
+        + estimator_len
+}
+
+/// Estimates the number of ways to distribute the wraparounds of the dropped
+/// packets' counters among the cells whose logged packets could have wrapped
+/// them, conditioned on at least one wraparound. See `unwrap_difference_iblt`.
+fn wraparound_candidates(
+    num_entries: usize,
+    num_hashes: u32,
+    bits_per_entry: usize,
+    max_dropped: usize,
+    expected_packets: usize,
+) -> f64 {
+    let wraps = expected_wraps(num_entries as u64, num_hashes, bits_per_entry,
+        max_dropped, false).ceil().max(1.0);
+    let slots = expected_wraps(num_entries as u64, num_hashes, bits_per_entry,
+        expected_packets, false).max(wraps);
+    // slots choose wraps, stopping once there are too many to enumerate
+    let mut candidates = 1.0;
+    let mut i = 0.0;
+    while i < wraps && candidates <= MAX_WRAPAROUND_CANDIDATES as f64 {
+        candidates *= (slots - i) / (wraps - i);
+        i += 1.0;
+    }
+    candidates
+}
+
+/// Probability that two of `expected_packets` logged packets map to the same
+/// u32, in which case validation has to check combinations of the colliding
+/// packets. No IBLT parameters affect it, so it is not a planning target.
+pub fn collision_probability(expected_packets: usize) -> f64 {
+    let n = expected_packets as f64;
+    (n * (n - 1.0) / 2.0 / (1_u64 << bloom_sd::DJB_HASH_SIZE) as f64).min(1.0)
+}
+
+/// Chooses the smallest IBLT accumulator parameters that fit in
+/// `byte_budget` bytes such that, when about `expected_packets` packets are
+/// logged and at most `max_dropped` of them are dropped per epoch, validation
+/// is undetermined with probability at most `p_undetermined` and reports a
+/// valid log as invalid with probability at most `p_false_invalid`. Ties are
+/// broken by the fewest hash functions. Returns None if no parameters fit,
+/// or if meeting the targets takes more than `MAX_CELLS_MULTIPLIER` cells
+/// per dropped packet.
+///
+/// The predictions are closed-form estimates, see `bloom_sd` for details.
+/// A wraparound of the difference IBLT only makes validation undetermined if
+/// the logged packets fill the counters of too many cells to enumerate them.
+pub fn plan_iblt(
+    max_dropped: usize,
+    expected_packets: usize,
+    p_undetermined: f64,
+    p_false_invalid: f64,
+    byte_budget: usize,
+) -> Option<IBLTPlan> {
+    if max_dropped == 0 {
+        return None;
+    }
+    // the dropped packets were logged too
+    let expected_packets = std::cmp::max(expected_packets, max_dropped);
+    let mut best: Option<IBLTPlan> = None;
+    for num_hashes in MIN_NUM_HASHES..=MAX_NUM_HASHES {
+        for bits_per_entry in 1..=MAX_BITS_PER_ENTRY {
+            // Both probabilities decrease with the number of cells, so the
+            // smallest multiplier that meets the targets is the best one.
+            for cells_multiplier in 1..=MAX_CELLS_MULTIPLIER {
+                let num_entries = cells_multiplier * max_dropped;
+                let num_bytes = serialized_len(num_entries, bits_per_entry);
+                if num_bytes > byte_budget {
+                    break;
+                }
+                let p_peel_failure = peel_failure_probability(
+                    num_entries as u64, num_hashes, max_dropped, false);
+                let p_wraparound = wraparound_probability(
+                    num_entries as u64, num_hashes, bits_per_entry,
+                    max_dropped, false);
+                let wraparound_candidates = wraparound_candidates(
+                    num_entries, num_hashes, bits_per_entry, max_dropped,
+                    expected_packets);
+                let p_wraparound_undetermined = if wraparound_candidates
+                        > MAX_WRAPAROUND_CANDIDATES as f64 {
+                    p_wraparound
+                } else {
+                    0.0
+                };
+                if p_peel_failure <= p_false_invalid
+                        && p_wraparound_undetermined <= p_undetermined {
+                    let better = match &best {
+                        Some(plan) => num_bytes < plan.num_bytes,
+                        None => true,
+                    };
+                    if better {
+                        best = Some(IBLTPlan {
+                            threshold: max_dropped,
+                            bits_per_entry,
+                            cells_multiplier,
+                            num_hashes,
+                            num_bytes,
+                            p_peel_success: 1.0 - p_peel_failure,
+                            p_wraparound,
+                            wraparound_candidates,
+                            p_undetermined: p_wraparound_undetermined,
+                        });
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Accumulator;
+
+    #[test]
+    fn test_serialized_len() {
+        for &(threshold, bits_per_entry, cells_multiplier) in
+                &[(10, 8, 10), (7, 3, 3), (1, 1, 1), (100, 5, 2)] {
+            let plan = IBLTPlan {
+                threshold,
+                bits_per_entry,
+                cells_multiplier,
+                num_hashes: 2,
+                num_bytes: 0,
+                p_peel_success: 1.0,
+                p_wraparound: 0.0,
+                wraparound_candidates: 0.0,
+                p_undetermined: 0.0,
+            };
+            let bytes = plan.build(None).to_bytes();
+            assert_eq!(
+                serialized_len(threshold * cells_multiplier, bits_per_entry),
+                bytes.len());
+        }
+    }
+
+    #[test]
+    fn test_wraparound_candidates() {
+        // every logged packet was dropped, so the counts are known
+        assert_eq!(wraparound_candidates(40, 3, 1, 20, 20), 1.0);
+        assert!(wraparound_candidates(40, 3, 2, 20, 1000)
+            > MAX_WRAPAROUND_CANDIDATES as f64);
+        assert!(wraparound_candidates(40, 3, 4, 20, 200)
+            < wraparound_candidates(40, 3, 4, 20, 400));
+    }
+
+    #[test]
+    fn test_plan_meets_targets() {
+        let plan = plan_iblt(20, 1000, 1e-6, 1e-3, 10_000).unwrap();
+        assert_eq!(plan.threshold, 20);
+        assert!(plan.num_bytes <= 10_000);
+        assert!(1.0 - plan.p_peel_success <= 1e-3);
+        assert!(plan.p_undetermined <= 1e-6);
+        // 20 drops never fill a counter of 5 bits
+        assert!(plan.bits_per_entry <= 5);
+    }
+
+    #[test]
+    fn test_plan_logged_load() {
+        // few logged packets fill few counters, so wraparounds can be
+        // enumerated and narrower counters suffice
+        let light = plan_iblt(20, 20, 1e-6, 1e-3, 100_000).unwrap();
+        let heavy = plan_iblt(20, 60_000, 1e-6, 1e-3, 100_000).unwrap();
+        assert!(light.wraparound_candidates
+            <= MAX_WRAPAROUND_CANDIDATES as f64);
+        assert!(light.num_bytes <= heavy.num_bytes);
+        assert!(light.bits_per_entry < heavy.bits_per_entry);
+        assert!(heavy.p_undetermined <= 1e-6);
+    }
+
+    #[test]
+    fn test_plan_stricter_targets_cost_more() {
+        let loose = plan_iblt(20, 1000, 1e-3, 1e-2, 100_000).unwrap();
+        let strict = plan_iblt(20, 1000, 1e-3, 1e-6, 100_000).unwrap();
+        assert!(loose.num_bytes <= strict.num_bytes);
+    }
+
+    #[test]
+    fn test_plan_exceeds_budget() {
+        assert_eq!(plan_iblt(1000, 1000, 1e-6, 1e-6, 100), None);
+        assert_eq!(plan_iblt(0, 1000, 1e-6, 1e-6, 100), None);
+    }
+
+    #[test]
+    fn test_plan_unreachable_targets() {
+        // peeling always fails with some probability
+        assert_eq!(plan_iblt(20, 1000, 1e-6, 0.0, usize::MAX), None);
+    }
+
+    #[test]
+    fn test_collision_probability() {
+        assert_eq!(collision_probability(1), 0.0);
+        assert!(collision_probability(1000) < 1e-3);
+        // more packets than fit in an epoch are still estimated
+        assert!(collision_probability(100_000) > 0.5);
+        assert_eq!(collision_probability(1 << 20), 1.0);
+    }
+}
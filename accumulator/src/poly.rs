@@ -0,0 +1,466 @@
+//! Polynomial arithmetic in GF(LARGE_PRIME) for converting power sums to the
+//! coefficients of the polynomial with those roots.
+//!
+//! LARGE_PRIME - 1 = 4 * 1073741757 has no large power of two factor, so the
+//! field has no roots of unity for an NTT. Polynomials are multiplied with
+//! Karatsuba instead, and the Newton identities are solved by divide and
+//! conquer on top of it, in O(n^1.59 log n) rather than O(n^2) time.
+//!
+//! Also solves small linear systems and finds the roots of polynomials in
+//! the field, for interpolating characteristic polynomials, and finds Pade
+//! approximants, for separating dropped from duplicated elements.
+use crate::power_sum::{add_and_mod, mul_and_mod, reduce, LARGE_PRIME_U32};
+
+/// Polynomials shorter than this are multiplied the schoolbook way.
+const KARATSUBA_THRESHOLD: usize = 32;
+/// Ranges of coefficients shorter than this are solved term by term.
+const NEWTON_THRESHOLD: usize = 64;
+
+#[inline]
+pub(crate) fn sub_and_mod(a: u32, b: u32) -> u32 {
+    add_and_mod(a, LARGE_PRIME_U32 - b)
+}
+
+/// The multiplicative inverses of 0..=n in the field, where the inverse of 0
+/// is 0, computed in linear time using inv(i) = -(p / i) * inv(p mod i).
+pub(crate) fn inverses(n: usize) -> Vec<u32> {
+    let p = LARGE_PRIME_U32 as usize;
+    let mut inv = vec![0, 1];
+    for i in 2..=n {
+        let x = mul_and_mod(LARGE_PRIME_U32 - (p / i) as u32, inv[p % i]);
+        inv.push(x);
+    }
+    inv.truncate(n + 1);
+    inv
+}
+
+fn mul_schoolbook(a: &[u32], b: &[u32]) -> Vec<u32> {
+    // the sum of fewer than 2^32 reduced values fits in a u64
+    let mut c = vec![0_u64; a.len() + b.len() - 1];
+    for (i, &x) in a.iter().enumerate() {
+        for (ci, &y) in c[i..].iter_mut().zip(b) {
+            *ci += mul_and_mod(x, y) as u64;
+        }
+    }
+    c.into_iter().map(reduce).collect()
+}
+
+fn add_into(c: &mut [u32], offset: usize, a: &[u32]) {
+    for (ci, &x) in c[offset..].iter_mut().zip(a) {
+        *ci = add_and_mod(*ci, x);
+    }
+}
+
+fn sub_into(c: &mut [u32], offset: usize, a: &[u32]) {
+    for (ci, &x) in c[offset..].iter_mut().zip(a) {
+        *ci = sub_and_mod(*ci, x);
+    }
+}
+
+fn add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (mut c, b) = if a.len() >= b.len() {
+        (a.to_vec(), b)
+    } else {
+        (b.to_vec(), a)
+    };
+    add_into(&mut c, 0, b);
+    c
+}
+
+/// Multiplies two polynomials with coefficients in the field, where the
+/// coefficient of x^i is at index i.
+pub(crate) fn poly_mul(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return vec![];
+    }
+    let (a, b) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    if b.len() < KARATSUBA_THRESHOLD {
+        return mul_schoolbook(a, b);
+    }
+    let mut c = vec![0; a.len() + b.len() - 1];
+    if b.len() <= a.len() / 2 {
+        // multiply balanced pieces of the longer polynomial
+        for (i, chunk) in a.chunks(b.len()).enumerate() {
+            add_into(&mut c, i * b.len(), &poly_mul(chunk, b));
+        }
+        return c;
+    }
+    // a = a0 + x^h a1 and b = b0 + x^h b1, where b1 is not empty
+    let h = a.len() / 2;
+    let (a0, a1) = a.split_at(h);
+    let (b0, b1) = b.split_at(h);
+    let z0 = poly_mul(a0, b0);
+    let z2 = poly_mul(a1, b1);
+    let mut z1 = poly_mul(&add(a0, a1), &add(b0, b1));
+    sub_into(&mut z1, 0, &z0);
+    sub_into(&mut z1, 0, &z2);
+    add_into(&mut c, 0, &z0);
+    add_into(&mut c, h, &z1);
+    add_into(&mut c, 2 * h, &z2);
+    c
+}
+
+/// Solves the Newton identities for the coefficients c of the polynomial
+/// whose roots have the power sums p, where p[j] is the (j+1)-th power sum.
+/// The coefficients are those of prod(1 - r x) in increasing order, i.e. of
+/// prod(x - r) in decreasing order, and satisfy
+///
+///   k * c[k] = -(c[k-1]*p[0] + c[k-2]*p[1] + ... + c[0]*p[k-1]).
+pub(crate) fn newton_identities(p: &[u32]) -> Vec<u32> {
+    let n = p.len();
+    let inv = inverses(n);
+    let mut c = vec![0; n + 1];
+    // the part of each sum from coefficients solved in earlier ranges
+    let mut partial = vec![0; n + 1];
+    solve_range(p, &inv, &mut c, &mut partial, 0, n + 1);
+    c
+}
+
+/// Solves for c[lo..hi] given the contributions of c[..lo] in `partial`.
+fn solve_range(
+    p: &[u32],
+    inv: &[u32],
+    c: &mut [u32],
+    partial: &mut [u32],
+    lo: usize,
+    hi: usize,
+) {
+    if hi - lo < NEWTON_THRESHOLD {
+        for k in lo..hi {
+            if k == 0 {
+                c[k] = 1;
+                continue;
+            }
+            let mut sum = partial[k] as u64;
+            for i in lo..k {
+                sum += mul_and_mod(c[i], p[k - i - 1]) as u64;
+            }
+            c[k] = mul_and_mod(LARGE_PRIME_U32 - reduce(sum), inv[k]);
+        }
+        return;
+    }
+    let mid = (lo + hi) / 2;
+    solve_range(p, inv, c, partial, lo, mid);
+    // add the contributions of c[lo..mid] to the sums of c[mid..hi]
+    let product = poly_mul(&c[lo..mid], &p[..(hi - lo - 1)]);
+    for k in mid..hi {
+        partial[k] = add_and_mod(partial[k], product[k - lo - 1]);
+    }
+    solve_range(p, inv, c, partial, mid, hi);
+}
+
+/// a^exp in the field.
+pub(crate) fn pow_mod(mut a: u32, mut exp: u64) -> u32 {
+    let mut result = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_and_mod(result, a);
+        }
+        a = mul_and_mod(a, a);
+        exp >>= 1;
+    }
+    result
+}
+
+/// The multiplicative inverse of a non-zero element of the field.
+pub(crate) fn inverse(a: u32) -> u32 {
+    assert_ne!(reduce(a as u64), 0, "zero has no inverse");
+    pow_mod(a, LARGE_PRIME_U32 as u64 - 2)
+}
+
+/// Solves the square system of linear equations `matrix * x = rhs` in the
+/// field by Gaussian elimination. Returns None if the matrix is singular.
+pub(crate) fn solve_linear_system(
+    mut matrix: Vec<Vec<u32>>,
+    mut rhs: Vec<u32>,
+) -> Option<Vec<u32>> {
+    let n = rhs.len();
+    for col in 0..n {
+        let pivot = (col..n).find(|&row| matrix[row][col] != 0)?;
+        matrix.swap(col, pivot);
+        rhs.swap(col, pivot);
+        let inv = inverse(matrix[col][col]);
+        for x in matrix[col].iter_mut() {
+            *x = mul_and_mod(*x, inv);
+        }
+        rhs[col] = mul_and_mod(rhs[col], inv);
+        let pivot_row = matrix[col].clone();
+        let pivot_rhs = rhs[col];
+        for (row, (x, b)) in matrix.iter_mut().zip(rhs.iter_mut()).enumerate() {
+            let factor = x[col];
+            if row == col || factor == 0 {
+                continue;
+            }
+            for (x, &y) in x[col..].iter_mut().zip(&pivot_row[col..]) {
+                *x = sub_and_mod(*x, mul_and_mod(factor, y));
+            }
+            *b = sub_and_mod(*b, mul_and_mod(factor, pivot_rhs));
+        }
+    }
+    Some(rhs)
+}
+
+/// The Pade approximant N / Q of a power series, where N has degree at most
+/// `m`, and Q has degree at most `n` and a constant term of 1. The
+/// coefficients of Q solve the equations that the coefficients of x^(m+1) to
+/// x^(m+n) in the series times Q vanish. Returns None if there is no such
+/// approximant, or if it does not agree with every term of the series, which
+/// must have more than m + n terms.
+pub(crate) fn pade(
+    series: &[u32],
+    m: usize,
+    n: usize,
+) -> Option<(Vec<u32>, Vec<u32>)> {
+    assert!(series.len() > m + n);
+    let term = |k: usize, j: usize| k.checked_sub(j).map_or(0, |i| series[i]);
+    let matrix = (m + 1..=m + n)
+        .map(|k| (1..=n).map(|j| term(k, j)).collect())
+        .collect();
+    let rhs = (m + 1..=m + n)
+        .map(|k| sub_and_mod(0, series[k]))
+        .collect();
+    let mut q = vec![1];
+    q.extend(solve_linear_system(matrix, rhs)?);
+    let mut product = poly_mul(series, &q);
+    product.truncate(series.len());
+    if product[m + 1..].iter().any(|&x| x != 0) {
+        return None;
+    }
+    product.truncate(m + 1);
+    Some((product, q))
+}
+
+/// Evaluates the polynomial at x, where the coefficient of x^i is at index i.
+pub(crate) fn evaluate(a: &[u32], x: u32) -> u32 {
+    a.iter().rev().fold(0, |value, &coeff|
+        add_and_mod(mul_and_mod(value, x), coeff))
+}
+
+fn trim(mut a: Vec<u32>) -> Vec<u32> {
+    while a.last() == Some(&0) {
+        a.pop();
+    }
+    a
+}
+
+/// The quotient and remainder of dividing a by a non-zero polynomial m.
+fn div_rem(a: &[u32], m: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let m = trim(m.to_vec());
+    if a.len() < m.len() {
+        return (vec![], trim(a.to_vec()));
+    }
+    let inv = inverse(*m.last().unwrap());
+    let mut r = a.to_vec();
+    let mut q = vec![0; a.len() - m.len() + 1];
+    for i in (0..q.len()).rev() {
+        let factor = mul_and_mod(r[i + m.len() - 1], inv);
+        q[i] = factor;
+        if factor != 0 {
+            sub_into(&mut r[i..], 0, &m.iter()
+                .map(|&x| mul_and_mod(x, factor))
+                .collect::<Vec<_>>());
+        }
+    }
+    r.truncate(m.len() - 1);
+    (q, trim(r))
+}
+
+/// The monic greatest common divisor of two polynomials.
+fn gcd(a: Vec<u32>, b: Vec<u32>) -> Vec<u32> {
+    let (mut a, mut b) = (trim(a), trim(b));
+    while !b.is_empty() {
+        let (_, r) = div_rem(&a, &b);
+        a = b;
+        b = r;
+    }
+    if let Some(&lead) = a.last() {
+        let inv = inverse(lead);
+        for x in a.iter_mut() {
+            *x = mul_and_mod(*x, inv);
+        }
+    }
+    a
+}
+
+/// base^exp modulo the polynomial m.
+fn pow_rem(base: &[u32], mut exp: u64, m: &[u32]) -> Vec<u32> {
+    let mut result = vec![1];
+    let mut base = div_rem(base, m).1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = div_rem(&poly_mul(&result, &base), m).1;
+        }
+        base = div_rem(&poly_mul(&base, &base), m).1;
+        exp >>= 1;
+    }
+    result
+}
+
+/// Splits a monic product of distinct linear factors into its roots with
+/// Cantor-Zassenhaus: for most a, (x + a)^((p - 1) / 2) - 1 vanishes at
+/// about half of the roots, so its GCD with the polynomial splits it.
+fn split_distinct(f: Vec<u32>, roots: &mut Vec<u32>) {
+    match f.len() {
+        0 | 1 => {},
+        2 => roots.push(sub_and_mod(0, f[0])),
+        _ => {
+            let exp = (LARGE_PRIME_U32 as u64 - 1) / 2;
+            for a in 0.. {
+                let mut h = pow_rem(&[a, 1], exp, &f);
+                if h.is_empty() {
+                    continue;
+                }
+                h[0] = sub_and_mod(h[0], 1);
+                let g = gcd(f.clone(), h);
+                if g.len() > 1 && g.len() < f.len() {
+                    let (q, _) = div_rem(&f, &g);
+                    split_distinct(g, roots);
+                    split_distinct(q, roots);
+                    return;
+                }
+            }
+        },
+    }
+}
+
+/// Finds every root of a monic polynomial in the field, with multiplicity,
+/// where the coefficient of x^i is at index i. Returns None if the
+/// polynomial does not split into linear factors in the field.
+pub(crate) fn find_roots(f: &[u32]) -> Option<Vec<u32>> {
+    let f = trim(f.to_vec());
+    if f.len() <= 1 {
+        return Some(vec![]);
+    }
+    // the distinct roots are those of gcd(f, x^p - x)
+    let mut g = pow_rem(&[0, 1], LARGE_PRIME_U32 as u64, &f);
+    g.resize(std::cmp::max(g.len(), 2), 0);
+    g[1] = sub_and_mod(g[1], 1);
+    let mut distinct = vec![];
+    split_distinct(gcd(f.clone(), g), &mut distinct);
+
+    // divide out each root as many times as it divides the polynomial
+    let mut f = f;
+    let mut roots = vec![];
+    for root in distinct {
+        loop {
+            let (q, r) = div_rem(&f, &[sub_and_mod(0, root), 1]);
+            if !r.is_empty() {
+                break;
+            }
+            f = q;
+            roots.push(root);
+        }
+    }
+    if f.len() == 1 {
+        Some(roots)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn gen_poly(n: usize) -> Vec<u32> {
+        let mut rng = rand::thread_rng();
+        (0..n).map(|_| rng.gen_range(0..LARGE_PRIME_U32)).collect()
+    }
+
+    #[test]
+    fn test_inverses() {
+        let inv = inverses(1000);
+        assert_eq!(inv.len(), 1001);
+        assert_eq!(inv[0], 0);
+        for (i, &x) in inv.iter().enumerate().skip(1) {
+            assert_eq!(mul_and_mod(i as u32, x), 1, "inverse of {}", i);
+        }
+        assert_eq!(inverses(0), vec![0]);
+    }
+
+    #[test]
+    fn test_poly_mul() {
+        assert_eq!(poly_mul(&[1, 2], &[3, 4]), vec![3, 10, 8]);
+        assert_eq!(poly_mul(&[1, 2], &[]), vec![]);
+        for &(n, m) in &[(100, 100), (257, 64), (1000, 31), (33, 500), (400, 300)] {
+            let a = gen_poly(n);
+            let b = gen_poly(m);
+            assert_eq!(poly_mul(&a, &b), mul_schoolbook(&a, &b), "{}x{}", n, m);
+        }
+    }
+
+    #[test]
+    fn test_newton_identities() {
+        // (1 - 2x)(1 - 3x)(1 - 5x)
+        assert_eq!(newton_identities(&[10, 38, 160]),
+            vec![1, LARGE_PRIME_U32 - 10, 31, LARGE_PRIME_U32 - 30]);
+        assert_eq!(newton_identities(&[]), vec![1]);
+        for &n in &[63, 64, 200, 1000] {
+            let p = gen_poly(n);
+            let c = newton_identities(&p);
+            for k in 1..=n {
+                let mut sum = mul_and_mod(k as u32, c[k]);
+                for i in 0..k {
+                    sum = add_and_mod(sum, mul_and_mod(c[i], p[k - i - 1]));
+                }
+                assert_eq!(sum, 0, "identity {} of {}", k, n);
+            }
+        }
+    }
+
+    #[test]
+    fn test_solve_linear_system() {
+        // x + 2y = 5, 3x + 4y = 6
+        let x = solve_linear_system(vec![vec![1, 2], vec![3, 4]], vec![5, 6]);
+        let x = x.unwrap();
+        assert_eq!(add_and_mod(x[0], mul_and_mod(2, x[1])), 5);
+        assert_eq!(add_and_mod(mul_and_mod(3, x[0]), mul_and_mod(4, x[1])), 6);
+        assert_eq!(solve_linear_system(vec![vec![1, 2], vec![2, 4]], vec![1, 2]),
+            None);
+    }
+
+    #[test]
+    fn test_pade() {
+        // (1 - 2x)(1 - 3x) / (1 - 5x) = 1 + 0x + 6x^2 + 30x^3 + ...
+        let num = poly_mul(&[1, LARGE_PRIME_U32 - 2], &[1, LARGE_PRIME_U32 - 3]);
+        let mut series = num.clone();
+        series.resize(6, 0);
+        for k in 1..series.len() {
+            series[k] = add_and_mod(series[k], mul_and_mod(5, series[k - 1]));
+        }
+        assert_eq!(series[..4], [1, 0, 6, 30]);
+        assert_eq!(pade(&series, 2, 1), Some((num, vec![1, LARGE_PRIME_U32 - 5])));
+        // no polynomial of degree 3 agrees with every term
+        assert_eq!(pade(&series, 3, 0), None);
+        assert_eq!(pade(&[1, 0, 0], 0, 0), Some((vec![1], vec![1])));
+    }
+
+    #[test]
+    fn test_find_roots() {
+        // (x - 2)(x - 3)^2(x - 4294967000)
+        let mut f = vec![1];
+        for &root in &[2, 3, 3, 4294967000] {
+            f = poly_mul(&f, &[sub_and_mod(0, root), 1]);
+        }
+        let mut roots = find_roots(&f).unwrap();
+        roots.sort();
+        assert_eq!(roots, vec![2, 3, 3, 4294967000]);
+        // x^2 + 1 splits since p = 1 mod 4, so -1 is a square
+        assert_eq!(LARGE_PRIME_U32 % 4, 1);
+        let mut roots = find_roots(&[1, 0, 1]).unwrap();
+        roots.sort();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(mul_and_mod(roots[0], roots[0]), LARGE_PRIME_U32 - 1);
+        assert_eq!(find_roots(&[1]), Some(vec![]));
+    }
+
+    #[test]
+    fn test_find_roots_not_split() {
+        // x^2 - a has no roots if a is not a quadratic residue
+        let a = (2..).find(|&a| pow_mod(a, (LARGE_PRIME_U32 as u64 - 1) / 2)
+            != 1).unwrap();
+        let f = poly_mul(&[sub_and_mod(0, a), 0, 1], &[LARGE_PRIME_U32 - 7, 1]);
+        assert_eq!(find_roots(&f), None);
+    }
+}
@@ -1,15 +1,20 @@
 #[cfg(not(feature = "disable_validation"))]
 use std::collections::{HashSet, HashMap};
 #[cfg(not(feature = "disable_validation"))]
+use std::ops::RangeInclusive;
+#[cfg(not(feature = "disable_validation"))]
 use std::time::Instant;
 
 use bincode;
 use serde::{Serialize, Deserialize};
+use crate::{Accumulator, ValidationOptions, ValidationResult};
+#[cfg(not(feature = "disable_validation"))]
+use crate::Executor;
 #[cfg(not(feature = "disable_validation"))]
-use tokio::task;
+use crate::executor::find_matching_combination;
 #[cfg(not(feature = "disable_validation"))]
-use tokio::runtime::Builder;
-use crate::{Accumulator, ValidationResult};
+use crate::window::{WindowSums, WindowValidator};
+use bloom_sd::StrataEstimator;
 use digest::{AmhHash, Digest};
 #[cfg(not(feature = "disable_validation"))]
 use itertools::Itertools;
@@ -17,10 +22,16 @@ use itertools::Itertools;
 /// I picked some random prime number in the range [2^32, 2^64] from
 /// https://en.wikipedia.org/wiki/List_of_prime_numbers.
 /// This one is a Thabit prime, which is not of significance.
+#[cfg(test)]
 const LARGE_PRIME: i64 =  4294967029;
-const LARGE_PRIME_U32: u32 =  4294967029;
+pub(crate) const LARGE_PRIME_U32: u32 =  4294967029;
 const LARGE_PRIME_U64: u64 =  4294967029;
-const DJB_MASK: u32 = (1 << 31) - 1;
+pub(crate) const DJB_MASK: u32 = (1 << 31) - 1;
+/// floor(2^64 / LARGE_PRIME) for Barrett reduction.
+const BARRETT_FACTOR: u64 = u64::MAX / LARGE_PRIME_U64;
+/// Number of elements whose powers are computed together in a batch. The
+/// powers of a chunk should fit in the L1 cache.
+const BATCH_CHUNK_SIZE: usize = 1024;
 
 /// The power sum accumulator stores the power sums of all processed elements
 /// up to the threshold number of lost elements.
@@ -36,10 +47,20 @@ const DJB_MASK: u32 = (1 << 31) - 1;
 /// Note that validation cannot be  performed if the number of lost elements
 /// exceeds the threshold. All calculations are done in a finite field, modulo
 /// some 2^32 < large prime < 2^64 (the range of possible elements).
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+///
+/// In buffered mode, the DJB hashes of processed elements are buffered and
+/// their powers are added to the power sums a batch at a time, which is much
+/// faster per element than updating every power sum for each element.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PowerSumAccumulator {
     digest: Digest,
     power_sums: Vec<u32>,
+    estimator: StrataEstimator,
+    // DJB hashes of processed elements not yet in the power sums
+    buffer: Vec<u32>,
+    // number of elements to buffer before updating the power sums, 0 if the
+    // power sums are updated on every element
+    buffer_len: usize,
 }
 
 #[cfg(not(feature = "disable_validation"))]
@@ -50,19 +71,37 @@ extern "C" {
         coeffs: *const u32,
         field: u32,
         degree: usize,
+        timeout_secs: i64,
     ) -> i32;
 }
 
-fn add_and_mod(a: u32, b: u32) -> u32 {
-    (((a as u64) + (b as u64)) % LARGE_PRIME_U64) as u32
+/// Reduces any u64 modulo LARGE_PRIME with Barrett reduction, which avoids
+/// a division. The quotient estimate is at most 2 less than the quotient.
+#[inline]
+pub(crate) fn reduce(x: u64) -> u32 {
+    let q = ((x as u128 * BARRETT_FACTOR as u128) >> 64) as u64;
+    let mut r = x - q * LARGE_PRIME_U64;
+    if r >= LARGE_PRIME_U64 {
+        r -= LARGE_PRIME_U64;
+    }
+    if r >= LARGE_PRIME_U64 {
+        r -= LARGE_PRIME_U64;
+    }
+    r as u32
 }
 
-fn mul_and_mod(a: u32, b: u32) -> u32 {
-    (((a as u64) * (b as u64)) % LARGE_PRIME_U64) as u32
+#[inline]
+pub(crate) fn add_and_mod(a: u32, b: u32) -> u32 {
+    reduce((a as u64) + (b as u64))
+}
+
+#[inline]
+pub(crate) fn mul_and_mod(a: u32, b: u32) -> u32 {
+    reduce((a as u64) * (b as u64))
 }
 
 // modular division
-#[cfg(not(feature = "disable_validation"))]
+#[cfg(test)]
 fn div_and_mod(a: u32, b: u32) -> u32 {
     // divide `a` and `b` by the GCD of `a` and `modulo`
     let mut a = a as i64;
@@ -112,46 +151,61 @@ fn div_and_mod(a: u32, b: u32) -> u32 {
     mul_and_mod(a as u32, mmi as u32)
 }
 
-#[cfg(not(feature = "disable_validation"))]
-async fn calculate_power_sums(elems: &Vec<u32>, num_psums: usize) -> Vec<u32> {
-    let ncpus = num_cpus::get();
-    let elems_per_thread = elems.len() / ncpus;
-    debug!("found {} cpus", ncpus);
-    let mut joins = vec![];
-    for i in 0..ncpus {
-        let lower = i * elems_per_thread;
-        let upper = if i == ncpus - 1 {
-            elems.len()
-        } else {
-            (i + 1) * elems_per_thread
-        };
-        let elems = elems[lower..upper].to_vec();  // TODO: avoid clone
-        joins.push(task::spawn(async move {
-            let mut power_sums: Vec<u32> = vec![0; num_psums];
-            for i in 0..elems.len() {
-                let mut value: u32 = 1;
-                for j in 0..power_sums.len() {
-                    value = mul_and_mod(value, elems[i]);
-                    power_sums[j] = add_and_mod(power_sums[j], value);
-                }
+/// Adds the first `power_sums.len()` powers of the element to the power sums.
+pub(crate) fn add_power_sums(power_sums: &mut [u32], elem_u32: u32) {
+    let mut value: u32 = 1;
+    for power_sum in power_sums.iter_mut() {
+        value = mul_and_mod(value, elem_u32);
+        *power_sum = add_and_mod(*power_sum, value);
+    }
+}
+
+/// Adds the first `power_sums.len()` powers of each element to the power
+/// sums. The powers of a chunk of elements are computed one power at a time,
+/// so the inner loops are over contiguous arrays without dependencies between
+/// iterations, and the sum of each power is only reduced once per chunk.
+pub(crate) fn add_power_sums_batch(power_sums: &mut [u32], elems_u32: &[u32]) {
+    let mut values = Vec::with_capacity(BATCH_CHUNK_SIZE);
+    for chunk in elems_u32.chunks(BATCH_CHUNK_SIZE) {
+        values.clear();
+        values.resize(chunk.len(), 1);
+        for power_sum in power_sums.iter_mut() {
+            // the sum of fewer than 2^32 reduced values fits in a u64
+            let mut sum = *power_sum as u64;
+            for (value, &elem_u32) in values.iter_mut().zip(chunk) {
+                *value = mul_and_mod(*value, elem_u32);
+                sum += *value as u64;
             }
-            power_sums
-        }));
+            *power_sum = reduce(sum);
+        }
     }
+}
+
+#[cfg(not(feature = "disable_validation"))]
+fn calculate_power_sums(
+    elems: &[u32],
+    num_psums: usize,
+    executor: &Executor,
+) -> Vec<u32> {
+    debug!("calculating power sums on {} threads", executor.num_threads());
+    let results = executor.map_chunks(elems, |chunk| {
+        let mut power_sums: Vec<u32> = vec![0; num_psums];
+        add_power_sums_batch(&mut power_sums, chunk);
+        power_sums
+    });
 
     // merge results
     let mut power_sums: Vec<u32> = vec![0; num_psums];
-    for join in joins {
-        let result = join.await.unwrap();
-        for i in 0..num_psums {
-            power_sums[i] = add_and_mod(power_sums[i], result[i]);
+    for result in results {
+        for (power_sum, value) in power_sums.iter_mut().zip(result) {
+            *power_sum = add_and_mod(*power_sum, value);
         }
     }
     power_sums
 }
 
 #[cfg(not(feature = "disable_validation"))]
-fn calculate_difference(lhs: Vec<u32>, rhs: &Vec<u32>) -> Vec<u32> {
+pub(crate) fn calculate_difference(lhs: Vec<u32>, rhs: &Vec<u32>) -> Vec<u32> {
     (0..std::cmp::min(lhs.len(), rhs.len()))
         .map(|i| add_and_mod(lhs[i], LARGE_PRIME_U32 - rhs[i]))
         .collect()
@@ -165,53 +219,80 @@ fn calculate_difference(lhs: Vec<u32>, rhs: &Vec<u32>) -> Vec<u32> {
 // 4*e4 = e3*p0 - e2*p1 + e1*p2 - e0*p3
 // ...
 // Returns the coefficients as positive numbers in the field GF(LARGE_PRIME).
+// The identities are solved in sub-quadratic time, see `crate::poly`.
 #[cfg(not(feature = "disable_validation"))]
-fn compute_polynomial_coefficients(p: Vec<u32>) -> Vec<u32> {
-    let n = p.len();
-    if n == 0 {
+pub(crate) fn compute_polynomial_coefficients(p: Vec<u32>) -> Vec<u32> {
+    if p.is_empty() {
         return vec![];
     }
-    let mut e: Vec<i64> = vec![1];
-    for i in 0..n {
-        let mut sum: i64 = 0;
-        for j in 0..(i+1) {
-            if j & 1 == 0 {
-                sum += mul_and_mod(e[i-j] as u32, p[j]) as i64;
-            } else {
-                sum -= mul_and_mod(e[i-j] as u32, p[j]) as i64;
-            }
+    // includes the leading coefficient
+    crate::poly::newton_identities(&p)
+}
+
+/// Finds the roots of a monic polynomial among the candidate values, with
+/// multiplicity, by dividing out each candidate root while it divides the
+/// polynomial. This only needs the field arithmetic, but only finds roots that
+/// are candidates. The coefficients are as returned by
+/// `compute_polynomial_coefficients()`. Returns None if the candidates do not
+/// account for every root.
+#[cfg(not(feature = "disable_validation"))]
+pub(crate) fn find_roots_among(
+    mut coeffs: Vec<u32>,
+    candidates: &HashSet<u32>,
+) -> Option<Vec<u32>> {
+    let mut roots = vec![];
+    for &candidate in candidates {
+        // most candidates are not roots, so evaluate the polynomial before
+        // allocating the quotient
+        let value = coeffs.iter().fold(0, |value, &coeff|
+            add_and_mod(mul_and_mod(value, candidate), coeff));
+        if value != 0 {
+            continue;
         }
-        while sum < 0 {
-            sum += LARGE_PRIME;
+        // synthetic division by (x - candidate)
+        while coeffs.len() > 1 {
+            let mut quotient = Vec::with_capacity(coeffs.len() - 1);
+            let mut value = 0;
+            for &coeff in &coeffs {
+                value = add_and_mod(mul_and_mod(value, candidate), coeff);
+                quotient.push(value);
+            }
+            if quotient.pop() != Some(0) {
+                break;
+            }
+            coeffs = quotient;
+            roots.push(candidate);
         }
-        e.push(div_and_mod((sum % LARGE_PRIME) as u32, i as u32 + 1) as i64);
     }
-    for i in 0..(n+1) {
-        if i & 1 != 0 {
-            e[i] *= -1;
-            e[i] += LARGE_PRIME;
-        }
+    if coeffs.len() == 1 {
+        Some(roots)
+    } else {
+        None
     }
-    // includes the leading coefficient
-    e.into_iter().map(|x| x as u32).collect()
 }
 
 #[cfg(not(feature = "disable_validation"))]
 fn find_integer_monic_polynomial_roots(
     coeffs: Vec<u32>,
+    options: &ValidationOptions,
 ) -> Result<Vec<u32>, String> {
+    // PARI takes the time limit in whole seconds, where 0 means none.
+    let timeout_secs = options.time_remaining()
+        .map(|time| std::cmp::max(time.as_secs_f64().ceil() as i64, 1))
+        .unwrap_or(0);
     let mut roots: Vec<u32> = vec![0; coeffs.len() - 1];
-    if unsafe {
+    match unsafe {
         find_integer_monic_polynomial_roots_libpari(
             roots.as_mut_ptr(),
             coeffs.as_ptr(),
             LARGE_PRIME_U32,
             roots.len(),
+            timeout_secs,
         )
-    } == 0 {
-        Ok(roots)
-    } else {
-        Err("could not factor polynomial".to_string())
+    } {
+        0 => Ok(roots),
+        -2 => Err("ran out of time factoring polynomial".to_string()),
+        _ => Err("could not factor polynomial".to_string()),
     }
 }
 
@@ -221,25 +302,41 @@ struct MiniPowerSumAccumulator {
     count: u16,          // expect ~1024 = 2^10
     seed: u64,           // seed for multiset hash, IBLT hash
     power_sums: Vec<u8>, // DJB_HASH_SIZE bits per power sum
+    estimator: StrataEstimator,
 }
 
 impl PowerSumAccumulator {
     pub fn new(
         threshold: usize,
         seed: Option<u64>,
+    ) -> Self {
+        Self::new_buffered(threshold, 0, seed)
+    }
+
+    /// Like `new()`, but buffers `buffer_len` elements before adding their
+    /// powers to the power sums. No elements are buffered if 0.
+    pub fn new_buffered(
+        threshold: usize,
+        buffer_len: usize,
+        seed: Option<u64>,
     ) -> Self {
         let digest = if let Some(seed) = seed {
             Digest::new_with_seed(seed.to_be_bytes())
         } else {
             Digest::new()
         };
+        let estimator =
+            StrataEstimator::new(u64::from_be_bytes(digest.nonce));
         Self {
             digest,
             power_sums: (0..threshold).map(|_| 0).collect(),
+            estimator,
+            buffer: Vec::with_capacity(buffer_len),
+            buffer_len,
         }
     }
 
-    pub fn from_bytes(bytes: &Vec<u8>) -> Self {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
         assert_eq!(bloom_sd::DJB_HASH_SIZE % 8, 0);
         let bytes_per_psum = bloom_sd::DJB_HASH_SIZE / 8;
         let x: MiniPowerSumAccumulator = bincode::deserialize(bytes).unwrap();
@@ -256,58 +353,123 @@ impl PowerSumAccumulator {
                 .map(|b| [b[0], b[1], b[2], b[3]])
                 .map(|bytes| u32::from_be_bytes(bytes))
                 .collect(),
+            estimator: x.estimator,
+            buffer: vec![],
+            buffer_len: 0,
         }
     }
-}
 
-impl Accumulator for PowerSumAccumulator {
-    fn to_bytes(&self) -> Vec<u8> {
-        assert_eq!(self.digest.count, (self.digest.count as u16) as u32);
-        bincode::serialize(&MiniPowerSumAccumulator {
-            hash: self.digest.hash,
-            count: self.digest.count as u16,
-            seed: u64::from_be_bytes(self.digest.nonce),
-            power_sums: self.power_sums.iter()
-                .flat_map(|psum| psum.to_be_bytes()).collect(),
-        }).unwrap()
+    /// Adds the powers of the buffered elements to the power sums.
+    fn flush(&mut self) {
+        add_power_sums_batch(&mut self.power_sums, &self.buffer);
+        self.buffer.clear();
     }
 
-    fn reset(&mut self) {
-        self.digest = Digest::new();
-        self.power_sums = vec![0; self.power_sums.len()];
+    /// The power sums of all processed elements, including buffered ones.
+    fn flushed_power_sums(&self) -> Vec<u32> {
+        let mut power_sums = self.power_sums.clone();
+        add_power_sums_batch(&mut power_sums, &self.buffer);
+        power_sums
     }
 
-    fn process(&mut self, elem: &[u8]) {
-        self.digest.add(elem);
-        let mut value: u32 = 1;
-        let elem_u32 = bloom_sd::elem_to_u32(elem) & DJB_MASK;
-        for i in 0..self.power_sums.len() {
-            value = mul_and_mod(value, elem_u32);
-            self.power_sums[i] = add_and_mod(self.power_sums[i], value);
+    /// Validates allowing up to `options.max_duplicates` extra copies of
+    /// logged elements to have been received.
+    ///
+    /// The power sums of the difference are those of the dropped elements
+    /// less those of the duplicated elements, so the Newton identities give
+    /// the power series of prod(1 - r x) over the dropped elements divided by
+    /// prod(1 - r x) over the duplicated elements. For each number of
+    /// duplicated elements, the Pade approximant of that degree separates
+    /// the two products. Both the dropped and the duplicated elements were
+    /// logged, so the roots are only looked for among the logged elements.
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_with_duplicates<F>(
+        &self,
+        elems: &[Vec<u8>],
+        logged_power_sums: F,
+        options: &ValidationOptions,
+    ) -> ValidationResult
+    where
+        F: FnOnce(usize) -> Vec<u32>,
+    {
+        let min_duplicates = self.total().saturating_sub(elems.len());
+        if min_duplicates > options.max_duplicates {
+            warn!("more elements received than logged, by more than {}",
+                options.max_duplicates);
+            return ValidationResult::Invalid;
         }
-    }
-
-    fn process_batch(&mut self, elems: &Vec<Vec<u8>>) {
-        for elem in elems {
-            self.process(elem);
+        let threshold = self.power_sums.len();
+        if elems.len() + 2 * min_duplicates - self.total() > threshold {
+            return ValidationResult::PsumExceedsThreshold;
         }
-    }
 
-    fn total(&self) -> usize {
-        self.digest.count as usize
-    }
+        let t1 = Instant::now();
+        let power_sums = logged_power_sums(threshold);
+        let power_sums_diff =
+            calculate_difference(power_sums, &self.flushed_power_sums());
+        let series = compute_polynomial_coefficients(power_sums_diff);
+        let candidates: HashSet<u32> = elems.iter()
+            .map(|elem| bloom_sd::elem_to_u32(elem) & DJB_MASK)
+            .collect();
+        debug!("computed the series of the difference: {:?}",
+            Instant::now() - t1);
 
-    #[cfg(feature = "disable_validation")]
-    fn validate(&self, _elems: &Vec<Vec<u8>>) -> ValidationResult {
-        panic!("validation not enabled")
+        for n_duplicated in min_duplicates..=options.max_duplicates {
+            let n_dropped = elems.len() + n_duplicated - self.total();
+            if n_dropped + n_duplicated > threshold {
+                return ValidationResult::PsumExceedsThreshold;
+            }
+            if options.is_exceeded() {
+                return ValidationResult::BudgetExceeded;
+            }
+            let Some((dropped, duplicated)) =
+                    crate::poly::pade(&series, n_dropped, n_duplicated) else {
+                continue;
+            };
+            let (Some(dropped), Some(duplicated)) = (
+                find_roots_among(dropped, &candidates),
+                find_roots_among(duplicated, &candidates),
+            ) else {
+                continue;
+            };
+            debug!("found {} dropped and {} duplicated elements: {:?}",
+                n_dropped, n_duplicated, Instant::now() - t1);
+            let duplicated = crate::duplicates::count(duplicated);
+            let checked = crate::duplicates::check_digest_with_duplicates(
+                &self.digest,
+                elems,
+                &crate::duplicates::count(dropped),
+                &duplicated,
+                |elem| bloom_sd::elem_to_u32(elem) & DJB_MASK,
+                options,
+            );
+            return crate::duplicates::duplicates_result(checked, &duplicated);
+        }
+        warn!("no dropped elements with at most {} duplicates",
+            options.max_duplicates);
+        ValidationResult::Invalid
     }
 
+    /// Validates against the logged elements, given a function that returns
+    /// the given number of power sums of the logged elements.
     #[cfg(not(feature = "disable_validation"))]
-    fn validate(&self, elems: &Vec<Vec<u8>>) -> ValidationResult {
+    fn validate_logged<F>(
+        &self,
+        elems: &[Vec<u8>],
+        logged_power_sums: F,
+        options: &ValidationOptions,
+    ) -> ValidationResult
+    where
+        F: FnOnce(usize) -> Vec<u32>,
+    {
         if self.total() == 0 {
             warn!("no elements received, valid by default");
             return ValidationResult::Valid;
         }
+        if options.max_duplicates > 0 {
+            return self.validate_with_duplicates(
+                elems, logged_power_sums, options);
+        }
         // The number of power sum equations we need is equal to
         // the number of lost elements. Validation cannot be performed
         // if this number exceeds the threshold.
@@ -337,17 +499,11 @@ impl Accumulator for PowerSumAccumulator {
         // Calculate the power sums of the given list of elements.
         // Find the difference with the power sums of the processed elements.
         let t1 = Instant::now();
-        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
-        let elems_u32: Vec<u32> = elems.iter()
-            .map(|elem| bloom_sd::elem_to_u32(elem) & DJB_MASK)
-            .collect();
-        let power_sums = rt.block_on(async {
-            calculate_power_sums(&elems_u32, n_values).await
-        }).into_iter().collect();
+        let power_sums = logged_power_sums(n_values);
         let t2 = Instant::now();
         debug!("calculated power sums: {:?}", t2 - t1);
-        let power_sums_diff = calculate_difference(power_sums, &self.power_sums
-            .iter().map(|x| *x as u32).collect());
+        let power_sums_diff =
+            calculate_difference(power_sums, &self.flushed_power_sums());
         let t3 = Instant::now();
         debug!("calculated power sum difference: {:?}", t3 - t2);
 
@@ -356,13 +512,20 @@ impl Accumulator for PowerSumAccumulator {
             power_sums_diff[..n_values].to_vec());
         let t4 = Instant::now();
         debug!("computed polynomial coefficients: {:?}", t4 - t3);
+        if options.is_exceeded() {
+            return ValidationResult::BudgetExceeded;
+        }
         let roots = {
-            let roots = find_integer_monic_polynomial_roots(coeffs);
+            let roots = find_integer_monic_polynomial_roots(coeffs, options);
             let t5 = Instant::now();
             debug!("found integer monic polynomial roots: {:?}", t5 - t4);
             match roots {
                 Ok(roots) => roots,
-                Err(_) => {
+                Err(e) => {
+                    warn!("{}", e);
+                    if options.is_exceeded() {
+                        return ValidationResult::BudgetExceeded;
+                    }
                     return ValidationResult::PsumErrorFindingRoots;
                 },
             }
@@ -450,27 +613,155 @@ impl Accumulator for PowerSumAccumulator {
         debug!("prepared combos for resolving djb collisions: {:?}", t7 - t6);
 
         debug!("accounted for {} dropped elements", dropped);
-        let mut n_digests = 0;
-        for curr in combinations.into_iter().multi_cartesian_product() {
-            // check curr, if good return it else return None
-            let mut digest = digest.clone();
-            for elem in curr.into_iter().flat_map(|val| val) {
-                digest.add(&elem);
-            }
-            n_digests += 1;
-            if digest.equals(&self.digest) {
-                return ValidationResult::PsumCollisionsValid;
-            }
+        if combinations.is_empty() {
+            return if digest.equals(&self.digest) {
+                ValidationResult::Valid
+            } else {
+                ValidationResult::Invalid
+            };
         }
+        let matched = find_matching_combination(
+            &digest,
+            &self.digest,
+            combinations.into_iter().multi_cartesian_product(),
+            options,
+        );
         let t8 = Instant::now();
-        debug!("recalculated {} digests: {:?}", n_digests, t8 - t7);
-        if digest.equals(&self.digest) {
-            ValidationResult::Valid
-        } else if n_digests == 0 {
-            ValidationResult::Invalid
+        debug!("recalculated digests: {:?}", t8 - t7);
+        match matched {
+            Some(true) => ValidationResult::PsumCollisionsValid,
+            Some(false) => ValidationResult::PsumCollisionsInvalid,
+            None => ValidationResult::BudgetExceeded,
+        }
+    }
+}
+
+impl PartialEq for PowerSumAccumulator {
+    fn eq(&self, other: &Self) -> bool {
+        self.digest == other.digest
+            && self.flushed_power_sums() == other.flushed_power_sums()
+            && self.estimator == other.estimator
+    }
+}
+
+impl Eq for PowerSumAccumulator {}
+
+impl Accumulator for PowerSumAccumulator {
+    fn to_bytes(&self) -> Vec<u8> {
+        assert_eq!(self.digest.count, (self.digest.count as u16) as u32);
+        bincode::serialize(&MiniPowerSumAccumulator {
+            hash: self.digest.hash,
+            count: self.digest.count as u16,
+            seed: u64::from_be_bytes(self.digest.nonce),
+            power_sums: self.flushed_power_sums().iter()
+                .flat_map(|psum| psum.to_be_bytes()).collect(),
+            estimator: self.estimator.clone(),
+        }).unwrap()
+    }
+
+    fn reset(&mut self) {
+        self.digest = Digest::new();
+        self.power_sums = vec![0; self.power_sums.len()];
+        self.buffer.clear();
+        self.estimator =
+            StrataEstimator::new(u64::from_be_bytes(self.digest.nonce));
+    }
+
+    fn process(&mut self, elem: &[u8]) {
+        self.digest.add(elem);
+        self.estimator.insert(elem);
+        let elem_u32 = bloom_sd::elem_to_u32(elem) & DJB_MASK;
+        if self.buffer_len == 0 {
+            add_power_sums(&mut self.power_sums, elem_u32);
         } else {
-            ValidationResult::PsumCollisionsInvalid
+            self.buffer.push(elem_u32);
+            if self.buffer.len() >= self.buffer_len {
+                self.flush();
+            }
+        }
+    }
+
+    fn process_batch(&mut self, elems: &Vec<Vec<u8>>) {
+        for elem in elems {
+            self.digest.add(elem);
+            self.estimator.insert(elem);
+            self.buffer.push(bloom_sd::elem_to_u32(elem) & DJB_MASK);
         }
+        self.flush();
+    }
+
+    fn total(&self) -> usize {
+        self.digest.count as usize
+    }
+
+    fn estimator(&self) -> &StrataEstimator {
+        &self.estimator
+    }
+
+    #[cfg(feature = "disable_validation")]
+    fn validate_with_options(
+        &self,
+        _elems: &Vec<Vec<u8>>,
+        _options: &ValidationOptions,
+    ) -> ValidationResult {
+        panic!("validation not enabled")
+    }
+
+    #[cfg(not(feature = "disable_validation"))]
+    fn validate_with_options(
+        &self,
+        elems: &Vec<Vec<u8>>,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        self.validate_logged(elems, |num_psums| {
+            let elems_u32: Vec<u32> = elems.iter()
+                .map(|elem| bloom_sd::elem_to_u32(elem) & DJB_MASK)
+                .collect();
+            calculate_power_sums(&elems_u32, num_psums, &options.executor)
+        }, options)
+    }
+
+    /// Precomputes the power sums of the parts of the log around the window
+    /// ends, so the power sums of each window combine three of them.
+    #[cfg(not(feature = "disable_validation"))]
+    fn window_validator<'a>(
+        &'a self,
+        log: &'a [Vec<u8>],
+        starts: RangeInclusive<usize>,
+        ends: RangeInclusive<usize>,
+    ) -> Box<dyn WindowValidator + 'a> {
+        let sums = WindowSums::new(
+            log, starts, ends, vec![0; self.power_sums.len()],
+            |power_sums, elem| add_power_sums(
+                power_sums, bloom_sd::elem_to_u32(elem) & DJB_MASK),
+            |lhs, rhs| lhs.iter().zip(rhs)
+                .map(|(&lhs, &rhs)| add_and_mod(lhs, rhs))
+                .collect());
+        Box::new(PowerSumWindows { accumulator: self, log, sums })
+    }
+}
+
+/// Validates windows of a log with the precomputed power sums.
+#[cfg(not(feature = "disable_validation"))]
+struct PowerSumWindows<'a> {
+    accumulator: &'a PowerSumAccumulator,
+    log: &'a [Vec<u8>],
+    sums: WindowSums<Vec<u32>>,
+}
+
+#[cfg(not(feature = "disable_validation"))]
+impl WindowValidator for PowerSumWindows<'_> {
+    fn validate_window(
+        &self,
+        start: usize,
+        end: usize,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        self.accumulator.validate_logged(&self.log[start..end], |num_psums| {
+            let mut power_sums = self.sums.window(start, end);
+            power_sums.truncate(num_psums);
+            power_sums
+        }, options)
     }
 }
 
@@ -480,13 +771,7 @@ mod test {
     use bincode;
     use rand;
     use rand::Rng;
-
-    const NBYTES: usize = 16;
-
-    fn gen_elems(n: usize) -> Vec<Vec<u8>> {
-        let mut rng = rand::thread_rng();
-        (0..n).map(|_| (0..NBYTES).map(|_| rng.gen::<u8>()).collect()).collect()
-    }
+    use crate::test_util::gen_elems;
 
     #[test]
     fn test_not_equals() {
@@ -532,6 +817,49 @@ mod test {
         assert_eq!(acc1, acc3);
     }
 
+    #[test]
+    fn test_reduce() {
+        let mut rng = rand::thread_rng();
+        let edge_cases = vec![0, 1, LARGE_PRIME_U64 - 1, LARGE_PRIME_U64,
+            LARGE_PRIME_U64 * LARGE_PRIME_U64, u64::MAX - 1, u64::MAX];
+        let random = (0..10000).map(|_| rng.gen::<u64>()).collect::<Vec<_>>();
+        for x in edge_cases.into_iter().chain(random) {
+            assert_eq!(reduce(x) as u64, x % LARGE_PRIME_U64, "{}", x);
+        }
+    }
+
+    #[test]
+    fn test_add_power_sums_batch() {
+        let mut rng = rand::thread_rng();
+        let elems = (0..(BATCH_CHUNK_SIZE * 2 + 10))
+            .map(|_| rng.gen::<u32>() & DJB_MASK)
+            .collect::<Vec<_>>();
+        let mut expected = vec![0; 20];
+        for &elem in &elems {
+            add_power_sums(&mut expected, elem);
+        }
+        let mut power_sums = vec![0; 20];
+        add_power_sums_batch(&mut power_sums, &elems);
+        assert_eq!(power_sums, expected);
+    }
+
+    #[test]
+    fn test_buffered_process() {
+        let elems = gen_elems(100);
+        let mut acc1 = PowerSumAccumulator::new(20, Some(1234));
+        let mut acc2 = PowerSumAccumulator::new_buffered(20, 7, Some(1234));
+        let mut acc3 = PowerSumAccumulator::new(20, Some(1234));
+        for elem in &elems {
+            acc1.process(elem);
+            acc2.process(elem);
+        }
+        acc3.process_batch(&elems);
+        assert!(!acc2.buffer.is_empty());
+        assert_eq!(acc1, acc2);
+        assert_eq!(acc1, acc3);
+        assert_eq!(acc1.to_bytes(), acc2.to_bytes());
+    }
+
     #[test]
     fn test_mul_and_mod() {
         // 4294967029
@@ -539,20 +867,66 @@ mod test {
         assert_eq!(mul_and_mod(429496702, 12), 858993395, "overflow");
     }
 
+    /// The quadratic solution of the Newton identities, for reference.
+    fn compute_polynomial_coefficients_quadratic(p: Vec<u32>) -> Vec<u32> {
+        let n = p.len();
+        if n == 0 {
+            return vec![];
+        }
+        let mut e: Vec<i64> = vec![1];
+        for i in 0..n {
+            let mut sum: i64 = 0;
+            for j in 0..(i+1) {
+                if j & 1 == 0 {
+                    sum += mul_and_mod(e[i-j] as u32, p[j]) as i64;
+                } else {
+                    sum -= mul_and_mod(e[i-j] as u32, p[j]) as i64;
+                }
+            }
+            while sum < 0 {
+                sum += LARGE_PRIME;
+            }
+            e.push(div_and_mod((sum % LARGE_PRIME) as u32, i as u32 + 1) as i64);
+        }
+        for i in 0..(n+1) {
+            if i & 1 != 0 {
+                e[i] *= -1;
+                e[i] += LARGE_PRIME;
+            }
+        }
+        // includes the leading coefficient
+        e.into_iter().map(|x| x as u32).collect()
+    }
+
+    #[test]
+    fn test_compute_polynomial_coefficients_matches_quadratic() {
+        let mut rng = rand::thread_rng();
+        for &n in &[1, 10, 100, 1000] {
+            let p: Vec<u32> = (0..n)
+                .map(|_| rng.gen_range(0..LARGE_PRIME_U32))
+                .collect();
+            assert_eq!(compute_polynomial_coefficients(p.clone()),
+                compute_polynomial_coefficients_quadratic(p));
+        }
+    }
+
     #[test]
     fn test_div_and_mod() {
         assert_eq!(div_and_mod(1717986808, 429496702), 4);
         assert_eq!(div_and_mod(858993395, 429496702), 12);
     }
 
-    #[tokio::test]
-    async fn test_calculate_power_sums() {
-        assert_eq!(calculate_power_sums(&vec![2, 3, 5], 2).await, vec![10, 38]);
-        assert_eq!(calculate_power_sums(&vec![2, 3, 5], 3).await, vec![10, 38, 160]);
-        let one_large_num = calculate_power_sums(&vec![294967295], 3).await;
-        assert_eq!(one_large_num, vec![294967295, 2507781770, 2201765005]);
-        let two_large_nums = calculate_power_sums(&vec![294967295, 2294967295], 3).await;
-        assert_eq!(two_large_nums, vec![2589934590, 1563208361, 4070406309]);
+    #[test]
+    fn test_calculate_power_sums() {
+        for executor in [Executor::CurrentThread, Executor::new(4)] {
+            assert_eq!(calculate_power_sums(&[2, 3, 5], 2, &executor), vec![10, 38]);
+            assert_eq!(calculate_power_sums(&[2, 3, 5], 3, &executor), vec![10, 38, 160]);
+            let one_large_num = calculate_power_sums(&[294967295], 3, &executor);
+            assert_eq!(one_large_num, vec![294967295, 2507781770, 2201765005]);
+            let two_large_nums = calculate_power_sums(&[294967295, 2294967295], 3, &executor);
+            assert_eq!(two_large_nums, vec![2589934590, 1563208361, 4070406309]);
+            assert_eq!(calculate_power_sums(&[], 2, &executor), vec![0, 0]);
+        }
     }
 
     #[test]
@@ -565,19 +939,19 @@ mod test {
         assert_eq!(overflow_diff, vec![4294967028]);
     }
 
-    #[tokio::test]
-    async fn test_compute_polynomial_coefficients_small_numbers() {
+    #[test]
+    fn test_compute_polynomial_coefficients_small_numbers() {
         let x = vec![2, 3, 5];
-        let power_sums_diff = calculate_power_sums(&x, 3).await;
+        let power_sums_diff = calculate_power_sums(&x, 3, &Executor::CurrentThread);
         assert_eq!(power_sums_diff, vec![10, 38, 160]);
         let coeffs = compute_polynomial_coefficients(power_sums_diff);
         assert_eq!(coeffs, vec![1, LARGE_PRIME_U32-10, 31, LARGE_PRIME_U32-30]);
     }
 
-    #[tokio::test]
-    async fn test_compute_polynomial_coefficients_large_numbers() {
+    #[test]
+    fn test_compute_polynomial_coefficients_large_numbers() {
         let x = vec![4294966796, 3987231002];
-        let power_sums_diff = calculate_power_sums(&x, 2).await;
+        let power_sums_diff = calculate_power_sums(&x, 2, &Executor::CurrentThread);
         assert_eq!(power_sums_diff, vec![3987230769, 3419665331]);
         let coeffs = compute_polynomial_coefficients(power_sums_diff);
         let e1 = add_and_mod(x[0], x[1]);
@@ -585,13 +959,14 @@ mod test {
         assert_eq!(coeffs, vec![1, LARGE_PRIME_U32-e1, e2]);
     }
 
-    #[tokio::test]
-    async fn test_find_integer_monic_polynomial_roots_small_numbers() {
+    #[test]
+    fn test_find_integer_monic_polynomial_roots_small_numbers() {
         let x = vec![2, 3, 5];
-        let power_sums_diff = calculate_power_sums(&x, x.len()).await;
+        let power_sums_diff = calculate_power_sums(&x, x.len(), &Executor::CurrentThread);
         let coeffs = compute_polynomial_coefficients(power_sums_diff);
         let mut roots = {
-            let roots = find_integer_monic_polynomial_roots(coeffs);
+            let roots = find_integer_monic_polynomial_roots(
+                coeffs, &ValidationOptions::default());
             assert!(roots.is_ok());
             roots.unwrap()
         };
@@ -599,13 +974,14 @@ mod test {
         assert_eq!(roots, x.into_iter().map(|x| x).collect::<Vec<_>>());
     }
 
-    #[tokio::test]
-    async fn test_find_integer_monic_polynomial_roots_large_numbers() {
+    #[test]
+    fn test_find_integer_monic_polynomial_roots_large_numbers() {
         let x = vec![3987231002, 4294966796];
-        let power_sums_diff = calculate_power_sums(&x, x.len()).await;
+        let power_sums_diff = calculate_power_sums(&x, x.len(), &Executor::CurrentThread);
         let coeffs = compute_polynomial_coefficients(power_sums_diff);
         let mut roots = {
-            let roots = find_integer_monic_polynomial_roots(coeffs);
+            let roots = find_integer_monic_polynomial_roots(
+                coeffs, &ValidationOptions::default());
             assert!(roots.is_ok());
             roots.unwrap()
         };
@@ -613,13 +989,14 @@ mod test {
         assert_eq!(roots, x.into_iter().map(|x| x).collect::<Vec<_>>());
     }
 
-    #[tokio::test]
-    async fn test_find_integer_monic_polynomial_roots_multiplicity() {
+    #[test]
+    fn test_find_integer_monic_polynomial_roots_multiplicity() {
         let x = vec![3987231002, 4294966796, 4294966796, 4294966796];
-        let power_sums_diff = calculate_power_sums(&x, x.len()).await;
+        let power_sums_diff = calculate_power_sums(&x, x.len(), &Executor::CurrentThread);
         let coeffs = compute_polynomial_coefficients(power_sums_diff);
         let mut roots = {
-            let roots = find_integer_monic_polynomial_roots(coeffs);
+            let roots = find_integer_monic_polynomial_roots(
+                coeffs, &ValidationOptions::default());
             assert!(roots.is_ok());
             roots.unwrap()
         };
@@ -627,10 +1004,25 @@ mod test {
         assert_eq!(roots, x.into_iter().map(|x| x).collect::<Vec<_>>());
     }
 
+    #[test]
+    fn test_find_roots_among() {
+        let x = vec![3987231002, 4294966796, 4294966796, 7];
+        let power_sums_diff = calculate_power_sums(&x, x.len(), &Executor::CurrentThread);
+        let coeffs = compute_polynomial_coefficients(power_sums_diff);
+        let candidates = vec![7, 8, 3987231002, 4294966796]
+            .into_iter().collect::<HashSet<_>>();
+        let mut roots = find_roots_among(coeffs.clone(), &candidates).unwrap();
+        roots.sort();
+        assert_eq!(roots, vec![7, 3987231002, 4294966796, 4294966796]);
+        let candidates = vec![7, 3987231002].into_iter().collect();
+        assert_eq!(find_roots_among(coeffs, &candidates), None);
+    }
+
     #[test]
     fn test_find_integer_monic_polynomial_roots_no_solution() {
         let coeffs = vec![1, 479202874, 1224376254, 3930719704];
-        let roots = find_integer_monic_polynomial_roots(coeffs);
+        let roots = find_integer_monic_polynomial_roots(
+            coeffs, &ValidationOptions::default());
         assert!(roots.is_err());
     }
 }
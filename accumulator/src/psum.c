@@ -2,38 +2,53 @@
 #include <stdint.h>
 #include <pari/pari.h>
 
+/**
+ * Returns 0 on success, -1 if the polynomial cannot be factored into linear
+ * factors, or -2 if factoring takes more than `timeout_secs` seconds. There
+ * is no time limit if `timeout_secs` is 0.
+ */
 int32_t find_integer_monic_polynomial_roots_libpari(
-    uint32_t *roots, const uint32_t *coeffs, long field, size_t degree
+    uint32_t *roots, const uint32_t *coeffs, long field, size_t degree,
+    long timeout_secs
 ) {
     size_t i;
     uint32_t j, m;
     GEN vec, p, res, f;
+    int32_t ret = 0;
     pari_init(1000000, 0);
     paristack_setsize(1000000, 100000000);
 
-    // Initialize mod polynomial and factor
-    vec = const_vecsmall(degree + 1, 0);
-    for (i = 0; i < degree+1; i++) {
-        vec[i+1] = coeffs[i];
-    }
-    p = gtopoly(vec, 0);
-    res = factormod0(p, utoi(field), 0);
+    pari_CATCH(e_ALARM) {
+        ret = -2;
+    } pari_TRY {
+        pari_alarm(timeout_secs);
 
-    // Copy results to roots vector
-    int n = 0;
-    for (i = 0; i < nbrows(res); i++) {
-        f = gcoeff(res, i+1, 1);
-        m = itou(gcoeff(res, i+1, 2));
-        if (degpol(f) != 1) {
-            // error: cannot be factored
-            return -1;
+        // Initialize mod polynomial and factor
+        vec = const_vecsmall(degree + 1, 0);
+        for (i = 0; i < degree+1; i++) {
+            vec[i+1] = coeffs[i];
         }
-        // TODO: Masot added cast to shut gcc up
-        for (j = 0; j < m; j++) {
-            roots[n++] = field - itou((void*)constant_coeff(f)[2]);
+        p = gtopoly(vec, 0);
+        res = factormod0(p, utoi(field), 0);
+        pari_alarm(0);
+
+        // Copy results to roots vector
+        int n = 0;
+        for (i = 0; i < nbrows(res) && ret == 0; i++) {
+            f = gcoeff(res, i+1, 1);
+            m = itou(gcoeff(res, i+1, 2));
+            if (degpol(f) != 1) {
+                // error: cannot be factored
+                ret = -1;
+                break;
+            }
+            // TODO: Masot added cast to shut gcc up
+            for (j = 0; j < m; j++) {
+                roots[n++] = field - itou((void*)constant_coeff(f)[2]);
+            }
         }
-    }
+    } pari_ENDCATCH
 
     pari_close();
-    return 0;
+    return ret;
 }
@@ -0,0 +1,179 @@
+use serde::{Serialize, Deserialize};
+use bloom_sd::StrataEstimator;
+use digest::sampling::Sampler;
+use crate::{Accumulator, ValidationOptions, ValidationResult};
+
+/// Processes only the elements kept by a sampler, and validates against only
+/// the logged elements kept by the same sampler. The sampler is serialized
+/// with the accumulator so the verifier samples the logs the same way.
+pub struct SampledAccumulator<A: Accumulator + ?Sized = dyn Accumulator + Send> {
+    sampler: Sampler,
+    accumulator: Box<A>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MiniSampledAccumulator {
+    sampler: Sampler,
+    accumulator: Vec<u8>,  // serialized inner accumulator
+}
+
+impl<A: Accumulator + ?Sized> SampledAccumulator<A> {
+    pub fn new(sampler: Sampler, accumulator: Box<A>) -> Self {
+        Self { sampler, accumulator }
+    }
+
+    /// Deserializes the inner accumulator with `from_bytes`, which must know
+    /// the type of the inner accumulator.
+    pub fn from_bytes<F>(bytes: &[u8], from_bytes: F) -> Self
+    where
+        F: FnOnce(&[u8]) -> Box<A>,
+    {
+        let x: MiniSampledAccumulator = bincode::deserialize(bytes).unwrap();
+        Self {
+            sampler: x.sampler,
+            accumulator: from_bytes(&x.accumulator),
+        }
+    }
+
+    pub fn sampler(&self) -> Sampler {
+        self.sampler
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.accumulator
+    }
+
+    /// Processes the element if the sampler keeps it. Returns whether it was
+    /// kept, so callers that keep more state per element only sample once.
+    pub fn process_if_kept(&mut self, elem: &[u8]) -> bool {
+        let keep = self.sampler.keep(elem);
+        if keep {
+            self.accumulator.process(elem);
+        }
+        keep
+    }
+
+    /// The elements kept by the sampler.
+    pub fn sample(&self, elems: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        elems.iter()
+            .filter(|elem| self.sampler.keep(elem))
+            .cloned()
+            .collect()
+    }
+}
+
+impl<A: Accumulator + ?Sized> Accumulator for SampledAccumulator<A> {
+    fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&MiniSampledAccumulator {
+            sampler: self.sampler,
+            accumulator: self.accumulator.to_bytes(),
+        }).unwrap()
+    }
+
+    fn reset(&mut self) {
+        self.accumulator.reset();
+    }
+
+    fn process(&mut self, elem: &[u8]) {
+        self.process_if_kept(elem);
+    }
+
+    fn process_batch(&mut self, elems: &Vec<Vec<u8>>) {
+        if self.sampler.is_sampling() {
+            self.accumulator.process_batch(&self.sample(elems));
+        } else {
+            self.accumulator.process_batch(elems);
+        }
+    }
+
+    /// The number of sampled elements.
+    fn total(&self) -> usize {
+        self.accumulator.total()
+    }
+
+    fn estimator(&self) -> &StrataEstimator {
+        self.accumulator.estimator()
+    }
+
+    fn estimate_difference(&self, elems: &[Vec<u8>]) -> usize {
+        if self.sampler.is_sampling() {
+            self.accumulator.estimate_difference(&self.sample(elems))
+        } else {
+            self.accumulator.estimate_difference(elems)
+        }
+    }
+
+    fn validate_with_options(
+        &self,
+        elems: &Vec<Vec<u8>>,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        if self.sampler.is_sampling() {
+            self.accumulator.validate_with_options(&self.sample(elems), options)
+        } else {
+            self.accumulator.validate_with_options(elems, options)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IBLTAccumulator;
+    use crate::iblt::{DEFAULT_BITS_PER_ENTRY, DEFAULT_NUM_HASHES};
+    use crate::test_util::gen_elems_with_seed;
+
+    fn new_accumulator(rate: f64) -> SampledAccumulator<IBLTAccumulator> {
+        SampledAccumulator::new(
+            Sampler::new(111, rate),
+            Box::new(IBLTAccumulator::new(100, Some(222))),
+        )
+    }
+
+    #[test]
+    fn test_serialization() {
+        let elems = gen_elems_with_seed(1000, 333);
+        let mut acc = new_accumulator(0.1);
+        acc.process_batch(&elems);
+        let bytes = acc.to_bytes();
+        let acc2 = SampledAccumulator::from_bytes(&bytes, |bytes| {
+            Box::new(IBLTAccumulator::from_bytes(
+                bytes, DEFAULT_BITS_PER_ENTRY, DEFAULT_NUM_HASHES))
+        });
+        assert_eq!(acc2.to_bytes(), bytes);
+        assert_eq!(acc2.sampler(), Sampler::new(111, 0.1));
+        assert_eq!(acc2.total(), acc.sample(&elems).len());
+        assert!(acc2.total() > 50 && acc2.total() < 150);
+    }
+
+    #[test]
+    fn test_process_if_kept() {
+        let elems = gen_elems_with_seed(1000, 333);
+        let mut acc = new_accumulator(0.1);
+        let kept = elems.iter()
+            .filter(|elem| acc.process_if_kept(elem))
+            .count();
+        assert_eq!(kept, acc.sample(&elems).len());
+        assert_eq!(acc.total(), kept);
+    }
+
+    #[test]
+    fn test_validate_sampled_subset() {
+        let elems = gen_elems_with_seed(1000, 333);
+        let mut acc = new_accumulator(0.1);
+        for elem in &elems[10..] {
+            acc.process(elem);
+        }
+        assert!(acc.validate(&elems).is_valid());
+        assert!(acc.estimate_difference(&elems) <= 32);
+
+        // Only injected elements that are sampled can be detected.
+        let injected = gen_elems_with_seed(100, 444);
+        let (sampled, unsampled): (Vec<_>, Vec<_>) = injected.into_iter()
+            .partition(|elem| acc.sampler().keep(elem));
+        acc.process(&unsampled[0]);
+        assert!(acc.validate(&elems).is_valid());
+        acc.process(&sampled[0]);
+        assert!(!acc.validate(&elems).is_valid());
+    }
+}
@@ -0,0 +1,166 @@
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+/// The capture timestamps of the first and last elements processed in an
+/// epoch, kept alongside the main accumulator. The verifier selects the
+/// router logs of the epoch by timestamp, so it does not need to know where
+/// the epoch starts in the logs, e.g. after missing a snapshot.
+///
+/// The router and the accumulator timestamp packets with their own clocks,
+/// so the logs are selected with some tolerance for clock skew and for the
+/// time packets spend in flight.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureSpan {
+    first: Option<Duration>,
+    last: Option<Duration>,
+}
+
+impl CaptureSpan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The earliest timestamp since the UNIX epoch.
+    pub fn first(&self) -> Option<Duration> {
+        self.first
+    }
+
+    /// The latest timestamp since the UNIX epoch.
+    pub fn last(&self) -> Option<Duration> {
+        self.last
+    }
+
+    /// Processes an element received at the timestamp since the UNIX epoch.
+    pub fn process(&mut self, timestamp: Duration) {
+        self.first = Some(self.first.map_or(timestamp, |t| t.min(timestamp)));
+        self.last = Some(self.last.map_or(timestamp, |t| t.max(timestamp)));
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Indexes in the log where the epoch may start, given when each entry
+    /// was logged, or None if no elements were processed or the log ends
+    /// before the first one (see `around()`).
+    pub fn starts(
+        &self,
+        timestamps: &[Option<Duration>],
+        skew: Duration,
+    ) -> Option<RangeInclusive<usize>> {
+        around(timestamps, self.first?, skew)
+    }
+
+    /// Indexes in the log where the epoch may end, exclusive, given when each
+    /// entry was logged, or None if no elements were processed or the log
+    /// ends before the last one (see `around()`).
+    pub fn ends(
+        &self,
+        timestamps: &[Option<Duration>],
+        skew: Duration,
+    ) -> Option<RangeInclusive<usize>> {
+        around(timestamps, self.last?, skew)
+    }
+}
+
+/// The indexes from the first entry logged at or after `timestamp - skew`
+/// through the first entry logged after `timestamp + skew`. Entries without
+/// a timestamp are skipped over.
+///
+/// Returns None if no entry was logged at or after `timestamp - skew`, e.g.
+/// if the log has not caught up to the accumulator yet.
+fn around(
+    timestamps: &[Option<Duration>],
+    timestamp: Duration,
+    skew: Duration,
+) -> Option<RangeInclusive<usize>> {
+    let position = |after: &dyn Fn(Duration) -> bool| timestamps.iter()
+        .position(|t| t.is_some_and(after))
+        .unwrap_or(timestamps.len());
+    let lo = timestamp.saturating_sub(skew);
+    let start = position(&|t| t >= lo);
+    if start == timestamps.len() {
+        return None;
+    }
+    let end = match timestamp.checked_add(skew) {
+        Some(hi) => position(&|t| t > hi),
+        None => timestamps.len(),
+    };
+    Some(start..=end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An entry logged every millisecond starting at 10 seconds, except for
+    /// an entry without a timestamp.
+    fn gen_timestamps(n: usize) -> Vec<Option<Duration>> {
+        (0..n).map(|i| if i == 505 {
+            None
+        } else {
+            Some(Duration::from_secs(10) + Duration::from_millis(i as u64))
+        }).collect()
+    }
+
+    #[test]
+    fn test_process_and_reset() {
+        let mut span = CaptureSpan::new();
+        assert_eq!((span.first(), span.last()), (None, None));
+        assert_eq!(span.starts(&gen_timestamps(10), Duration::ZERO), None);
+        span.process(Duration::from_millis(20));
+        span.process(Duration::from_millis(10));
+        span.process(Duration::from_millis(30));
+        assert_eq!(span.first(), Some(Duration::from_millis(10)));
+        assert_eq!(span.last(), Some(Duration::from_millis(30)));
+        let bytes = bincode::serialize(&span).unwrap();
+        assert_eq!(bincode::deserialize::<CaptureSpan>(&bytes).unwrap(), span);
+        span.reset();
+        assert_eq!(span, CaptureSpan::new());
+    }
+
+    #[test]
+    fn test_select_by_timestamp() {
+        let timestamps = gen_timestamps(1000);
+        let mut span = CaptureSpan::new();
+        span.process(Duration::from_millis(10_200));
+        span.process(Duration::from_millis(10_700));
+        let skew = Duration::from_millis(5);
+        assert_eq!(span.starts(&timestamps, skew), Some(195..=206));
+        assert_eq!(span.ends(&timestamps, skew), Some(695..=706));
+        assert_eq!(span.starts(&timestamps, Duration::ZERO), Some(200..=201));
+
+        // The entry without a timestamp is skipped over.
+        let mut span = CaptureSpan::new();
+        span.process(Duration::from_millis(10_505));
+        assert_eq!(span.starts(&timestamps, Duration::ZERO), Some(506..=506));
+
+        // The skew reaches past either end of the logs.
+        let mut span = CaptureSpan::new();
+        span.process(Duration::from_millis(9_990));
+        span.process(Duration::from_millis(10_995));
+        assert_eq!(span.starts(&timestamps, skew), Some(0..=0));
+        assert_eq!(span.ends(&timestamps, skew), Some(990..=1000));
+        assert_eq!(span.starts(&timestamps, Duration::MAX), Some(0..=1000));
+    }
+
+    #[test]
+    fn test_select_past_end_of_log() {
+        let timestamps = gen_timestamps(1000);
+        let skew = Duration::from_millis(5);
+        let mut span = CaptureSpan::new();
+        span.process(Duration::from_millis(10_900));
+        span.process(Duration::from_millis(11_100));
+        assert_eq!(span.starts(&timestamps, skew), Some(895..=906));
+        assert_eq!(span.ends(&timestamps, skew), None);
+        assert_eq!(span.ends(&timestamps, Duration::from_millis(200)),
+            Some(900..=1000));
+
+        let mut span = CaptureSpan::new();
+        span.process(Duration::from_millis(11_010));
+        assert_eq!(span.starts(&timestamps, skew), None);
+        assert_eq!(span.starts(&[], skew), None);
+    }
+}
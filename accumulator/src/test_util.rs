@@ -0,0 +1,32 @@
+//! Elements for the tests of the accumulators.
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// Number of bytes in each random element.
+const NBYTES: usize = 16;
+
+/// `n` random elements that differ on every run.
+pub(crate) fn gen_elems(n: usize) -> Vec<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    (0..n).map(|_| (0..NBYTES).map(|_| rng.gen::<u8>()).collect()).collect()
+}
+
+/// `n` random elements, the same for the same seed.
+pub(crate) fn gen_elems_with_seed(n: usize, seed: u64) -> Vec<Vec<u8>> {
+    gen_elems_with_len(n, NBYTES, seed)
+}
+
+/// `n` random elements of `len` bytes, the same for the same seed.
+pub(crate) fn gen_elems_with_len(
+    n: usize,
+    len: usize,
+    seed: u64,
+) -> Vec<Vec<u8>> {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    (0..n).map(|_| (0..len).map(|_| rng.gen::<u8>()).collect()).collect()
+}
+
+/// The big-endian bytes of the integers `0..n`.
+pub(crate) fn gen_sequential_elems(n: usize) -> Vec<Vec<u8>> {
+    (0..n as u32).map(|i| i.to_be_bytes().to_vec()).collect()
+}
@@ -0,0 +1,302 @@
+#[cfg(not(feature = "disable_validation"))]
+use std::ops::RangeInclusive;
+
+use crate::{Accumulator, ValidationOptions, ValidationResult};
+
+/// Validates an accumulator against windows `log[start..end]` of the same
+/// log. See `Accumulator::window_validator()`.
+pub trait WindowValidator {
+    fn validate_window(
+        &self,
+        start: usize,
+        end: usize,
+        options: &ValidationOptions,
+    ) -> ValidationResult;
+}
+
+/// Validates each window from scratch.
+pub(crate) struct FromScratch<'a, A: Accumulator + ?Sized> {
+    pub(crate) accumulator: &'a A,
+    pub(crate) log: &'a [Vec<u8>],
+}
+
+impl<A: Accumulator + ?Sized> WindowValidator for FromScratch<'_, A> {
+    fn validate_window(
+        &self,
+        start: usize,
+        end: usize,
+        options: &ValidationOptions,
+    ) -> ValidationResult {
+        self.accumulator.validate_with_options(
+            &self.log[start..end].to_vec(), options)
+    }
+}
+
+/// Summaries of the windows `log[start..end]` for every start in `starts` and
+/// end in `ends`, where every start is at most every end. A summary is e.g.
+/// the power sums of the elements, and can be combined with another summary
+/// of disjoint elements.
+///
+/// Keeps the summaries of the suffixes `log[start..starts.end()]` and of the
+/// prefixes `log[ends.start()..end]`, each computed incrementally from the
+/// previous one, and of the middle `log[starts.end()..ends.start()]`. The
+/// summary of a window combines three of them instead of every element.
+#[cfg(not(feature = "disable_validation"))]
+pub(crate) struct WindowSums<T> {
+    starts: RangeInclusive<usize>,
+    ends: RangeInclusive<usize>,
+    // suffixes[i] summarizes log[starts.start() + i..starts.end()]
+    suffixes: Vec<T>,
+    middle: T,
+    // prefixes[i] summarizes log[ends.start()..ends.start() + i]
+    prefixes: Vec<T>,
+    combine: fn(&T, &T) -> T,
+}
+
+#[cfg(not(feature = "disable_validation"))]
+impl<T: Clone> WindowSums<T> {
+    pub(crate) fn new<F>(
+        log: &[Vec<u8>],
+        starts: RangeInclusive<usize>,
+        ends: RangeInclusive<usize>,
+        empty: T,
+        add: F,
+        combine: fn(&T, &T) -> T,
+    ) -> Self
+    where
+        F: Fn(&mut T, &[u8]),
+    {
+        assert!(starts.end() <= ends.start() && *ends.end() <= log.len());
+        let mut suffix = empty.clone();
+        let mut suffixes = vec![suffix.clone()];
+        for elem in log[*starts.start()..*starts.end()].iter().rev() {
+            add(&mut suffix, elem);
+            suffixes.push(suffix.clone());
+        }
+        suffixes.reverse();
+        let mut middle = empty.clone();
+        for elem in &log[*starts.end()..*ends.start()] {
+            add(&mut middle, elem);
+        }
+        let mut prefix = empty;
+        let mut prefixes = vec![prefix.clone()];
+        for elem in &log[*ends.start()..*ends.end()] {
+            add(&mut prefix, elem);
+            prefixes.push(prefix.clone());
+        }
+        Self { starts, ends, suffixes, middle, prefixes, combine }
+    }
+
+    /// The summary of `log[start..end]`.
+    pub(crate) fn window(&self, start: usize, end: usize) -> T {
+        assert!(self.starts.contains(&start) && self.ends.contains(&end));
+        let suffix = &self.suffixes[start - self.starts.start()];
+        let prefix = &self.prefixes[end - self.ends.start()];
+        (self.combine)(&(self.combine)(suffix, &self.middle), prefix)
+    }
+}
+
+/// The window of the log that an accumulator was matched to.
+#[derive(Debug)]
+pub struct WindowReport {
+    pub start: usize,
+    pub end: usize,
+    /// The verdict on the window.
+    pub result: ValidationResult,
+    /// Number of windows that were validated.
+    pub probes: usize,
+}
+
+/// Searches for the shortest window `log[start..end]` with the start in
+/// `starts` and the end in `ends` that the accumulator is valid against,
+/// assuming it is valid against any window that contains a valid window.
+/// Windows that are too long to validate, i.e. with more dropped elements
+/// than the threshold, break that assumption, so the bounds should be tight
+/// enough for the widest window to be validated.
+///
+/// First bisects for the latest start with the widest end, then for the
+/// earliest end with that start. If the widest window is not valid, reports
+/// the verdict on it.
+#[cfg(not(feature = "disable_validation"))]
+pub fn search_window(
+    accumulator: &dyn Accumulator,
+    log: &[Vec<u8>],
+    starts: RangeInclusive<usize>,
+    ends: RangeInclusive<usize>,
+    options: &ValidationOptions,
+) -> WindowReport {
+    // The window cannot end before it starts.
+    let starts = *starts.start()..=std::cmp::min(*starts.end(), *ends.end());
+    let ends = std::cmp::max(*ends.start(), *starts.end())..=*ends.end();
+    let validator =
+        accumulator.window_validator(log, starts.clone(), ends.clone());
+    let (mut start, mut end) = (*starts.start(), *ends.end());
+    let mut result = validator.validate_window(start, end, options);
+    let mut probes = 1;
+    if !result.is_valid() {
+        return WindowReport { start, end, result, probes };
+    }
+
+    // The latest valid start, in start..=hi.
+    let mut hi = *starts.end();
+    while start < hi && !options.is_exceeded() {
+        let mid = (start + hi).div_ceil(2);
+        let mid_result = validator.validate_window(mid, end, options);
+        probes += 1;
+        if mid_result.is_valid() {
+            start = mid;
+            result = mid_result;
+        } else {
+            hi = mid - 1;
+        }
+    }
+
+    // The earliest valid end, in lo..=end.
+    let mut lo = *ends.start();
+    while lo < end && !options.is_exceeded() {
+        let mid = (lo + end) / 2;
+        let mid_result = validator.validate_window(start, mid, options);
+        probes += 1;
+        if mid_result.is_valid() {
+            end = mid;
+            result = mid_result;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    debug!("matched log entries {}..{} in {} probes", start, end, probes);
+    WindowReport { start, end, result, probes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CharPolyAccumulator, PowerSumAccumulator};
+    use crate::{HybridAccumulator, IBLTAccumulator, MultiLevelIBLTAccumulator};
+    use crate::test_util::gen_elems_with_seed;
+
+    #[test]
+    fn test_window_sums() {
+        let log = (0..20_u8).map(|i| vec![i]).collect::<Vec<_>>();
+        let sums = WindowSums::new(&log, 2..=5, 8..=12, 0,
+            |sum: &mut usize, elem| *sum += elem[0] as usize,
+            |a, b| a + b);
+        for start in 2..=5 {
+            for end in 8..=12 {
+                assert_eq!(sums.window(start, end), (start..end).sum());
+            }
+        }
+    }
+
+    /// Receives log[100..300] with three elements dropped in the middle.
+    fn search_test(mut accumulator: Box<dyn Accumulator>) {
+        let log = gen_elems_with_seed(400, 111);
+        for (i, elem) in log[100..300].iter().enumerate() {
+            if i % 50 != 25 {
+                accumulator.process(elem);
+            }
+        }
+        let options = ValidationOptions {
+            max_duplicates: 1,
+            ..Default::default()
+        };
+        let report = search_window(
+            accumulator.as_ref(), &log, 80..=120, 290..=320, &options);
+        assert!(report.result.is_valid(), "{:?}", report);
+        assert_eq!((report.start, report.end), (100, 300));
+        assert!(report.probes <= 12, "{:?}", report);
+
+        // The widest window does not contain the first received element.
+        let report = search_window(
+            accumulator.as_ref(), &log, 101..=120, 290..=320, &options);
+        assert!(!report.result.is_valid());
+        assert_eq!((report.start, report.end, report.probes), (101, 320, 1));
+    }
+
+    #[test]
+    fn test_search_window_to_end_of_log() {
+        let log = gen_elems_with_seed(10, 111);
+        let mut accumulator = PowerSumAccumulator::new(10, Some(222));
+        accumulator.process_batch(&log[2..].to_vec());
+        let options = ValidationOptions {
+            max_duplicates: 1,
+            ..Default::default()
+        };
+        let report = search_window(&accumulator, &log, 0..=10, 8..=10, &options);
+        assert!(report.result.is_valid(), "{:?}", report);
+        assert_eq!((report.start, report.end), (2, 10));
+
+        // The window may start after the last element.
+        let accumulator = PowerSumAccumulator::new(10, Some(222));
+        let report =
+            search_window(&accumulator, &log, 10..=10, 10..=10, &options);
+        assert!(report.result.is_valid(), "{:?}", report);
+        assert_eq!((report.start, report.end), (10, 10));
+    }
+
+    #[test]
+    fn test_search_window_empty_log() {
+        let accumulator = CharPolyAccumulator::new(10, Some(222));
+        let report = search_window(
+            &accumulator, &[], 0..=0, 0..=0, &ValidationOptions::default());
+        assert!(report.result.is_valid(), "{:?}", report);
+        assert_eq!((report.start, report.end, report.probes), (0, 0, 1));
+    }
+
+    #[test]
+    fn test_search_window_charpoly() {
+        search_test(Box::new(CharPolyAccumulator::new(60, Some(222))));
+    }
+
+    /// The precomputed summaries give the same verdicts as validating each
+    /// window from scratch.
+    fn window_validator_test(mut accumulator: Box<dyn Accumulator>) {
+        let log = gen_elems_with_seed(60, 111);
+        accumulator.process_batch(&log[10..50].to_vec());
+        let validator = accumulator.window_validator(&log, 5..=15, 45..=55);
+        let options = ValidationOptions::default();
+        for start in 5..=15 {
+            for end in 45..=55 {
+                let expected = accumulator.validate_with_options(
+                    &log[start..end].to_vec(), &options);
+                assert_eq!(validator.validate_window(start, end, &options),
+                    expected, "window {}..{}", start, end);
+                assert_eq!(expected.is_valid(), start <= 10 && end >= 50);
+            }
+        }
+    }
+
+    #[test]
+    fn test_window_validator_iblt() {
+        window_validator_test(Box::new(IBLTAccumulator::new(20, Some(222))));
+        window_validator_test(Box::new(
+            IBLTAccumulator::new_with_hash_check(20, 8, 10, 2, Some(222))));
+    }
+
+    #[test]
+    fn test_window_validator_hybrid() {
+        window_validator_test(Box::new(HybridAccumulator::new(20, Some(222))));
+    }
+
+    #[test]
+    fn test_window_validator_multilevel() {
+        window_validator_test(
+            Box::new(MultiLevelIBLTAccumulator::new(20, Some(222))));
+    }
+
+    #[test]
+    fn test_search_window_iblt() {
+        search_test(Box::new(IBLTAccumulator::new(60, Some(222))));
+    }
+
+    #[test]
+    fn test_search_window_hybrid() {
+        search_test(Box::new(HybridAccumulator::new(60, Some(222))));
+    }
+
+    #[test]
+    fn test_search_window_power_sum() {
+        // Tolerating duplicates finds the roots without factoring.
+        search_test(Box::new(PowerSumAccumulator::new(60, Some(222))));
+    }
+}
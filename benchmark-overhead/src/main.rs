@@ -24,7 +24,14 @@ impl Accumulator for MockAccumulator {
     fn total(&self) -> usize {
         unimplemented!()
     }
-    fn validate(&self, _elems: &Vec<Vec<u8>>) -> ValidationResult {
+    fn estimator(&self) -> &StrataEstimator {
+        unimplemented!()
+    }
+    fn validate_with_options(
+        &self,
+        _elems: &Vec<Vec<u8>>,
+        _options: &ValidationOptions,
+    ) -> ValidationResult {
         unimplemented!()
     }
 }
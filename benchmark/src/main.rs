@@ -13,6 +13,7 @@ fn build_accumulator<G: Iterator<Item = Vec<u8>>>(
     accumulator_ty: &str,
     threshold: usize,
     iblt_params: Option<Vec<&str>>,
+    num_power_sums: usize,
     seed: Option<u64>,
 ) -> Box<dyn Accumulator> {
     let mut accumulator: Box<dyn Accumulator> = {
@@ -29,7 +30,22 @@ fn build_accumulator<G: Iterator<Item = Vec<u8>>>(
             } else {
                 Box::new(IBLTAccumulator::new(threshold, seed))
             },
+            "multi_iblt" => Box::new(MultiLevelIBLTAccumulator::new(
+                threshold, seed)),
+            "hybrid" => {
+                let params = iblt_params.unwrap();
+                assert_eq!(params.len(), 3);
+                Box::new(HybridAccumulator::new_with_params(
+                    threshold,
+                    params[0].parse().unwrap(),
+                    params[1].parse().unwrap(),
+                    params[2].parse().unwrap(),
+                    num_power_sums,
+                    seed,
+                ))
+            },
             "power_sum" => Box::new(PowerSumAccumulator::new(threshold, seed)),
+            "charpoly" => Box::new(CharPolyAccumulator::new(threshold, seed)),
             _ => unreachable!(),
         }
     };
@@ -76,6 +92,37 @@ fn validate(
     }
 }
 
+/// Print the IBLT parameters that meet the targets with the smallest digest.
+fn plan(
+    expected_packets: usize,
+    max_dropped: usize,
+    p_undetermined: f64,
+    p_false_invalid: f64,
+    byte_budget: usize,
+) {
+    if expected_packets > u16::MAX as usize {
+        warn!("the accumulator counts at most {} packets per epoch, reset \
+            it more often", u16::MAX);
+    }
+    warn!("{} packets collide with probability {:.3e}", expected_packets,
+        collision_probability(expected_packets));
+    let plan = plan_iblt(max_dropped, expected_packets, p_undetermined,
+        p_false_invalid, byte_budget);
+    if let Some(plan) = plan {
+        warn!("threshold\tbpe\tmult\thashes\tbytes\tp_peel\tp_wrap\t\
+            candidates\tp_undet");
+        warn!("{}\t{}\t{}\t{}\t{}\t{:.3e}\t{:.3e}\t{:.1}\t{:.3e}",
+            plan.threshold, plan.bits_per_entry, plan.cells_multiplier,
+            plan.num_hashes, plan.num_bytes, plan.p_peel_success,
+            plan.p_wraparound, plan.wraparound_candidates,
+            plan.p_undetermined);
+        warn!("--iblt-params {} {} {} -t {}", plan.bits_per_entry,
+            plan.cells_multiplier, plan.num_hashes, plan.threshold);
+    } else {
+        warn!("no IBLT parameters meet the targets in {} bytes", byte_budget);
+    }
+}
+
 fn median<T: std::ops::Add<Output = T>
            + std::ops::Div<u32, Output = T>
            + Copy + PartialOrd + Ord>(mut results: Vec<T>) -> T {
@@ -125,8 +172,8 @@ fn main() {
                 and definitely not dropped.")
             .long("malicious"))
         .arg(Arg::new("threshold")
-            .help("Threshold number of log packets for the CBF \
-                and power sum accumulators.")
+            .help("Threshold number of log packets for the CBF, power \
+                sum, and characteristic polynomial accumulators.")
             .short('t')
             .long("threshold")
             .takes_value(true)
@@ -137,8 +184,14 @@ fn main() {
             .value_names(&["bits_per_entry", "cells_multiplier", "num_hashes"])
             .takes_value(true)
             .number_of_values(3)
-            .required_if_eq("accumulator", "iblt")
+            .required_if_eq_any(&[("accumulator", "iblt"),
+                ("accumulator", "hybrid")])
             .default_values(&["4", "2", "4"]))
+        .arg(Arg::new("num-power-sums")
+            .help("Number of power sums of the hybrid accumulator.")
+            .long("num-power-sums")
+            .takes_value(true)
+            .default_value("8"))
         .arg(Arg::new("seed")
             .help("IBLT and load generator seed for reproducible results.")
             .long("seed")
@@ -149,14 +202,50 @@ fn main() {
             .takes_value(true)
             .default_value("1"))
         .arg(Arg::new("accumulator")
-            .help("")
+            .help("Accumulators to compare on the same trials.")
             .short('a')
             .long("accumulator")
             .takes_value(true)
+            .multiple_values(true)
             .possible_value("naive")
             .possible_value("iblt")
+            .possible_value("multi_iblt")
+            .possible_value("hybrid")
             .possible_value("power_sum")
+            .possible_value("charpoly")
             .required(true))
+        .subcommand_negates_reqs(true)
+        .subcommand(Command::new("plan")
+            .about("Choose IBLT parameters for target failure rates.")
+            .arg(Arg::new("expected-packets")
+                .help("Expected number of logged packets per epoch, to \
+                    estimate how likely their hashes collide and how many \
+                    ways the counters could wrap around.")
+                .long("expected-packets")
+                .takes_value(true)
+                .default_value("1000"))
+            .arg(Arg::new("max-dropped")
+                .help("Maximum number of dropped packets per epoch.")
+                .long("max-dropped")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::new("p-undetermined")
+                .help("Acceptable probability that validation is \
+                    undetermined.")
+                .long("p-undetermined")
+                .takes_value(true)
+                .default_value("0.000001"))
+            .arg(Arg::new("p-false-invalid")
+                .help("Acceptable probability that a valid log is \
+                    reported as invalid.")
+                .long("p-false-invalid")
+                .takes_value(true)
+                .default_value("0.001"))
+            .arg(Arg::new("byte-budget")
+                .help("Maximum size of the digest in bytes.")
+                .long("byte-budget")
+                .takes_value(true)
+                .required(true)))
         .get_matches();
 
     let debug_level = match matches.value_of("debug-level").unwrap() {
@@ -169,6 +258,16 @@ fn main() {
         _ => unreachable!(),
     };
     env_logger::builder().filter_level(debug_level).init();
+    if let Some(matches) = matches.subcommand_matches("plan") {
+        plan(
+            matches.value_of_t("expected-packets").unwrap(),
+            matches.value_of_t("max-dropped").unwrap(),
+            matches.value_of_t("p-undetermined").unwrap(),
+            matches.value_of_t("p-false-invalid").unwrap(),
+            matches.value_of_t("byte-budget").unwrap(),
+        );
+        return;
+    }
     let trials: usize = matches.value_of_t("trials").unwrap();
     let num_logged: usize = matches.value_of_t("num-logged").unwrap();
     let p_dropped: Option<f32> = matches.value_of("p-dropped")
@@ -176,49 +275,70 @@ fn main() {
     let n_dropped: Option<usize> = matches.value_of("n-dropped")
         .map(|n_dropped| n_dropped.parse().unwrap());
     let malicious: bool = matches.is_present("malicious");
-    let accumulator_ty = matches.value_of("accumulator").unwrap();
+    let accumulator_tys: Vec<&str> = matches.values_of("accumulator")
+        .unwrap().collect();
     let threshold: usize = matches.value_of_t("threshold").unwrap();
     let iblt_params: Option<Vec<&str>> = matches.values_of("iblt-params")
         .map(|values| values.collect());
-    let mut seed_generator = SeedGenerator::new(matches.value_of("seed")
-        .map(|seed| seed.parse().unwrap()));
+    let num_power_sums: usize = matches.value_of_t("num-power-sums").unwrap();
+    // Accumulators are compared on the same trials, so seed the trials if
+    // there is more than one accumulator even if no seed is provided.
+    let seed: Option<u64> = matches.value_of("seed")
+        .map(|seed| seed.parse().unwrap())
+        .or_else(|| if accumulator_tys.len() > 1 {
+            Some(rand::random())
+        } else {
+            None
+        });
 
-    let mut results = vec![];
-    let mut dropped = vec![];
-    let mut errors = 0;
-    let mut ilp = 0;
-    let mut collisions = 0;
-    for i in 0..trials {
-        let seed = seed_generator.next();
-        let mut g: Box<dyn LoadGenerator<Item = Vec<u8>>> =
-            build_loadgen(seed, num_logged, p_dropped, n_dropped, malicious);
-        let t1 = Instant::now();
-        let acc = build_accumulator(&mut g, accumulator_ty, threshold,
-            iblt_params.clone(), seed.clone());
-        let t2 = Instant::now();
-        debug!(
-            "dropped {}/{} elements: {:?}",
-            g.num_dropped(),
-            g.num_logged(),
-            t2 - t1,
-        );
-        dropped.push(g.num_dropped() as u32);
-        if i == trials - 1 {
-            warn!("digest size = {} bytes", acc.to_bytes().len());
-        }
-        if let Ok((duration, result)) = validate(acc, g.log(), malicious) {
-            results.push(duration);
-            if result.is_ilp() {
-                ilp += 1;
+    let mut rows = vec![];
+    for accumulator_ty in accumulator_tys {
+        let mut seed_generator = SeedGenerator::new(seed);
+        let mut results = vec![];
+        let mut processing = vec![];
+        let mut dropped = vec![];
+        let mut errors = 0;
+        let mut ilp = 0;
+        let mut collisions = 0;
+        for i in 0..trials {
+            let seed = seed_generator.next();
+            let mut g: Box<dyn LoadGenerator<Item = Vec<u8>>> =
+                build_loadgen(seed, num_logged, p_dropped, n_dropped,
+                    malicious);
+            let t1 = Instant::now();
+            let acc = build_accumulator(&mut g, accumulator_ty, threshold,
+                iblt_params.clone(), num_power_sums, seed);
+            let t2 = Instant::now();
+            debug!(
+                "dropped {}/{} elements: {:?}",
+                g.num_dropped(),
+                g.num_logged(),
+                t2 - t1,
+            );
+            processing.push(t2 - t1);
+            dropped.push(g.num_dropped() as u32);
+            if i == trials - 1 {
+                warn!("{} digest size = {} bytes", accumulator_ty,
+                    acc.to_bytes().len());
             }
-            if result.is_collisions() {
-                collisions += 1;
+            if let Ok((duration, result)) = validate(acc, g.log(), malicious) {
+                results.push(duration);
+                if result.is_ilp() {
+                    ilp += 1;
+                }
+                if result.is_collisions() {
+                    collisions += 1;
+                }
+            } else {
+                errors += 1;
             }
-        } else {
-            errors += 1;
         }
+        rows.push(format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:?}\t{:?}",
+            accumulator_ty, trials, collisions, ilp, errors, num_logged,
+            median(dropped), median(processing), median(results)));
+    }
+    warn!("acc\ttrials\tcollis\tilp\terrors\tlogged\tn_drop\tprocess\tmedian");
+    for row in rows {
+        warn!("{}", row);
     }
-    warn!("trials\tcollis\tilp\terrors\tlogged\tn_drop\tmedian");
-    warn!("{}\t{}\t{}\t{}\t{}\t{}\t{:?}", trials, collisions, ilp, errors,
-       num_logged, median(dropped), median(results));
 }
@@ -0,0 +1,224 @@
+//! Closed-form estimates of how an IBLT behaves for a given number of
+//! elements, used to choose its parameters without running trials.
+
+/// Load thresholds c_k for random k-uniform hypergraphs, indexed by k. If the
+/// ratio of elements to cells is below c_k, the 2-core is empty with high
+/// probability, i.e. peeling succeeds, and above it peeling almost surely
+/// gets stuck.
+const PEELING_THRESHOLDS: [f64; 8] =
+    [0.0, 0.0, 0.5, 0.8185, 0.7723, 0.7017, 0.6370, 0.5817];
+
+/// The natural logarithm of n!.
+fn ln_factorial(n: u64) -> f64 {
+    if n <= 20 {
+        (2..=n).map(|i| (i as f64).ln()).sum()
+    } else {
+        // Stirling's series
+        let n = n as f64;
+        n * n.ln() - n + 0.5 * (2.0 * std::f64::consts::PI * n).ln()
+            + 1.0 / (12.0 * n)
+    }
+}
+
+/// The probability that a Poisson random variable with mean `lambda` is at
+/// least `t`.
+fn poisson_tail(lambda: f64, t: u64) -> f64 {
+    if t == 0 {
+        return 1.0;
+    }
+    if lambda == 0.0 {
+        return 0.0;
+    }
+    if (t as f64) <= lambda {
+        // the tail is large, so subtract the head from 1
+        let mut term = (-lambda).exp();
+        let mut head = 0.0;
+        for j in 0..t {
+            head += term;
+            term *= lambda / ((j + 1) as f64);
+        }
+        return (1.0 - head).max(0.0);
+    }
+    // the terms are decreasing, so sum until they are negligible
+    let mut term = (-lambda + (t as f64) * lambda.ln() - ln_factorial(t)).exp();
+    let mut tail = 0.0;
+    let mut j = t;
+    while term > 0.0 && term > tail * f64::EPSILON {
+        tail += term;
+        j += 1;
+        term *= lambda / (j as f64);
+    }
+    tail.min(1.0)
+}
+
+/// Estimates the probability that peeling an IBLT with `num_entries` cells
+/// and `num_hashes` hash functions into which `num_elems` distinct elements
+/// were inserted does not recover every element.
+///
+/// With 2 hash functions the IBLT is a random graph, which peels if and only
+/// if it is acyclic. With more, peeling fails with high probability above the
+/// load threshold, and below it failure is dominated by pairs of elements
+/// that hash to the same cells.
+///
+/// # Panics
+///
+/// Panics if `num_hashes` is not between 2 and 7.
+pub fn peel_failure_probability(
+    num_entries: u64,
+    num_hashes: u32,
+    num_elems: usize,
+    partitioned: bool,
+) -> f64 {
+    assert!((2..PEELING_THRESHOLDS.len() as u32).contains(&num_hashes),
+        "no estimate for {} hash functions", num_hashes);
+    if num_elems == 0 {
+        return 0.0;
+    }
+    let m = num_entries as f64;
+    let n = num_elems as f64;
+    let k = num_hashes as i32;
+    if n / m >= PEELING_THRESHOLDS[num_hashes as usize] {
+        return 1.0;
+    }
+    if num_hashes == 2 {
+        // a random multigraph with average degree `d` has no cycles,
+        // including self-loops and multi-edges, with probability sqrt(1-d)
+        let d = 2.0 * n / m;
+        return 1.0 - (1.0 - d).sqrt();
+    }
+    let p_same_cells = if partitioned {
+        (k as f64 / m).powi(k)
+    } else {
+        ln_factorial(num_hashes as u64).exp() / m.powi(k)
+    };
+    (n * (n - 1.0) / 2.0 * p_same_cells).min(1.0)
+}
+
+/// Estimates how many times the counters of an IBLT with `num_entries`
+/// cells, `num_hashes` hash functions and `bits_per_entry` bits per counter
+/// wrap around in total after inserting `num_elems` elements, assuming
+/// Poisson-distributed cell loads.
+pub fn expected_wraps(
+    num_entries: u64,
+    num_hashes: u32,
+    bits_per_entry: usize,
+    num_elems: usize,
+    partitioned: bool,
+) -> f64 {
+    if bits_per_entry >= 64 {
+        return 0.0;
+    }
+    // the most elements a single cell can hold
+    let max_load = if partitioned {
+        num_elems as u64
+    } else {
+        num_elems as u64 * num_hashes as u64
+    };
+    let t = 1_u64 << bits_per_entry;
+    if max_load < t {
+        return 0.0;
+    }
+    let lambda = (num_elems as f64) * (num_hashes as f64)
+        / (num_entries as f64);
+    let wraps_per_cell = if (t as f64) <= lambda {
+        // E[floor(X / t)], approximating X mod t as uniform
+        ((lambda - (t - 1) as f64 / 2.0) / t as f64).max(0.0)
+    } else {
+        // E[floor(X / t)] is the sum of P(X >= j * t) over j > 0, and the
+        // terms decrease quickly past the mean
+        let mut sum = 0.0;
+        let mut j = t;
+        while j <= max_load {
+            let term = poisson_tail(lambda, j);
+            if term <= sum * f64::EPSILON {
+                break;
+            }
+            sum += term;
+            j += t;
+        }
+        sum
+    };
+    (num_entries as f64) * wraps_per_cell
+}
+
+/// Estimates the probability that some counter of an IBLT with `num_entries`
+/// cells, `num_hashes` hash functions and `bits_per_entry` bits per counter
+/// wraps around after inserting `num_elems` elements, i.e. that some cell
+/// holds at least 2^bits_per_entry elements. This is a union bound over the
+/// cells, assuming Poisson-distributed cell loads.
+pub fn wraparound_probability(
+    num_entries: u64,
+    num_hashes: u32,
+    bits_per_entry: usize,
+    num_elems: usize,
+    partitioned: bool,
+) -> f64 {
+    if bits_per_entry >= 64 {
+        return 0.0;
+    }
+    // the most elements a single cell can hold
+    let max_load = if partitioned {
+        num_elems as u64
+    } else {
+        num_elems as u64 * num_hashes as u64
+    };
+    let t = 1_u64 << bits_per_entry;
+    if max_load < t {
+        return 0.0;
+    }
+    let lambda = (num_elems as f64) * (num_hashes as f64)
+        / (num_entries as f64);
+    ((num_entries as f64) * poisson_tail(lambda, t)).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poisson_tail() {
+        assert_eq!(poisson_tail(2.0, 0), 1.0);
+        assert!((poisson_tail(2.0, 1) - (1.0 - (-2.0_f64).exp())).abs() < 1e-12);
+        // P(X >= 3) for X ~ Poisson(1)
+        let expected = 1.0 - (-1.0_f64).exp() * 2.5;
+        assert!((poisson_tail(1.0, 3) - expected).abs() < 1e-12);
+        assert!(poisson_tail(1.0, 1 << 16) < 1e-100);
+    }
+
+    #[test]
+    fn test_peel_failure_probability() {
+        // more cells or more hashes peel more reliably
+        let p1 = peel_failure_probability(100, 3, 20, false);
+        let p2 = peel_failure_probability(200, 3, 20, false);
+        let p3 = peel_failure_probability(200, 4, 20, false);
+        assert!(p1 > p2 && p2 > p3);
+        assert!(p1 > 0.0 && p1 < 1.0);
+        // above the load threshold, peeling fails
+        assert_eq!(peel_failure_probability(100, 3, 90, false), 1.0);
+        assert_eq!(peel_failure_probability(100, 2, 50, false), 1.0);
+        assert_eq!(peel_failure_probability(100, 2, 0, false), 0.0);
+    }
+
+    #[test]
+    fn test_wraparound_probability() {
+        // too few elements to fill any counter
+        assert_eq!(wraparound_probability(100, 2, 4, 7, false), 0.0);
+        assert_eq!(wraparound_probability(100, 2, 4, 15, true), 0.0);
+        let p1 = wraparound_probability(100, 2, 4, 500, false);
+        let p2 = wraparound_probability(100, 2, 5, 500, false);
+        assert!(p1 > p2);
+        assert_eq!(wraparound_probability(10, 2, 1, 500, false), 1.0);
+    }
+
+    #[test]
+    fn test_expected_wraps() {
+        assert_eq!(expected_wraps(100, 2, 4, 7, false), 0.0);
+        // every cell holds about 100 elements, which wrap 4 bits 6 times
+        let wraps = expected_wraps(10, 2, 4, 500, false);
+        assert!(wraps > 55.0 && wraps < 65.0);
+        let p = wraparound_probability(100, 2, 4, 200, false);
+        assert!(expected_wraps(100, 2, 4, 200, false) >= p);
+        assert!(expected_wraps(100, 2, 4, 500, false)
+            > expected_wraps(100, 2, 5, 500, false));
+    }
+}
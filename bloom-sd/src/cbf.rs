@@ -7,7 +7,7 @@ use serde::{Serialize, Deserialize};
 use siphasher::sip128::SipHasher13;
 
 use crate::valuevec::ValueVec;
-use crate::hashing::HashIter;
+use crate::hashing::{HashIter, IndexIter};
 use crate::SipHasher13Def;
 
 #[derive(Serialize, Deserialize)]
@@ -15,10 +15,9 @@ pub struct CountingBloomFilter {
     counters: ValueVec,
     num_entries: u64,
     num_hashes: u32,
+    partitioned: bool,
     #[serde(with = "SipHasher13Def")]
-    hash_builder_one: SipHasher13,
-    #[serde(with = "SipHasher13Def")]
-    hash_builder_two: SipHasher13,
+    hash_builder: SipHasher13,
 }
 
 impl CountingBloomFilter {
@@ -30,6 +29,25 @@ impl CountingBloomFilter {
         bits_per_entry: usize,
         rate: f32,
         expected_num_items: u32,
+    ) -> Self {
+        Self::new(bits_per_entry, rate, expected_num_items, false)
+    }
+
+    /// Like `with_rate()`, but the counters are split into one subtable per
+    /// hash function, so the indexes of an item are always distinct.
+    pub fn with_rate_partitioned(
+        bits_per_entry: usize,
+        rate: f32,
+        expected_num_items: u32,
+    ) -> Self {
+        Self::new(bits_per_entry, rate, expected_num_items, true)
+    }
+
+    fn new(
+        bits_per_entry: usize,
+        rate: f32,
+        expected_num_items: u32,
+        partitioned: bool,
     ) -> Self {
         let num_entries = bloom::bloom::needed_bits(rate, expected_num_items);
         let num_hashes = bloom::bloom::optimal_num_hashes(
@@ -41,8 +59,8 @@ impl CountingBloomFilter {
             counters: ValueVec::new(bits_per_entry, num_entries),
             num_entries: num_entries as u64,
             num_hashes,
-            hash_builder_one: SipHasher13::new_with_keys(rng.gen(), rng.gen()),
-            hash_builder_two: SipHasher13::new_with_keys(rng.gen(), rng.gen()),
+            partitioned,
+            hash_builder: SipHasher13::new_with_keys(rng.gen(), rng.gen()),
         }
     }
 
@@ -53,8 +71,8 @@ impl CountingBloomFilter {
             counters: ValueVec::new(bits_per_entry, self.num_entries as usize),
             num_entries: self.num_entries,
             num_hashes: self.num_hashes,
-            hash_builder_one: self.hash_builder_one.clone(),
-            hash_builder_two: self.hash_builder_two.clone(),
+            partitioned: self.partitioned,
+            hash_builder: self.hash_builder,
         }
     }
 
@@ -74,35 +92,27 @@ impl CountingBloomFilter {
         self.num_hashes
     }
 
+    /// Whether each hash function maps into its own subtable.
+    pub fn partitioned(&self) -> bool {
+        self.partitioned
+    }
+
     pub fn equals(&self, other: &Self) -> bool {
         if self.num_entries != other.num_entries
             || self.num_hashes != other.num_hashes
-            || self.hash_builder_one.keys() != other.hash_builder_one.keys()
-            || self.hash_builder_two.keys() != other.hash_builder_two.keys()
+            || self.partitioned != other.partitioned
+            || self.hash_builder.keys() != other.hash_builder.keys()
         {
             return false;
         }
-        let nbits = self.counters.len();
-        if nbits != other.counters.len() {
-            return false;
-        }
-        for i in 0..(nbits / self.counters.bits_per_val()) {
-            if self.counters.get(i) != other.counters.get(i) {
-                return false;
-            }
-        }
-        true
+        self.counters == other.counters
     }
 
     /// Inserts an item, returns true if the item was already in the filter
     /// any number of times.
     pub fn insert<T: Hash>(&mut self, item: &T) -> bool {
-        let mut min = u32::max_value();
-        for h in HashIter::from(item,
-                                self.num_hashes,
-                                &self.hash_builder_one,
-                                &self.hash_builder_two) {
-            let idx = (h % self.num_entries) as usize;
+        let mut min = u64::max_value();
+        for idx in self.index_iter(item) {
             let cur = self.counters.get(idx);
             if cur < min {
                 min = cur;
@@ -119,11 +129,7 @@ impl CountingBloomFilter {
     /// Checks if the item has been inserted into this CountingBloomFilter.
     /// This function can return false positives, but not false negatives.
     pub fn contains<T: Hash>(&self, item: &T) -> bool {
-        for h in HashIter::from(item,
-                                self.num_hashes,
-                                &self.hash_builder_one,
-                                &self.hash_builder_two) {
-            let idx = (h % self.num_entries) as usize;
+        for idx in self.index_iter(item) {
             let cur = self.counters.get(idx);
             if cur == 0 {
                 return false;
@@ -134,13 +140,12 @@ impl CountingBloomFilter {
 
     /// Gets the indexes of the item in the vector.
     pub fn indexes<T: Hash>(&self, item: &T) -> Vec<usize> {
-        HashIter::from(item,
-                       self.num_hashes,
-                       &self.hash_builder_one,
-                       &self.hash_builder_two)
-            .into_iter()
-            .map(|h| (h % self.num_entries) as usize)
-            .collect()
+        self.index_iter(item).collect()
+    }
+
+    fn index_iter<T: Hash>(&self, item: &T) -> IndexIter {
+        HashIter::from(item, self.num_hashes, &self.hash_builder)
+            .indexes(self.num_entries, self.partitioned)
     }
 }
 
@@ -154,8 +159,7 @@ mod tests {
     }
 
     fn vvsum(vec: &ValueVec) -> usize {
-        let num_entries = vec.len() / vec.bits_per_val();
-        (0..num_entries).map(|i| vec.get(i)).sum::<u32>() as usize
+        vec.sum() as usize
     }
 
     #[test]
@@ -264,4 +268,20 @@ mod tests {
         cbf.insert(&1234);
         cbf.insert(&1234);
     }
+
+    #[test]
+    fn test_partitioned_indexes() {
+        let mut cbf = CountingBloomFilter::with_rate_partitioned(8, 0.01, 10);
+        assert!(cbf.partitioned());
+        assert_eq!(cbf.num_hashes(), 2);
+        let half = (cbf.num_entries() / 2) as usize;
+        for item in 0..100 {
+            let indexes = cbf.indexes(&item);
+            assert!(indexes[0] < half);
+            assert!(indexes[1] >= half);
+        }
+        cbf.insert(&1234);
+        assert!(cbf.contains(&1234));
+        assert!(!cbf.equals(&CountingBloomFilter::with_rate(8, 0.01, 10)));
+    }
 }
@@ -1,6 +1,8 @@
-use std::hash::{Hash, Hasher};
-use siphasher::sip128::SipHasher13;
+use std::hash::Hash;
+use siphasher::sip128::{Hasher128, SipHasher13};
 
+/// Iterates over `count` hash values of an item, all derived from a single
+/// 128-bit SipHash output using double hashing: g_i = h1 + i * h2.
 pub struct HashIter {
     h1: u64,
     h2: u64,
@@ -15,15 +17,8 @@ impl Iterator for HashIter {
         if self.i == self.count {
             return None;
         }
-        let r = match self.i {
-            0 => { self.h1 }
-            1 => { self.h2 }
-            _ => {
-                let p1 = self.h1.wrapping_add(self.i as u64);
-                p1.wrapping_mul(self.h2)
-            }
-        };
-        self.i+=1;
+        let r = self.h1.wrapping_add((self.i as u64).wrapping_mul(self.h2));
+        self.i += 1;
         Some(r)
     }
 }
@@ -32,20 +27,141 @@ impl HashIter {
     pub fn from<T: Hash>(
         item: T,
         count: u32,
-        build_hasher_one: &SipHasher13,
-        build_hasher_two: &SipHasher13,
+        hash_builder: &SipHasher13,
     ) -> HashIter {
-        let mut hasher_one = build_hasher_one.clone();
-        let mut hasher_two = build_hasher_two.clone();
-        item.hash(&mut hasher_one);
-        item.hash(&mut hasher_two);
-        let h1 = hasher_one.finish();
-        let h2 = hasher_two.finish();
+        let mut hasher = *hash_builder;
+        item.hash(&mut hasher);
+        let hash = hasher.finish128();
         HashIter {
-            h1: h1,
-            h2: h2,
+            h1: hash.h1,
+            h2: hash.h2,
             i: 0,
-            count: count,
+            count,
+        }
+    }
+
+    /// Maps the hash values to indexes in a table with `num_entries` cells.
+    ///
+    /// If `partitioned`, the table is split into `count` subtables of
+    /// (nearly) equal size and hash i maps into subtable i. Otherwise index i
+    /// is h1 + i * h2 modulo the size of the whole table, where the step h2
+    /// is made coprime with the size so it only cycles back to h1 after
+    /// visiting every cell. Either way, the indexes of an item are distinct
+    /// if the table has at least `count` cells.
+    pub fn indexes(self, num_entries: u64, partitioned: bool) -> IndexIter {
+        assert!(num_entries > 0);
+        if partitioned {
+            assert!(num_entries >= self.count as u64,
+                "partitioned table needs at least one cell per hash");
+        }
+        let mut step = self.h2 % num_entries;
+        while gcd(step, num_entries) != 1 {
+            step = (step + 1) % num_entries;
+        }
+        IndexIter {
+            start: self.h1 % num_entries,
+            step,
+            hashes: self,
+            num_entries,
+            partitioned,
+        }
+    }
+}
+
+/// Iterates over the indexes of an item in a table. See `HashIter::indexes`.
+pub struct IndexIter {
+    hashes: HashIter,
+    num_entries: u64,
+    partitioned: bool,
+    // the first index and the step between indexes if not partitioned
+    start: u64,
+    step: u64,
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+impl Iterator for IndexIter {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let i = self.hashes.i as u64;
+        let h = self.hashes.next()?;
+        if !self.partitioned {
+            let offset = i as u128 * self.step as u128;
+            let index = (self.start as u128 + offset) % self.num_entries as u128;
+            return Some(index as usize);
         }
+        let count = self.hashes.count as u64;
+        let lo = i * self.num_entries / count;
+        let hi = (i + 1) * self.num_entries / count;
+        Some((lo + h % (hi - lo)) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_builder() -> SipHasher13 {
+        SipHasher13::new_with_keys(111, 222)
+    }
+
+    #[test]
+    fn test_hash_iter_count_and_determinism() {
+        let hashes = HashIter::from(1234_u32, 5, &hash_builder())
+            .collect::<Vec<_>>();
+        assert_eq!(hashes.len(), 5);
+        assert_eq!(hashes, HashIter::from(1234_u32, 5, &hash_builder())
+            .collect::<Vec<_>>());
+        assert_ne!(hashes, HashIter::from(5678_u32, 5, &hash_builder())
+            .collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_partitioned_indexes_are_distinct() {
+        let num_entries = 103;
+        let count = 4;
+        for item in 0..1000_u32 {
+            let indexes = HashIter::from(item, count, &hash_builder())
+                .indexes(num_entries, true)
+                .collect::<Vec<_>>();
+            assert_eq!(indexes.len(), count as usize);
+            for (i, &index) in indexes.iter().enumerate() {
+                let lo = (i as u64) * num_entries / (count as u64);
+                let hi = (i as u64 + 1) * num_entries / (count as u64);
+                assert!((index as u64) >= lo && (index as u64) < hi);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unpartitioned_indexes_are_distinct() {
+        // Neither a prime nor a power of two, so some steps share a factor
+        // with the table size.
+        for num_entries in [7, 30, 64] {
+            for item in 0..1000_u32 {
+                let mut indexes = HashIter::from(item, 4, &hash_builder())
+                    .indexes(num_entries, false)
+                    .collect::<Vec<_>>();
+                assert!(indexes.iter().all(|&i| (i as u64) < num_entries));
+                indexes.sort();
+                indexes.dedup();
+                assert_eq!(indexes.len(), 4, "{} cells", num_entries);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unpartitioned_indexes_wrap_around() {
+        // More hashes than cells visit every cell before repeating.
+        let indexes = HashIter::from(1234_u32, 6, &hash_builder())
+            .indexes(3, false)
+            .collect::<Vec<_>>();
+        assert_eq!(indexes[..3], indexes[3..]);
+        let mut cells = indexes[..3].to_vec();
+        cells.sort();
+        assert_eq!(cells, vec![0, 1, 2]);
     }
 }
@@ -1,5 +1,5 @@
-use std::collections::HashSet;
-use std::hash::Hasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::num::Wrapping;
 
 use rand;
@@ -8,23 +8,59 @@ use djb_hash::{HasherU32, x33a_u32::*};
 use siphasher::sip128::SipHasher13;
 
 use crate::valuevec::ValueVec;
-use crate::hashing::HashIter;
+use crate::hashing::{HashIter, IndexIter};
 use crate::SipHasher13Def;
 
 pub const DJB_HASH_SIZE: usize = 32;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct InvBloomLookupTable {
     counters: ValueVec,
     // sum of djb_hashed data with wraparound overflow
     data: ValueVec,
+    // sum of keyed checksums of the djb_hashed data, if enabled
+    hash_checks: Option<ValueVec>,
     num_entries: u64,
     num_hashes: u32,
+    partitioned: bool,
     seed: u64,
     #[serde(with = "SipHasher13Def")]
-    hash_builder_one: SipHasher13,
+    hash_builder: SipHasher13,
     #[serde(with = "SipHasher13Def")]
-    hash_builder_two: SipHasher13,
+    hash_builder_check: SipHasher13,
+}
+
+/// Evidence found while peeling that the IBLT could not have been produced
+/// by honestly inserting and removing elements.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PeelError {
+    /// The cells at these indexes have a counter of 1 but their hash check
+    /// does not match their data, so they hold a mix of elements (e.g. two
+    /// inserted and one removed) rather than a single element.
+    HashCheckMismatch(Vec<usize>),
+    /// The element was peeled more than once.
+    DuplicateElem(u32),
+}
+
+/// The elements peeled from an IBLT, and the cells that could not be peeled.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PeelResult {
+    /// The peeled elements.
+    pub removed: HashSet<u32>,
+    /// The peeled elements in the order they were removed.
+    pub order: Vec<u32>,
+    /// The indexes of the cells with nonzero counters after peeling, i.e. the
+    /// stuck 2-core of the elements that remain in the IBLT.
+    pub core: Vec<usize>,
+}
+
+/// The elements peeled from an IBLT with signed counters.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SignedElems {
+    /// Elements with a count of 1, e.g. logged but not received.
+    pub inserted: HashSet<u32>,
+    /// Elements with a count of -1, e.g. received but not logged.
+    pub removed: HashSet<u32>,
 }
 
 /// Maps an element in the lookup table to a u32.
@@ -59,17 +95,52 @@ impl InvBloomLookupTable {
         bits_per_entry: usize,
         num_entries: usize,
         num_hashes: u32,
+    ) -> Self {
+        Self::new_with_options(
+            seed, bits_per_entry, num_entries, num_hashes, false, false)
+    }
+
+    /// Like `new_with_seed()`, but each cell additionally stores the sum of
+    /// a keyed checksum of its elements. Peeling only accepts a cell with a
+    /// counter of 1 if the checksum of its data matches the stored checksum.
+    pub fn new_with_hash_check(
+        seed: u64,
+        bits_per_entry: usize,
+        num_entries: usize,
+        num_hashes: u32,
+    ) -> Self {
+        Self::new_with_options(
+            seed, bits_per_entry, num_entries, num_hashes, true, false)
+    }
+
+    /// Like `new_with_seed()`, with the optional features of the table.
+    /// - `hash_check`: see `new_with_hash_check()`
+    /// - `partitioned`: split the cells into one subtable per hash function,
+    ///   so the indexes of an element are always distinct
+    pub fn new_with_options(
+        seed: u64,
+        bits_per_entry: usize,
+        num_entries: usize,
+        num_hashes: u32,
+        hash_check: bool,
+        partitioned: bool,
     ) -> Self {
         use rand::{SeedableRng, rngs::SmallRng, Rng};
         let mut rng = SmallRng::seed_from_u64(seed);
         InvBloomLookupTable {
             data: ValueVec::new(DJB_HASH_SIZE, num_entries),
+            hash_checks: if hash_check {
+                Some(ValueVec::new(DJB_HASH_SIZE, num_entries))
+            } else {
+                None
+            },
             counters: ValueVec::new(bits_per_entry, num_entries),
             num_entries: num_entries as u64,
             num_hashes,
+            partitioned,
             seed,
-            hash_builder_one: SipHasher13::new_with_keys(rng.gen(), rng.gen()),
-            hash_builder_two: SipHasher13::new_with_keys(rng.gen(), rng.gen()),
+            hash_builder: SipHasher13::new_with_keys(rng.gen(), rng.gen()),
+            hash_builder_check: SipHasher13::new_with_keys(rng.gen(), rng.gen()),
         }
     }
 
@@ -78,12 +149,41 @@ impl InvBloomLookupTable {
         let bits_per_entry = self.counters.bits_per_val();
         Self {
             data: ValueVec::new(DJB_HASH_SIZE, self.num_entries as usize),
+            hash_checks: self.hash_checks.as_ref().map(|_| {
+                ValueVec::new(DJB_HASH_SIZE, self.num_entries as usize)
+            }),
             counters: ValueVec::new(bits_per_entry, self.num_entries as usize),
             num_entries: self.num_entries,
             num_hashes: self.num_hashes,
+            partitioned: self.partitioned,
+            seed: self.seed,
+            hash_builder: self.hash_builder,
+            hash_builder_check: self.hash_builder_check,
+        }
+    }
+
+    /// Clones the InvBloomLookupTable with `bits_per_entry` bits per counter,
+    /// e.g. to store counts that do not fit in the original counters.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a counter does not fit in the new number of bits.
+    pub fn with_counter_bits(&self, bits_per_entry: usize) -> Self {
+        let mut counters =
+            ValueVec::new(bits_per_entry, self.num_entries as usize);
+        for (i, count) in self.counters.iter().enumerate() {
+            counters.set(i, count);
+        }
+        Self {
+            counters,
+            data: self.data.clone(),
+            hash_checks: self.hash_checks.clone(),
+            num_entries: self.num_entries,
+            num_hashes: self.num_hashes,
+            partitioned: self.partitioned,
             seed: self.seed,
-            hash_builder_one: self.hash_builder_one.clone(),
-            hash_builder_two: self.hash_builder_two.clone(),
+            hash_builder: self.hash_builder,
+            hash_builder_check: self.hash_builder_check,
         }
     }
 
@@ -95,6 +195,26 @@ impl InvBloomLookupTable {
         &mut self.data
     }
 
+    pub fn hash_checks(&self) -> Option<&ValueVec> {
+        self.hash_checks.as_ref()
+    }
+
+    pub fn hash_checks_mut(&mut self) -> Option<&mut ValueVec> {
+        self.hash_checks.as_mut()
+    }
+
+    /// Whether each cell stores a hash check field.
+    pub fn has_hash_check(&self) -> bool {
+        self.hash_checks.is_some()
+    }
+
+    /// The keyed checksum of an element that has been mapped to a u32.
+    pub fn hash_check(&self, item_u32: u32) -> u32 {
+        let mut hasher = self.hash_builder_check;
+        item_u32.hash(&mut hasher);
+        hasher.finish() as u32
+    }
+
     pub fn counters(&self) -> &ValueVec {
         &self.counters
     }
@@ -115,29 +235,38 @@ impl InvBloomLookupTable {
         self.seed
     }
 
+    /// Whether each hash function maps into its own subtable.
+    pub fn partitioned(&self) -> bool {
+        self.partitioned
+    }
+
     pub fn equals(&self, other: &Self) -> bool {
         let a = self.num_entries == other.num_entries;
         let b = self.num_hashes == other.num_hashes;
-        let c = self.hash_builder_one.keys() == other.hash_builder_one.keys();
-        let d = self.hash_builder_two.keys() == other.hash_builder_two.keys();
+        let c = self.hash_builder.keys() == other.hash_builder.keys();
+        let d = self.partitioned == other.partitioned;
         let e = self.data == other.data;
         let f = self.counters == other.counters;
-        println!("{} {} {} {} {} {}", a, b, c, d, e, f);
-        println!("{} {}", self.data.bits_per_val, other.data.bits_per_val);
-
-        a && b && c && d && e && f
+        let g = self.hash_checks == other.hash_checks
+            && self.hash_builder_check.keys() == other.hash_builder_check.keys();
+        a && b && c && d && e && f && g
     }
 
     /// Inserts an item, returns true if the item was already in the filter
     /// any number of times.
     pub fn insert(&mut self, item: &[u8]) -> bool {
-        let mut min = u32::max_value();
         let item_u32 = elem_to_u32(item);
-        for h in HashIter::from(item_u32,
-                                self.num_hashes,
-                                &self.hash_builder_one,
-                                &self.hash_builder_two) {
-            let idx = (h % self.num_entries) as usize;
+        self.insert_u32(item_u32)
+    }
+
+    fn insert_u32(&mut self, item_u32: u32) -> bool {
+        let mut min = u64::max_value();
+        let check = if self.has_hash_check() {
+            self.hash_check(item_u32)
+        } else {
+            0
+        };
+        for idx in self.index_iter(item_u32) {
             let cur = self.counters.get(idx);
             if cur < min {
                 min = cur;
@@ -148,8 +277,10 @@ impl InvBloomLookupTable {
                 // TODO: write a test for wraparound
                 self.counters.set(idx, 0);
             }
-            self.data.set(
-                idx, (Wrapping(self.data.get(idx)) + Wrapping(item_u32)).0);
+            self.data.wrapping_add_at(idx, item_u32 as u64);
+            if let Some(hash_checks) = self.hash_checks.as_mut() {
+                hash_checks.wrapping_add_at(idx, check as u64);
+            }
         }
         min > 0
     }
@@ -161,11 +292,12 @@ impl InvBloomLookupTable {
     }
 
     fn remove_u32(&mut self, item_u32: u32) {
-        for h in HashIter::from(item_u32,
-                            self.num_hashes,
-                            &self.hash_builder_one,
-                            &self.hash_builder_two) {
-            let idx = (h % self.num_entries) as usize;
+        let check = if self.has_hash_check() {
+            self.hash_check(item_u32)
+        } else {
+            0
+        };
+        for idx in self.index_iter(item_u32) {
             let cur = self.counters.get(idx);
             if cur == 0 {
                 // wraparound
@@ -173,8 +305,37 @@ impl InvBloomLookupTable {
             } else {
                 self.counters.set(idx, cur - 1);
             }
-            self.data.set(
-                idx, (Wrapping(self.data.get(idx)) - Wrapping(item_u32)).0);
+            self.data.wrapping_sub_at(idx, item_u32 as u64);
+            if let Some(hash_checks) = self.hash_checks.as_mut() {
+                hash_checks.wrapping_sub_at(idx, check as u64);
+            }
+        }
+    }
+
+    /// Whether the cell at index `i` holds exactly one removed element, i.e.
+    /// the counter is -1 and the data and hash check are the negations of
+    /// those of a single element. Requires the hash check.
+    fn is_pure_negative(&self, i: usize) -> bool {
+        if self.counters.get(i) != self.counters.max_value() {
+            return false;
+        }
+        let item = (Wrapping(0) - Wrapping(self.data.get(i) as u32)).0;
+        let check = (Wrapping(0) - Wrapping(self.hash_check(item))).0;
+        self.hash_checks.as_ref().unwrap().get(i) == check as u64
+    }
+
+    /// Whether the cell at index `i` holds exactly one element, according to
+    /// the counter and, if enabled, the hash check.
+    fn is_pure(&self, i: usize) -> bool {
+        if self.counters.get(i) != 1 {
+            return false;
+        }
+        match &self.hash_checks {
+            Some(hash_checks) => {
+                let item = self.data.get(i) as u32;
+                hash_checks.get(i) == self.hash_check(item) as u64
+            },
+            None => true,
         }
     }
 
@@ -182,11 +343,7 @@ impl InvBloomLookupTable {
     /// This function can return false positives, but not false negatives.
     pub fn contains(&self, item: &[u8]) -> bool {
         let item_u32 = elem_to_u32(item);
-        for h in HashIter::from(item_u32,
-                                self.num_hashes,
-                                &self.hash_builder_one,
-                                &self.hash_builder_two) {
-            let idx = (h % self.num_entries) as usize;
+        for idx in self.index_iter(item_u32) {
             let cur = self.counters.get(idx);
             if cur == 0 {
                 return false;
@@ -198,38 +355,129 @@ impl InvBloomLookupTable {
     /// Gets the indexes of the item in the vector.
     pub fn indexes(&self, item: &[u8]) -> Vec<usize> {
         let item_u32 = elem_to_u32(item);
-        HashIter::from(item_u32,
-                       self.num_hashes,
-                       &self.hash_builder_one,
-                       &self.hash_builder_two)
-            .into_iter()
-            .map(|h| (h % self.num_entries) as usize)
+        self.indexes_u32(item_u32)
+    }
+
+    fn indexes_u32(&self, item_u32: u32) -> Vec<usize> {
+        self.index_iter(item_u32).collect()
+    }
+
+    fn index_iter(&self, item_u32: u32) -> IndexIter {
+        HashIter::from(item_u32, self.num_hashes, &self.hash_builder)
+            .indexes(self.num_entries, self.partitioned)
+    }
+
+    /// Whether the cell at index `i` can be peeled, either as an inserted
+    /// element or, if `signed`, as a removed element.
+    fn is_peelable(&self, i: usize, signed: bool) -> bool {
+        self.is_pure(i) || (signed && self.is_pure_negative(i))
+    }
+
+    /// Peels pure cells using a worklist. After an initial scan of all cells,
+    /// only the cells touched by a removal are revisited, so this is
+    /// O(num_entries + num_hashes * num_peeled) rather than a full scan per
+    /// round. Returns the peeled elements in order, and whether each had a
+    /// count of 1 (true) or -1 (false).
+    fn peel(&mut self, signed: bool) -> Result<Vec<(u32, bool)>, PeelError> {
+        let mut order = vec![];
+        let mut peeled = HashSet::new();
+        let mut queue = (0..(self.num_entries as usize))
+            .filter(|&i| self.is_peelable(i, signed))
+            .collect::<VecDeque<_>>();
+        while let Some(i) = queue.pop_front() {
+            // The cell may have changed since it was queued
+            let (item, inserted) = if self.is_pure(i) {
+                (self.data.get(i) as u32, true)
+            } else if signed && self.is_pure_negative(i) {
+                ((Wrapping(0) - Wrapping(self.data.get(i) as u32)).0, false)
+            } else {
+                continue;
+            };
+            if !peeled.insert(item) {
+                return Err(PeelError::DuplicateElem(item));
+            }
+            if inserted {
+                self.remove_u32(item);
+            } else {
+                self.insert_u32(item);
+            }
+            order.push((item, inserted));
+            for idx in self.indexes_u32(item) {
+                if self.is_peelable(idx, signed) {
+                    queue.push_back(idx);
+                }
+            }
+        }
+        Ok(order)
+    }
+
+    /// The indexes of cells that look pure by their counter, i.e. 1 or, if
+    /// `signed`, -1, but that fail the hash check.
+    fn mismatched_cells(&self, signed: bool) -> Vec<usize> {
+        let max_value = self.counters.max_value();
+        (0..(self.num_entries as usize))
+            .filter(|&i| {
+                let count = self.counters.get(i);
+                count == 1 || (signed && count == max_value)
+            })
             .collect()
     }
 
     /// Enumerates as many items as possible in the IBLT and removes them.
-    /// Returns the removed items. Note removed elements must be unique
-    /// because the corresponding counters would be at least 2.
+    /// Returns the removed items in the order they were peeled, and the
+    /// indexes of the remaining nonzero cells. Note removed elements must be
+    /// unique because the corresponding counters would be at least 2.
     /// The caller will need to map elements to u32.
-    pub fn eliminate_elems(&mut self) -> HashSet<u32> {
-        // Loop through all the counters of the IBLT until there are no
-        // remaining cells with count 1. This is O(num_counters*max_count).
-        let mut removed_set: HashSet<u32> = HashSet::new();
-        loop {
-            let mut removed = false;
-            for i in 0..(self.num_entries as usize) {
-                if self.counters.get(i) != 1 {
-                    continue;
-                }
-                let item = self.data.get(i).clone();
-                self.remove_u32(item);
-                assert!(removed_set.insert(item));
-                removed = true;
+    ///
+    /// Returns an error if an element would be removed twice, or if the hash
+    /// check is enabled and a cell with a counter of 1 remains whose checksum
+    /// does not match its data. Either is evidence of tampering unless the
+    /// counters may have wrapped around, which the caller must rule out.
+    pub fn eliminate_elems(&mut self) -> Result<PeelResult, PeelError> {
+        let order = self.peel(false)?
+            .into_iter()
+            .map(|(item, _)| item)
+            .collect::<Vec<_>>();
+        if self.hash_checks.is_some() {
+            let mismatched = self.mismatched_cells(false);
+            if !mismatched.is_empty() {
+                return Err(PeelError::HashCheckMismatch(mismatched));
             }
-            if !removed {
-                return removed_set;
+        }
+        let core = (0..(self.num_entries as usize))
+            .filter(|&i| self.counters.get(i) != 0)
+            .collect();
+        Ok(PeelResult {
+            removed: order.iter().copied().collect(),
+            order,
+            core,
+        })
+    }
+
+    /// Like `eliminate_elems()`, but interprets the counters as signed
+    /// integers modulo 2^bits_per_entry, as in the difference of two IBLTs
+    /// where neither set is a subset of the other. Cells with a count of 1
+    /// are peeled as inserted elements, and cells with a count of -1 are
+    /// peeled as removed elements. Requires the hash check, since otherwise
+    /// a count of -1 is far more likely to be a mix of several elements.
+    ///
+    /// Returns an error if an element would be peeled twice, or if a cell
+    /// with a count of 1 or -1 remains whose hash check does not match.
+    pub fn eliminate_elems_signed(&mut self) -> Result<SignedElems, PeelError> {
+        assert!(self.has_hash_check(), "signed peeling requires hash check");
+        let mut elems = SignedElems::default();
+        for (item, inserted) in self.peel(true)? {
+            if inserted {
+                elems.inserted.insert(item);
+            } else {
+                elems.removed.insert(item);
             }
         }
+        let mismatched = self.mismatched_cells(true);
+        if !mismatched.is_empty() {
+            return Err(PeelError::HashCheckMismatch(mismatched));
+        }
+        Ok(elems)
     }
 }
 
@@ -243,18 +491,11 @@ mod tests {
     }
 
     fn vvsum(vec: &ValueVec) -> usize {
-        let num_entries = vec.len() / vec.bits_per_val();
-        (0..num_entries).map(|i| vec.get(i)).sum::<u32>() as usize
+        vec.sum() as usize
     }
 
     fn data_is_nonzero(vec: &ValueVec) -> bool {
-        let num_entries = vec.len() / vec.bits_per_val();
-        for i in 0..num_entries {
-            if vec.get(i) != 0 {
-                return true;
-            }
-        }
-        false
+        vec.count_nonzero() > 0
     }
 
     #[test]
@@ -344,6 +585,22 @@ mod tests {
             iblt2.indexes(&1234_u32.to_be_bytes()));
     }
 
+    #[test]
+    fn test_with_counter_bits() {
+        let mut iblt = InvBloomLookupTable::new_with_hash_check(111, 2, 10, 2);
+        for i in 0..5_u32 {
+            iblt.insert(&i.to_be_bytes());
+        }
+        let wide = iblt.with_counter_bits(16);
+        assert_eq!(wide.counters().bits_per_val(), 16);
+        assert_eq!(
+            wide.counters().iter().collect::<Vec<_>>(),
+            iblt.counters().iter().collect::<Vec<_>>());
+        assert!(wide.data() == iblt.data());
+        assert!(wide.hash_checks() == iblt.hash_checks());
+        assert_eq!(wide.indexes(&[1, 2, 3, 4]), iblt.indexes(&[1, 2, 3, 4]));
+    }
+
     #[test]
     fn test_insert_with_counter_overflow() {
         let mut iblt = InvBloomLookupTable::new(1, 10, 1);  // 1 bit per entry
@@ -354,12 +611,12 @@ mod tests {
         // counters and data are updated
         iblt.insert(&elem);
         assert_eq!(iblt.counters().get(i), 1);
-        assert_eq!(iblt.data().get(i), elem_u32);
+        assert_eq!(iblt.data().get(i), elem_u32 as u64);
 
         // on overflow, counter is zero but data is nonzero
         iblt.insert(&elem);
         assert_eq!(iblt.counters().get(i), 0);
-        assert_eq!(iblt.data().get(i), elem_u32 * 2);
+        assert_eq!(iblt.data().get(i), elem_u32 as u64 * 2);
     }
 
     #[test]
@@ -373,13 +630,13 @@ mod tests {
         // counters and data are updated
         iblt.insert(&elem);
         assert_eq!(iblt.counters().get(i), 1);
-        assert_eq!(iblt.data().get(i), elem_u32);
+        assert_eq!(iblt.data().get(i), elem_u32 as u64);
 
         // on overflow, counter is zero but data is nonzero
         iblt.insert(&elem);
         iblt.insert(&elem);
         assert_eq!(iblt.counters().get(i), 3);
-        assert!(iblt.data().get(i) < elem_u32);
+        assert!(iblt.data().get(i) < elem_u32 as u64);
     }
 
     #[test]
@@ -394,9 +651,16 @@ mod tests {
         }
         assert_eq!(vvsum(iblt.counters()), n * (iblt.num_hashes() as usize));
         assert_eq!(hashes.len(), n, "djb hashes are unique in this test");
+        for i in 0..n {
+            let indexes = iblt.indexes(&(i as u32).to_be_bytes());
+            assert_ne!(indexes[0], indexes[1], "no element is a self-loop");
+        }
 
         // Return the original elements
-        let elems = iblt.eliminate_elems();
+        let elems = iblt.eliminate_elems().unwrap();
+        assert_eq!(elems.order.len(), n);
+        assert!(elems.core.is_empty());
+        let elems = elems.removed;
         assert_eq!(elems.len(), n);
         assert_eq!(vvsum(iblt.counters()), 0);
         assert_eq!(vvsum(iblt.data()), 0);
@@ -420,7 +684,12 @@ mod tests {
         assert_eq!(hashes.len(), n, "djb hashes are unique in this test");
 
         // Not all elements were eliminated
-        let elems = iblt.eliminate_elems();
+        let elems = iblt.eliminate_elems().unwrap();
+        assert!(!elems.core.is_empty());
+        for &i in &elems.core {
+            assert!(iblt.counters().get(i) >= 2, "stuck cells are not pure");
+        }
+        let elems = elems.removed;
         assert!(elems.len() < n);
         assert_eq!(vvsum(iblt.counters()),
             (n - elems.len()) * (iblt.num_hashes() as usize));
@@ -428,5 +697,155 @@ mod tests {
         // Test that the sums were updated correctly?
         assert!(data_is_nonzero(iblt.data()));
     }
-}
 
+    #[test]
+    fn test_hash_check_serialization_and_empty_clone() {
+        let mut iblt1 = InvBloomLookupTable::new_with_hash_check(111, 8, 100, 2);
+        assert!(iblt1.has_hash_check());
+        iblt1.insert(&1234_u32.to_be_bytes());
+        assert!(data_is_nonzero(iblt1.hash_checks().unwrap()));
+        let bytes = bincode::serialize(&iblt1).unwrap();
+        let iblt2: InvBloomLookupTable = bincode::deserialize(&bytes).unwrap();
+        assert!(iblt1.equals(&iblt2));
+        let iblt3 = iblt1.empty_clone();
+        assert!(iblt3.has_hash_check());
+        assert_eq!(vvsum(iblt3.hash_checks().unwrap()), 0);
+        let iblt4 = InvBloomLookupTable::new_with_seed(111, 8, 100, 2);
+        assert!(!iblt4.has_hash_check());
+        assert_eq!(
+            iblt4.indexes(&1234_u32.to_be_bytes()),
+            iblt1.indexes(&1234_u32.to_be_bytes()),
+            "hash check does not change the indexes");
+    }
+
+    #[test]
+    fn test_eliminate_all_elems_with_hash_check() {
+        let mut iblt = InvBloomLookupTable::new_with_hash_check(111, 8, 10, 2);
+        let n: usize = 6;
+        for i in 0..n {
+            let elem = (i as u32).to_be_bytes();
+            let indexes = iblt.indexes(&elem);
+            assert_ne!(indexes[0], indexes[1], "no element is a self-loop");
+            iblt.insert(&elem);
+        }
+        let elems = iblt.eliminate_elems().unwrap().removed;
+        assert_eq!(elems.len(), n);
+        assert_eq!(vvsum(iblt.counters()), 0);
+        assert!(!data_is_nonzero(iblt.hash_checks().unwrap()));
+    }
+
+    #[test]
+    fn test_eliminate_elems_mixed_cell() {
+        // A single cell holding two inserted and one removed element reads as
+        // a counter of 1, but the data is not an element.
+        let (a, b, c) = (
+            1_u32.to_be_bytes(), 2_u32.to_be_bytes(), 3_u32.to_be_bytes());
+        let mut iblt = InvBloomLookupTable::new_with_hash_check(111, 8, 1, 1);
+        iblt.insert(&a);
+        iblt.insert(&b);
+        iblt.remove(&c);
+        assert_eq!(iblt.counters().get(0), 1);
+        assert_eq!(
+            iblt.eliminate_elems(),
+            Err(PeelError::HashCheckMismatch(vec![0])));
+        assert_eq!(iblt.counters().get(0), 1, "mixed cell was not peeled");
+
+        // Without the hash check, the mixed cell is peeled as an element.
+        let mut iblt = InvBloomLookupTable::new_with_seed(111, 8, 1, 1);
+        iblt.insert(&a);
+        iblt.insert(&b);
+        iblt.remove(&c);
+        let elems = iblt.eliminate_elems().unwrap().removed;
+        assert_eq!(elems.len(), 1);
+        assert!(!elems.contains(&elem_to_u32(&a)));
+    }
+
+    #[test]
+    fn test_eliminate_elems_signed() {
+        let mut iblt = InvBloomLookupTable::new_with_hash_check(111, 8, 30, 2);
+        let inserted = (0..4_u32)
+            .map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+        let removed = (100..103_u32)
+            .map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+        for elem in &inserted {
+            iblt.insert(elem);
+        }
+        for elem in &removed {
+            iblt.remove(elem);
+        }
+        let elems = iblt.eliminate_elems_signed().unwrap();
+        assert_eq!(elems.inserted, inserted.iter()
+            .map(|elem| elem_to_u32(elem)).collect::<HashSet<_>>());
+        assert_eq!(elems.removed, removed.iter()
+            .map(|elem| elem_to_u32(elem)).collect::<HashSet<_>>());
+        assert_eq!(vvsum(iblt.counters()), 0);
+        assert!(!data_is_nonzero(iblt.data()));
+    }
+
+    #[test]
+    fn test_eliminate_elems_signed_mixed_cell() {
+        // Two removed elements and one inserted element in the same cell
+        // read as a counter of -1, but cannot be peeled.
+        let mut iblt = InvBloomLookupTable::new_with_hash_check(111, 8, 1, 1);
+        iblt.remove(&1_u32.to_be_bytes());
+        iblt.remove(&2_u32.to_be_bytes());
+        iblt.insert(&3_u32.to_be_bytes());
+        assert_eq!(iblt.counters().get(0), iblt.counters().max_value());
+        assert_eq!(
+            iblt.eliminate_elems_signed(),
+            Err(PeelError::HashCheckMismatch(vec![0])));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_eliminate_elems_signed_requires_hash_check() {
+        let mut iblt = InvBloomLookupTable::new_with_seed(111, 8, 10, 2);
+        let _ = iblt.eliminate_elems_signed();
+    }
+
+    #[test]
+    fn test_eliminate_elems_peel_order() {
+        // Each element in the peel order was in a pure cell at the time it was
+        // peeled, given the elements peeled before it.
+        let mut iblt = InvBloomLookupTable::new_with_seed(111, 8, 1000, 2);
+        let n: u32 = 50;
+        for i in 0..n {
+            iblt.insert(&i.to_be_bytes());
+        }
+        let mut copy = iblt.empty_clone();
+        for i in 0..n {
+            copy.insert(&i.to_be_bytes());
+        }
+        let result = iblt.eliminate_elems().unwrap();
+        assert_eq!(result.order.len(), result.removed.len());
+        assert!(result.core.is_empty());
+
+        // Replaying the peel order always finds a pure cell for the element.
+        for item in result.order {
+            let indexes = copy.indexes_u32(item);
+            assert!(indexes.iter().any(|&i| copy.is_pure(i)));
+            copy.remove_u32(item);
+        }
+        assert_eq!(vvsum(copy.counters()), 0);
+    }
+
+    #[test]
+    fn test_partitioned_iblt() {
+        let mut iblt = InvBloomLookupTable::new_with_options(
+            111, 8, 30, 3, true, true);
+        assert!(iblt.partitioned());
+        for i in 0..100_u32 {
+            let indexes = iblt.indexes(&i.to_be_bytes());
+            for (j, &index) in indexes.iter().enumerate() {
+                assert_eq!(index / 10, j, "hash j maps into subtable j");
+            }
+        }
+        let n: u32 = 8;
+        for i in 0..n {
+            iblt.insert(&i.to_be_bytes());
+        }
+        let elems = iblt.eliminate_elems().unwrap();
+        assert_eq!(elems.removed.len(), n as usize);
+        assert!(iblt.equals(&iblt.empty_clone()));
+    }
+}
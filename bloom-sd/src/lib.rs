@@ -2,11 +2,16 @@ mod hashing;
 mod valuevec;
 mod cbf;
 mod iblt;
+mod analysis;
+mod strata;
 
 pub use cbf::CountingBloomFilter;
-pub use iblt::InvBloomLookupTable;
+pub use iblt::{InvBloomLookupTable, PeelError, PeelResult, SignedElems};
 pub use iblt::{elem_to_u32, DJB_HASH_SIZE};
 pub use valuevec::ValueVec;
+pub use analysis::{peel_failure_probability, wraparound_probability};
+pub use analysis::expected_wraps;
+pub use strata::{StrataEstimator, DEFAULT_NUM_STRATA, DEFAULT_CELLS_PER_STRATUM};
 
 use bit_vec::BitVec;
 use siphasher::sip128::SipHasher13;
@@ -0,0 +1,290 @@
+//! A strata estimator of the size of the difference of two sets, from
+//! "What's the Difference? Efficient Set Reconciliation without Prior
+//! Context" (Eppstein et al., SIGCOMM 2011).
+use std::fmt;
+use std::hash::Hasher;
+use serde::{Serialize, Deserialize};
+use siphasher::sip128::SipHasher13;
+
+use crate::iblt::{InvBloomLookupTable, DJB_HASH_SIZE};
+use crate::valuevec::ValueVec;
+
+pub const DEFAULT_NUM_STRATA: usize = 16;
+pub const DEFAULT_CELLS_PER_STRATUM: usize = 16;
+/// The counters only need to hold the per-cell difference of the two sets,
+/// so they are allowed to wrap around when inserting.
+const STRATA_BITS_PER_ENTRY: usize = 8;
+const STRATA_NUM_HASHES: u32 = 3;
+
+/// Estimates the number of elements in one set but not the other.
+///
+/// Stratum i is a small IBLT of the elements whose keyed hash has i trailing
+/// zeros, i.e. about 1/2^(i+1) of the elements. To estimate the difference of
+/// two sets, the strata of their estimators are subtracted and peeled from
+/// the deepest up. The first stratum that does not peel holds too many
+/// elements to count, so the count of the strata below it is scaled by the
+/// rate at which they sample elements.
+///
+/// The estimate is only meant to choose how to validate. It is accurate to
+/// within a small factor, and counts elements in the other set but not in
+/// this one as undecodable, overestimating the difference.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "MiniStrataEstimator", from = "MiniStrataEstimator")]
+pub struct StrataEstimator {
+    seed: u64,
+    strata: Vec<InvBloomLookupTable>,
+    sampler: SipHasher13,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MiniStrataEstimator {
+    seed: u64,
+    num_strata: u8,
+    cells_per_stratum: u16,
+    counters: Vec<u8>, // bits_per_val = STRATA_BITS_PER_ENTRY
+    data: Vec<u8>,     // bits_per_val = DJB_HASH_SIZE
+}
+
+impl From<StrataEstimator> for MiniStrataEstimator {
+    fn from(estimator: StrataEstimator) -> Self {
+        Self {
+            seed: estimator.seed,
+            num_strata: estimator.num_strata() as u8,
+            cells_per_stratum: estimator.cells_per_stratum() as u16,
+            counters: estimator.strata.iter()
+                .flat_map(|iblt| iblt.counters().bits.to_bytes())
+                .collect(),
+            data: estimator.strata.iter()
+                .flat_map(|iblt| iblt.data().bits.to_bytes())
+                .collect(),
+        }
+    }
+}
+
+impl From<MiniStrataEstimator> for StrataEstimator {
+    fn from(x: MiniStrataEstimator) -> Self {
+        let num_strata = x.num_strata as usize;
+        let cells = x.cells_per_stratum as usize;
+        let mut estimator = Self::new_with_params(x.seed, num_strata, cells);
+        let counters_len = (cells * STRATA_BITS_PER_ENTRY).div_ceil(8);
+        let data_len = (cells * DJB_HASH_SIZE).div_ceil(8);
+        for (i, iblt) in estimator.strata.iter_mut().enumerate() {
+            *iblt.counters_mut() = ValueVec::from_bytes(
+                STRATA_BITS_PER_ENTRY,
+                &x.counters[(i * counters_len)..((i + 1) * counters_len)],
+            );
+            *iblt.data_mut() = ValueVec::from_bytes(
+                DJB_HASH_SIZE,
+                &x.data[(i * data_len)..((i + 1) * data_len)],
+            );
+        }
+        estimator
+    }
+}
+
+impl fmt::Debug for StrataEstimator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StrataEstimator")
+            .field("seed", &self.seed)
+            .field("num_strata", &self.num_strata())
+            .field("cells_per_stratum", &self.cells_per_stratum())
+            .finish()
+    }
+}
+
+impl PartialEq for StrataEstimator {
+    fn eq(&self, other: &Self) -> bool {
+        self.equals(other)
+    }
+}
+
+impl Eq for StrataEstimator {}
+
+impl StrataEstimator {
+    /// Creates an estimator with the default number of strata and cells.
+    /// Estimators must have the same seed to be compared.
+    pub fn new(seed: u64) -> Self {
+        Self::new_with_params(
+            seed, DEFAULT_NUM_STRATA, DEFAULT_CELLS_PER_STRATUM)
+    }
+
+    /// Creates an estimator with `num_strata` strata of `cells_per_stratum`
+    /// cells each. More strata estimate larger differences, and more cells
+    /// estimate them more accurately.
+    pub fn new_with_params(
+        seed: u64,
+        num_strata: usize,
+        cells_per_stratum: usize,
+    ) -> Self {
+        assert!(num_strata > 0 && num_strata <= u8::MAX as usize);
+        assert!(cells_per_stratum > 0
+            && cells_per_stratum <= u16::MAX as usize);
+        let strata = (0..num_strata)
+            .map(|i| InvBloomLookupTable::new_with_seed(
+                seed.wrapping_add(i as u64),
+                STRATA_BITS_PER_ENTRY,
+                cells_per_stratum,
+                STRATA_NUM_HASHES,
+            ))
+            .collect();
+        Self {
+            seed,
+            strata,
+            sampler: SipHasher13::new_with_keys(seed, !seed),
+        }
+    }
+
+    /// Clones the estimator where all strata are empty.
+    pub fn empty_clone(&self) -> Self {
+        Self {
+            seed: self.seed,
+            strata: self.strata.iter().map(|iblt| iblt.empty_clone()).collect(),
+            sampler: self.sampler,
+        }
+    }
+
+    /// The number of bytes of an estimator with `num_strata` strata of
+    /// `cells_per_stratum` cells when serialized with bincode.
+    pub fn serialized_len(
+        num_strata: usize,
+        cells_per_stratum: usize,
+    ) -> usize {
+        let cells = cells_per_stratum;
+        let counters_len = (cells * STRATA_BITS_PER_ENTRY).div_ceil(8);
+        let data_len = (cells * DJB_HASH_SIZE).div_ceil(8);
+        // seed, num_strata, cells_per_stratum, and a u64 length per Vec
+        8 + 1 + 2 + 8 + 8 + num_strata * (counters_len + data_len)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn num_strata(&self) -> usize {
+        self.strata.len()
+    }
+
+    pub fn cells_per_stratum(&self) -> usize {
+        self.strata[0].num_entries() as usize
+    }
+
+    pub fn equals(&self, other: &Self) -> bool {
+        self.seed == other.seed
+            && self.strata.len() == other.strata.len()
+            && self.strata.iter().zip(&other.strata)
+                .all(|(iblt1, iblt2)| iblt1.equals(iblt2))
+    }
+
+    /// The stratum the item belongs to.
+    fn stratum(&self, item: &[u8]) -> usize {
+        let mut hasher = self.sampler;
+        hasher.write(item);
+        let trailing_zeros = hasher.finish().trailing_zeros() as usize;
+        std::cmp::min(trailing_zeros, self.strata.len() - 1)
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        let i = self.stratum(item);
+        self.strata[i].insert(item);
+    }
+
+    /// Estimates the number of elements inserted into this estimator but not
+    /// `other`, e.g. the elements of a log that a router did not process.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the estimators do not have the same parameters.
+    pub fn estimate_difference(&self, other: &Self) -> usize {
+        assert_eq!(self.seed, other.seed, "estimators have different seeds");
+        assert_eq!(self.num_strata(), other.num_strata());
+        assert_eq!(self.cells_per_stratum(), other.cells_per_stratum());
+        let mut count = 0;
+        for i in (0..self.strata.len()).rev() {
+            let mut iblt = self.strata[i].clone();
+            iblt.counters_mut().wrapping_sub(other.strata[i].counters());
+            iblt.data_mut().wrapping_sub(other.strata[i].data());
+            match iblt.eliminate_elems() {
+                Ok(peeled) if peeled.core.is_empty() => {
+                    count += peeled.removed.len();
+                },
+                _ => {
+                    // strata 0..=i sample 1 - 1/2^(i+1) of the elements, so
+                    // the strata above sample 1/2^(i+1) of them
+                    return std::cmp::max(count, 1) << (i + 1);
+                },
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_elems(start: u32, end: u32) -> Vec<[u8; 4]> {
+        (start..end).map(|i| i.to_be_bytes()).collect()
+    }
+
+    fn build(seed: u64, elems: &[[u8; 4]]) -> StrataEstimator {
+        let mut estimator = StrataEstimator::new(seed);
+        for elem in elems {
+            estimator.insert(elem);
+        }
+        estimator
+    }
+
+    #[test]
+    fn test_serialization() {
+        let estimator1 = build(123, &gen_elems(0, 1000));
+        let bytes = bincode::serialize(&estimator1).unwrap();
+        let estimator2: StrataEstimator = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(estimator1, estimator2);
+        assert_ne!(estimator1, estimator1.empty_clone());
+    }
+
+    #[test]
+    fn test_serialized_len() {
+        for &(num_strata, cells) in &[(16, 16), (1, 1), (5, 7)] {
+            let estimator = StrataEstimator::new_with_params(0, num_strata, cells);
+            let bytes = bincode::serialize(&estimator).unwrap();
+            assert_eq!(bytes.len(),
+                StrataEstimator::serialized_len(num_strata, cells));
+        }
+    }
+
+    #[test]
+    fn test_no_difference() {
+        let elems = gen_elems(0, 1000);
+        let estimator1 = build(123, &elems);
+        let estimator2 = build(123, &elems);
+        assert_eq!(estimator1.estimate_difference(&estimator2), 0);
+    }
+
+    #[test]
+    fn test_small_difference_is_exact() {
+        let elems = gen_elems(0, 1000);
+        let estimator1 = build(123, &elems);
+        let estimator2 = build(123, &elems[5..]);
+        assert_eq!(estimator1.estimate_difference(&estimator2), 5);
+    }
+
+    #[test]
+    fn test_large_difference_is_close() {
+        let elems = gen_elems(0, 10000);
+        let estimator1 = build(123, &elems);
+        for &n_dropped in &[200, 1000, 4000] {
+            let estimator2 = build(123, &elems[n_dropped..]);
+            let estimate = estimator1.estimate_difference(&estimator2);
+            assert!(estimate >= n_dropped / 4 && estimate <= n_dropped * 4,
+                "estimated {} for {}", estimate, n_dropped);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_different_seeds() {
+        let elems = gen_elems(0, 10);
+        build(123, &elems).estimate_difference(&build(456, &elems));
+    }
+}
@@ -19,30 +19,50 @@ use serde::{Serialize, Deserialize};
 use crate::BitVecDef;
 
 /// A ValueVec is a bit vector that holds fixed sized unsigned integer
-/// values.
-#[derive(Serialize, Deserialize, PartialEq, Eq)]
+/// values of up to 64 bits.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct ValueVec {
     pub bits_per_val: usize,
-    pub mask: u32,
+    pub mask: u64,
     #[serde(with = "BitVecDef")]
     pub bits: BitVec,
 }
 
+fn mask_for(bits_per_val: usize) -> u64 {
+    if bits_per_val == 64 {
+        u64::MAX
+    } else {
+        (1_u64 << (bits_per_val as u64)) - 1
+    }
+}
+
 impl ValueVec {
 
     /// Create a ValueVec that holds values with `bits_per_val` bits and
     /// space to hold `count` values.
     pub fn new(bits_per_val: usize, count: usize) -> ValueVec {
         assert!(bits_per_val > 0);
-        assert!(bits_per_val <= 32);
+        assert!(bits_per_val <= 64);
         let bits = bits_per_val*count;
         ValueVec {
             bits_per_val: bits_per_val,
-            mask: ((1_u64 << (bits_per_val as u64))-1) as u32,
+            mask: mask_for(bits_per_val),
             bits: BitVec::from_elem(bits,false),
         }
     }
 
+    /// Create a ValueVec that holds values with `bits_per_val` bits from
+    /// the bytes of its bit vector, e.g. as returned by `bits.to_bytes()`.
+    pub fn from_bytes(bits_per_val: usize, bytes: &[u8]) -> ValueVec {
+        assert!(bits_per_val > 0);
+        assert!(bits_per_val <= 64);
+        ValueVec {
+            bits_per_val,
+            mask: mask_for(bits_per_val),
+            bits: BitVec::from_bytes(bytes),
+        }
+    }
+
     /// Create a ValueVec that can hold `count` values where the
     /// maximam value of each entry is at least `max_val` (inclusive)
     ///
@@ -54,7 +74,7 @@ impl ValueVec {
     /// vv.set(0,7); // okay
     /// vv.set(0,8); // will panic
     /// ```
-    pub fn with_max(max_val: u32, count: usize) -> ValueVec {
+    pub fn with_max(max_val: u64, count: usize) -> ValueVec {
         let mut bits_per_val = 0;
         let mut cur = max_val;
         // there are fancy faster versions of this, but this is only
@@ -72,44 +92,36 @@ impl ValueVec {
     }
 
     /// The maximum value this ValueVec can hold per entry
-    pub fn max_value(&self) -> u32 {
+    pub fn max_value(&self) -> u64 {
         self.mask
     }
 
+    /// The number of values this ValueVec holds
+    pub fn num_values(&self) -> usize {
+        self.bits.len() / self.bits_per_val
+    }
+
     /// Resets all values to 0 in this ValueVec
     pub fn clear(&mut self) {
         self.bits.clear();
     }
 
-    fn set_bits(&mut self, idx: usize,  val: u32, num_bits: usize) {
-        let blocks = unsafe {self.bits.storage_mut()};
-        let blockidx = idx/32;
-        let shift = 32-(idx%32)-num_bits;
-        let mask =
-            if num_bits==self.bits_per_val {
-                self.mask
-            } else {
-                2u32.pow(num_bits as u32)-1
-            } << shift;
-        let block = blocks[blockidx];
-
-        // this will be the value with all bits in our value set to zero
-        let zeroed = (block ^ mask) & block;
-        // or in the new val
-        blocks[blockidx] = zeroed | (val<<shift);
-    }
-
-    fn get_bits(&self, idx: usize, num_bits: usize) -> u32 {
-        let blocks = self.bits.storage();
-        let shift = 32-(idx%32)-num_bits;
-        let mask =
-            if num_bits==self.bits_per_val {
-                self.mask
-            } else {
-                2u32.pow(num_bits as u32)-1
-            } << shift;
-        let val = blocks[idx/32] & mask;
-        val >> shift
+    /// Whether values never straddle two blocks of the backing storage, so
+    /// that whole blocks can be operated on at once.
+    fn is_block_aligned(&self) -> bool {
+        32 % self.bits_per_val == 0
+    }
+
+    /// The mask of the highest bit of every value in a block. Only
+    /// meaningful if the ValueVec is block-aligned.
+    fn high_bits(&self) -> u32 {
+        let mut high = 0_u32;
+        let mut shift = 0;
+        while shift < 32 {
+            high |= 1 << (shift + self.bits_per_val - 1);
+            shift += self.bits_per_val;
+        }
+        high
     }
 
     /// Get the total number of bits this valuevec is using
@@ -123,40 +135,226 @@ impl ValueVec {
     ///
     /// Panics if `val` needs more bits to store than the number of
     /// bits this vec is using per value
-    pub fn set(&mut self, i: usize, val: u32) {
+    pub fn set(&mut self, i: usize, val: u64) {
         if val > self.mask {
             panic!("set with val {}, max value this ValueVec can hold is {}",
                    val,self.mask);
         }
         let idx = i*self.bits_per_val;
-        //println!("idx is: {}",idx);
-        let rem = 32-(idx%32);
-        if rem < self.bits_per_val {
-            // rem is how many bits needed in the lower part
-            let left = self.bits_per_val-rem;
-            let lowerval = val>>left;
-            self.set_bits(idx,lowerval,rem);
-
-            // now put the rest of the bits in
-            let upval = val&(2u32.pow(left as u32)-1);
-            self.set_bits(idx+rem,upval,left);
-        } else {
-            let vs = self.bits_per_val;
-            self.set_bits(idx,val,vs);
+        let blocks = unsafe {self.bits.storage_mut()};
+        let blockidx = idx/32;
+        // values are stored most significant bit first across at most three
+        // consecutive 32-bit blocks, so work in a 96-bit window
+        let nblocks = (idx%32 + self.bits_per_val).div_ceil(32);
+        let shift = 96-(idx%32)-self.bits_per_val;
+        let mut window = 0_u128;
+        for j in 0..nblocks {
+            window |= (blocks[blockidx+j] as u128) << (64 - 32*j);
+        }
+        window &= !((self.mask as u128) << shift);
+        window |= (val as u128) << shift;
+        for j in 0..nblocks {
+            blocks[blockidx+j] = (window >> (64 - 32*j)) as u32;
         }
     }
 
     /// Get the value in this ValueVec stored at index `i`
-    pub fn get(&self, i: usize) -> u32 {
+    pub fn get(&self, i: usize) -> u64 {
         let idx = i*self.bits_per_val;
-        let rem = 32-(idx%32);
-        if rem < self.bits_per_val {
-            let lower = self.get_bits(idx,rem);
-            let left = self.bits_per_val-rem;
-            let upper = self.get_bits(idx+rem,left);
-            (lower<<left)|upper
+        let blocks = self.bits.storage();
+        let blockidx = idx/32;
+        if self.is_block_aligned() {
+            let shift = 32-(idx%32)-self.bits_per_val;
+            return ((blocks[blockidx] >> shift) as u64) & self.mask;
+        }
+        let nblocks = (idx%32 + self.bits_per_val).div_ceil(32);
+        let shift = 96-(idx%32)-self.bits_per_val;
+        let mut window = 0_u128;
+        for j in 0..nblocks {
+            window |= (blocks[blockidx+j] as u128) << (64 - 32*j);
+        }
+        ((window >> shift) as u64) & self.mask
+    }
+
+    /// Adds `val` to the value at index `i`, modulo 2^bits_per_val.
+    pub fn wrapping_add_at(&mut self, i: usize, val: u64) {
+        let sum = self.get(i).wrapping_add(val) & self.mask;
+        self.set(i, sum);
+    }
+
+    /// Subtracts `val` from the value at index `i`, modulo 2^bits_per_val.
+    pub fn wrapping_sub_at(&mut self, i: usize, val: u64) {
+        let difference = self.get(i).wrapping_sub(val) & self.mask;
+        self.set(i, difference);
+    }
+
+    /// Iterates over the values in this ValueVec
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.num_values()).map(move |i| self.get(i))
+    }
+
+    /// Adds each value in `other` to the corresponding value in this
+    /// ValueVec, modulo 2^bits_per_val.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ValueVecs differ in length or bits per value
+    pub fn wrapping_add(&mut self, other: &ValueVec) {
+        assert_eq!(self.bits_per_val, other.bits_per_val);
+        assert_eq!(self.bits.len(), other.bits.len());
+        if self.is_block_aligned() {
+            // add all values in a block at once without carrying between
+            // them, then fix up the highest bit of each value
+            let high = self.high_bits();
+            let blocks = unsafe {self.bits.storage_mut()};
+            for (x, &y) in blocks.iter_mut().zip(other.bits.storage()) {
+                *x = ((*x & !high).wrapping_add(y & !high)) ^ ((*x ^ y) & high);
+            }
         } else {
-            self.get_bits(idx,self.bits_per_val)
+            for i in 0..self.num_values() {
+                self.wrapping_add_at(i, other.get(i));
+            }
+        }
+    }
+
+    /// Subtracts each value in `other` from the corresponding value in this
+    /// ValueVec, modulo 2^bits_per_val.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ValueVecs differ in length or bits per value
+    pub fn wrapping_sub(&mut self, other: &ValueVec) {
+        assert_eq!(self.bits_per_val, other.bits_per_val);
+        assert_eq!(self.bits.len(), other.bits.len());
+        if self.is_block_aligned() {
+            // borrow into the highest bit of each value so that no value
+            // borrows from its neighbor, then fix up the highest bits
+            let high = self.high_bits();
+            let blocks = unsafe {self.bits.storage_mut()};
+            for (x, &y) in blocks.iter_mut().zip(other.bits.storage()) {
+                *x = ((*x | high).wrapping_sub(y & !high)) ^ ((*x ^ !y) & high);
+            }
+        } else {
+            for i in 0..self.num_values() {
+                self.wrapping_sub_at(i, other.get(i));
+            }
+        }
+    }
+
+    /// The sum of all values in this ValueVec, wrapping on overflow
+    pub fn sum(&self) -> u64 {
+        if self.is_block_aligned() {
+            let mut sum = 0_u64;
+            for &block in self.bits.storage() {
+                let mut shift = 0;
+                while shift < 32 {
+                    sum = sum.wrapping_add((block >> shift) as u64 & self.mask);
+                    shift += self.bits_per_val;
+                }
+            }
+            sum
+        } else {
+            self.iter().fold(0, |sum, val| sum.wrapping_add(val))
+        }
+    }
+
+    /// The number of nonzero values in this ValueVec
+    pub fn count_nonzero(&self) -> usize {
+        if self.is_block_aligned() {
+            let mut count = 0;
+            for &block in self.bits.storage().iter().filter(|&&b| b != 0) {
+                let mut shift = 0;
+                while shift < 32 {
+                    if (block >> shift) as u64 & self.mask != 0 {
+                        count += 1;
+                    }
+                    shift += self.bits_per_val;
+                }
+            }
+            count
+        } else {
+            self.iter().filter(|&val| val != 0).count()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_vals(bits_per_val: usize, vals: &[u64]) -> ValueVec {
+        let mut vec = ValueVec::new(bits_per_val, vals.len());
+        for (i, &val) in vals.iter().enumerate() {
+            vec.set(i, val);
+        }
+        vec
+    }
+
+    #[test]
+    fn test_get_set_all_widths() {
+        for bits_per_val in 1..=64 {
+            let mask = mask_for(bits_per_val);
+            let vals = (0..37_u64)
+                .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15) & mask)
+                .collect::<Vec<_>>();
+            let vec = from_vals(bits_per_val, &vals);
+            assert_eq!(vec.num_values(), vals.len());
+            assert_eq!(vec.iter().collect::<Vec<_>>(), vals);
+        }
+    }
+
+    #[test]
+    fn test_set_does_not_touch_neighbors() {
+        let mut vec = from_vals(24, &[0xffffff, 0xffffff, 0xffffff]);
+        vec.set(1, 0);
+        assert_eq!(vec.iter().collect::<Vec<_>>(), vec![0xffffff, 0, 0xffffff]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_set_too_large() {
+        let mut vec = ValueVec::new(4, 10);
+        vec.set(0, 16);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let vec1 = from_vals(40, &[1, 1 << 39, 12345]);
+        let vec2 = ValueVec::from_bytes(40, &vec1.bits.to_bytes());
+        assert!(vec1 == vec2);
+    }
+
+    #[test]
+    fn test_wrapping_add_and_sub() {
+        // byte-aligned fast paths and the general case
+        for &bits_per_val in &[3, 4, 8, 16, 24, 32, 64] {
+            let mask = mask_for(bits_per_val);
+            let xs = (0..21_u64)
+                .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15) & mask)
+                .collect::<Vec<_>>();
+            let ys = (0..21_u64)
+                .map(|i| (i + 7).wrapping_mul(0xc2b2ae3d27d4eb4f) & mask)
+                .collect::<Vec<_>>();
+            let mut sum = from_vals(bits_per_val, &xs);
+            sum.wrapping_add(&from_vals(bits_per_val, &ys));
+            let mut difference = from_vals(bits_per_val, &xs);
+            difference.wrapping_sub(&from_vals(bits_per_val, &ys));
+            for i in 0..xs.len() {
+                assert_eq!(sum.get(i), xs[i].wrapping_add(ys[i]) & mask);
+                assert_eq!(difference.get(i), xs[i].wrapping_sub(ys[i]) & mask);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sum_and_count_nonzero() {
+        for &bits_per_val in &[5, 8, 16, 32, 64] {
+            let vals = [0, 3, 0, 0, 1, 30, 0, 7, 2];
+            let vec = from_vals(bits_per_val, &vals);
+            assert_eq!(vec.sum(), 43);
+            assert_eq!(vec.count_nonzero(), 5);
         }
+        let vec = from_vals(64, &[u64::MAX, 2]);
+        assert_eq!(vec.sum(), 1, "sum wraps around");
     }
 }
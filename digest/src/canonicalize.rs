@@ -0,0 +1,459 @@
+//! Canonical elements of packets, so the router and the accumulator derive
+//! the same element from a packet even if hops in between rewrite parts of
+//! its headers.
+//!
+//! Except for the raw profile, the element is `ELEM_LEN` bytes:
+//! - the IP version, the transport protocol, and the length of the IP
+//!   payload (4 bytes),
+//! - a hash of the 5-tuple, which identifies the flow (4 bytes),
+//! - the first 8 bytes of the destination address, zero-padded for IPv4,
+//! - a hash of the masked IP and transport headers and the first
+//!   `PAYLOAD_PREFIX_LEN` bytes of the transport payload (8 bytes).
+//!
+//! The addresses and ports are hashed after masking, so the NAT profile
+//! cannot tell flows apart.
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+use sha3::{Digest, Sha3_256};
+
+/// Number of bytes in a canonical element.
+pub const ELEM_LEN: usize = 24;
+const FLOW_RANGE: std::ops::Range<usize> = 4..8;
+const DST_RANGE: std::ops::Range<usize> = 8..16;
+/// Number of bytes of the transport payload covered by the element.
+pub const PAYLOAD_PREFIX_LEN: usize = 64;
+/// The longest IPv4 header with options plus the longest TCP header.
+const MAX_HEADERS_LEN: usize = 60 + 60;
+/// The longest link-layer header that is stripped before canonicalizing:
+/// Ethernet with two VLAN tags.
+pub const MAX_LINK_HEADER_LEN: usize = 14 + 2 * 4;
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_ICMPV6: u8 = 58;
+
+/// Which header fields are masked before computing the element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// The first bytes of the packet, unchanged and zero-padded.
+    Raw,
+    /// Masks the fields every router rewrites: the IPv4 TTL and header
+    /// checksum, the IPv6 hop limit, and the ECN bits.
+    Hop,
+    /// Also masks the addresses, ports, ICMP echo identifiers, and transport
+    /// checksums that NAT may rewrite.
+    Nat,
+}
+
+impl Profile {
+    pub const NAMES: [&'static str; 3] = ["raw", "hop", "nat"];
+
+    /// The number of bytes of each packet to capture after the link-layer
+    /// header. The raw profile captures `nbytes` bytes, and the others
+    /// capture enough to cover the headers and the payload prefix.
+    pub fn snaplen(&self, nbytes: usize) -> usize {
+        match self {
+            Profile::Raw => nbytes,
+            Profile::Hop | Profile::Nat => MAX_HEADERS_LEN + PAYLOAD_PREFIX_LEN,
+        }
+    }
+
+    /// The number of bytes of each packet to capture including the
+    /// link-layer header, e.g. the snapshot length for tcpdump.
+    pub fn capture_len(&self, nbytes: usize) -> usize {
+        MAX_LINK_HEADER_LEN + self.snaplen(nbytes)
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Profile::Raw),
+            "hop" => Ok(Profile::Hop),
+            "nat" => Ok(Profile::Nat),
+            _ => Err(format!("unknown canonicalization profile: {}", s)),
+        }
+    }
+}
+
+/// Returns the element of a packet that starts at the IP header.
+/// - `profile`: which header fields to mask
+/// - `packet`: the captured bytes of the packet, which may be truncated
+/// - `nbytes`: the length of the element in the raw profile
+///
+/// Packets that cannot be parsed as IPv4 or IPv6 are hashed unmasked, with
+/// a version and protocol of zero.
+pub fn canonicalize(profile: Profile, packet: &[u8], nbytes: usize) -> Vec<u8> {
+    if profile == Profile::Raw {
+        let mut elem = packet[..std::cmp::min(nbytes, packet.len())].to_vec();
+        elem.resize(nbytes, 0);
+        return elem;
+    }
+    let mut packet = packet[..std::cmp::min(
+        MAX_HEADERS_LEN + PAYLOAD_PREFIX_LEN, packet.len())].to_vec();
+    let parsed = match packet.first().map(|byte| byte >> 4) {
+        Some(4) => mask_ipv4(profile, &mut packet),
+        Some(6) => mask_ipv6(profile, &mut packet),
+        _ => None,
+    };
+    let (version, protocol, payload_len, transport_start) =
+        parsed.unwrap_or_default();
+    // Drop any link-layer padding after the IP datagram, e.g. of a short
+    // Ethernet frame, which other captures of the packet may not have.
+    if parsed.is_some() {
+        packet.truncate(transport_start + payload_len as usize);
+    }
+    let transport_len = mask_transport(
+        profile, protocol, &mut packet[transport_start..]);
+    let payload_end = std::cmp::min(
+        packet.len(), transport_start + transport_len + PAYLOAD_PREFIX_LEN);
+    let (src, dst) = match version {
+        4 => (&packet[12..16], &packet[16..20]),
+        6 => (&packet[8..24], &packet[24..40]),
+        _ => (&[][..], &[][..]),
+    };
+    let ports = match protocol {
+        PROTO_TCP | PROTO_UDP if transport_len > 0 => {
+            &packet[transport_start..transport_start + 4]
+        },
+        _ => &[][..],
+    };
+
+    let mut elem = Vec::with_capacity(ELEM_LEN);
+    elem.push(version);
+    elem.push(protocol);
+    elem.extend_from_slice(&payload_len.to_be_bytes());
+    let mut hasher = Sha3_256::new();
+    hasher.update(src);
+    hasher.update(dst);
+    hasher.update([protocol]);
+    hasher.update(ports);
+    elem.extend_from_slice(&hasher.finalize()[..FLOW_RANGE.len()]);
+    let dst_len = std::cmp::min(dst.len(), DST_RANGE.len());
+    elem.extend_from_slice(&dst[..dst_len]);
+    elem.resize(DST_RANGE.end, 0);
+    let mut hasher = Sha3_256::new();
+    hasher.update(&packet[..payload_end]);
+    let hash = hasher.finalize();
+    elem.extend_from_slice(&hash[..ELEM_LEN - elem.len()]);
+    elem
+}
+
+/// How to group canonical elements into flows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlowKey {
+    /// Packets with the same 5-tuple.
+    FiveTuple,
+    /// Packets to destination addresses with the same prefix of this many
+    /// bits, at most 64.
+    DstPrefix(u8),
+}
+
+impl FlowKey {
+    /// The bytes of the element that identify its flow. Elements that are
+    /// not canonical, i.e. from the raw profile, are all in the same flow.
+    pub fn key(&self, elem: &[u8]) -> Vec<u8> {
+        if elem.len() != ELEM_LEN {
+            return vec![];
+        }
+        match self {
+            FlowKey::FiveTuple => elem[FLOW_RANGE].to_vec(),
+            FlowKey::DstPrefix(bits) => {
+                let bits = std::cmp::min(*bits as usize, DST_RANGE.len() * 8);
+                let mut key = elem[DST_RANGE][..bits.div_ceil(8)].to_vec();
+                if !bits.is_multiple_of(8) {
+                    *key.last_mut().unwrap() &= 0xff << (8 - bits % 8);
+                }
+                key.insert(0, elem[0]);
+                key
+            },
+        }
+    }
+
+    /// A human-readable name for the flow of the element, e.g.
+    /// `10.0.1.0/24` or `flow 1a2b3c4d`.
+    pub fn describe(&self, elem: &[u8]) -> String {
+        let key = self.key(elem);
+        match (self, key.first()) {
+            (_, None) => "all packets".to_string(),
+            (FlowKey::FiveTuple, _) => format!("flow {}", hex(&key)),
+            (FlowKey::DstPrefix(bits), Some(4)) => {
+                let mut addr = [0; 4];
+                let len = std::cmp::min(key.len() - 1, addr.len());
+                addr[..len].copy_from_slice(&key[1..1 + len]);
+                format!("{}/{}", Ipv4Addr::from(addr), std::cmp::min(*bits, 32))
+            },
+            (FlowKey::DstPrefix(bits), Some(6)) => {
+                let mut addr = [0; 16];
+                addr[..key.len() - 1].copy_from_slice(&key[1..]);
+                format!("{}/{}", Ipv6Addr::from(addr), bits)
+            },
+            (FlowKey::DstPrefix(_), Some(_)) => "non-IP packets".to_string(),
+        }
+    }
+}
+
+impl FromStr for FlowKey {
+    type Err = String;
+
+    /// Parses `5tuple` or `prefix/<bits>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "5tuple" {
+            return Ok(FlowKey::FiveTuple);
+        }
+        match s.strip_prefix("prefix/").map(|bits| bits.parse::<u8>()) {
+            Some(Ok(bits)) if bits <= 64 => Ok(FlowKey::DstPrefix(bits)),
+            _ => Err(format!("unknown flow key: {}", s)),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Masks the IPv4 header. Returns the version, protocol, payload length,
+/// and offset of the transport header.
+fn mask_ipv4(
+    profile: Profile,
+    packet: &mut [u8],
+) -> Option<(u8, u8, u16, usize)> {
+    if packet.len() < 20 {
+        return None;
+    }
+    let header_len = ((packet[0] & 0x0f) as usize) * 4;
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]);
+    if header_len < 20 || packet.len() < header_len {
+        return None;
+    }
+    let protocol = packet[9];
+    packet[1] &= 0xfc;  // ECN
+    packet[8] = 0;  // TTL
+    packet[10..12].fill(0);  // header checksum
+    if profile == Profile::Nat {
+        packet[12..20].fill(0);  // addresses
+    }
+    // Only the first fragment has the transport header.
+    let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+    let protocol = if fragment_offset == 0 { protocol } else { 0 };
+    let payload_len = total_len.saturating_sub(header_len as u16);
+    Some((4, protocol, payload_len, header_len))
+}
+
+/// Masks the IPv6 header. Returns the version, next header, payload length,
+/// and offset of the transport header. Extension headers are treated as
+/// part of the payload.
+fn mask_ipv6(
+    profile: Profile,
+    packet: &mut [u8],
+) -> Option<(u8, u8, u16, usize)> {
+    if packet.len() < 40 {
+        return None;
+    }
+    let payload_len = u16::from_be_bytes([packet[4], packet[5]]);
+    let next_header = packet[6];
+    packet[1] &= 0xcf;  // ECN
+    packet[7] = 0;  // hop limit
+    if profile == Profile::Nat {
+        packet[8..40].fill(0);  // addresses
+    }
+    Some((6, next_header, payload_len, 40))
+}
+
+/// Masks the transport header. Returns its length, or 0 if the protocol is
+/// not known or the header is truncated, in which case nothing is masked.
+fn mask_transport(profile: Profile, protocol: u8, header: &mut [u8]) -> usize {
+    let nat = profile == Profile::Nat;
+    match protocol {
+        PROTO_TCP if header.len() >= 20 => {
+            let header_len = ((header[12] >> 4) as usize) * 4;
+            if nat {
+                header[0..4].fill(0);  // ports
+                header[16..18].fill(0);  // checksum
+            }
+            std::cmp::min(std::cmp::max(header_len, 20), header.len())
+        },
+        PROTO_UDP if header.len() >= 8 => {
+            if nat {
+                header[0..4].fill(0);  // ports
+                header[6..8].fill(0);  // checksum
+            }
+            8
+        },
+        PROTO_ICMP | PROTO_ICMPV6 if header.len() >= 8 => {
+            if nat {
+                header[2..4].fill(0);  // checksum
+                header[4..6].fill(0);  // echo identifier
+            }
+            8
+        },
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_udp(ttl: u8, src: [u8; 4], sport: u16, payload: &[u8]) -> Vec<u8> {
+        let total_len = (20 + 8 + payload.len()) as u16;
+        let mut packet = vec![0x45, 0x00];
+        packet.extend_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(&[0x12, 0x34, 0x40, 0x00, ttl, PROTO_UDP]);
+        packet.extend_from_slice(&[ttl, 0xff]);  // checksum
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&[10, 0, 0, 2]);
+        packet.extend_from_slice(&sport.to_be_bytes());
+        packet.extend_from_slice(&53u16.to_be_bytes());
+        packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[sport as u8, 0xab]);  // checksum
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn ipv6_tcp(hop_limit: u8, ecn: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, ecn << 4, 0x00, 0x01];
+        packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[PROTO_TCP, hop_limit]);
+        packet.extend_from_slice(&[0x20; 16]);
+        packet.extend_from_slice(&[0x30; 16]);
+        let mut tcp = vec![0; 20];
+        tcp[12] = 5 << 4;
+        tcp[4..8].copy_from_slice(&1234u32.to_be_bytes());
+        packet.extend_from_slice(&tcp);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_raw_profile() {
+        let packet = ipv4_udp(64, [10, 0, 0, 1], 1000, &[1, 2, 3]);
+        let elem = canonicalize(Profile::Raw, &packet, 16);
+        assert_eq!(elem, packet[..16].to_vec());
+        let elem = canonicalize(Profile::Raw, &packet, 40);
+        assert_eq!(elem[..packet.len()], packet[..]);
+        assert_eq!(elem[packet.len()..], vec![0; 40 - packet.len()]);
+    }
+
+    #[test]
+    fn test_ipv4_ttl_and_checksum() {
+        let a = ipv4_udp(64, [10, 0, 0, 1], 1000, &[1, 2, 3]);
+        let b = ipv4_udp(63, [10, 0, 0, 1], 1000, &[1, 2, 3]);
+        let elem = canonicalize(Profile::Hop, &a, 16);
+        assert_eq!(elem.len(), ELEM_LEN);
+        assert_eq!(elem[..4], [4, PROTO_UDP, 0, 11]);
+        assert_eq!(elem, canonicalize(Profile::Hop, &b, 16));
+        assert_ne!(canonicalize(Profile::Raw, &a, 16),
+            canonicalize(Profile::Raw, &b, 16));
+    }
+
+    #[test]
+    fn test_ipv6_hop_limit_and_ecn() {
+        let a = ipv6_tcp(64, 0, &[1, 2, 3]);
+        let b = ipv6_tcp(1, 3, &[1, 2, 3]);
+        let elem = canonicalize(Profile::Hop, &a, 16);
+        assert_eq!(elem[..4], [6, PROTO_TCP, 0, 23]);
+        assert_eq!(elem, canonicalize(Profile::Hop, &b, 16));
+    }
+
+    #[test]
+    fn test_nat_addresses_and_ports() {
+        let a = ipv4_udp(64, [10, 0, 0, 1], 1000, &[1, 2, 3]);
+        let b = ipv4_udp(63, [192, 168, 1, 1], 2000, &[1, 2, 3]);
+        assert_ne!(canonicalize(Profile::Hop, &a, 16),
+            canonicalize(Profile::Hop, &b, 16));
+        assert_eq!(canonicalize(Profile::Nat, &a, 16),
+            canonicalize(Profile::Nat, &b, 16));
+    }
+
+    #[test]
+    fn test_payload_prefix() {
+        let mut payload = vec![7; 2 * PAYLOAD_PREFIX_LEN];
+        let a = ipv6_tcp(64, 0, &payload);
+        payload[PAYLOAD_PREFIX_LEN] = 8;
+        let b = ipv6_tcp(64, 0, &payload);
+        payload[0] = 8;
+        let c = ipv6_tcp(64, 0, &payload);
+        assert_eq!(canonicalize(Profile::Hop, &a, 16),
+            canonicalize(Profile::Hop, &b, 16));
+        assert_ne!(canonicalize(Profile::Hop, &a, 16),
+            canonicalize(Profile::Hop, &c, 16));
+    }
+
+    #[test]
+    fn test_link_layer_padding() {
+        // Padded to the minimum Ethernet payload of 46 bytes.
+        let a = ipv4_udp(64, [10, 0, 0, 1], 1000, &[1, 2]);
+        let mut b = a.clone();
+        b.resize(46, 0);
+        assert_eq!(canonicalize(Profile::Hop, &a, 16),
+            canonicalize(Profile::Hop, &b, 16));
+        // The payload is still covered.
+        let c = ipv4_udp(64, [10, 0, 0, 1], 1000, &[1, 3]);
+        assert_ne!(canonicalize(Profile::Hop, &a, 16),
+            canonicalize(Profile::Hop, &c, 16));
+
+        let ack = ipv6_tcp(64, 0, &[]);
+        let mut padded = ack.clone();
+        padded.extend_from_slice(&[0xff; 6]);
+        assert_eq!(canonicalize(Profile::Nat, &ack, 16),
+            canonicalize(Profile::Nat, &padded, 16));
+    }
+
+    #[test]
+    fn test_not_ip() {
+        let elem = canonicalize(Profile::Hop, &[0x12; 30], 16);
+        assert_eq!(elem.len(), ELEM_LEN);
+        assert_eq!(elem[..4], [0, 0, 0, 0]);
+        assert_ne!(elem, canonicalize(Profile::Hop, &[0x13; 30], 16));
+        assert_eq!(canonicalize(Profile::Nat, &[], 16).len(), ELEM_LEN);
+    }
+
+    #[test]
+    fn test_flow_key() {
+        let a = canonicalize(Profile::Hop, &ipv4_udp(64, [10, 0, 0, 1], 1000, &[1]), 16);
+        let b = canonicalize(Profile::Hop, &ipv4_udp(64, [10, 0, 0, 1], 1000, &[2]), 16);
+        let c = canonicalize(Profile::Hop, &ipv4_udp(64, [10, 0, 0, 1], 1001, &[1]), 16);
+        let key = FlowKey::FiveTuple;
+        assert_ne!(a, b);
+        assert_eq!(key.key(&a), key.key(&b));
+        assert_ne!(key.key(&a), key.key(&c));
+        assert!(key.describe(&a).starts_with("flow "));
+
+        let key = FlowKey::DstPrefix(20);
+        assert_eq!(key.key(&a), key.key(&c));
+        assert_eq!(key.key(&a), vec![4, 10, 0, 0]);
+        assert_eq!(key.describe(&a), "10.0.0.0/20");
+        let d = canonicalize(Profile::Hop, &ipv6_tcp(64, 0, &[1]), 16);
+        assert_eq!(FlowKey::DstPrefix(48).describe(&d), "3030:3030:3030::/48");
+        assert_eq!(key.key(&[1, 2, 3]), Vec::<u8>::new());
+        assert_eq!(key.describe(&[1, 2, 3]), "all packets");
+    }
+
+    #[test]
+    fn test_nat_profile_merges_flows() {
+        let a = canonicalize(Profile::Nat, &ipv4_udp(64, [10, 0, 0, 1], 1000, &[1]), 16);
+        let c = canonicalize(Profile::Nat, &ipv4_udp(64, [10, 0, 0, 1], 1001, &[1]), 16);
+        assert_eq!(FlowKey::FiveTuple.key(&a), FlowKey::FiveTuple.key(&c));
+    }
+
+    #[test]
+    fn test_flow_key_from_str() {
+        assert_eq!("5tuple".parse(), Ok(FlowKey::FiveTuple));
+        assert_eq!("prefix/24".parse(), Ok(FlowKey::DstPrefix(24)));
+        assert!("prefix/65".parse::<FlowKey>().is_err());
+        assert!("prefix".parse::<FlowKey>().is_err());
+    }
+
+    #[test]
+    fn test_profile_from_str() {
+        for name in Profile::NAMES {
+            assert!(name.parse::<Profile>().is_ok());
+        }
+        assert!("foo".parse::<Profile>().is_err());
+    }
+}
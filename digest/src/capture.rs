@@ -0,0 +1,289 @@
+use std::time::Duration;
+
+use pcap_parser::{Block, Linktype, PcapBlockOwned};
+use pcap_parser::traits::PcapNGPacketBlock;
+
+/// Linux cooked capture v2, which pcap-parser does not name.
+const LINKTYPE_LINUX_SLL2: Linktype = Linktype(276);
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const ETHERTYPE_QINQ_OLD: u16 = 0x9100;
+
+fn ethertype(bytes: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]))
+}
+
+/// Returns the IP packet if the ethertype is IPv4 or IPv6.
+fn ip_payload(ethertype: u16, payload: &[u8]) -> Option<&[u8]> {
+    match ethertype {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(payload),
+        _ => None,
+    }
+}
+
+/// Strips the link-layer header from a frame of the given link type.
+/// Returns the IPv4 or IPv6 packet, or None if the frame is truncated, does
+/// not carry IP, or the link type is not supported.
+///
+/// Supports Ethernet with any number of 802.1Q or QinQ VLAN tags, Linux
+/// cooked captures (SLL and SLL2), and raw IP.
+pub fn strip_link_layer(linktype: Linktype, frame: &[u8]) -> Option<&[u8]> {
+    match linktype {
+        Linktype::ETHERNET => {
+            let mut offset = 12;
+            loop {
+                let ethertype = ethertype(frame.get(offset..)?)?;
+                match ethertype {
+                    ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_OLD => {
+                        offset += 4;
+                    },
+                    _ => {
+                        return ip_payload(ethertype, frame.get(offset + 2..)?);
+                    },
+                }
+            }
+        },
+        Linktype::LINUX_SLL => {
+            let ethertype = ethertype(frame.get(14..)?)?;
+            ip_payload(ethertype, frame.get(16..)?)
+        },
+        LINKTYPE_LINUX_SLL2 => {
+            let ethertype = ethertype(frame)?;
+            ip_payload(ethertype, frame.get(20..)?)
+        },
+        Linktype::RAW => match frame.first()? >> 4 {
+            4 | 6 => Some(frame),
+            _ => None,
+        },
+        Linktype::IPV4 | Linktype::IPV6 => Some(frame),
+        _ => None,
+    }
+}
+
+/// An IP packet extracted from a capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet<'a> {
+    /// When the packet was captured, since the UNIX epoch. Simple packet
+    /// blocks do not have a timestamp.
+    pub timestamp: Option<Duration>,
+    pub data: &'a [u8],
+}
+
+/// The link type and timestamp format of an interface.
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    linktype: Linktype,
+    ts_offset: u64,
+    ts_resol: u8,
+}
+
+fn to_duration(secs: u32, fractional: u32, unit: u64) -> Duration {
+    let nanos = fractional as u128 * 1_000_000_000 / unit as u128;
+    Duration::from_secs(secs as u64) + Duration::from_nanos(nanos as u64)
+}
+
+/// Extracts IP packets from the blocks of a pcap or pcapng capture, using
+/// the link type in the pcap header or of the pcapng interface that
+/// captured each packet.
+#[derive(Debug, Default)]
+pub struct PacketExtractor {
+    /// The link type of each interface, indexed by interface ID. A legacy
+    /// pcap file has a single interface.
+    interfaces: Vec<Interface>,
+}
+
+impl PacketExtractor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the IP packet in the block, or None if the block is not a
+    /// packet or the packet does not carry IP. Blocks must be passed in the
+    /// order they are read so the link types are known.
+    pub fn extract<'a>(&mut self, block: &'a PcapBlockOwned) -> Option<&'a [u8]> {
+        self.extract_packet(block).map(|packet| packet.data)
+    }
+
+    /// Like `extract()`, but also returns when the packet was captured.
+    pub fn extract_packet<'a>(
+        &mut self,
+        block: &'a PcapBlockOwned,
+    ) -> Option<Packet<'a>> {
+        let (if_id, frame) = match block {
+            PcapBlockOwned::LegacyHeader(header) => {
+                let ts_resol = if header.is_nanosecond_precision() { 9 } else { 6 };
+                self.interfaces = vec![Interface {
+                    linktype: header.network,
+                    ts_offset: 0,
+                    ts_resol,
+                }];
+                return None;
+            },
+            PcapBlockOwned::Legacy(block) => (0, block.data),
+            PcapBlockOwned::NG(Block::SectionHeader(_)) => {
+                // Interface IDs are scoped to the section.
+                self.interfaces.clear();
+                return None;
+            },
+            PcapBlockOwned::NG(Block::InterfaceDescription(idb)) => {
+                self.interfaces.push(Interface {
+                    linktype: idb.linktype,
+                    ts_offset: idb.if_tsoffset,
+                    ts_resol: idb.if_tsresol,
+                });
+                return None;
+            },
+            PcapBlockOwned::NG(Block::EnhancedPacket(epb)) => {
+                (epb.if_id as usize, epb.packet_data())
+            },
+            // Simple packet blocks are always from the first interface.
+            PcapBlockOwned::NG(Block::SimplePacket(spb)) => {
+                (0, spb.packet_data())
+            },
+            PcapBlockOwned::NG(_) => { return None; },
+        };
+        let interface = match self.interfaces.get(if_id) {
+            Some(&interface) => interface,
+            None => {
+                warn!("packet from unknown interface {}", if_id);
+                return None;
+            },
+        };
+        let linktype = interface.linktype;
+        let data = match strip_link_layer(linktype, frame) {
+            Some(data) => data,
+            None => {
+                trace!("skipping non-IP {} frame of {} bytes", linktype,
+                    frame.len());
+                return None;
+            },
+        };
+        let timestamp = match block {
+            PcapBlockOwned::Legacy(block) => {
+                let unit = 10u64.pow(interface.ts_resol as u32);
+                Some(to_duration(block.ts_sec, block.ts_usec, unit))
+            },
+            PcapBlockOwned::NG(Block::EnhancedPacket(epb)) => {
+                let (secs, fractional, unit) =
+                    epb.decode_ts(interface.ts_offset, interface.ts_resol);
+                Some(to_duration(secs, fractional, unit))
+            },
+            _ => None,
+        };
+        Some(Packet { timestamp, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use pcap_parser::{create_reader, PcapError};
+
+    /// The IP packets in every fixture: an IPv4 UDP packet and an IPv6 TCP
+    /// packet. Each fixture also has an ARP frame where the link type allows.
+    fn expected_packets() -> Vec<Vec<u8>> {
+        let ipv4 = include_bytes!("../fixtures/ipv4_udp.bin").to_vec();
+        let ipv6 = include_bytes!("../fixtures/ipv6_tcp.bin").to_vec();
+        vec![ipv4, ipv6]
+    }
+
+    fn extract_all_packets(capture: &[u8]) -> Vec<(Option<Duration>, Vec<u8>)> {
+        let mut reader = create_reader(65536, Cursor::new(capture)).unwrap();
+        let mut extractor = PacketExtractor::new();
+        let mut packets = vec![];
+        loop {
+            match reader.next() {
+                Ok((offset, block)) => {
+                    if let Some(packet) = extractor.extract_packet(&block) {
+                        packets.push((packet.timestamp, packet.data.to_vec()));
+                    }
+                    reader.consume(offset);
+                },
+                Err(PcapError::Eof) => break,
+                Err(PcapError::Incomplete) => reader.refill().unwrap(),
+                Err(e) => panic!("error while reading: {:?}", e),
+            }
+        }
+        packets
+    }
+
+    fn extract_all(capture: &[u8]) -> Vec<Vec<u8>> {
+        extract_all_packets(capture).into_iter().map(|(_, data)| data).collect()
+    }
+
+    fn extract_timestamps(capture: &[u8]) -> Vec<Option<Duration>> {
+        extract_all_packets(capture).into_iter().map(|(ts, _)| ts).collect()
+    }
+
+    #[test]
+    fn test_ethernet() {
+        let capture = include_bytes!("../fixtures/ethernet.pcap");
+        assert_eq!(extract_all(capture), expected_packets());
+    }
+
+    #[test]
+    fn test_vlan_and_qinq() {
+        let capture = include_bytes!("../fixtures/vlan.pcap");
+        assert_eq!(extract_all(capture), expected_packets());
+    }
+
+    #[test]
+    fn test_linux_sll() {
+        let capture = include_bytes!("../fixtures/sll.pcap");
+        assert_eq!(extract_all(capture), expected_packets());
+    }
+
+    #[test]
+    fn test_linux_sll2() {
+        let capture = include_bytes!("../fixtures/sll2.pcap");
+        assert_eq!(extract_all(capture), expected_packets());
+    }
+
+    #[test]
+    fn test_raw() {
+        let capture = include_bytes!("../fixtures/raw.pcap");
+        assert_eq!(extract_all(capture), expected_packets());
+    }
+
+    #[test]
+    fn test_pcapng_enhanced_packet_blocks() {
+        // An Ethernet interface and a raw IP interface.
+        let capture = include_bytes!("../fixtures/ethernet_raw.pcapng");
+        let mut expected = expected_packets();
+        expected.extend(expected_packets());
+        assert_eq!(extract_all(capture), expected);
+    }
+
+    #[test]
+    fn test_timestamps() {
+        // The ARP frame in between was captured a second after the first.
+        let capture = include_bytes!("../fixtures/ethernet.pcap");
+        assert_eq!(extract_timestamps(capture), vec![
+            Some(Duration::from_secs(1_700_000_000)),
+            Some(Duration::from_secs(1_700_000_002)),
+        ]);
+        // Microsecond resolution by default.
+        let capture = include_bytes!("../fixtures/ethernet_raw.pcapng");
+        assert_eq!(extract_timestamps(capture), [1, 3, 4, 5].iter()
+            .map(|&micros| Some(Duration::from_micros(micros)))
+            .collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_truncated_frames() {
+        let packet = &expected_packets()[0];
+        assert_eq!(strip_link_layer(Linktype::ETHERNET, &[0; 13]), None);
+        assert_eq!(strip_link_layer(Linktype::LINUX_SLL, &[0; 15]), None);
+        assert_eq!(strip_link_layer(LINKTYPE_LINUX_SLL2, &[0; 1]), None);
+        assert_eq!(strip_link_layer(Linktype::RAW, &[]), None);
+        assert_eq!(strip_link_layer(Linktype::IPV4, packet), Some(&packet[..]));
+        assert_eq!(strip_link_layer(Linktype::NULL, packet), None);
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+        assert_eq!(strip_link_layer(Linktype::ETHERNET, &frame), None);
+    }
+}
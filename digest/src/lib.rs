@@ -1,5 +1,11 @@
+#[macro_use]
+extern crate log;
+
 mod xor;
 mod mset;
+pub mod canonicalize;
+pub mod capture;
+pub mod sampling;
 
 /// Type alias to easily switch between digest types in crates that
 /// use this library. Both the XOR digest and additive mset hash digest
@@ -9,4 +15,4 @@ pub type Digest = AdditiveMsetHash;
 
 pub use xor::XorDigest;
 pub use mset::AdditiveMsetHash;
-pub use mset::AmhHash;
+pub use mset::{AmhHash, AmhNonce};
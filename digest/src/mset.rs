@@ -42,6 +42,17 @@ fn add_hashes(a: &AmhHash, b: &AmhHash) -> AmhHash {
     hash
 }
 
+/// subtract two hashes modulo 2^256
+fn sub_hashes(a: &AmhHash, b: &AmhHash) -> AmhHash {
+    let modulus = BigUint::from(1u8) << (NBYTES_HASH * 8);
+    let a = BigUint::from_bytes_le(a);
+    let b = BigUint::from_bytes_le(b);
+    let c = ((a + &modulus - b) % modulus).to_bytes_le();
+    let mut hash: AmhHash = Default::default();
+    hash[..c.len()].copy_from_slice(&c);
+    hash
+}
+
 impl AdditiveMsetHash {
     pub fn new_with_seed(nonce: AmhNonce) -> Self {
         Self {
@@ -73,6 +84,14 @@ impl AdditiveMsetHash {
         self.count += elems.len() as u32;
     }
 
+    /// Adds the elements of another digest to the digest, as if each had
+    /// been added individually. The digests may have different nonces.
+    pub fn union(&mut self, other: &Self) {
+        let hash = sub_hashes(&other.hash, &hash_fn(0, &other.nonce));
+        self.hash = add_hashes(&self.hash, &hash);
+        self.count += other.count;
+    }
+
     /// Returns the digest hash.
     pub fn value(&self) -> &AmhHash {
         &self.hash
@@ -166,6 +185,23 @@ mod tests {
         assert!(digest_a.equals(&digest_b));
     }
 
+    #[test]
+    fn union_of_digests() {
+        let set = gen_elements(10);
+        let mut digest_a = AdditiveMsetHash::new();
+        let mut digest_b = AdditiveMsetHash::new();
+        let mut digest_c = AdditiveMsetHash::new();
+        digest_a.add_all(&set);
+        digest_b.add_all(&set[..3].to_vec());
+        digest_c.add_all(&set[3..].to_vec());
+        digest_b.union(&digest_c);
+        assert!(digest_a.equals(&digest_b));
+        digest_b.union(&AdditiveMsetHash::new());
+        assert!(digest_a.equals(&digest_b), "union with an empty digest");
+        digest_b.union(&digest_c);
+        assert!(!digest_a.equals(&digest_b));
+    }
+
     #[test]
     fn deterministic_hash_fn() {
         assert_eq!(
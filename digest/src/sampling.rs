@@ -0,0 +1,108 @@
+//! Consistent sampling of elements, so the router, the accumulator and the
+//! verifier all keep the same subset of packets at high packet rates.
+//!
+//! An element is sampled if its keyed hash falls below the sampling rate.
+//! The key should only be shared with the devices that record packets:
+//! anyone who knows it can tell which packets are not sampled, and drop or
+//! inject only those.
+use std::hash::Hasher;
+use serde::{Serialize, Deserialize};
+use siphasher::sip::SipHasher13;
+
+/// Keeps the elements whose keyed hash falls below a rate.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sampler {
+    key: u64,
+    rate: f64,
+}
+
+impl Sampler {
+    /// Samples elements with probability `rate` in (0, 1].
+    pub fn new(key: u64, rate: f64) -> Self {
+        assert!(rate > 0.0 && rate <= 1.0, "invalid sampling rate: {}", rate);
+        Self { key, rate }
+    }
+
+    /// Keeps every element.
+    pub fn all() -> Self {
+        Self::new(0, 1.0)
+    }
+
+    pub fn key(&self) -> u64 {
+        self.key
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Whether some elements are not kept.
+    pub fn is_sampling(&self) -> bool {
+        self.rate < 1.0
+    }
+
+    /// Whether to keep the element.
+    pub fn keep(&self, elem: &[u8]) -> bool {
+        if !self.is_sampling() {
+            return true;
+        }
+        let mut hasher = SipHasher13::new_with_keys(self.key, !self.key);
+        hasher.write(elem);
+        // The hash is below the rate as a fraction of the range of hashes.
+        (hasher.finish() as f64) < self.rate * (u64::MAX as f64)
+    }
+
+    /// The probability that at least one of `n` dropped or injected packets
+    /// is sampled, and so can be detected.
+    pub fn detection_probability(&self, n: usize) -> f64 {
+        1.0 - (1.0 - self.rate).powi(n as i32)
+    }
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_elems(n: usize) -> Vec<Vec<u8>> {
+        (0..n as u32).map(|i| i.to_be_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_keep_all() {
+        let sampler = Sampler::default();
+        assert!(!sampler.is_sampling());
+        assert!(gen_elems(100).iter().all(|elem| sampler.keep(elem)));
+        assert_eq!(sampler.detection_probability(1), 1.0);
+    }
+
+    #[test]
+    fn test_sampling_rate() {
+        let elems = gen_elems(10000);
+        let sampler = Sampler::new(111, 0.1);
+        let kept = elems.iter().filter(|elem| sampler.keep(elem)).count();
+        assert!(kept > 800 && kept < 1200, "kept {}", kept);
+        assert!((sampler.detection_probability(10) - 0.6513).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_consistent_for_key() {
+        let elems = gen_elems(1000);
+        let kept = |sampler: Sampler| elems.iter()
+            .filter(|elem| sampler.keep(elem))
+            .collect::<Vec<_>>();
+        assert_eq!(kept(Sampler::new(111, 0.5)), kept(Sampler::new(111, 0.5)));
+        assert_ne!(kept(Sampler::new(111, 0.5)), kept(Sampler::new(222, 0.5)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_rate() {
+        Sampler::new(111, 0.0);
+    }
+}
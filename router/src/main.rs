@@ -3,9 +3,14 @@ extern crate log;
 
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::Path;
 
+use digest::canonicalize::{canonicalize, Profile};
+use digest::capture::PacketExtractor;
+use digest::sampling::Sampler;
+use pcap_parser::{create_reader, Block, PcapBlockOwned, PcapError};
+
 fn write_data(f: &mut File, bytes: usize, data: &[u8]) {
     let len = std::cmp::min(data.len(), bytes);
     if len < bytes {
@@ -17,6 +22,8 @@ fn write_data(f: &mut File, bytes: usize, data: &[u8]) {
 fn pcap_listen_mock(
     f: &mut File,
     bytes: usize,
+    profile: Profile,
+    sampler: Sampler,
     _timeout: i32,
 ) {
     warn!("mock data - not an actual pcap file");
@@ -27,31 +34,92 @@ fn pcap_listen_mock(
         vec![88; bytes],
     ];
     for data in packets {
-        write_data(f, bytes, &data);
+        if sampler.keep(&canonicalize(profile, &data, bytes)) {
+            write_data(f, bytes, &data);
+        }
+    }
+}
+
+/// Copies the capture to the file, except for packets that the sampler does
+/// not keep and packets that do not carry IP. Blocks are copied unchanged.
+fn write_sampled<R: Read>(
+    input: R,
+    f: &mut File,
+    bytes: usize,
+    profile: Profile,
+    sampler: Sampler,
+) {
+    let mut reader = create_reader(65536, input).unwrap();
+    let mut extractor = PacketExtractor::new();
+    let (mut n_kept, mut n_total) = (0, 0);
+    loop {
+        let (offset, keep) = match reader.next() {
+            Ok((offset, block)) => {
+                let keep = match extractor.extract(&block) {
+                    Some(packet) => {
+                        n_total += 1;
+                        sampler.keep(&canonicalize(profile, packet, bytes))
+                    },
+                    None => !matches!(block,
+                        PcapBlockOwned::Legacy(_) |
+                        PcapBlockOwned::NG(Block::EnhancedPacket(_)) |
+                        PcapBlockOwned::NG(Block::SimplePacket(_))),
+                };
+                (offset, keep)
+            },
+            Err(PcapError::Eof) => break,
+            Err(PcapError::Incomplete) => {
+                reader.refill().unwrap();
+                continue;
+            },
+            Err(e) => {
+                error!("error while reading: {:?}", e);
+                break;
+            },
+        };
+        if keep {
+            f.write_all(&reader.data()[..offset]).unwrap();
+            n_kept += 1;
+        }
+        reader.consume(offset);
     }
+    f.flush().unwrap();
+    info!("sampled {}/{} packets", n_kept, n_total);
 }
 
 fn pcap_listen(
     fname: &str,
     bytes: usize,
+    profile: Profile,
+    sampler: Sampler,
     timeout: i32,
 ) {
-    use std::process::Command;
+    use std::process::{Command, Stdio};
     use signal_child::{Signalable, signal};
 
     debug!("listening on tcpdump");
-    let mut child = Command::new("tcpdump")
+    let mut command = Command::new("tcpdump");
+    command
         .arg("--immediate-mode")
         .arg("-i")
         .arg("eth0.2")
         .arg("-Q")
         .arg("out")
-        .arg("-w")
-        .arg(fname)
         .arg("-s")
-        .arg(format!("{}", 14 + bytes))
-        .spawn()
-        .unwrap();
+        .arg(format!("{}", profile.capture_len(bytes)));
+    // Only write the sampled packets to the file if sampling.
+    if sampler.is_sampling() {
+        command.arg("-w").arg("/dev/stdout").stdout(Stdio::piped());
+    } else {
+        command.arg("-w").arg(fname);
+    }
+    let mut child = command.spawn().unwrap();
+    let writer = child.stdout.take().map(|stdout| {
+        let mut f = File::create(fname).unwrap();
+        std::thread::spawn(move || {
+            write_sampled(stdout, &mut f, bytes, profile, sampler);
+        })
+    });
 
     // TODO: This seems to be dropping lots of packets at the end, call sigusr2 and give it some
     // extra time before signinting.
@@ -61,6 +129,9 @@ fn pcap_listen(
     child.signal(signal::SIGUSR2).expect("Error interrupting child");
     child.signal(signal::SIGINT).expect("Error interrupting child");
     child.wait().ok();
+    if let Some(writer) = writer {
+        writer.join().unwrap();
+    }
     info!("exiting");
 }
 
@@ -96,10 +167,38 @@ fn main() {
             .long("bytes")
             .takes_value(true)
             .default_value("16"))
+        .arg(Arg::new("profile")
+            .help("Which header fields to mask so that packets rewritten by \
+                routers along the way still match. The raw profile records \
+                the first bytes of each packet unchanged.")
+            .long("profile")
+            .takes_value(true)
+            .possible_values(Profile::NAMES)
+            .default_value("raw"))
+        .arg(Arg::new("sample-rate")
+            .help("Fraction of packets to log, sampled by a keyed hash of \
+                each packet. Must match the accumulator's.")
+            .long("sample-rate")
+            .takes_value(true)
+            .requires("sample-key"))
+        .arg(Arg::new("sample-key")
+            .help("Key of the hash that samples packets. Must match the \
+                accumulator's.")
+            .long("sample-key")
+            .takes_value(true))
         .get_matches();
 
     let timeout: i32 = matches.value_of("timeout").unwrap().parse().unwrap();
     let bytes: usize = matches.value_of("bytes").unwrap().parse().unwrap();
+    let profile: Profile = matches.value_of("profile").unwrap().parse()
+        .unwrap();
+    let sampler = match matches.value_of("sample-rate") {
+        Some(rate) => Sampler::new(
+            matches.value_of("sample-key").unwrap().parse().unwrap(),
+            rate.parse().unwrap(),
+        ),
+        None => Sampler::all(),
+    };
     let filename = matches.value_of("filename").unwrap();
     let overwrite = matches.is_present("overwrite");
 
@@ -111,9 +210,9 @@ fn main() {
     info!("writing router data to {}", filename);
     let mut f = fs::File::create(path).unwrap();
     if matches.is_present("mock") {
-        pcap_listen_mock(&mut f, bytes, timeout);
+        pcap_listen_mock(&mut f, bytes, profile, sampler, timeout);
     } else {
         drop(f);
-        pcap_listen(&filename, bytes, timeout);
+        pcap_listen(&filename, bytes, profile, sampler, timeout);
     }
 }
@@ -2,10 +2,13 @@
 #[macro_use]
 extern crate log;
 
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+mod watch;
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::net::TcpStream;
 use std::io::{Read, Write, Cursor};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::RangeInclusive;
 use std::path::Path;
 
 use hex;
@@ -13,11 +16,25 @@ use bincode;
 use ssh2::Session;
 use clap::{Arg, Command};
 use accumulator::*;
+use digest::canonicalize::{canonicalize, Profile};
+use watch::{EpochRecord, Snapshot, StateStore, WatchState};
 
 use pcap_parser::*;
 // use pcap_parser::traits::PcapReaderIterator;
 use std::fs::File;
 
+/// The estimated difference between the router logs and the digest may
+/// exceed the number of missing packets by this factor due to estimator
+/// error, plus some slack for small differences.
+const MAX_ESTIMATE_FACTOR: usize = 4;
+const MAX_ESTIMATE_SLACK: usize = 32;
+/// Flags in the first byte of a request to the accumulator's TCP service.
+const REQUEST_RESET: u8 = 0x01;
+const REQUEST_BINS: u8 = 0x02;
+const REQUEST_SPAN: u8 = 0x04;
+/// Maximum number of flows to list for each bucket with drops or injections.
+const MAX_FLOWS_REPORTED: usize = 10;
+
 /// Connect to the SSH server and assert the session is authenticated.
 fn establish_ssh_session(
     addr: &str,
@@ -39,25 +56,33 @@ fn establish_ssh_session(
     sess
 }
 
-/// Call the accumulator's TCP service and read the bytes.
-/// Assume we know which type of accumulator it is using.
+/// Call the accumulator's TCP service and read the bytes, and the time bins
+/// and capture span if requested.
 /// TODO: SSH into Pi and call the TCP service from there since
 /// the TCP port shouldn't be externally exposed.
-fn get_accumulator(
+fn fetch_accumulator(
     ssh: Option<Vec<&str>>,
     reset: bool,
+    bins: bool,
+    span: bool,
     port: u32,
-    ty: &str,
-) -> Box<dyn Accumulator> {
+) -> (Vec<u8>, Option<TimeBins>, Option<CaptureSpan>) {
+    let mut request = 0;
+    if reset {
+        request |= REQUEST_RESET;
+    }
+    if bins {
+        request |= REQUEST_BINS;
+    }
+    if span {
+        request |= REQUEST_SPAN;
+    }
     let mut buf = Vec::new();
     if let Some(ssh) = ssh {
         let sess = establish_ssh_session(ssh[0], ssh[1], ssh[2]);
         let mut channel = sess.channel_session().unwrap();
-        let cmd = if reset {
-            format!("echo -n -e '\\x01' | nc -v 127.0.0.1 {}", port)
-        } else {
-            format!("echo -n -e '\\x00' | nc -v 127.0.0.1 {}", port)
-        };
+        let cmd = format!("echo -n -e '\\x{:02x}' | nc -v 127.0.0.1 {}",
+            request, port);
         channel.exec(&cmd).unwrap();
         channel.read_to_end(&mut buf).unwrap();
         channel.wait_close().unwrap();
@@ -69,10 +94,25 @@ fn get_accumulator(
     } else {
         let address = format!("127.0.0.1:{}", port);
         let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(&[request]).unwrap();
         stream.read_to_end(&mut buf).unwrap();
     };
+    let (buf, bins, span) = if bins || span {
+        let (buf, bins, span): (Vec<u8>, Option<TimeBins>,
+            Option<CaptureSpan>) = bincode::deserialize(&buf).unwrap();
+        if let Some(bins) = &bins {
+            info!("{} time bins of {:?}", bins.num_bins(), bins.bin_len());
+        } else if request & REQUEST_BINS != 0 {
+            warn!("the accumulator is not keeping time bins");
+        }
+        if let Some(span) = &span {
+            info!("captured from {:?} to {:?}", span.first(), span.last());
+        }
+        (buf, bins, span)
+    } else {
+        (buf, None, None)
+    };
     info!("accumulator size = {} bytes", buf.len());
-    info!("accumulator type = {}", ty);
 
     let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
     let path = format!("results/digests/{}.digest", time);
@@ -80,17 +120,44 @@ fn get_accumulator(
     f.write_all(&buf[..]).unwrap();
     f.flush().unwrap();
     debug!("saving digest in {}", path);
+    (buf, bins, span)
+}
+
+/// Deserialize the accumulator from its bytes.
+/// Assume we know which type of accumulator it is using.
+fn get_accumulator(buf: &[u8], ty: &str) -> Box<dyn Accumulator> {
+    info!("accumulator type = {}", ty);
     match ty {
-        "naive" => Box::new(bincode::deserialize::<NaiveAccumulator>(&buf).unwrap()),
+        "naive" => Box::new(bincode::deserialize::<NaiveAccumulator>(buf).unwrap()),
         "iblt" => {
             warn!("do IBLT parameters match the router's?");
             Box::new(IBLTAccumulator::from_bytes(
-                &buf,
+                buf,
+                DEFAULT_BITS_PER_ENTRY,
+                DEFAULT_NUM_HASHES,
+            ))
+        },
+        "multi_iblt" => {
+            warn!("do IBLT parameters match the router's?");
+            Box::new(MultiLevelIBLTAccumulator::from_bytes(
+                buf,
                 DEFAULT_BITS_PER_ENTRY,
                 DEFAULT_NUM_HASHES,
             ))
         },
-        "power_sum" => Box::new(PowerSumAccumulator::from_bytes(&buf)),
+        "hybrid" => {
+            warn!("do IBLT parameters match the router's?");
+            Box::new(HybridAccumulator::from_bytes(
+                buf,
+                DEFAULT_BITS_PER_ENTRY,
+                DEFAULT_NUM_HASHES,
+            ))
+        },
+        "power_sum" => Box::new(PowerSumAccumulator::from_bytes(buf)),
+        "charpoly" => Box::new(CharPolyAccumulator::from_bytes(buf)),
+        "flow_iblt" | "flow_power_sum" => {
+            Box::new(FlowPartitionedAccumulator::from_bytes(buf))
+        },
         _ => unreachable!(),
     }
 }
@@ -99,14 +166,40 @@ fn get_accumulator(
 /// - `ssh`: address and port to SSH into, if provided
 /// - `filename`: name of the file, if remote make sure to specify full path
 /// - `nbytes`: number of bytes per packet
-/// TODO: SFTP logs from router.
+/// - `profile`: how to canonicalize each packet into an element
+/// - `sampler`: which elements to keep, the same as the accumulator
+///
+/// Returns the elements and when each packet was logged.
 fn get_router_logs(
-    mut pkts_to_skip: usize,
+    pkts_to_skip: usize,
     ssh: Option<Vec<&str>>,
     filename: &str,
     nbytes: usize,
+    profile: Profile,
+    sampler: Sampler,
     drop: Option<usize>
-) -> Vec<Vec<u8>> {
+) -> (Vec<Vec<u8>>, Vec<Option<Duration>>) {
+    let (mut res, mut timestamps, mut ends) = get_router_logs_from(
+        0, pkts_to_skip, ssh, filename, nbytes, profile, sampler);
+    if let Some(drop) = drop {
+        drop_router_logs(drop, &mut res, &mut timestamps, &mut ends);
+    }
+    (res, timestamps)
+}
+
+/// Like `get_router_logs()`, but resumes parsing at the byte `offset` of the
+/// file, see `parse_router_logs()`. Also returns the byte offset just past
+/// each packet.
+/// TODO: SFTP logs from router.
+fn get_router_logs_from(
+    offset: usize,
+    pkts_to_skip: usize,
+    ssh: Option<Vec<&str>>,
+    filename: &str,
+    nbytes: usize,
+    profile: Profile,
+    sampler: Sampler,
+) -> (Vec<Vec<u8>>, Vec<Option<Duration>>, Vec<usize>) {
     let t = Instant::now();
     let data: Vec<u8> = if let Some(ssh) = ssh {
         let remote_path = format!("{}@{}:{}", ssh[1], ssh[0], filename);
@@ -128,34 +221,113 @@ fn get_router_logs(
     };
     debug!("loaded local file: {:?}", Instant::now() - t);
 
-    // https://docs.rs/pcap-parser/latest/pcap_parser/struct.PcapNGReader.html
-    info!("parsing router logs: {} bytes", data.len());
+    let logs = parse_router_logs(
+        &data, offset, pkts_to_skip, nbytes, profile, sampler);
+    debug!("parsed {} packets: {:?}", logs.0.len(), Instant::now() - t);
+    logs
+}
+
+/// Removes `drop` random entries from the router logs, to simulate packets
+/// that were dropped before they were logged.
+fn drop_router_logs(
+    drop: usize,
+    res: &mut Vec<Vec<u8>>,
+    timestamps: &mut Vec<Option<Duration>>,
+    ends: &mut Vec<usize>,
+) {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    for _ in 0..drop {
+        if res.is_empty() {
+            break;
+        }
+        let i = rng.gen_range(0..res.len());
+        res.remove(i);
+        timestamps.remove(i);
+        ends.remove(i);
+        debug!("removed index {}", i);
+    }
+}
+
+/// The length of the blocks at the start of a capture before the first
+/// packet, e.g. the pcap header or the pcapng section header and interface
+/// descriptions.
+fn header_len(data: &[u8]) -> usize {
     let mut reader = create_reader(65536, Cursor::new(data)).unwrap();
+    let mut len = 0;
+    loop {
+        match reader.next() {
+            Ok((offset, block)) => {
+                match block {
+                    PcapBlockOwned::Legacy(_)
+                    | PcapBlockOwned::NG(Block::EnhancedPacket(_))
+                    | PcapBlockOwned::NG(Block::SimplePacket(_)) => {
+                        return len;
+                    },
+                    _ => {},
+                }
+                len += offset;
+                reader.consume(offset);
+            },
+            Err(PcapError::Incomplete) => {
+                if reader.refill().is_err() {
+                    return len;
+                }
+            },
+            Err(_) => { return len; },
+        }
+    }
+}
+
+/// Parse the router logs in a pcap or pcapng capture. If `offset` is not
+/// zero, parsing resumes at the packet block at that byte offset, after the
+/// header blocks at the start of the capture so the link types are known.
+/// - `pkts_to_skip`: number of sampled packets to skip before the first
+///   returned element
+///
+/// Returns the elements, when each packet was logged, and the byte offset
+/// just past the block of each packet, where parsing resumes after it.
+fn parse_router_logs(
+    data: &[u8],
+    offset: usize,
+    mut pkts_to_skip: usize,
+    nbytes: usize,
+    profile: Profile,
+    sampler: Sampler,
+) -> (Vec<Vec<u8>>, Vec<Option<Duration>>, Vec<usize>) {
+    assert!(offset <= data.len(), "the router logs were truncated");
+    let header_len = if offset > 0 { header_len(data) } else { 0 };
+
+    // https://docs.rs/pcap-parser/latest/pcap_parser/struct.PcapNGReader.html
+    info!("parsing router logs: {} bytes", data.len() - offset);
+    let stream = Cursor::new(&data[..header_len])
+        .chain(Cursor::new(&data[offset..]));
+    let mut reader = create_reader(65536, stream).unwrap();
+    let mut extractor = PacketExtractor::new();
     let mut res = Vec::new();
+    let mut timestamps = Vec::new();
+    let mut ends = Vec::new();
+    // the position in the stream, where the header is followed by the offset
+    let mut pos = 0;
     let mut maybe_truncated = false;
     loop {
         match reader.next() {
-            Ok((offset, block)) => {
+            Ok((size, block)) => {
                 maybe_truncated = false;
-                match block {
-                    PcapBlockOwned::Legacy(block) => {
+                pos += size;
+                if let Some(packet) = extractor.extract_packet(&block) {
+                    let elem = canonicalize(profile, packet.data, nbytes);
+                    if sampler.keep(&elem) {
                         if pkts_to_skip != 0 {
                             pkts_to_skip -= 1;
                         } else {
-                            let hi = std::cmp::min(14 + nbytes, block.data.len());
-                            let mut elem = block.data[14..hi].to_vec();
-                            if elem.len() < nbytes {
-                                elem.append(&mut vec![0; nbytes - elem.len()]);
-                            }
                             res.push(elem);
+                            timestamps.push(packet.timestamp);
+                            ends.push(pos - header_len + offset);
                         }
-                    },
-                    PcapBlockOwned::NG(block) => {
-                        debug!("ignoring NG({:?}) offset={}", block, offset);
-                    },
-                    PcapBlockOwned::LegacyHeader(_) => {},
+                    }
                 }
-                reader.consume(offset);
+                reader.consume(size);
             },
             Err(PcapError::Eof) => {
                 debug!("reached eof");
@@ -173,20 +345,7 @@ fn get_router_logs(
             Err(e) => error!("error while reading: {:?}", e),
         }
     }
-    debug!("parsed {} packets: {:?}", res.len(), Instant::now() - t);
-    if let Some(drop) = drop {
-        use rand::Rng;
-        let mut rng = rand::thread_rng();
-        for _ in 0..drop {
-            if res.is_empty() {
-                break;
-            }
-            let i = rng.gen_range(0..res.len());
-            res.remove(i);
-            debug!("removed index {}", i);
-        }
-    }
-    res
+    (res, timestamps, ends)
 }
 
 /// Logs seem to have many repeated entries.
@@ -273,17 +432,18 @@ fn check_acc_logs(
     router_filename: &str,
     acc_filename: &str,
     bytes: usize,
+    profile: Profile,
 ) {
     info!("router logs:");
-    let router_logs = get_router_logs(
-        0, router_ssh, router_filename, bytes, drop);
+    let (router_logs, _) = get_router_logs(
+        0, router_ssh, router_filename, bytes, profile, Sampler::all(), drop);
     let router_logs_map = to_map(&router_logs);
     for i in 0..std::cmp::min(10, router_logs.len()) {
         println!("0x{}", hex::encode(&router_logs[i]));
     }
     info!("accumulator logs:");
-    let accumulator_logs = get_router_logs(
-        0, acc_ssh, acc_filename, bytes, None);
+    let (accumulator_logs, _) = get_router_logs(
+        0, acc_ssh, acc_filename, bytes, profile, Sampler::all(), None);
     let accumulator_logs_map = to_map(&accumulator_logs);
     for i in 0..std::cmp::min(10, accumulator_logs.len()) {
         println!("0x{}", hex::encode(&accumulator_logs[i]));
@@ -291,18 +451,208 @@ fn check_acc_logs(
     compare_maps(router_logs_map, accumulator_logs_map);
 }
 
+/// Estimates how many of the router logs the digest did not process before
+/// validating. If this is much more than the difference in the number of
+/// packets, the digest processed packets that are not in the logs: either the
+/// logs are misaligned with the digest, e.g. the start index is wrong, or the
+/// router injected packets. Returns false in that case. Validation must still
+/// run, since injected packets would otherwise go unreported.
+fn check_estimate(
+    accumulator: &dyn Accumulator,
+    logs: &[Vec<u8>],
+) -> bool {
+    let t = Instant::now();
+    let n_missing = logs.len().saturating_sub(accumulator.total());
+    let estimate = accumulator.estimate_difference(logs);
+    info!("estimated {} missing packets, actual {}: {:?}", estimate,
+        n_missing, Instant::now() - t);
+    estimate <= MAX_ESTIMATE_FACTOR * n_missing + MAX_ESTIMATE_SLACK
+}
+
+/// Bisects over the time bins of the accumulator to find when the packets
+/// that made the accumulator invalid were received, tolerating a clock
+/// `skew` between the router and the accumulator.
+fn locate_misbehavior(
+    bins: &TimeBins,
+    logs: &[Vec<u8>],
+    timestamps: &[Option<Duration>],
+    skew: Duration,
+) {
+    let t = Instant::now();
+    let logs = logs.iter()
+        .zip(timestamps)
+        .filter_map(|(elem, timestamp)| Some(((*timestamp)?, elem.clone())))
+        .collect::<Vec<_>>();
+    if logs.len() < timestamps.len() {
+        warn!("{} logged packets have no timestamp",
+            timestamps.len() - logs.len());
+    }
+    let reports = bins.locate(&logs, skew);
+    info!("located {} mismatched time bins: {:?}", reports.len(),
+        Instant::now() - t);
+    for report in reports {
+        let verdict = if report.injected() {
+            "injected"
+        } else {
+            "dropped or injected"
+        };
+        warn!("{:.3}s to {:.3}s: {}/{} packets received, {}",
+            report.start.as_secs_f64(), report.end.as_secs_f64(),
+            report.received, report.logged, verdict);
+    }
+}
+
+/// Summarizes the result in a word.
+fn verdict(result: &ValidationResult) -> &'static str {
+    if result.is_valid() {
+        "valid"
+    } else if result.is_undetermined() {
+        "undetermined"
+    } else {
+        "invalid"
+    }
+}
+
+/// Validates each bucket of the flow-partitioned accumulator, and reports the
+/// flows or destination prefixes in the buckets with dropped packets or that
+/// are not valid. Returns the combined result of the buckets.
+fn validate_flows(
+    accumulator: &FlowPartitionedAccumulator,
+    logs: &[Vec<u8>],
+    options: &ValidationOptions,
+) -> ValidationResult {
+    let key = accumulator.key();
+    let reports = accumulator.validate_flows(logs, options);
+    for report in &reports {
+        if report.dropped() == 0 && report.result.is_valid() {
+            continue;
+        }
+        let flows = logs.iter()
+            .filter(|elem| accumulator.bucket(elem) == report.bucket)
+            .map(|elem| key.describe(elem))
+            .collect::<BTreeSet<_>>();
+        let verdict = verdict(&report.result);
+        let n_flows = flows.len();
+        let mut flows = flows.into_iter()
+            .take(MAX_FLOWS_REPORTED)
+            .collect::<Vec<_>>();
+        if n_flows > MAX_FLOWS_REPORTED {
+            flows.push("...".to_string());
+        }
+        warn!("bucket {}: {} ({:?}), {}/{} packets received, {} flows: {}",
+            report.bucket, verdict, report.result, report.received,
+            report.logged, n_flows, flows.join(", "));
+    }
+    FlowReport::combine(reports)
+}
+
+/// Aligns an epoch that would start at `start` in the logs with its capture
+/// span, given a clock `skew`. If entries were logged before the span, e.g.
+/// those of a missed snapshot, skips over them and starts the epoch over
+/// without carried packets. Returns the index of the logs that the epoch
+/// ends by, or None if it cannot be aligned, e.g. if the logs end before the
+/// span does.
+fn align_epoch(
+    validator: &mut EpochValidator,
+    epoch: usize,
+    span: Option<CaptureSpan>,
+    timestamps: &[Option<Duration>],
+    skew: Option<Duration>,
+    start: &mut usize,
+) -> Option<usize> {
+    let (span, skew) = span.zip(skew)?;
+    if let Some(starts) = span.starts(timestamps, skew) {
+        if *start < *starts.start() {
+            warn!("skipping log entries {}..{} logged before epoch {}",
+                start, starts.start(), epoch);
+            *start = *starts.start();
+            validator.set_carried(vec![]);
+        }
+    }
+    span.ends(timestamps, skew).map(|ends| *ends.end())
+}
+
+/// Validates snapshots of consecutive epochs against the router logs that
+/// follow the previous epoch, where up to `slack` packets logged at the end of
+/// each epoch may be received in the next one. Returns the number of log
+/// entries in the epochs, where the next epoch would start.
+///
+/// With a clock `skew`, each epoch also ends by the last entry logged within
+/// the skew of its capture span, and skips over the entries logged before its
+/// capture span, e.g. those of a missed snapshot.
+fn validate_epochs(
+    snapshots: &[(Vec<u8>, Option<CaptureSpan>)],
+    accumulator_type: &str,
+    logs: &[Vec<u8>],
+    timestamps: &[Option<Duration>],
+    slack: usize,
+    skew: Option<Duration>,
+    options: &ValidationOptions,
+) -> usize {
+    let accumulators = snapshots.iter()
+        .map(|(buf, _)| SampledAccumulator::<dyn Accumulator>::from_bytes(
+            buf, |bytes| get_accumulator(bytes, accumulator_type)))
+        .collect::<Vec<_>>();
+    let mut remaining_received = accumulators.iter()
+        .map(|accumulator| accumulator.total())
+        .sum::<usize>();
+    let mut validator = EpochValidator::new(slack);
+    let mut start = 0;
+    for (epoch, accumulator) in accumulators.iter().enumerate() {
+        // Any packets logged but not received in the remaining epochs could
+        // have been dropped in this epoch, so the epoch ends by then.
+        let received = accumulator.total();
+        let max_end = align_epoch(&mut validator, epoch, snapshots[epoch].1,
+            timestamps, skew, &mut start);
+        let max_dropped = (logs.len() - start + validator.carried().len())
+            .saturating_sub(remaining_received);
+        let mut end = std::cmp::min(logs.len(), start + received + max_dropped);
+        if let Some(max_end) = max_end {
+            end = std::cmp::max(start, std::cmp::min(end, max_end));
+        }
+        remaining_received -= received;
+        // Each epoch is validated within its own budget of digests.
+        let options = ValidationOptions {
+            digests: DigestCounter::new(),
+            ..options.clone()
+        };
+        let t = Instant::now();
+        let report = validator.validate_epoch(
+            accumulator.inner(), &logs[start..end], &options);
+        let verdict = verdict(&report.result);
+        info!("epoch {}: {} ({:?}), {}/{} packets received, log entries \
+            {}..{}, carried {} packets: {:?}", epoch, verdict, report.result,
+            report.received, report.logged, start + report.start,
+            start + report.consumed + report.overlap, report.carried,
+            Instant::now() - t);
+        start += report.consumed;
+    }
+    start
+}
+
 /// Attempts to truncate as much of the log as possible such that it is still
 /// a subset, assuming validation passed initially. Returns the number of
 /// packets one can truncate while still being a superset of the digest.
+/// At most `n_dropped` packets can be truncated, and the same number of
+/// duplicated packets is tolerated as in the initial validation.
 fn check_truncation(
-    accumulator: &Box<dyn Accumulator>,
+    accumulator: &dyn Accumulator,
     logs: &Vec<Vec<u8>>,
+    n_dropped: usize,
+    max_duplicates: usize,
+    executor: &Executor,
 ) -> usize {
+    let options = ValidationOptions {
+        executor: executor.clone(),
+        max_duplicates,
+        ..Default::default()
+    };
     let mut lo = 0;
-    let mut hi = logs.len() - accumulator.total();
+    let mut hi = n_dropped;
     while lo != hi {
         let mid = (lo + hi) / 2;
-        if accumulator.validate(&logs[..logs.len() - mid].to_vec()).is_valid() {
+        let logs = logs[..logs.len() - mid].to_vec();
+        if accumulator.validate_with_options(&logs, &options).is_valid() {
             lo = mid + 1;
         } else {
             hi = mid;
@@ -311,6 +661,41 @@ fn check_truncation(
     lo
 }
 
+/// The bounds on the start and end of the window of the logs to search,
+/// with the start among the first `search_start` entries and the end among
+/// the last `search_end` entries. By default the window starts at the first
+/// entry, and the end is after at least as many entries as packets received.
+fn search_bounds(
+    accumulator: &dyn Accumulator,
+    n_logs: usize,
+    search_start: Option<usize>,
+    search_end: Option<usize>,
+    max_duplicates: usize,
+) -> (RangeInclusive<usize>, RangeInclusive<usize>) {
+    let starts = 0..=std::cmp::min(search_start.unwrap_or(0), n_logs);
+    let min_end = match search_end {
+        Some(search_end) => n_logs.saturating_sub(search_end),
+        None => std::cmp::min(n_logs,
+            accumulator.total().saturating_sub(max_duplicates)),
+    };
+    (starts, min_end..=n_logs)
+}
+
+/// The bounds on the start and end of the window of the logs to search,
+/// from the timestamps of the entries logged within `skew` of the first and
+/// last packets the accumulator captured. None if it captured no packets, or
+/// if the logs end before the packets it captured.
+fn align_bounds(
+    span: &CaptureSpan,
+    timestamps: &[Option<Duration>],
+    skew: Duration,
+) -> Option<(RangeInclusive<usize>, RangeInclusive<usize>)> {
+    let starts = span.starts(timestamps, skew)?;
+    let ends = span.ends(timestamps, skew)?;
+    info!("aligned by timestamp: starts in {:?}, ends in {:?}", starts, ends);
+    Some((starts, ends))
+}
+
 fn main() {
     env_logger::builder().filter_level(log::LevelFilter::Debug).init();
 
@@ -346,6 +731,14 @@ fn main() {
             .long("bytes")
             .takes_value(true)
             .default_value("40"))
+        .arg(Arg::new("profile")
+            .help("Which header fields to mask so that packets rewritten by \
+                routers along the way still match. The raw profile records \
+                the first bytes of each packet unchanged.")
+            .long("profile")
+            .takes_value(true)
+            .possible_values(Profile::NAMES)
+            .default_value("raw"))
         .arg(Arg::new("drop")
             .help("Purposefully drop this number of packets to mimic \
                 malicious packets that were not logged, hoping they were \
@@ -353,6 +746,29 @@ fn main() {
             .short('d')
             .long("drop")
             .takes_value(true))
+        .arg(Arg::new("bins")
+            .help("Also request the time bins of the accumulator, started \
+                with --bin-ms, to locate when packets were injected if the \
+                router is invalid.")
+            .long("bins"))
+        .arg(Arg::new("epochs")
+            .help("Fetch and reset the accumulator this many times, \
+                --epoch-ms apart, and validate the snapshots as consecutive \
+                epochs of the router logs starting at --index.")
+            .long("epochs")
+            .takes_value(true))
+        .arg(Arg::new("epoch-ms")
+            .help("Milliseconds between snapshots with --epochs or watch.")
+            .long("epoch-ms")
+            .takes_value(true)
+            .default_value("1000"))
+        .arg(Arg::new("slack")
+            .help("Number of packets logged at the end of each epoch that \
+                may be received in the next epoch, e.g. in flight when the \
+                accumulator was reset.")
+            .long("slack")
+            .takes_value(true)
+            .default_value("0"))
         .arg(Arg::new("reset")
             .help("If the flag is set, resets the digest each time it is \
                 serialized.")
@@ -383,20 +799,103 @@ fn main() {
             .possible_value("naive")
             .possible_value("cbf")
             .possible_value("iblt")
+            .possible_value("multi_iblt")
+            .possible_value("hybrid")
             .possible_value("power_sum")
+            .possible_value("charpoly")
+            .possible_value("flow_iblt")
+            .possible_value("flow_power_sum")
             .required(true))
+        .arg(Arg::new("timeout")
+            .help("Give up on validation after this many seconds.")
+            .long("timeout")
+            .takes_value(true))
+        .arg(Arg::new("max-digests")
+            .help("Give up on validation after computing this many digests \
+                in a single step.")
+            .long("max-digests")
+            .takes_value(true))
+        .arg(Arg::new("max-duplicates")
+            .help("Accept up to this many extra copies of logged packets, \
+                e.g. duplicated by the network. Requires the hash check \
+                for IBLT accumulators.")
+            .long("max-duplicates")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::new("search-start")
+            .help("Search for the start of the log window among this many \
+                entries from --index, instead of starting at --index.")
+            .long("search-start")
+            .takes_value(true))
+        .arg(Arg::new("search-end")
+            .help("Search for the end of the log window among this many \
+                entries from the end of the log. Default with --search-start \
+                is any end after as many entries as packets received.")
+            .long("search-end")
+            .takes_value(true))
+        .arg(Arg::new("skew-ms")
+            .help("Select the router logs by the capture timestamps of the \
+                accumulator instead of by --index, tolerating this many \
+                milliseconds of clock skew between the router and the \
+                accumulator. Validation then refines the window. Also the \
+                tolerance when locating misbehavior in time bins, 10 ms if \
+                not set.")
+            .long("skew-ms")
+            .takes_value(true))
+        .arg(Arg::new("threads")
+            .help("Number of threads to validate with. Validation runs on \
+                the calling thread if 1. Default is one per CPU.")
+            .long("threads")
+            .takes_value(true))
+        .subcommand(Command::new("watch")
+            .about("Fetch and reset the accumulator every --epoch-ms, and \
+                validate each snapshot as the next epoch of the router logs. \
+                Resumes where it left off if restarted.")
+            .arg(Arg::new("state")
+                .help("Directory to keep the cursor into the router logs, a \
+                    record of each epoch, and the snapshots in. Starts at \
+                    --index if the directory has no cursor yet.")
+                .long("state")
+                .takes_value(true)
+                .default_value("results/watch")))
         .get_matches();
 
     let port: u32 = matches.value_of("port").unwrap().parse().unwrap();
     let filename = matches.value_of("filename").unwrap();
     let bytes: usize = matches.value_of("bytes").unwrap().parse().unwrap();
+    let profile: Profile = matches.value_of("profile").unwrap().parse()
+        .unwrap();
     let accumulator_type = matches.value_of("accumulator").unwrap();
     let reset = matches.is_present("reset");
+    let request_bins = matches.is_present("bins");
     let accumulator_ssh = matches.values_of("accumulator-ssh").map(|ssh|
        ssh.collect());
     let router_ssh = matches.values_of("router-ssh").map(|ssh| ssh.collect());
     let drop: Option<usize> = matches.value_of("drop").map(|num|
         num.parse().unwrap());
+    let timeout: Option<u64> = matches.value_of("timeout").map(|secs|
+        secs.parse().unwrap());
+    let max_digests: Option<usize> = matches.value_of("max-digests").map(|num|
+        num.parse().unwrap());
+    let max_duplicates: usize = matches.value_of("max-duplicates").unwrap()
+        .parse().unwrap();
+    let epochs: Option<usize> = matches.value_of("epochs").map(|num|
+        num.parse().unwrap());
+    let epoch_len = Duration::from_millis(
+        matches.value_of("epoch-ms").unwrap().parse().unwrap());
+    let slack: usize = matches.value_of("slack").unwrap().parse().unwrap();
+    let search_start: Option<usize> = matches.value_of("search-start")
+        .map(|num| num.parse().unwrap());
+    let search_end: Option<usize> = matches.value_of("search-end")
+        .map(|num| num.parse().unwrap());
+    let skew: Option<Duration> = matches.value_of("skew-ms")
+        .map(|ms| Duration::from_millis(ms.parse().unwrap()));
+    let executor = match matches.value_of("threads").map(|num| num.parse()) {
+        Some(Ok(1)) => Executor::CurrentThread,
+        Some(Ok(threads)) => Executor::new(threads),
+        Some(Err(e)) => panic!("invalid number of threads: {:?}", e),
+        None => Executor::shared(),
+    };
 
     if let Some(acc_filename) = matches.value_of("check-acc-logs") {
         check_acc_logs(
@@ -406,51 +905,324 @@ fn main() {
             filename,
             acc_filename,
             bytes,
+            profile,
         )
+    } else if let Some(watch) = matches.subcommand_matches("watch") {
+        let store =
+            StateStore::open(Path::new(watch.value_of("state").unwrap()));
+        let mut state = store.load().unwrap_or_else(|| WatchState {
+            cursor: matches.value_of("index").unwrap().parse().unwrap(),
+            ..Default::default()
+        });
+        info!("watching from epoch {} at log entry {} (byte {})", state.epoch,
+            state.cursor, state.offset);
+        let mut options = match timeout {
+            Some(secs) => ValidationOptions::with_timeout(
+                Duration::from_secs(secs)),
+            None => ValidationOptions::default(),
+        };
+        options.max_digests = max_digests;
+        options.max_duplicates = max_duplicates;
+        options.executor = executor;
+        let mut validator = EpochValidator::new(slack);
+        if state.overlap > 0 {
+            validator.set_overlap(state.overlap);
+        } else {
+            validator.set_carried(std::mem::take(&mut state.carried));
+        }
+        loop {
+            // Unless the snapshot of this epoch was fetched before a restart.
+            let snapshot = store.snapshot(state.epoch, || {
+                std::thread::sleep(epoch_len);
+                let time = SystemTime::now().duration_since(UNIX_EPOCH)
+                    .unwrap().as_secs();
+                let (buf, _, span) = fetch_accumulator(accumulator_ssh.clone(),
+                    true, false, skew.is_some(), port);
+                Snapshot { time, buf, span }
+            });
+            let Snapshot { time, buf, span } = snapshot;
+            let sampled = SampledAccumulator::<dyn Accumulator>::from_bytes(
+                &buf, |bytes| get_accumulator(bytes, accumulator_type));
+            // The logs start at the cursor, which is at the saved byte offset
+            // unless no epoch consumed any logs yet.
+            let pkts_to_skip = if state.offset == 0 { state.cursor } else { 0 };
+            let (mut router_logs, mut timestamps, mut ends) =
+                get_router_logs_from(
+                    state.offset,
+                    pkts_to_skip,
+                    router_ssh.clone(),
+                    filename,
+                    bytes,
+                    profile,
+                    sampled.sampler(),
+                );
+            if let Some(drop) = drop {
+                drop_router_logs(
+                    drop, &mut router_logs, &mut timestamps, &mut ends);
+            }
+            let mut start = 0;
+            let end = align_epoch(&mut validator, state.epoch, span,
+                &timestamps, skew, &mut start)
+                .map_or(router_logs.len(), |end| std::cmp::max(start,
+                    std::cmp::min(router_logs.len(), end)));
+            options.digests = DigestCounter::new();
+            let t = Instant::now();
+            let report = validator.validate_epoch(
+                sampled.inner(), &router_logs[start..end], &options);
+            let record = EpochRecord {
+                epoch: state.epoch,
+                time,
+                start: state.cursor + start + report.start,
+                end: state.cursor + start + report.consumed + report.overlap,
+                received: report.received,
+                logged: report.logged,
+                carried: report.carried,
+                overlap: report.overlap,
+                verdict: verdict(&report.result),
+                result: format!("{:?}", report.result),
+                elapsed_ms: (Instant::now() - t).as_millis(),
+            };
+            println!("{}", record);
+            store.append_record(&record);
+            state.epoch += 1;
+            let consumed = start + report.consumed;
+            state.cursor += consumed;
+            if consumed > 0 {
+                state.offset = ends[consumed - 1];
+            }
+            state.carried = validator.carried().to_vec();
+            state.overlap = validator.overlap();
+            store.save(&state);
+        }
+    } else if let Some(n_epochs) = epochs {
+        let mut snapshots = vec![];
+        for epoch in 0..n_epochs {
+            if epoch > 0 {
+                std::thread::sleep(epoch_len);
+            }
+            let (buf, _, span) = fetch_accumulator(
+                accumulator_ssh.clone(), true, false, skew.is_some(), port);
+            snapshots.push((buf, span));
+        }
+        let sampler = SampledAccumulator::<dyn Accumulator>::from_bytes(
+            &snapshots[0].0, |bytes| get_accumulator(bytes, accumulator_type))
+            .sampler();
+        let start_index = matches.value_of("index").unwrap().parse().unwrap();
+        let (router_logs, timestamps) = get_router_logs(
+            start_index,
+            router_ssh,
+            filename,
+            bytes,
+            profile,
+            sampler,
+            drop,
+        );
+        let mut options = match timeout {
+            Some(secs) => ValidationOptions::with_timeout(
+                Duration::from_secs(secs)),
+            None => ValidationOptions::default(),
+        };
+        options.max_digests = max_digests;
+        options.max_duplicates = max_duplicates;
+        options.executor = executor;
+        let consumed = validate_epochs(&snapshots, accumulator_type,
+            &router_logs, &timestamps, slack, skew, &options);
+        info!("next start index would be {}", start_index + consumed);
     } else {
         let t1 = Instant::now();
-        let accumulator = get_accumulator(
-            accumulator_ssh,
-            reset,
-            port,
-            accumulator_type,
-        );
+        let (buf, bins, span) = fetch_accumulator(
+            accumulator_ssh, reset, request_bins, skew.is_some(), port);
+        // The logs are sampled when parsed, so validate the inner accumulator.
+        let sampled = SampledAccumulator::<dyn Accumulator>::from_bytes(
+            &buf, |bytes| get_accumulator(bytes, accumulator_type));
+        let sampler = sampled.sampler();
+        let accumulator = sampled.inner();
         let t2 = Instant::now();
         info!("get_accumulator: {:?}", t2 - t1);
         let start_index = matches.value_of("index").unwrap().parse().unwrap();
-        let router_logs = get_router_logs(
+        let (router_logs, timestamps) = get_router_logs(
             start_index,
             router_ssh,
             filename,
             bytes,
+            profile,
+            sampler,
             drop,
         );
         let t3 = Instant::now();
         info!("get_router_logs: {:?}", t3 - t2);
         info!("{}/{} packets received", accumulator.total(), router_logs.len());
-        assert!(accumulator.total() <= router_logs.len());
-        let valid = accumulator.validate(&router_logs).is_valid();
+        if accumulator.total() > router_logs.len() {
+            warn!("{} more packets received than logged",
+                accumulator.total() - router_logs.len());
+        }
+        if sampler.is_sampling() {
+            info!("sampled {:.2}% of packets: detects 1, 10, or 100 dropped \
+                or injected packets with probability {:.3}, {:.3}, {:.3}",
+                sampler.rate() * 100.0,
+                sampler.detection_probability(1),
+                sampler.detection_probability(10),
+                sampler.detection_probability(100));
+        }
+        let bounds = match span.zip(skew) {
+            Some((span, skew)) => {
+                let bounds = align_bounds(&span, &timestamps, skew);
+                if span.first().is_none() {
+                    warn!("no packets captured to align by timestamp");
+                } else if bounds.is_none() {
+                    warn!("the router logs end before the captured packets, \
+                        not aligning by timestamp");
+                }
+                bounds
+            },
+            None if search_start.is_some() || search_end.is_some() => {
+                Some(search_bounds(accumulator, router_logs.len(),
+                    search_start, search_end, max_duplicates))
+            },
+            None => None,
+        };
+        // Only the logs that could be in the window need to line up.
+        let candidates = match &bounds {
+            Some((starts, ends)) => {
+                let end = std::cmp::max(*starts.start(), *ends.end());
+                &router_logs[*starts.start()..end]
+            },
+            None => &router_logs[..],
+        };
+        let aligned = check_estimate(accumulator, candidates);
+        if !aligned {
+            warn!("the router logs do not line up with the digest, or the \
+                router injected packets");
+        }
+        let mut options = match timeout {
+            Some(secs) => ValidationOptions::with_timeout(
+                Duration::from_secs(secs)),
+            None => ValidationOptions::default(),
+        };
+        options.max_digests = max_digests;
+        options.max_duplicates = max_duplicates;
+        options.executor = executor.clone();
+        let mut window = None;
+        let result = match accumulator_type {
+            _ if bounds.is_some() => {
+                let (starts, ends) = bounds.unwrap();
+                let report = search_window(
+                    accumulator, &router_logs, starts, ends, &options);
+                info!("chosen window: log entries {}..{} after {} probes",
+                    start_index + report.start, start_index + report.end,
+                    report.probes);
+                window = Some((report.start, report.end));
+                report.result
+            },
+            "flow_iblt" | "flow_power_sum" => validate_flows(
+                SampledAccumulator::from_bytes(&buf, |bytes| {
+                    Box::new(FlowPartitionedAccumulator::from_bytes(bytes))
+                }).inner(),
+                &router_logs,
+                &options,
+            ),
+            _ => accumulator.validate_with_options(&router_logs, &options),
+        };
+        let valid = result.is_valid();
         if valid {
             info!("valid router");
+            if let ValidationResult::DuplicatesValid(duplicated) = &result {
+                info!("{} packets received more than once with DJB hashes \
+                    {:?}", duplicated.len(), duplicated);
+            }
+        } else if let ValidationResult::CharPolyInjected(injected) = &result {
+            warn!("invalid router: {} packets received but never logged \
+                with DJB hashes {:?}", injected.len(), injected);
+        } else if let ValidationResult::IbltSampledValid {
+                level, estimated_dropped } = result {
+            warn!("undetermined: only the packets sampled in level {} were \
+                validated, ~{} packets dropped", level, estimated_dropped);
+        } else if result == ValidationResult::BudgetExceeded {
+            warn!("undetermined: validation exceeded its budget");
         } else {
-            warn!("invalid router");
+            warn!("invalid router: {:?}", result);
+        }
+        if !valid && !result.is_undetermined() {
+            if let Some(bins) = &bins {
+                locate_misbehavior(bins, &router_logs, &timestamps,
+                    skew.unwrap_or(DEFAULT_SKEW));
+            }
         }
         let t4 = Instant::now();
         info!("validation: {:?}", t4 - t3);
         info!("TOTAL VERIFICATION TIME: {:?}", t4 - t1);
+        if !valid && !aligned {
+            warn!("misaligned or invalid router: is the start index correct?");
+            std::process::exit(1);
+        }
 
         if valid {
-            let num_truncated = check_truncation(&accumulator, &router_logs);
-            let t5 = Instant::now();
-            info!("truncated {}/{} packets: {:?}", num_truncated,
-                router_logs.len(), t5 - t4);
-            let num_dropped = router_logs.len() - accumulator.total() -
-                num_truncated;
+            // Duplicated packets were received but did not use up a log entry.
+            let num_duplicated = match &result {
+                ValidationResult::DuplicatesValid(duplicated) => duplicated.len(),
+                _ => 0,
+            };
+            // The searched window already excludes entries on both ends.
+            let (num_skipped, num_truncated) = match window {
+                Some((start, end)) => (start, router_logs.len() - end),
+                None => {
+                    let num_dropped = router_logs.len() + num_duplicated
+                        - accumulator.total();
+                    let num_truncated = check_truncation(
+                        accumulator, &router_logs, num_dropped,
+                        max_duplicates, &executor);
+                    let t5 = Instant::now();
+                    info!("truncated {}/{} packets: {:?}", num_truncated,
+                        router_logs.len(), t5 - t4);
+                    (0, num_truncated)
+                },
+            };
+            let num_dropped = router_logs.len() - num_skipped - num_truncated
+                + num_duplicated - accumulator.total();
             info!("probably dropped {} packets", num_dropped);
             info!("received {} packets", accumulator.total());
             info!("next start index would be {}, or {} if conservative",
                 start_index + router_logs.len() - num_truncated,
-                start_index + accumulator.total());
+                start_index + num_skipped + accumulator.total()
+                    - num_duplicated);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8], offset: usize, pkts_to_skip: usize)
+            -> (Vec<Vec<u8>>, Vec<Option<Duration>>, Vec<usize>) {
+        parse_router_logs(
+            data, offset, pkts_to_skip, 64, Profile::Raw, Sampler::all())
+    }
+
+    /// Resuming at the end of each packet parses the packets after it.
+    fn resume_test(data: &[u8], n_packets: usize) {
+        let (elems, timestamps, ends) = parse(data, 0, 0);
+        assert_eq!(elems.len(), n_packets);
+        assert_eq!(ends.last(), Some(&data.len()));
+        for i in 0..elems.len() {
+            let resumed = parse(data, ends[i], 0);
+            assert_eq!(resumed.0, elems[i + 1..]);
+            assert_eq!(resumed.1, timestamps[i + 1..]);
+            assert_eq!(resumed.2, ends[i + 1..]);
+            assert_eq!(parse(data, 0, i + 1), resumed);
+        }
+    }
+
+    #[test]
+    fn test_resume_pcap() {
+        resume_test(include_bytes!("../../digest/fixtures/ethernet.pcap"), 2);
+    }
+
+    #[test]
+    fn test_resume_pcapng() {
+        // The packets of the second interface need its description, which
+        // is in the header.
+        resume_test(
+            include_bytes!("../../digest/fixtures/ethernet_raw.pcapng"), 4);
+    }
+}
@@ -0,0 +1,236 @@
+//! The on-disk state of `verifier watch`, so a restarted verifier resumes at
+//! the epoch where it left off. The state store is a directory with:
+//! - `state`: the cursor into the router logs, its byte offset, and the
+//!   packets carried forward, replaced after each epoch
+//! - `records`: one record per epoch, appended after each epoch
+//! - `digests/<epoch>.digest`: the serialized snapshot of each epoch, saved
+//!   as soon as it is fetched, and `digests/<epoch>.span` with its capture
+//!   span if any
+//!
+//! The state is replaced only after the record of the epoch is appended, and
+//! names the epoch it is waiting on. If that epoch's snapshot was already
+//! saved when the verifier restarts, the accumulator was reset since, so the
+//! saved snapshot is validated instead of fetching a new one. An epoch
+//! interrupted before the state was replaced is thus validated again, and
+//! may be recorded twice.
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use accumulator::CaptureSpan;
+use serde::{Serialize, Deserialize};
+
+/// Where the next epoch starts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct WatchState {
+    /// The number of epochs validated so far.
+    pub epoch: usize,
+    /// Index of the router logs where the next epoch starts.
+    pub cursor: usize,
+    /// Byte offset in the router logs just past the entry before the cursor,
+    /// where parsing resumes. Zero if no epoch consumed any entries yet, in
+    /// which case parsing starts over and skips `cursor` entries.
+    pub offset: usize,
+    /// Logged packets carried forward from the previous epoch.
+    pub carried: Vec<Vec<u8>>,
+    /// Number of log entries before the cursor that may also belong to the
+    /// next epoch, if no packets were carried forward.
+    pub overlap: usize,
+}
+
+/// A snapshot of the accumulator, taken at the end of an epoch.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// When the snapshot was taken, in seconds since the UNIX epoch.
+    pub time: u64,
+    pub buf: Vec<u8>,
+    pub span: Option<CaptureSpan>,
+}
+
+/// The verdict on one epoch, written as a line of `key=value` pairs.
+#[derive(Debug)]
+pub struct EpochRecord {
+    pub epoch: usize,
+    /// When the snapshot was taken, in seconds since the UNIX epoch.
+    pub time: u64,
+    /// Indexes of the router logs in the epoch, not including the packets
+    /// carried from the previous epoch. The last `overlap` entries may also
+    /// belong to the next epoch.
+    pub start: usize,
+    pub end: usize,
+    pub received: usize,
+    pub logged: usize,
+    pub carried: usize,
+    pub overlap: usize,
+    pub verdict: &'static str,
+    pub result: String,
+    pub elapsed_ms: u128,
+}
+
+impl fmt::Display for EpochRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "epoch={} time={} start={} end={} received={} logged={} \
+            carried={} overlap={} verdict={} result={:?} elapsed_ms={}",
+            self.epoch, self.time, self.start, self.end, self.received,
+            self.logged, self.carried, self.overlap, self.verdict,
+            self.result, self.elapsed_ms)
+    }
+}
+
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    /// Opens the state store in the directory, creating it if needed.
+    pub fn open(dir: &Path) -> Self {
+        fs::create_dir_all(dir.join("digests")).unwrap();
+        Self { dir: dir.to_path_buf() }
+    }
+
+    /// The saved state, or None if nothing was saved yet.
+    pub fn load(&self) -> Option<WatchState> {
+        let bytes = fs::read(self.dir.join("state")).ok()?;
+        Some(bincode::deserialize(&bytes).unwrap())
+    }
+
+    /// Replaces the saved state. Writes to a temporary file first so an
+    /// interrupted write does not lose the previous state.
+    pub fn save(&self, state: &WatchState) {
+        let tmp = self.dir.join("state.tmp");
+        let mut f = File::create(&tmp).unwrap();
+        f.write_all(&bincode::serialize(state).unwrap()).unwrap();
+        f.sync_all().unwrap();
+        fs::rename(&tmp, self.dir.join("state")).unwrap();
+    }
+
+    /// The snapshot of the epoch. Fetches and saves it unless it was already
+    /// saved, e.g. before the verifier was interrupted.
+    pub fn snapshot<F>(&self, epoch: usize, fetch: F) -> Snapshot
+    where
+        F: FnOnce() -> Snapshot,
+    {
+        if let Some(snapshot) = self.load_snapshot(epoch) {
+            info!("resuming with the saved snapshot of epoch {}", epoch);
+            return snapshot;
+        }
+        let snapshot = fetch();
+        self.save_snapshot(epoch, &snapshot);
+        snapshot
+    }
+
+    fn load_snapshot(&self, epoch: usize) -> Option<Snapshot> {
+        let path = self.digest_path(epoch, "digest");
+        let buf = fs::read(&path).ok()?;
+        let time = fs::metadata(&path).unwrap().modified().unwrap()
+            .duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let span = fs::read(self.digest_path(epoch, "span")).ok()
+            .map(|bytes| bincode::deserialize(&bytes).unwrap());
+        Some(Snapshot { time, buf, span })
+    }
+
+    /// Saves the span before the digest, so a saved digest always has its
+    /// span, and renames the digest into place so it is never partial.
+    fn save_snapshot(&self, epoch: usize, snapshot: &Snapshot) {
+        if let Some(span) = &snapshot.span {
+            fs::write(self.digest_path(epoch, "span"),
+                bincode::serialize(span).unwrap()).unwrap();
+        }
+        let tmp = self.digest_path(epoch, "digest.tmp");
+        fs::write(&tmp, &snapshot.buf).unwrap();
+        fs::rename(&tmp, self.digest_path(epoch, "digest")).unwrap();
+    }
+
+    fn digest_path(&self, epoch: usize, extension: &str) -> PathBuf {
+        self.dir.join("digests").join(format!("{}.{}", epoch, extension))
+    }
+
+    pub fn append_record(&self, record: &EpochRecord) {
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("records"))
+            .unwrap();
+        writeln!(f, "{}", record).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    /// An empty state store in a temporary directory.
+    fn open_store(name: &str) -> StateStore {
+        let dir = std::env::temp_dir()
+            .join(format!("verifier-watch-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        StateStore::open(&dir)
+    }
+
+    fn gen_snapshot(time: u64) -> Snapshot {
+        let mut span = CaptureSpan::new();
+        span.process(Duration::from_secs(time));
+        Snapshot { time, buf: vec![1, 2, 3], span: Some(span) }
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let store = open_store("state");
+        assert!(store.load().is_none());
+        let state = WatchState {
+            epoch: 3,
+            cursor: 250,
+            offset: 4096,
+            carried: vec![vec![1, 2], vec![3]],
+            overlap: 0,
+        };
+        store.save(&state);
+        let loaded = store.load().unwrap();
+        assert_eq!((loaded.epoch, loaded.cursor, loaded.overlap), (3, 250, 0));
+        assert_eq!(loaded.offset, 4096);
+        assert_eq!(loaded.carried, state.carried);
+    }
+
+    #[test]
+    fn test_load_ignores_partial_save() {
+        let store = open_store("partial");
+        store.save(&WatchState { epoch: 1, ..Default::default() });
+        // interrupted while writing the next state
+        fs::write(store.dir.join("state.tmp"), [0xff; 5]).unwrap();
+        assert_eq!(store.load().unwrap().epoch, 1);
+        store.save(&WatchState { epoch: 2, ..Default::default() });
+        assert_eq!(store.load().unwrap().epoch, 2);
+    }
+
+    #[test]
+    fn test_resume_after_crash() {
+        let store = open_store("resume");
+        let fetches = &Cell::new(0);
+        let fetch = |time| move || {
+            fetches.set(fetches.get() + 1);
+            gen_snapshot(time)
+        };
+        let snapshot = store.snapshot(0, fetch(10));
+        assert_eq!(snapshot, gen_snapshot(10));
+        assert_eq!(fetches.get(), 1);
+
+        // Interrupted before the state was saved, so restarts at epoch 0 with
+        // the snapshot that was already fetched.
+        let store = StateStore::open(&store.dir);
+        assert!(store.load().is_none());
+        let snapshot = store.snapshot(0, fetch(20));
+        assert_eq!(snapshot.buf, vec![1, 2, 3]);
+        assert_eq!(snapshot.span, gen_snapshot(10).span);
+        assert_eq!(fetches.get(), 1);
+
+        // The next epoch is fetched.
+        store.save(&WatchState { epoch: 1, ..Default::default() });
+        let epoch = store.load().unwrap().epoch;
+        assert_eq!(store.snapshot(epoch, fetch(20)), gen_snapshot(20));
+        assert_eq!(fetches.get(), 2);
+    }
+}